@@ -9,20 +9,37 @@
 //! ```not_rust
 //! cd examples && cargo run -p sync-backend
 //! ```
+//!
+//! Listen address is set with `--listen`, either `tcp:host:port` (default `tcp:0.0.0.0:8080`)
+//! or `unix:/path/to.sock`:
+//!
+//! ```not_rust
+//! cd examples && cargo run -p sync-backend -- --listen unix:/tmp/sync-backend.sock
+//! ```
+//!
+//! `GET /healthz` returns backend status as JSON, for load balancer and orchestrator checks.
 
 use axum::{
     extract::{BodyStream, Path, State, Query},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     body,
     response,
     routing::get,
-    Router, Server,
+    Json, Router, Server,
 };
 use futures::StreamExt;
-use journal::{Journal, AsyncReadJournalStream, AsyncWriteJournalStream};
+use journal::{AsyncJournal, Journal, AsyncReadJournalStream, AsyncWriteJournalStream};
 use tokio::io::AsyncWriteExt;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
 
 fn to_error<T: std::fmt::Debug>(_e: T) -> StatusCode {
     StatusCode::INTERNAL_SERVER_ERROR
@@ -35,25 +52,55 @@ struct Params {
     snapshot_id: u64,
 }
 
+/// header, which identifies pushed snapshot range: `<domain>:<from_snapshot>:<to_snapshot>`
+const IDEMPOTENCY_KEY_HEADER: &str = "x-idempotency-key";
+
 /// post new journal snapshots
+///
+/// Push with the same idempotency key as the last applied one is a retry and is ignored.
 async fn post_snapshot(
     State(state): State<AppState>,
-    Path(_domain): Path<String>,
+    Path(domain): Path<String>,
+    headers: HeaderMap,
     mut stream: BodyStream,
 ) -> Result<&'static str, StatusCode> {
+    state.track_domain(domain);
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    if idempotency_key.is_some()
+        && *state.last_idempotency_key.lock().unwrap() == idempotency_key
+    {
+        return Ok("OK");
+    }
     let mut write_stream = AsyncWriteJournalStream::new(state.journal_path).spawn();
     while let Some(chunk) = stream.next().await {
         let chunk = chunk.map_err(to_error)?;
         write_stream.write_all(&chunk).await.map_err(to_error)?;
     };
+    // wait for stream to be applied, key is remembered only for applied pushes
+    write_stream.shutdown().await.map_err(to_error)?;
+    write_stream.join().await.map_err(to_error)?.map_err(to_error)?;
+    // push is acknowledged only once applied snapshots are on disk
+    AsyncJournal::try_from(state.journal_path)
+        .await
+        .map_err(to_error)?
+        .finalize()
+        .await
+        .map_err(to_error)?;
+    if idempotency_key.is_some() {
+        *state.last_idempotency_key.lock().unwrap() = idempotency_key;
+    }
     Ok("OK")
 }
 
 /// get latest knowns snapshot num
 async fn head_snapshot(
     State(state): State<AppState>,
-    Path(_domain): Path<String>,
+    Path(domain): Path<String>,
 ) -> Result<impl response::IntoResponse, StatusCode> {
+    state.track_domain(domain);
     let res = tokio::task::spawn_blocking(move ||{
         let journal = Journal::try_from(state.journal_path)
             .or_else(|_e| Journal::create(state.journal_path))?;
@@ -67,9 +114,10 @@ async fn head_snapshot(
 /// get new snapshots
 async fn get_snapshot(
     State(state): State<AppState>,
-    Path(_domain): Path<String>,
+    Path(domain): Path<String>,
     params: Option<Query<Params>>,
 ) -> Result<impl response::IntoResponse, StatusCode> {
+    state.track_domain(domain);
     let stream = AsyncReadJournalStream::new(
         state.journal_path,
         params.map(|p| p.snapshot_id).unwrap_or(0)
@@ -77,17 +125,131 @@ async fn get_snapshot(
     Ok(body::StreamBody::new(tokio_util::io::ReaderStream::new(stream)))
 }
 
+/// backend status
+#[derive(Debug, Serialize)]
+struct Health {
+    /// seconds since backend start
+    uptime_secs: u64,
+    /// distinct domains requested since backend start
+    domains: usize,
+    /// snapshots in journal
+    snapshots: u64,
+}
+
+/// liveness/readiness check
+///
+/// Missing journal is not an error, backend just didn't receive any snapshots yet.
+async fn healthz(State(state): State<AppState>) -> Result<Json<Health>, StatusCode> {
+    let journal_path = state.journal_path;
+    let res = tokio::task::spawn_blocking(move || match Journal::try_from(journal_path) {
+        Ok(journal) => Ok(journal.get_header().snapshot_counter),
+        Err(e) if e.journal_not_exists() => Ok(0),
+        Err(e) => Err(e),
+    });
+    let snapshots = res.await.map_err(to_error)?.map_err(to_error)?;
+    Ok(Json(Health {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        domains: state.domains.lock().unwrap().len(),
+        snapshots,
+    }))
+}
+
 #[derive(Debug, Clone)]
 struct AppState {
-    journal_path: &'static str
+    journal_path: &'static str,
+    last_idempotency_key: Arc<Mutex<Option<String>>>,
+    started_at: Instant,
+    domains: Arc<Mutex<HashSet<String>>>,
 }
 
 impl AppState {
     fn new() -> Self {
+        Self::with_journal_path("/tmp/journal")
+    }
+
+    fn with_journal_path(journal_path: &'static str) -> Self {
         Self {
-            journal_path: "/tmp/journal"
+            journal_path,
+            last_idempotency_key: Arc::new(Mutex::new(None)),
+            started_at: Instant::now(),
+            domains: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// remember domain, requested by client
+    fn track_domain(&self, domain: String) {
+        self.domains.lock().unwrap().insert(domain);
+    }
+}
+
+/// Address backend listens on
+#[derive(Debug, Clone, PartialEq)]
+enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(path.into())),
+            Some(("tcp", addr)) => addr
+                .to_socket_addrs()
+                .map_err(|e| format!("invalid tcp address {addr:?}: {e}"))?
+                .next()
+                .map(Self::Tcp)
+                .ok_or_else(|| format!("tcp address {addr:?} resolved to nothing")),
+            _ => Err(format!("expected unix:/path/to.sock or tcp:host:port, got {s:?}")),
+        }
+    }
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self::Tcp(SocketAddr::from(([0, 0, 0, 0], 8080)))
+    }
+}
+
+/// parse `--listen <address>` from command line
+fn parse_listen() -> Result<Listen, String> {
+    let mut args = std::env::args().skip(1);
+    let mut listen = Listen::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => {
+                listen = args.next().ok_or("--listen requires an address")?.parse()?;
+            }
+            other => return Err(format!("unexpected argument {other:?}")),
         }
     }
+    Ok(listen)
+}
+
+fn app(state: AppState) -> Router {
+    Router::new()
+        .route("/domain/:domain", get(get_snapshot).head(head_snapshot).post(post_snapshot))
+        .route("/healthz", get(healthz))
+        .with_state(state)
+}
+
+async fn serve(
+    listen: Listen,
+    app: Router,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tracing::debug!("listening on {:?}", listen);
+    match listen {
+        Listen::Tcp(addr) => Server::bind(&addr).serve(app.into_make_service()).await?,
+        Listen::Unix(path) => {
+            // socket file left behind by previous run
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)?;
+            let accept = hyper::server::accept::from_stream(UnixListenerStream::new(listener));
+            Server::builder(accept).serve(app.into_make_service()).await?
+        }
+    };
+    Ok(())
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -100,14 +262,214 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let app = Router::new()
-        .route("/domain/:domain", get(get_snapshot).head(head_snapshot).post(post_snapshot))
-        .with_state(AppState::new());
+    let listen = match parse_listen() {
+        Ok(listen) => listen,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+    serve(listen, app(AppState::new())).await.unwrap()
+}
 
-    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], 8080));
-    tracing::debug!("listening on {:?}", addr);
-    Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{Method, Request};
+    use hyper::client::conn::SendRequest;
+    use journal::{Header, Stream, JOURNAL_VERSION};
+    use mycelite::test_support::TestDatabase;
+    use std::io::{Cursor, Read};
+    use std::time::Duration;
+
+    #[test]
+    fn test_listen_parse() {
+        assert_eq!(
+            "unix:/tmp/backend.sock".parse(),
+            Ok(Listen::Unix("/tmp/backend.sock".into()))
+        );
+        assert_eq!(
+            "tcp:127.0.0.1:8080".parse(),
+            Ok(Listen::Tcp(([127, 0, 0, 1], 8080).into()))
+        );
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!("127.0.0.1:8080".parse::<Listen>().is_err());
+    }
+
+    /// serve backend over unix socket in given dir, returning connected client
+    async fn connect(dir: &std::path::Path) -> SendRequest<body::Body> {
+        let socket_path = dir.join("backend.sock");
+        let journal_path = dir.join("journal").to_str().unwrap().to_owned();
+        let state = AppState::with_journal_path(Box::leak(journal_path.into_boxed_str()));
+        tokio::spawn(serve(Listen::Unix(socket_path.clone()), app(state)));
+
+        let stream = loop {
+            match tokio::net::UnixStream::connect(&socket_path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        let (sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    async fn head_snapshot_id(sender: &mut SendRequest<body::Body>) -> String {
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri("/domain/test")
+            .body(body::Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.headers()["x-snapshot-id"].to_str().unwrap().to_owned()
+    }
+
+    async fn push(sender: &mut SendRequest<body::Body>, key: &str, stream: Vec<u8>) -> StatusCode {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/domain/test")
+            .header(IDEMPOTENCY_KEY_HEADER, key)
+            .body(body::Body::from(stream))
+            .unwrap();
+        sender.send_request(request).await.unwrap().status()
+    }
+
+    /// protocol stream of snapshots from `from` up to `to`, exclusive
+    fn snapshots(from: u64, to: u64) -> Vec<u8> {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for i in 0..to {
+            journal.new_snapshot(4096).unwrap();
+            journal.new_blob(i * 4096, &[i as u8; 4096]).unwrap();
+            journal.commit().unwrap();
+        }
+        let mut stream = vec![];
+        Stream::from((JOURNAL_VERSION, journal.into_iter().skip_snapshots(from)))
+            .read_to_end(&mut stream)
+            .unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_head_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sender = connect(dir.path()).await;
+        assert_eq!(head_snapshot_id(&mut sender).await, "0");
+    }
+
+    async fn get_health(sender: &mut SendRequest<body::Body>) -> serde_json::Value {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri("/healthz")
+            .body(body::Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sender = connect(dir.path()).await;
+        let health = get_health(&mut sender).await;
+        assert!(health["uptime_secs"].is_u64());
+        assert_eq!(health["domains"], 0);
+        assert_eq!(health["snapshots"], 0);
+
+        assert_eq!(push(&mut sender, "test:0:2", snapshots(0, 2)).await, StatusCode::OK);
+        let health = get_health(&mut sender).await;
+        assert_eq!(health["domains"], 1);
+        assert_eq!(health["snapshots"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_retried_push_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sender = connect(dir.path()).await;
+        assert_eq!(push(&mut sender, "test:0:2", snapshots(0, 2)).await, StatusCode::OK);
+        assert_eq!(head_snapshot_id(&mut sender).await, "2");
+        let journal = std::fs::read(dir.path().join("journal")).unwrap();
+
+        // retry of the same range is a no-op
+        assert_eq!(push(&mut sender, "test:0:2", snapshots(0, 2)).await, StatusCode::OK);
+        assert_eq!(head_snapshot_id(&mut sender).await, "2");
+        assert_eq!(std::fs::read(dir.path().join("journal")).unwrap(), journal);
+
+        assert_eq!(push(&mut sender, "test:2:3", snapshots(2, 3)).await, StatusCode::OK);
+        assert_eq!(head_snapshot_id(&mut sender).await, "3");
+    }
+
+    /// serve backend over tcp on free local port, returning its endpoint
+    fn serve_tcp(rt: &tokio::runtime::Runtime, dir: &std::path::Path) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let journal_path = dir.join("journal").to_str().unwrap().to_owned();
+        let state = AppState::with_journal_path(Box::leak(journal_path.into_boxed_str()));
+        let _guard = rt.enter();
+        let server = Server::from_tcp(listener).unwrap().serve(app(state).into_make_service());
+        rt.spawn(server);
+        endpoint
+    }
+
+    /// poll `check`, until it holds or timeout elapses
+    fn wait_for(mut check: impl FnMut() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(30) {
+            if check() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    // writer with async replicator pushes snapshots, reader pulls and restores them
+    #[test]
+    fn test_async_replicator_round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = serve_tcp(&rt, dir.path());
+        let backend_snapshots = || match Journal::try_from(dir.path().join("journal")) {
+            Ok(journal) => journal.get_header().snapshot_counter,
+            Err(_) => 0,
+        };
+
+        let writer_db = TestDatabase::new();
+        let writer = writer_db.writer().unwrap();
+        // replication starts, once domain is set
+        for (key, value) in [
+            ("client_id", "client"),
+            ("secret", "secret"),
+            ("endpoint", endpoint.as_str()),
+            ("domain", "test"),
+        ] {
+            TestDatabase::configure(&writer, key, value).unwrap();
+        }
+        writer
+            .execute_batch("CREATE TABLE t(x INTEGER); INSERT INTO t VALUES (1), (2);")
+            .unwrap();
+        assert!(wait_for(|| backend_snapshots() == writer_db.snapshot_count()));
+
+        let reader_db = TestDatabase::new();
+        let reader = reader_db.reader().unwrap();
+        for (key, value) in [("endpoint", endpoint.as_str()), ("domain", "test")] {
+            TestDatabase::configure(&reader, key, value).unwrap();
+        }
+        let count = || {
+            reader
+                .query_row("SELECT count(*) FROM t", [], |row| row.get::<_, i64>(0))
+                .ok()
+        };
+        assert!(wait_for(|| count() == Some(2)));
+
+        // later snapshots are pulled on top of restored ones
+        writer.execute("INSERT INTO t VALUES (3)", []).unwrap();
+        assert!(wait_for(|| backend_snapshots() == writer_db.snapshot_count()));
+        assert!(wait_for(|| count() == Some(3)));
+        writer.close().unwrap();
+        reader.close().unwrap();
+    }
 }
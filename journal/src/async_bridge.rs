@@ -1,14 +1,20 @@
 //! Temporary async wrapping to sync journal
 
-use crate::{Error as JournalError, Journal, Protocol, Stream as JournalStream};
+use crate::journal::{DigestCursor, DuplicateSnapshot};
+use crate::{Error as JournalError, Journal, Protocol, Stream as JournalStream, JOURNAL_VERSION};
 use serde_sqlite::de;
 use tokio::sync::mpsc::error::TrySendError;
-use std::io::{BufRead, Read, Write};
+use std::io::{BufRead, Read};
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Default amount of buffers in flight between blocking worker and async handle
+const DEFAULT_CHANNEL_DEPTH: usize = 1;
 
 fn to_err<E: std::error::Error + Send + Sync + 'static>(err: E) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, err)
@@ -28,50 +34,57 @@ impl AsyncReadJournalStream {
     }
 
     pub fn spawn(self) -> AsyncReadJournalStreamHandle {
-        let (waker_tx, mut waker_rx) = channel::<Waker>(1);
-        let (mut buffer_tx, buffer_rx) = channel::<Vec<u8>>(1);
-        let join_handle =
-            tokio::task::spawn_blocking(move || self.enter_loop(&mut waker_rx, &mut buffer_tx));
+        self.spawn_with_depth(DEFAULT_CHANNEL_DEPTH)
+    }
+
+    /// Spawn blocking worker, which reads up to `depth` buffers ahead of the handle
+    pub fn spawn_with_depth(self, depth: usize) -> AsyncReadJournalStreamHandle {
+        let (mut buffer_tx, buffer_rx) = channel::<Vec<u8>>(depth.max(1));
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let worker_buffered = Arc::clone(&buffered);
+        let join_handle = tokio::task::spawn_blocking(move || {
+            self.enter_loop(&mut buffer_tx, worker_buffered.as_ref())
+        });
         AsyncReadJournalStreamHandle {
-            tx: waker_tx,
             rx: buffer_rx,
             buf: None,
             read: 0,
+            buffered,
             join_handle,
         }
     }
 
+    /// Read journal stream into channel, until EOF or until handle is dropped
+    ///
+    /// EOF is signaled by empty buffer.
     pub fn enter_loop(
         self,
-        rx: &mut Receiver<Waker>,
         tx: &mut Sender<Vec<u8>>,
+        buffered: &AtomicUsize,
     ) -> Result<(), JournalError> {
         let mut journal = Journal::try_from(self.journal_path.as_path())?;
-        let version = journal.get_header().version;
         let mut stream = JournalStream::new(
-            journal.into_iter().skip_snapshots(self.snapshot_id),
-            version,
+            journal
+                .into_iter()
+                .stored()
+                .skip_snapshots(self.snapshot_id),
+            JOURNAL_VERSION,
         );
 
-        while let Some(waker) = rx.blocking_recv() {
+        loop {
             let mut buf = Vec::<u8>::with_capacity(0x0001_0000); // 65kb buffer
             unsafe { buf.set_len(buf.capacity()) };
-            let read = match stream.read(buf.as_mut_slice()) {
-                Ok(read) => read,
-                Err(e) => {
-                    waker.wake();
-                    return Err(e.into());
-                }
-            };
+            let read = stream.read(buf.as_mut_slice())?;
             unsafe { buf.set_len(read) };
-            let res = tx.blocking_send(buf);
-            waker.wake();
-            if let Err(tokio::sync::mpsc::error::SendError(_)) = res {
+            buffered.fetch_add(1, Ordering::Relaxed);
+            if let Err(tokio::sync::mpsc::error::SendError(_)) = tx.blocking_send(buf) {
                 let err = std::io::Error::new(std::io::ErrorKind::Other, "channel closed");
                 return Err(err.into());
             }
+            if read == 0 {
+                return Ok(());
+            }
         }
-        Ok(())
     }
 }
 
@@ -80,11 +93,16 @@ pub struct AsyncReadJournalStreamHandle {
     buf: Option<Vec<u8>>,
     read: usize,
     rx: Receiver<Vec<u8>>,
-    tx: Sender<Waker>,
+    buffered: Arc<AtomicUsize>,
     join_handle: tokio::task::JoinHandle<Result<(), JournalError>>,
 }
 
 impl AsyncReadJournalStreamHandle {
+    /// Amount of buffers read ahead by worker, but not yet received by handle
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
     pub async fn join(self) -> Result<Result<(), JournalError>, tokio::task::JoinError> {
         self.join_handle.await
     }
@@ -98,19 +116,19 @@ impl AsyncRead for AsyncReadJournalStreamHandle {
     ) -> Poll<std::io::Result<()>> {
         let p = self.get_mut();
         if p.buf.is_none() {
-            match p.rx.try_recv() {
-                // EOF
-                Ok(buf) if buf.is_empty() => return Poll::Ready(Ok(())),
-                Ok(buf) => {
+            match p.rx.poll_recv(ctx) {
+                Poll::Ready(Some(buf)) => {
+                    p.buffered.fetch_sub(1, Ordering::Relaxed);
+                    // EOF
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
                     p.buf = Some(buf);
                     p.read = 0;
                 }
                 // stream thread quit, FIXME: distinction between thread error and EOF
-                Err(TryRecvError::Disconnected) => return Poll::Ready(Ok(())),
-                Err(TryRecvError::Empty) => {
-                    p.tx.try_send(ctx.waker().clone()).map_err(to_err)?;
-                    return Poll::Pending;
-                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
             }
         }
 
@@ -121,8 +139,7 @@ impl AsyncRead for AsyncReadJournalStreamHandle {
             len if len == start => {
                 // inner buf was read to the end
                 p.buf = None;
-                p.tx.try_send(ctx.waker().clone()).map_err(to_err)?;
-                Poll::Pending
+                Pin::new(p).poll_read(ctx, buf)
             }
             len if len > end => {
                 // inner buf have enough data to fill incoming buf to the end
@@ -152,6 +169,8 @@ pub struct ReadReceiver {
     buf: Vec<u8>,
     buf_pos: usize,
     waker: Option<Waker>,
+    /// writer shut down, no data will arrive after current buffer
+    shutdown: bool,
     rx: Receiver<AsyncWriteProto>,
 }
 
@@ -161,9 +180,15 @@ impl ReadReceiver {
             buf: vec![],
             buf_pos: 0,
             waker: None,
+            shutdown: false,
             rx,
         }
     }
+
+    /// Check if writer shut down and all received data was consumed
+    fn is_exhausted(&self) -> bool {
+        self.shutdown && self.buf_pos == self.buf.len()
+    }
 }
 
 impl BufRead for ReadReceiver {
@@ -174,6 +199,9 @@ impl BufRead for ReadReceiver {
             self.buf_pos = 0;
             self.buf.clear();
         }
+        if self.shutdown {
+            return Ok(&[]);
+        }
 
         loop {
             match self.rx.blocking_recv() {
@@ -185,6 +213,7 @@ impl BufRead for ReadReceiver {
                 },
                 Some(AsyncWriteProto::Shutdown(waker)) => {
                     self.waker = Some(waker);
+                    self.shutdown = true;
                     break;
                 },
                 None => {
@@ -204,27 +233,19 @@ impl BufRead for ReadReceiver {
 }
 
 impl Read for ReadReceiver {
+    /// Read is partial: only data of already received buffer is returned.
+    ///
+    /// Zero-sized read means writer shut down, callers relying on `read_exact` get
+    /// `UnexpectedEof` if shutdown arrives mid-frame.
     fn read(&mut self, write_buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut total = 0;
-        let mut write_buf_len = write_buf.len();
-        let mut write_buf = std::io::Cursor::new(write_buf);
-        loop {
-            if write_buf_len == 0 {
-                break;
-            };
-            let mut read_buf = self.fill_buf()?;
-            if read_buf.is_empty() {
-                break;
-            }
-            if read_buf.len() >= write_buf_len {
-                read_buf = &read_buf[..write_buf_len];
-            }
-            let written = write_buf.write(read_buf)?;
-            total += written;
-            write_buf_len -= written;
-            self.consume(written)
+        if write_buf.is_empty() {
+            return Ok(0);
         }
-        Ok(total)
+        let read_buf = self.fill_buf()?;
+        let read = read_buf.len().min(write_buf.len());
+        write_buf[..read].copy_from_slice(&read_buf[..read]);
+        self.consume(read);
+        Ok(read)
     }
 }
 
@@ -254,49 +275,81 @@ impl AsyncWriteJournalStream {
         }
     }
 
-    pub fn spawn(mut self) -> AsyncWriteJournalStreamHandle {
-        let (tx, rx) = channel(1); // enough space to store waker and buf
+    pub fn spawn(self) -> AsyncWriteJournalStreamHandle {
+        self.spawn_with_depth(DEFAULT_CHANNEL_DEPTH)
+    }
+
+    /// Spawn blocking worker, handle accepts up to `depth` writes ahead of the worker
+    pub fn spawn_with_depth(mut self, depth: usize) -> AsyncWriteJournalStreamHandle {
+        let (tx, rx) = channel(depth.max(1)); // each message carries waker and buf
         let read_receiver = ReadReceiver::new(rx);
         let join_handle = tokio::task::spawn_blocking(move || self.enter_loop(read_receiver));
         AsyncWriteJournalStreamHandle { tx, join_handle }
     }
 
+    /// Apply stream to journal
+    ///
+    /// Stream which ends before EndOfStream, including shutdown in the middle of a frame, fails
+    /// with [`JournalError::MissingEndOfStream`]. Snapshot in progress is left uncommitted.
+    /// Stream snapshot, which journal has already, has to match journal snapshot, otherwise
+    /// stream fails with [`JournalError::SnapshotDivergence`].
     pub fn enter_loop(&mut self, mut read_receiver: ReadReceiver) -> Result<(), JournalError> {
         let mut journal = match Journal::try_from(self.journal_path.as_path()) {
             Ok(j) => j,
             Err(e) if e.journal_not_exists() => Journal::create(self.journal_path.as_path())?,
             Err(e) => return Err(e),
         };
+        match Self::apply(&mut journal, &mut read_receiver) {
+            Err(_) if read_receiver.is_exhausted() => Err(JournalError::MissingEndOfStream),
+            res => res,
+        }
+    }
 
-        let expected = Protocol::JournalVersion(1.into());
-        match de::from_reader::<Protocol, _>(&mut read_receiver).map_err(to_err)? {
-            msg if msg == expected => (),
+    fn apply(journal: &mut Journal, read_receiver: &mut ReadReceiver) -> Result<(), JournalError> {
+        // streams of older journal versions are accepted, see Journal::apply_stream
+        let version = match de::from_reader::<Protocol, _>(&mut *read_receiver).map_err(to_err)? {
+            Protocol::JournalVersion(v) if (1..=JOURNAL_VERSION).contains(&u32::from(v)) => {
+                u32::from(v)
+            }
             other => {
                 let err = std::io::Error::new(
                     std::io::ErrorKind::Other,
-                    format!("expected {}, got: {}", expected, other),
+                    format!("expected JournalVersion(1..={JOURNAL_VERSION}), got: {other}"),
                 );
                 return Err(err.into());
             }
-        }
+        };
+        // blobs of duplicate snapshot are hashed instead of written, divergence is reported as is
+        let mut duplicate = None;
+        let mut cursor = DigestCursor::default();
         loop {
-            match de::from_reader::<Protocol, _>(&mut read_receiver).map_err(to_err)? {
+            match Protocol::read_frame(&mut *read_receiver, version).map_err(to_err)? {
                 Protocol::SnapshotHeader(snapshot_header) => {
+                    duplicate.take().map(DuplicateSnapshot::verify).transpose()?;
                     journal.commit().map_err(to_err)?;
-                    journal.add_snapshot(&snapshot_header).map_err(to_err)?;
+                    duplicate = journal
+                        .add_stream_snapshot(&snapshot_header, &mut cursor)
+                        .map_err(to_err)?;
                 }
                 Protocol::BlobHeader(blob_header) => {
-                    let mut blob = vec![0; blob_header.blob_size as usize];
+                    blob_header.validate().map_err(to_err)?;
+                    let mut blob = vec![0; blob_header.stored_size() as usize];
                     read_receiver
                         .read_exact(blob.as_mut_slice())
                         .map_err(to_err)?;
-                    journal
-                        .add_blob(&blob_header, blob.as_slice())
-                        .map_err(to_err)?;
+                    match duplicate.as_mut() {
+                        Some(duplicate) => duplicate
+                            .digest
+                            .update(&blob_header, &blob)
+                            .map_err(to_err)?,
+                        None => journal
+                            .add_blob(&blob_header, blob.as_slice())
+                            .map_err(to_err)?,
+                    }
                 }
                 Protocol::EndOfStream(_) => {
+                    duplicate.take().map(DuplicateSnapshot::verify).transpose()?;
                     journal.commit().map_err(to_err)?;
-                    drop(journal);
                     return Ok(());
                 }
                 msg => {
@@ -1,9 +1,13 @@
 use crate::error::Error;
-use crate::{journal::DEFAULT_BUFFER_SIZE, BlobHeader, Header, SnapshotHeader};
+use crate::{
+    journal::{Timestamps, DEFAULT_BUFFER_SIZE, JOURNAL_VERSION, LAST_BLOB_FLAG},
+    system_clock, BlobHeader, Clock, Compression, Header, JournalVersion, Protocol, SnapshotHeader,
+};
 use async_stream::try_stream;
 use block::Block;
 
-use futures::Stream;
+use futures::{pin_mut, Stream, StreamExt};
+use std::time::{Duration, Instant};
 use std::{path, pin::Pin};
 
 use serde_sqlite::{from_bytes, to_bytes};
@@ -25,8 +29,28 @@ where
     fd: F,
     /// snapshot page count
     blob_count: Option<u32>,
+    /// page size of current snapshot
+    page_size: Option<u32>,
+    /// timestamp of current snapshot
+    snapshot_timestamp: Option<i64>,
+    /// Timestamp of last read snapshot header, relative timestamp of next one is based on it
+    prev_timestamp: Option<i64>,
     /// Buffer size
     buffer_sz: usize,
+    /// Header is written once per given amount of commits
+    batch_size: usize,
+    /// Header is written once given time elapsed since first batched commit
+    batch_window: Option<Duration>,
+    /// Commits with header not written yet
+    pending_commits: usize,
+    /// Time of first pending commit
+    batch_started: Option<Instant>,
+    /// Position of first blob header of current snapshot
+    first_blob_position: Option<u64>,
+    /// Position of implied terminator, following last read blob of single blob snapshot
+    implied_terminator: Option<u64>,
+    /// Timestamps of new snapshots
+    timestamps: Timestamps,
 }
 
 impl AsyncJournal<tokio::fs::File> {
@@ -49,13 +73,26 @@ impl AsyncJournal<tokio::fs::File> {
             .open(p)
             .await?;
         let header = Self::read_header(&mut fd).await?;
+        header.check_blob_alignment()?;
         Ok(Self::from(header, fd, None))
     }
+
+    /// Commit snapshot in progress, write header of batched commits and sync file to disk
+    ///
+    /// Journal is consumed, once it returns, ingested snapshots survive crash of the host.
+    pub async fn finalize(mut self) -> Result<()> {
+        self.commit().await?;
+        self.flush().await?;
+        self.fd.flush().await?;
+        self.fd.sync_all().await?;
+        Ok(())
+    }
 }
 
 impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F> {
     /// Instantiate journal & force header write
     pub async fn new(header: Header, mut fd: F, blob_count: Option<u32>) -> Result<Self> {
+        header.check_blob_alignment()?;
         Self::write_header(Box::pin(&mut fd), &header).await?;
         Ok(Self::from(header, fd, blob_count))
     }
@@ -64,11 +101,33 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
         Self {
             header,
             blob_count,
+            page_size: None,
+            snapshot_timestamp: None,
+            prev_timestamp: None,
             buffer_sz: DEFAULT_BUFFER_SIZE,
+            batch_size: 1,
+            batch_window: None,
+            pending_commits: 0,
+            batch_started: None,
+            first_blob_position: None,
+            implied_terminator: None,
+            timestamps: Timestamps::new(system_clock),
             fd,
         }
     }
 
+    /// Batch commits
+    ///
+    /// Header is written once per `size` commits, or on first commit after `window` elapsed
+    /// since first batched commit, whichever comes first.
+    /// Batched commits are not visible to other journal readers until header is written,
+    /// see [`AsyncJournal::flush`], and are lost if journal is dropped before that, so ingestion
+    /// should flush on EndOfStream.
+    pub fn set_commit_batch(&mut self, size: usize, window: Option<Duration>) {
+        self.batch_size = size.max(1);
+        self.batch_window = window;
+    }
+
     /// Set buffer size
     pub fn set_buffer_size(&mut self, buffer_sz: usize) {
         self.buffer_sz = buffer_sz;
@@ -79,6 +138,11 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
         self.buffer_sz
     }
 
+    /// Set source of timestamps of new snapshots, see [`crate::Journal::set_clock`]
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.timestamps.set_clock(clock);
+    }
+
     /// Initiate new snapshot
     ///
     /// * update journal header to correctly setup offset
@@ -89,10 +153,14 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
         if self.blob_count.is_some() {
             return Ok(());
         }
-        self.update_header().await?;
+        if page_size == 0 {
+            return Err(Error::InvalidPageSize { page_size });
+        }
+        self.refresh_header().await?;
+        self.header.check_page_size(page_size)?;
         let snapshot_header = SnapshotHeader::new(
             self.header.snapshot_counter,
-            chrono::Utc::now().timestamp_micros(),
+            self.timestamps.next(self.header.last_timestamp),
             Some(page_size),
         );
         self.write_snapshot(&snapshot_header).await
@@ -103,7 +171,7 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
     ///
     /// Re-syncs journal header
     pub async fn add_snapshot(&mut self, snapshot_header: &SnapshotHeader) -> Result<()> {
-        self.update_header().await?;
+        self.refresh_header().await?;
         self.write_snapshot(snapshot_header).await
     }
 
@@ -125,29 +193,63 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
                 blob_count: self.blob_count,
             });
         }
+        blob_header.validate()?;
+        if self.header.undo_log {
+            return Err(Error::InvalidPreImage);
+        }
+        // async journal doesn't compress blobs, compressed blobs from stream are stored as is
+        let (blob_header, blob) = self.header.stored_blob(blob_header, blob, Compression::None)?;
+        let blob_header = &blob_header;
         self.blob_count.as_mut().map(|x| {
             *x += 1;
             *x
         });
-        self.fd.write_all(&to_bytes(blob_header)?).await?;
-        self.fd.write_all(blob).await?;
+        self.write_blob_padding().await?;
+        if blob_header.blob_num == 0 {
+            self.first_blob_position = Some(self.fd.stream_position().await?);
+        }
+        self.fd.write_all(&self.header.blob_header_to_bytes(blob_header)?).await?;
+        self.fd.write_all(&blob).await?;
+        Ok(())
+    }
+
+    /// Write zero padding, so blob following blob header is aligned
+    async fn write_blob_padding(&mut self) -> Result<()> {
+        if self.header.blob_alignment.is_none() {
+            return Ok(());
+        }
+        let padding = self.header.blob_padding(self.fd.stream_position().await?);
+        self.fd.write_all(&vec![0; padding as usize]).await?;
         Ok(())
     }
 
+    /// Read blob header, skipping padding in front of it
+    ///
+    /// Terminator of single blob snapshot, implied by last blob flag, is returned without read.
     pub async fn read_blob_header(&mut self) -> Result<BlobHeader> {
-        let mut buf: Vec<u8> = Vec::with_capacity(BlobHeader::block_size());
-        self.fd.read_buf(&mut buf).await?;
-        from_bytes::<BlobHeader>(&buf).map_err(Into::into)
+        if let Some(position) = self.implied_terminator.take() {
+            if position == self.fd.stream_position().await? {
+                return Ok(BlobHeader::last());
+            }
+        }
+        if self.header.blob_alignment.is_some() {
+            let padding = self.header.blob_padding(self.fd.stream_position().await?);
+            self.fd.seek(SeekFrom::Current(padding as i64)).await?;
+        }
+        let buf = read_exact_buf(&mut self.fd, self.header.blob_header_size()).await?;
+        let mut blob_header = self.header.blob_header_from_bytes(&buf)?;
+        if self.header.implicit_terminators && blob_header.blob_num & LAST_BLOB_FLAG != 0 {
+            blob_header.blob_num &= !LAST_BLOB_FLAG;
+            let end = self.fd.stream_position().await?
+                + self.header.blob_record_size(&blob_header) as u64;
+            self.implied_terminator = Some(end);
+        }
+        Ok(blob_header)
     }
 
+    /// Read blob of given size, short reads are retried until whole blob is read
     pub async fn read_blob(&mut self, size: u32) -> Result<Vec<u8>> {
-        if size == 0 {
-            let result: Vec<u8> = Vec::new();
-            return Ok(result);
-        }
-        let mut buf: Vec<u8> = Vec::with_capacity(size as usize);
-        self.fd.read_buf(&mut buf).await?;
-        Ok(buf)
+        read_exact_buf(&mut self.fd, size as usize).await
     }
 
     fn snapshot_started(&self) -> bool {
@@ -156,7 +258,8 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
 
     /// Commit snapshot
     ///
-    /// * write final empty page to indicate end of snapshot
+    /// * write final empty page to indicate end of snapshot, or mark the only blob of snapshot
+    ///   as last, if journal has implicit terminators
     /// * flush bufwriter (seek() on BufWriter will force flush)
     /// * write new header
     /// * flush bufwriter
@@ -165,15 +268,55 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
         if !self.snapshot_started() {
             return Ok(());
         }
-        // commit snapshot by writting final empty page
-        self.fd.write_all(&to_bytes(&BlobHeader::last())?).await?;
+        let first_blob_position = self.first_blob_position.take();
+        match (
+            self.header.implicit_terminators,
+            self.blob_count,
+            first_blob_position,
+        ) {
+            (true, Some(1), Some(position)) => {
+                let end = self.fd.stream_position().await?;
+                self.fd.seek(SeekFrom::Start(position + 8)).await?;
+                self.fd.write_all(&LAST_BLOB_FLAG.to_be_bytes()).await?;
+                self.fd.seek(SeekFrom::Start(end)).await?;
+            }
+            _ => {
+                // commit snapshot by writting final empty page
+                self.write_blob_padding().await?;
+                self.fd.write_all(&self.header.blob_header_to_bytes(&BlobHeader::last())?).await?;
+            }
+        }
         self.blob_count = None;
 
         self.header.snapshot_counter += 1;
+        self.header.page_size = self.page_size.take().or(self.header.page_size);
+        self.header.last_timestamp = self
+            .snapshot_timestamp
+            .take()
+            .unwrap_or(self.header.last_timestamp);
         self.header.eof = self.fd.stream_position().await?;
 
+        self.pending_commits += 1;
+        let batch_started = *self.batch_started.get_or_insert_with(Instant::now);
+        let window_elapsed = self
+            .batch_window
+            .map(|window| batch_started.elapsed() >= window)
+            .unwrap_or(false);
+        if self.pending_commits >= self.batch_size || window_elapsed {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Write header of batched commits, if any
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.pending_commits == 0 {
+            return Ok(());
+        }
         Self::write_header(Box::pin(&mut self.fd), &self.header).await?;
         self.fd.flush().await?;
+        self.pending_commits = 0;
+        self.batch_started = None;
         Ok(())
     }
 
@@ -185,8 +328,7 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
         fd: &mut R,
     ) -> Result<Header> {
         fd.rewind().await?;
-        let mut buf = Vec::with_capacity(Header::block_size());
-        fd.read_buf(&mut buf).await?;
+        let buf = read_exact_buf(fd, Header::block_size()).await?;
 
         from_bytes::<Header>(&buf).map_err(Into::into)
         // from_reader(BufReader::new(fd)).map_err(Into::into).unwrap()
@@ -196,23 +338,37 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
     ///
     /// This function assumes journal header is up to date
     async fn write_snapshot(&mut self, snapshot_header: &SnapshotHeader) -> Result<()> {
-        if snapshot_header.id != self.header.snapshot_counter {
-            return Err(Error::OutOfOrderSnapshot {
-                snapshot_id: snapshot_header.id,
-                journal_snapshot_id: self.header.snapshot_counter,
-            });
+        match snapshot_header.id {
+            id if id > self.header.snapshot_counter => {
+                return Err(Error::SnapshotGap {
+                    have: self.header.snapshot_counter,
+                    got: id,
+                })
+            }
+            id if id < self.header.snapshot_counter => return Err(Error::DuplicateSnapshot { id }),
+            _ => (),
         }
+        let bytes = self.header.snapshot_header_to_bytes(snapshot_header)?;
         self.fd.seek(SeekFrom::Start(self.header.eof)).await?;
-        self.fd.write_all(&to_bytes(snapshot_header)?).await?;
+        self.fd.write_all(&bytes).await?;
         self.blob_count = Some(0);
+        self.page_size = snapshot_header.page_size;
+        self.snapshot_timestamp = Some(snapshot_header.timestamp);
         Ok(())
     }
 
+    /// Read snapshot header, relative timestamp is resolved against previous snapshot header
     pub async fn read_snapshot(&mut self) -> Result<SnapshotHeader> {
-        let mut buf = Vec::with_capacity(SnapshotHeader::block_size());
-        self.fd.read_buf(&mut buf).await?;
-
-        from_bytes::<SnapshotHeader>(&buf).map_err(Into::into)
+        let mut buf = read_exact_buf(&mut self.fd, self.header.snapshot_header_size()).await?;
+        if self.header.relative_timestamps {
+            let size = self.header.absolute_timestamp_size(&buf)?;
+            buf.extend(read_exact_buf(&mut self.fd, size).await?);
+        }
+        let snapshot_header = self
+            .header
+            .snapshot_header_from_bytes(&buf, self.prev_timestamp)?;
+        self.prev_timestamp = Some(snapshot_header.timestamp);
+        Ok(snapshot_header)
     }
 
     /// Write header to a given fd
@@ -238,21 +394,50 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
     }
 
     /// Update journal header
+    ///
+    /// Batched commits are flushed first.
     pub async fn update_header(&mut self) -> Result<()> {
+        self.flush().await?;
         let h = Self::read_header(&mut self.fd).await?;
         self.header = h;
         Ok(())
     }
 
+    /// Re-read journal header, unless in-memory header is ahead due to batched commits
+    async fn refresh_header(&mut self) -> Result<()> {
+        match self.pending_commits {
+            0 => self.update_header().await,
+            _ => Ok(()),
+        }
+    }
+
     pub fn get_header(&self) -> &Header {
         &self.header
     }
 
+    /// Stream committed blobs, compressed blobs are decompressed, same as by journal iterator
     pub fn stream(
         &mut self,
+    ) -> impl Stream<Item = Result<(SnapshotHeader, BlobHeader, Vec<u8>)>> + '_ {
+        self.blob_stream(false)
+    }
+
+    /// Stream committed blobs as stored, i.e. compressed blobs are not decompressed, see
+    /// [`crate::journal::IntoIter::stored`]
+    pub fn stored_stream(
+        &mut self,
+    ) -> impl Stream<Item = Result<(SnapshotHeader, BlobHeader, Vec<u8>)>> + '_ {
+        self.blob_stream(true)
+    }
+
+    fn blob_stream(
+        &mut self,
+        stored: bool,
     ) -> impl Stream<Item = Result<(SnapshotHeader, BlobHeader, Vec<u8>)>> + '_ {
         try_stream! {
+            // header is re-read, so fd is at first snapshot
             self.update_header().await?;
+            self.prev_timestamp = None;
             let mut eoi = self.header.snapshot_counter == 0;
             while !eoi {
                 let snapshot_header = self.read_snapshot().await?;
@@ -262,12 +447,88 @@ impl<F: AsyncRead + AsyncWrite + AsyncSeek + std::marker::Unpin> AsyncJournal<F>
                         eoi = snapshot_header.id + 1 == self.header.snapshot_counter;
                         break
                     }
-                    let blob = self.read_blob(blob_header.blob_size).await?;
+                    let blob = self.read_blob(blob_header.stored_size()).await?;
+                    self.header.verify_checksum(&blob_header, &blob)?;
+                    if self.header.undo_log {
+                        self.fd.seek(SeekFrom::Current(blob_header.blob_size as i64)).await?;
+                    }
+                    let (blob_header, blob) = match stored || blob_header.compressed_size == 0 {
+                        true => (blob_header, blob),
+                        false => {
+                            let (blob_header, blob) = blob_header.decompress(&blob)?;
+                            (blob_header, blob.into_owned())
+                        }
+                    };
                     yield (snapshot_header, blob_header, blob)
                 }
             }
         }
     }
+
+    /// Serialized Protocol stream of snapshots, starting from `from_snapshot`, frame per item
+    ///
+    /// Yields the same bytes as [`crate::Stream`] over journal iterator, filtered with
+    /// [`crate::IntoIter::skip_snapshots`], but without blocking thread, so stream can be used
+    /// as e.g. HTTP response body directly. Blob frames carry blob as stored.
+    pub fn protocol_stream(
+        &mut self,
+        from_snapshot: u64,
+    ) -> impl Stream<Item = Result<Vec<u8>>> + '_ {
+        try_stream! {
+            yield to_bytes(&Protocol::from(JournalVersion::from(JOURNAL_VERSION)))?;
+            let blobs = self.stored_stream();
+            pin_mut!(blobs);
+            let mut snapshot_id = None;
+            while let Some(item) = blobs.next().await {
+                let (snapshot_header, blob_header, blob) = item?;
+                if snapshot_header.id < from_snapshot {
+                    continue;
+                }
+                if snapshot_id != Some(snapshot_header.id) {
+                    snapshot_id = Some(snapshot_header.id);
+                    yield to_bytes(&Protocol::from(snapshot_header))?;
+                }
+                let mut frame = to_bytes(&Protocol::from(blob_header))?;
+                frame.extend_from_slice(&blob);
+                yield frame;
+            }
+            yield to_bytes(&Protocol::end())?;
+        }
+    }
+
+    /// Write serialized Protocol stream of snapshots, starting from `from_snapshot`, into `writer`
+    ///
+    /// See [`AsyncJournal::protocol_stream`], writer is flushed once EndOfStream is written.
+    pub async fn write_protocol_stream<W: AsyncWrite + std::marker::Unpin>(
+        &mut self,
+        writer: &mut W,
+        from_snapshot: u64,
+    ) -> Result<()> {
+        let frames = self.protocol_stream(from_snapshot);
+        pin_mut!(frames);
+        while let Some(frame) = frames.next().await {
+            writer.write_all(&frame?).await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Read exactly `size` bytes, looping over short reads
+///
+/// Reader can return fewer bytes than requested, end of file before `size` bytes is an error.
+async fn read_exact_buf<R: AsyncRead + std::marker::Unpin>(
+    fd: &mut R,
+    size: usize,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(size);
+    // read_buf fills spare capacity only, so nothing is read past `size`
+    while buf.len() < size {
+        if fd.read_buf(&mut buf).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+    Ok(buf)
 }
 
 #[cfg(test)]
@@ -320,6 +581,100 @@ mod tests {
         assert_ne!(journal.header, Header::default());
     }
 
+    #[tokio::test]
+    async fn journal_batched_commits() {
+        async fn ingest(path: &Path, batch_size: usize) -> AsyncJournal {
+            let mut journal = AsyncJournal::create(path).await.unwrap();
+            journal.set_commit_batch(batch_size, None);
+            for id in 0..7 {
+                journal.add_snapshot(&SnapshotHeader::new(id, id as i64, Some(4))).await.unwrap();
+                for blob_num in 0..3 {
+                    let blob = [id as u8; 4];
+                    let blob_header = BlobHeader::new(blob_num as u64 * 4, blob_num, 4);
+                    journal.add_blob(&blob_header, &blob).await.unwrap();
+                }
+                journal.commit().await.unwrap();
+            }
+            journal
+        }
+        let batched_path = tempfile::NamedTempFile::new().unwrap();
+        let batched_path = DropFile{ path: batched_path.path() };
+        let mut batched = ingest(batched_path.path, 3).await;
+        // 7th commit is pending, header on disk points to 6th
+        assert_eq!(batched.get_header().snapshot_counter, 7);
+        let on_disk = AsyncJournal::try_from(batched_path.path).await.unwrap();
+        assert_eq!(on_disk.get_header().snapshot_counter, 6);
+        batched.flush().await.unwrap();
+
+        let path = tempfile::NamedTempFile::new().unwrap();
+        let path = DropFile{ path: path.path() };
+        let journal = ingest(path.path, 1).await;
+        assert_eq!(batched.get_header(), journal.get_header());
+        assert_eq!(
+            std::fs::read(batched_path.path).unwrap(),
+            std::fs::read(path.path).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn journal_finalize() {
+        let journal_path = tempfile::NamedTempFile::new().unwrap();
+        let journal_path = DropFile{ path: journal_path.path() };
+        let mut journal = AsyncJournal::create(journal_path.path).await.unwrap();
+        journal.set_commit_batch(10, None);
+        for id in 0..3 {
+            journal.add_snapshot(&SnapshotHeader::new(id, id as i64, Some(4))).await.unwrap();
+            journal.add_blob(&BlobHeader::new(id * 4, 0, 4), &[id as u8; 4]).await.unwrap();
+            journal.commit().await.unwrap();
+        }
+        // last snapshot is still in progress
+        journal.add_snapshot(&SnapshotHeader::new(3, 3, Some(4))).await.unwrap();
+        journal.add_blob(&BlobHeader::new(12, 0, 4), &[3; 4]).await.unwrap();
+        journal.finalize().await.unwrap();
+
+        let mut journal = AsyncJournal::try_from(journal_path.path).await.unwrap();
+        assert_eq!(journal.current_snapshot().await, Some(4));
+        let stream = journal.stream();
+        pin_mut!(stream);
+        let mut blobs = vec![];
+        while let Some(item) = stream.next().await {
+            let (_, blob_header, blob) = item.unwrap();
+            blobs.push((blob_header.offset, blob));
+        }
+        assert_eq!(blobs, (0..4).map(|id| (id * 4, vec![id as u8; 4])).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn journal_stream_checksum_mismatch() {
+        let mut journal = AsyncJournal::new(Header::default(), std::io::Cursor::new(vec![]), None)
+            .await
+            .unwrap();
+        journal.add_snapshot(&SnapshotHeader::new(0, 0, Some(4))).await.unwrap();
+        journal.add_blob(&BlobHeader::new(0, 0, 4), &[1; 4]).await.unwrap();
+        journal.commit().await.unwrap();
+        // flip first byte of blob, followed by terminator
+        let mut bytes = journal.fd.into_inner();
+        let pos = bytes.len() - BlobHeader::block_size() - 4;
+        bytes[pos] ^= 0xFF;
+
+        let mut journal = AsyncJournal::from(journal.header, std::io::Cursor::new(bytes), None);
+        let stream = journal.stream().collect::<Vec<_>>().await;
+        assert!(
+            matches!(stream.as_slice(), [Err(Error::ChecksumMismatch { blob_num: 0, .. })]),
+            "{stream:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn journal_zero_page_size() {
+        let journal_path = tempfile::NamedTempFile::new().unwrap();
+        let journal_path = DropFile{ path: journal_path.path() };
+        let mut journal = AsyncJournal::create(journal_path.path).await.unwrap();
+        let result = journal.new_snapshot(0).await;
+        assert!(matches!(result, Err(Error::InvalidPageSize { page_size: 0 })), "{result:?}");
+        assert_eq!(journal.blob_count, None);
+    }
+
     #[tokio::test]
     async fn journal_empty_stream() {
         let journal_path = tempfile::NamedTempFile::new().unwrap();
@@ -328,4 +683,98 @@ mod tests {
         let stream = journal.stream().collect::<Vec<_>>().await;
         assert!(stream.len() == 0, "{:#?}", stream);
     }
+
+    /// Fd, which reads at most one byte per poll
+    struct OneByteReads(std::io::Cursor<Vec<u8>>);
+
+    impl AsyncRead for OneByteReads {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            ctx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let mut byte = [0];
+            let mut one = tokio::io::ReadBuf::new(&mut byte);
+            if buf.remaining() > 0 {
+                std::task::ready!(Pin::new(&mut self.get_mut().0).poll_read(ctx, &mut one))?;
+            }
+            buf.put_slice(one.filled());
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for OneByteReads {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            ctx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(ctx, buf)
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            ctx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(ctx)
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            ctx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_shutdown(ctx)
+        }
+    }
+
+    impl AsyncSeek for OneByteReads {
+        fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+            Pin::new(&mut self.get_mut().0).start_seek(position)
+        }
+
+        fn poll_complete(
+            self: Pin<&mut Self>,
+            ctx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<u64>> {
+            Pin::new(&mut self.get_mut().0).poll_complete(ctx)
+        }
+    }
+
+    #[tokio::test]
+    async fn journal_stream_short_reads() {
+        let blob = (0..0x0001_0000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut journal =
+            crate::Journal::new(Header::default(), std::io::Cursor::new(vec![]), None).unwrap();
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(0, &blob).unwrap();
+        journal.new_blob(blob.len() as u64, &blob[..100]).unwrap();
+        journal.commit().unwrap();
+        let mut fd = journal.take_fd().unwrap();
+        fd.set_position(0);
+
+        let mut fd = OneByteReads(fd);
+        let header = AsyncJournal::<OneByteReads>::read_header(&mut fd)
+            .await
+            .unwrap();
+        let mut journal = AsyncJournal::from(header, fd, None);
+        let stream = journal.stream().collect::<Result<Vec<_>>>().await.unwrap();
+        assert_eq!(stream.len(), 2);
+        assert_eq!(stream[0].2, blob);
+        assert_eq!(stream[1].2, &blob[..100]);
+
+        // truncated blob is an error, not a short blob
+        let mut fd = journal.fd.0;
+        let len = fd.get_ref().len();
+        fd.get_mut().truncate(len - 200);
+        let mut fd = OneByteReads(fd);
+        let header = AsyncJournal::<OneByteReads>::read_header(&mut fd)
+            .await
+            .unwrap();
+        let mut journal = AsyncJournal::from(header, fd, None);
+        let stream = journal.stream().collect::<Vec<_>>().await;
+        assert!(
+            matches!(stream.last(), Some(Err(Error::IOError(_)))),
+            "{stream:?}"
+        );
+    }
 }
@@ -16,6 +16,10 @@ pub enum Error {
         snapshot_id: u64,
         journal_snapshot_id: u64,
     },
+    /// Snapshot id is ahead of journal, snapshots in between are missing
+    SnapshotGap { have: u64, got: u64 },
+    /// Snapshot with given id is already present in journal
+    DuplicateSnapshot { id: u64 },
     /// Snapshot not started
     SnapshotNotStarted,
     /// Attemt to add out of order blob
@@ -25,6 +29,54 @@ pub enum Error {
     },
     /// Unexpected Journal Version
     UnexpectedJournalVersion { expected: u32, got: u32 },
+    /// Blob doesn't fit into max possible database size
+    InvalidOffset { offset: u64, size: u32 },
+    /// Blob exceeds max blob size
+    BlobTooLarge { blob_size: u32, max: u32 },
+    /// Page size differs from page size of previous snapshots, journal needs to be re-bootstrapped
+    PageSizeChanged { expected: u32, got: u32 },
+    /// Protocol stream doesn't start with journal version
+    MissingJournalVersion,
+    /// Protocol stream ended without EndOfStream
+    MissingEndOfStream,
+    /// Protocol stream has data after EndOfStream
+    TrailingStreamData,
+    /// Unexpected protocol frame
+    UnexpectedFrame { frame: String },
+    /// Journal keeps undo log, blob requires pre-image of the same size
+    InvalidPreImage,
+    /// Journal doesn't keep undo log
+    UndoLogDisabled,
+    /// Snapshot is in progress, uncommitted blobs would be lost
+    SnapshotInProgress,
+    /// Snapshot with given id is not present in journal
+    SnapshotNotFound { id: u64 },
+    /// Snapshot range is empty, `from` is past `to`
+    InvalidSnapshotRange { from: u64, to: u64 },
+    /// Page size can't be zero, zero page size is indistinguishable from missing one
+    InvalidPageSize { page_size: u32 },
+    /// Journal header has unexpected magic number, i.e. fd doesn't hold journal
+    InvalidMagic { magic: u32 },
+    /// Page numbers start from 1, same as in sqlite
+    InvalidPageNumber { page_no: u32 },
+    /// Blob alignment has to be power of two, not larger than max sqlite page size
+    InvalidBlobAlignment { alignment: u32 },
+    /// Blob doesn't match checksum of its header, i.e. blob is corrupted
+    ChecksumMismatch {
+        blob_num: u32,
+        expected: u32,
+        got: u32,
+    },
+    /// Compressed blob doesn't decompress into blob of its uncompressed size
+    InvalidCompressedBlob { blob_num: u32 },
+    /// Journal is built without `compression` feature, blobs can't be compressed or decompressed
+    CompressionUnsupported,
+    /// Snapshot of stream differs from journal snapshot with the same id, i.e. stream and
+    /// journal histories diverged, e.g. there is more than one writer
+    SnapshotDivergence { id: u64 },
+    /// Snapshot with relative timestamp has no previous snapshot, its timestamp is based on,
+    /// i.e. journal is corrupted
+    MissingBaseTimestamp { id: u64 },
 }
 
 impl From<IOError> for Error {
@@ -61,4 +113,32 @@ impl Error {
             _ => false,
         }
     }
+
+    /// Check if operation, failed with error, is worth retrying
+    ///
+    /// Transient IO errors, i.e. interrupted or would block calls, network timeouts or dropped
+    /// connections and stale network filesystem handles, are retryable. Everything else, i.e. corrupted journal, version
+    /// mismatch, out of order or invalid input, fails again on retry.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::IOError(e) => is_retryable_io(e),
+            Self::SerdeSqliteError(SerdeSqliteError::IoError(e)) => is_retryable_io(e),
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn is_retryable_io(e: &IOError) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::BrokenPipe
+            | ErrorKind::StaleNetworkFileHandle
+    )
 }
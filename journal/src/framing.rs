@@ -0,0 +1,142 @@
+//! Length-delimited framing for Protocol stream
+//!
+//! Protocol stream relies on block sizes for framing, which doesn't fit message oriented
+//! transports. Length-delimited framing prefixes each protocol frame with big-endian `u32`
+//! length, so each frame can be carried as separate message.
+//! Blob header frame includes blob data.
+
+use crate::error::Error as JournalError;
+use crate::stream::Protocol;
+use serde_sqlite::{from_reader, to_bytes};
+use std::io::{BufRead, BufReader, Read};
+
+/// Length-delimited framing over Protocol stream, i.e. [`crate::Stream`]
+#[derive(Debug)]
+pub struct LengthDelimitedStream<R: Read> {
+    reader: BufReader<R>,
+    buf: Vec<u8>,
+    read: usize,
+    finished: bool,
+}
+
+impl<R: Read> LengthDelimitedStream<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: vec![],
+            read: 0,
+            finished: false,
+        }
+    }
+
+    /// Read next protocol frame, without length prefix
+    ///
+    /// Returns `None` after EndOfStream frame.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, JournalError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let frame = from_reader::<Protocol, _>(&mut self.reader)?;
+        let mut buf = to_bytes(&frame)?;
+        match frame {
+            Protocol::BlobHeader(blob_header) => {
+                let size = blob_header.stored_size() as u64;
+                if (&mut self.reader).take(size).read_to_end(&mut buf)? as u64 != size {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            Protocol::EndOfStream(_) => self.finished = true,
+            _ => (),
+        };
+        Ok(Some(buf))
+    }
+}
+
+impl<R: Read> Read for LengthDelimitedStream<R> {
+    fn read(&mut self, write_buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.read == self.buf.len() {
+            let frame = match self.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(0),
+                Err(JournalError::IOError(e)) => return Err(e),
+                Err(e) => return Err(std::io::Error::other(e)),
+            };
+            self.buf.clear();
+            self.buf
+                .extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            self.buf.extend_from_slice(&frame);
+            self.read = 0;
+        }
+        let read = (self.buf.len() - self.read).min(write_buf.len());
+        write_buf[..read].copy_from_slice(&self.buf[self.read..self.read + read]);
+        self.read += read;
+        Ok(read)
+    }
+}
+
+/// Reader of length-delimited frames, produced by [`LengthDelimitedStream`]
+///
+/// Strips length prefixes, exposing original Protocol stream.
+#[derive(Debug)]
+pub struct LengthDelimitedReader<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    read: usize,
+}
+
+impl<R: Read> LengthDelimitedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: vec![],
+            read: 0,
+        }
+    }
+
+    /// Read next frame, without length prefix
+    ///
+    /// Returns `None` if underlying reader is exhausted on frame boundary.
+    pub fn next_frame(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut len = [0_u8; 4];
+        match self.reader.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut len[1..])?,
+        };
+        let len = u32::from_be_bytes(len) as u64;
+        let mut frame = vec![];
+        if (&mut self.reader).take(len).read_to_end(&mut frame)? as u64 != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        Ok(Some(frame))
+    }
+}
+
+impl<R: Read> BufRead for LengthDelimitedReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        // skip empty frames
+        while self.read == self.buf.len() {
+            match self.next_frame()? {
+                Some(frame) => {
+                    self.buf = frame;
+                    self.read = 0;
+                }
+                None => break,
+            }
+        }
+        Ok(&self.buf[self.read..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read += amt;
+    }
+}
+
+impl<R: Read> Read for LengthDelimitedReader<R> {
+    fn read(&mut self, write_buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_buf = self.fill_buf()?;
+        let read = read_buf.len().min(write_buf.len());
+        write_buf[..read].copy_from_slice(&read_buf[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
@@ -1,18 +1,190 @@
-//! Journal (v1)
+//! Journal (v2)
 
-use crate::error::Error;
+use crate::error::{is_retryable_io, Error};
+#[cfg(unix)]
+use crate::mmap::MmapCursor;
+use crate::stream::Protocol;
 use block::{block, Block};
 use serde::{Deserialize, Serialize};
-use serde_sqlite::{from_reader, to_bytes};
+use serde_sqlite::{from_bytes, from_reader, to_bytes};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::fs;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path;
+use std::time::{Duration, Instant};
 
 pub(crate) const MAGIC: u32 = 0x00907A70;
 pub(crate) const DEFAULT_BUFFER_SIZE: usize = 65536;
+/// Max sqlite database size: 4294967294 pages of 65536 bytes
+pub(crate) const MAX_DATABASE_SIZE: u64 = 0xFFFF_FFFE * 0x0001_0000;
+/// Max blob size, sanity limit for blob headers read from untrusted input
+///
+/// Blobs written by VFS never exceed max sqlite page size of 64KiB, limit leaves room for
+/// larger blobs written through journal API.
+pub(crate) const MAX_BLOB_SIZE: u32 = 0x0100_0000;
+/// Size of compacted baseline blobs, if journal doesn't record page size
+const DEFAULT_PAGE_SIZE: u32 = 4096;
+/// Max blob alignment, max sqlite page size
+pub(crate) const MAX_BLOB_ALIGNMENT: u32 = 0x0001_0000;
+/// Blob number flag of the only blob of snapshot, which has no terminator, see
+/// [`Header::implicit_terminators`]
+pub(crate) const LAST_BLOB_FLAG: u32 = 0x8000_0000;
+/// Current journal version, version 2 added blob checksums, version 3 added compressed blobs
+///
+/// Protocol stream always starts with current version, since blob header frames carry
+/// checksum and compressed size regardless of version of streamed journal.
+pub const JOURNAL_VERSION: u32 = 3;
+/// Page size flag of snapshot header with relative timestamp, which is followed by absolute
+/// timestamp, see [`Header::relative_timestamps`]
+const ABSOLUTE_TIMESTAMP_FLAG: u32 = 0x8000_0000;
+/// Size of absolute timestamp, following flagged snapshot header with relative timestamp
+const ABSOLUTE_TIMESTAMP_SIZE: usize = 8;
+/// Size of version 1 blob header, which has no checksum
+pub(crate) const BLOB_HEADER_V1_SIZE: usize = 16;
+/// Size of version 2 blob header, which has no compressed size
+pub(crate) const BLOB_HEADER_V2_SIZE: usize = 20;
+/// First journal version, which can store compressed blobs
+const COMPRESSION_VERSION: u32 = 3;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Compression of blobs, written by journal, see [`Journal::set_compression`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Blobs are stored as is
+    #[default]
+    None,
+    /// Blobs are compressed with zstd at given level, blobs which don't shrink are stored as is
+    ///
+    /// Requires `compression` feature, otherwise writes fail with
+    /// [`Error::CompressionUnsupported`].
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    /// Compress blob, `None` if compression is off or blob doesn't shrink
+    #[cfg(feature = "compression")]
+    fn compress(&self, blob: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::None => Ok(None),
+            Self::Zstd { level } => {
+                let compressed = zstd::bulk::compress(blob, *level)?;
+                Ok(Some(compressed).filter(|compressed| compressed.len() < blob.len()))
+            }
+        }
+    }
+
+    /// Compress blob, `None` if compression is off
+    #[cfg(not(feature = "compression"))]
+    fn compress(&self, _blob: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::None => Ok(None),
+            Self::Zstd { .. } => Err(Error::CompressionUnsupported),
+        }
+    }
+}
+
+/// Policy of underlying fd flushes on snapshot commit
+///
+/// Committed snapshot is always written into underlying fd, policy only controls how often
+/// `flush` is called on it. Pending flush is done on journal drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flush on every commit
+    #[default]
+    EveryCommit,
+    /// Flush on every n-th commit
+    EveryN(u32),
+    /// Flush on commit, if at least given duration passed since last flush
+    Interval(Duration),
+    /// Flush only on [`Journal::flush`]
+    Manual,
+}
+
+/// Source of snapshot timestamps, microseconds since unix epoch, see [`Journal::set_clock`]
+///
+/// `None` means wall-clock is unavailable, e.g. clock reads are denied by sandbox.
+pub type Clock = fn() -> Option<i64>;
+
+/// System wall-clock, unavailable if it's not set, i.e. it's at or before unix epoch
+pub fn system_clock() -> Option<i64> {
+    Some(chrono::Utc::now().timestamp_micros()).filter(|now| *now > 0)
+}
+
+/// Timestamps of snapshots, started by journal
+///
+/// Timestamp is wall-clock time, if clock is available. Otherwise it's the previous timestamp
+/// plus one microsecond, so snapshots still get increasing timestamps. Previous timestamp is the
+/// later of last timestamp, started by journal, and last timestamp, committed to journal, so
+/// timestamps keep increasing after journal is reopened.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timestamps {
+    clock: Clock,
+    last: i64,
+}
+
+impl Timestamps {
+    pub(crate) fn new(clock: Clock) -> Self {
+        Self { clock, last: 0 }
+    }
+
+    pub(crate) fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Next timestamp, `committed` is last timestamp, committed to journal
+    pub(crate) fn next(&mut self, committed: i64) -> i64 {
+        self.last = (self.clock)().unwrap_or(self.last.max(committed).saturating_add(1));
+        self.last
+    }
+}
+
+/// Retry policy of journal file opens
+///
+/// Opens, failed with transient error, e.g. interrupted call or stale handle of network
+/// filesystem, are retried with exponential backoff, starting from `backoff`. Missing journal
+/// is never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenRetry {
+    /// Total number of open attempts, zero is the same as one
+    pub attempts: u32,
+    /// Delay before first retry, doubled on each next one
+    pub backoff: Duration,
+}
+
+impl Default for OpenRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+}
+
+impl OpenRetry {
+    /// Single attempt, no retries
+    pub const NONE: Self = Self {
+        attempts: 1,
+        backoff: Duration::ZERO,
+    };
+
+    /// Run `open`, retrying transient errors
+    pub fn run<T>(&self, mut open: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+        let mut backoff = self.backoff;
+        for _ in 1..self.attempts {
+            match open() {
+                Err(e) if is_retryable_io(&e) => {
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+        open()
+    }
+}
+
 #[derive(Debug)]
 pub struct Journal<F = fs::File>
 where
@@ -24,8 +196,34 @@ where
     fd: Fd<F, BufWriter<F>, BufReader<F>>,
     /// snapshot page count
     blob_count: Option<u32>,
+    /// page size of current snapshot
+    page_size: Option<u32>,
+    /// timestamp of current snapshot
+    snapshot_timestamp: Option<i64>,
+    /// Timestamp of last read snapshot header, relative timestamp of next one is based on it
+    prev_timestamp: Option<i64>,
     /// Buffer size
     buffer_sz: usize,
+    /// Max size of written blob
+    max_blob_size: u32,
+    /// Position of first blob header of current snapshot
+    first_blob_position: Option<u64>,
+    /// Position of implied terminator, following last read blob of single blob snapshot
+    implied_terminator: Option<u64>,
+    /// Position of provisional terminator of snapshot in progress, see [`Journal::sync_snapshot`]
+    synced_terminator: Option<u64>,
+    /// Journal path, known only for journals opened by path
+    path: Option<path::PathBuf>,
+    /// Flush policy
+    flush_policy: FlushPolicy,
+    /// Number of commits since last flush
+    unflushed_commits: u32,
+    /// Time of last flush
+    last_flush: Instant,
+    /// Timestamps of new snapshots
+    timestamps: Timestamps,
+    /// Compression of new blobs
+    compression: Compression,
 }
 
 #[derive(Debug)]
@@ -50,10 +248,52 @@ where
         }
     }
 
-    /// Swith Fd to 'raw' mode
-    pub fn as_raw(&mut self) {
-        let fd = self.as_fd();
-        let _ = std::mem::replace(self, Fd::Raw(fd));
+    /// Write out buffered data and switch Fd to 'raw' mode, without flushing underlying fd
+    pub fn drain(&mut self) -> std::io::Result<()> {
+        match std::mem::replace(self, Self::Nada) {
+            Self::Writer(fd) => match fd.into_inner() {
+                Ok(fd) => *self = Self::Raw(fd),
+                Err(e) => {
+                    let (e, fd) = e.into_parts();
+                    *self = Self::Writer(fd);
+                    return Err(e);
+                }
+            },
+            Self::Reader(fd) => *self = Self::Raw(fd.into_inner()),
+            fd => *self = fd,
+        };
+        Ok(())
+    }
+
+    /// Flush underlying fd, buffered data is left intact
+    pub fn flush_fd(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Raw(fd) => fd.flush(),
+            Self::Writer(fd) => fd.get_mut().flush(),
+            Self::Reader(fd) => fd.get_mut().flush(),
+            Self::Nada => unreachable!(),
+        }
+    }
+
+    /// Current position, buffered data is left intact
+    pub fn position(&mut self) -> std::io::Result<u64> {
+        match self {
+            Self::Raw(fd) => fd.stream_position(),
+            Self::Reader(fd) => fd.stream_position(),
+            // buffered data is always appended at inner fd position
+            Self::Writer(fd) => Ok(fd.get_mut().stream_position()? + fd.buffer().len() as u64),
+            Self::Nada => unreachable!(),
+        }
+    }
+
+    /// Seek relative to current position, buffered data is kept in read mode
+    ///
+    /// Skipped blobs of header scans are mostly within read buffer, plain seek would discard it.
+    pub fn seek_relative(&mut self, offset: i64) -> std::io::Result<()> {
+        match self {
+            Self::Reader(fd) => fd.seek_relative(offset),
+            fd => fd.seek(SeekFrom::Current(offset)).map(|_| ()),
+        }
     }
 
     /// Switch Fd to buffered write mode
@@ -125,37 +365,290 @@ impl<F: Seek, W: Seek, R: Seek> Seek for Fd<F, W, R> {
 impl Journal<fs::File> {
     /// Create new journal
     pub fn create<P: AsRef<path::Path>>(p: P) -> Result<Self> {
-        let fd = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(p.as_ref())?;
-        Self::new(Header::default(), fd, None)
+        Self::create_with_header(p, Header::default())
+    }
+
+    /// Create new journal, retrying transient open errors according to `retry`
+    pub fn create_with_retry<P: AsRef<path::Path>>(p: P, retry: OpenRetry) -> Result<Self> {
+        Self::open_new(p, Header::default(), retry)
+    }
+
+    /// Create new journal, which keeps pre-images of blobs to allow undo of last snapshot
+    pub fn create_with_undo_log<P: AsRef<path::Path>>(p: P) -> Result<Self> {
+        let header = Header {
+            undo_log: true,
+            ..Header::default()
+        };
+        Self::create_with_header(p, header)
+    }
+
+    /// Create new journal, which starts every blob at offset, multiple of `alignment`
+    ///
+    /// Blob headers are preceded by zero padding, so blobs can be read in place, e.g. from
+    /// memory mapped journal. Alignment has to be power of two, up to max sqlite page size.
+    pub fn create_with_blob_alignment<P: AsRef<path::Path>>(p: P, alignment: u32) -> Result<Self> {
+        let header = Header {
+            blob_alignment: Some(alignment),
+            ..Header::default()
+        };
+        header.check_blob_alignment()?;
+        Self::create_with_header(p, header)
+    }
+
+    /// Create new journal, where snapshots with single blob have no terminator
+    ///
+    /// Saves size of blob header per snapshot, for journals with many tiny snapshots.
+    pub fn create_with_implicit_terminators<P: AsRef<path::Path>>(p: P) -> Result<Self> {
+        let header = Header {
+            implicit_terminators: true,
+            ..Header::default()
+        };
+        Self::create_with_header(p, header)
+    }
+
+    /// Create new journal, where snapshot timestamps are stored as delta from timestamp of
+    /// previous snapshot
+    ///
+    /// Halves size of snapshot header, for journals with many frequent snapshots. Timestamps are
+    /// still read as absolute ones.
+    pub fn create_with_relative_timestamps<P: AsRef<path::Path>>(p: P) -> Result<Self> {
+        let header = Header {
+            relative_timestamps: true,
+            ..Header::default()
+        };
+        Self::create_with_header(p, header)
+    }
+
+    fn create_with_header<P: AsRef<path::Path>>(p: P, header: Header) -> Result<Self> {
+        Self::open_new(p, header, OpenRetry::default())
+    }
+
+    fn open_new<P: AsRef<path::Path>>(p: P, header: Header, retry: OpenRetry) -> Result<Self> {
+        let fd = retry.run(|| {
+            fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .read(true)
+                .open(p.as_ref())
+        })?;
+        let mut journal = Self::new(header, fd, None)?;
+        journal.path = Some(p.as_ref().into());
+        Ok(journal)
+    }
+
+    /// Create new journal, pre-seeded from Protocol stream
+    ///
+    /// Stream is applied to temporary file next to journal path, which is moved into place only
+    /// once whole stream is applied, so journal is either fully populated or doesn't exist.
+    /// Fails if journal already exists.
+    pub fn create_from_stream<P: AsRef<path::Path>, R: Read>(p: P, stream: R) -> Result<Self> {
+        let path = p.as_ref();
+        if path.exists() {
+            return Err(std::io::Error::from(std::io::ErrorKind::AlreadyExists).into());
+        }
+        Self::populate_from_stream(path, stream)
+    }
+
+    /// Rebuild journal at given path from Protocol stream
+    ///
+    /// Same as [`Journal::create_from_stream`], but existing journal is replaced once whole
+    /// stream is applied. Stream is written to disk blob by blob, so memory usage is bounded by
+    /// blob size rather than stream size.
+    pub fn rebuild_from<R: Read, P: AsRef<path::Path>>(reader: R, out: P) -> Result<Self> {
+        Self::populate_from_stream(out.as_ref(), reader)
+    }
+
+    fn populate_from_stream<R: Read>(path: &path::Path, stream: R) -> Result<Self> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = path::PathBuf::from(tmp_path);
+        // leftover of interrupted attempt
+        fs::remove_file(&tmp_path).ok();
+        let populate = || -> Result<Self> {
+            let mut journal = Self::create(&tmp_path)?;
+            journal.apply_stream(stream)?;
+            if let Fd::Raw(fd) = &journal.fd {
+                fd.sync_all()?;
+            }
+            Ok(journal)
+        };
+        let result = populate().and_then(|journal| {
+            fs::rename(&tmp_path, path)?;
+            Ok(journal)
+        });
+        match result {
+            Ok(mut journal) => {
+                journal.path = Some(path.into());
+                Ok(journal)
+            }
+            Err(e) => {
+                fs::remove_file(&tmp_path).ok();
+                Err(e)
+            }
+        }
     }
 
     /// Try to instantiate journal from given path
+    ///
+    /// Transient open errors are retried according to default [`OpenRetry`].
     pub fn try_from<P: AsRef<path::Path>>(p: P) -> Result<Self> {
-        let mut fd = fs::OpenOptions::new().write(true).read(true).open(p)?;
+        Self::try_from_with_retry(p, OpenRetry::default())
+    }
+
+    /// Try to instantiate journal from given path, retrying transient open errors according to
+    /// `retry`
+    pub fn try_from_with_retry<P: AsRef<path::Path>>(p: P, retry: OpenRetry) -> Result<Self> {
+        let fd = retry.run(|| {
+            fs::OpenOptions::new()
+                .write(true)
+                .read(true)
+                .open(p.as_ref())
+        })?;
+        let mut journal = Self::from_fd(fd)?;
+        journal.path = Some(p.as_ref().into());
+        Ok(journal)
+    }
+
+    /// Open independent read-only view over the same journal file
+    ///
+    /// View has its own fd, so any number of views can be iterated concurrently
+    /// while the journal itself is shared by reference.
+    /// View sees only snapshots committed at the time of iteration.
+    pub fn view(&self) -> Result<JournalView> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "journal wasn't opened by path",
+                )
+                .into())
+            }
+        };
+        let mut fd = fs::OpenOptions::new().read(true).open(path)?;
         let header = Self::read_header(&mut fd)?;
-        Ok(Self::from(header, fd, None))
+        let mut journal = Self::from(header, fd, None);
+        journal.set_buffer_size(self.buffer_sz);
+        Ok(JournalView { journal })
+    }
+}
+
+#[cfg(unix)]
+impl Journal<MmapCursor> {
+    /// Open existing journal read-only over memory mapped file
+    ///
+    /// Meant for replicas, which only iterate journal to restore database: iteration doesn't
+    /// issue read syscalls and mapping shares OS page cache with other readers. Writes fail.
+    pub fn open_mmap<P: AsRef<path::Path>>(p: P) -> Result<Self> {
+        let mut journal = Self::from_fd(MmapCursor::open(p.as_ref())?)?;
+        journal.path = Some(p.as_ref().into());
+        Ok(journal)
+    }
+
+    /// Remap journal file, if writer appended to it, and re-read journal header
+    pub fn reopen(&mut self) -> Result<()> {
+        self.fd.drain()?;
+        if let Fd::Raw(fd) = &mut self.fd {
+            fd.remap()?;
+        }
+        self.update_header()
+    }
+}
+
+/// Read-only view over journal file
+#[derive(Debug)]
+pub struct JournalView {
+    journal: Journal<fs::File>,
+}
+
+impl JournalView {
+    /// Get journal header, as it was on view creation
+    pub fn get_header(&self) -> &Header {
+        self.journal.get_header()
+    }
+
+    /// Return current snapshot counter, as it was on view creation
+    pub fn current_snapshot(&self) -> Option<u64> {
+        self.journal.current_snapshot()
+    }
+
+    /// Iterate over committed snapshots
+    pub fn iter(&mut self) -> IntoIter<'_> {
+        self.journal.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut JournalView {
+    type IntoIter = IntoIter<'a>;
+    type Item = <Self::IntoIter as Iterator>::Item;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
 impl<F: Read + Write + Seek> Journal<F> {
     /// Instantiate journal & force header write
     pub fn new(header: Header, mut fd: F, blob_count: Option<u32>) -> Result<Self> {
+        header.check_blob_alignment()?;
         Self::write_header(&mut fd, &header)?;
         Ok(Self::from(header, fd, blob_count))
     }
 
+    /// Open existing journal over arbitrary fd, e.g. encryption or compression layer over file
+    ///
+    /// Header is read from fd and validated, unlike [`Journal::from`], which trusts given header,
+    /// and [`Journal::new`], which writes fresh header.
+    pub fn from_fd(mut fd: F) -> Result<Self> {
+        let header = Self::read_header(&mut fd)?;
+        Self::check_header(&header)?;
+        Ok(Self::from(header, fd, None))
+    }
+
     /// Instantiate journal
     pub fn from(header: Header, fd: F, blob_count: Option<u32>) -> Self {
         Self {
             header,
             fd: Fd::Raw(fd),
             blob_count,
+            page_size: None,
+            snapshot_timestamp: None,
+            prev_timestamp: None,
             buffer_sz: DEFAULT_BUFFER_SIZE,
+            max_blob_size: MAX_BLOB_SIZE,
+            first_blob_position: None,
+            implied_terminator: None,
+            synced_terminator: None,
+            path: None,
+            flush_policy: FlushPolicy::default(),
+            unflushed_commits: 0,
+            last_flush: Instant::now(),
+            timestamps: Timestamps::new(system_clock),
+            compression: Compression::None,
+        }
+    }
+
+    /// Set flush policy
+    pub fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+        self.flush_policy = flush_policy;
+    }
+
+    /// Get flush policy
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    /// Flush underlying fd, if there are commits since last flush
+    ///
+    /// Snapshot in progress stays buffered, since it's not committed yet.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.unflushed_commits == 0 {
+            return Ok(());
         }
+        self.fd.flush_fd()?;
+        self.unflushed_commits = 0;
+        self.last_flush = Instant::now();
+        Ok(())
     }
 
     /// Set buffer size
@@ -168,20 +661,102 @@ impl<F: Read + Write + Seek> Journal<F> {
         self.buffer_sz
     }
 
+    /// Set source of timestamps of new snapshots, [`system_clock`] by default
+    ///
+    /// If clock is unavailable, snapshot gets timestamp of previous snapshot plus one
+    /// microsecond.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.timestamps.set_clock(clock);
+    }
+
+    /// Set compression of blobs, written into journal, blobs are not compressed by default
+    ///
+    /// Blobs are compressed one by one, so each blob can still be read on its own. Blobs from
+    /// stream, which are compressed already, are stored as is. Version 1 and 2 journals can't
+    /// store compressed blobs, compression is ignored and compressed blobs are decompressed.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Get compression of blobs, written into journal
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Set max size of blob, accepted by journal
+    ///
+    /// Larger blobs, written directly or applied from stream, are rejected before they're
+    /// written or allocated. Max is capped by hard limit of 16MiB, which is the default.
+    pub fn set_max_blob_size(&mut self, max_blob_size: u32) {
+        self.max_blob_size = max_blob_size.min(MAX_BLOB_SIZE);
+    }
+
+    /// Get max size of blob, accepted by journal
+    pub fn max_blob_size(&self) -> u32 {
+        self.max_blob_size
+    }
+
+    /// Set page size of journal, which doesn't know its page size yet
+    ///
+    /// Hint is recorded only for journal without page size, i.e. journal of empty database, so
+    /// first snapshot is checked against it. Otherwise hint is ignored.
+    pub fn set_page_size_hint(&mut self, page_size: u32) -> Result<()> {
+        if page_size == 0 {
+            return Err(Error::InvalidPageSize { page_size });
+        }
+        if self.snapshot_started() {
+            return Err(Error::SnapshotInProgress);
+        }
+        self.update_header()?;
+        if self.header.page_size.is_some() {
+            return Ok(());
+        }
+        self.header.page_size = Some(page_size);
+        self.fd.drain()?;
+        Self::write_header(&mut self.fd, &self.header)?;
+        self.fd.flush()?;
+        Ok(())
+    }
+
     /// Initiate new snapshot
     ///
     /// * update journal header to correctly setup offset
     /// * to initiate snapshot we seek to current end of the file (value stored in header)
     /// * switch fd to buffered mode
     /// * write snapshot header with current header counter number
+    /// * check page size matches page size of previous snapshots
     pub fn new_snapshot(&mut self, page_size: u32) -> Result<()> {
         if self.blob_count.is_some() {
             return Ok(());
         }
         self.update_header()?;
+        self.header.check_page_size(page_size)?;
+        self.start_snapshot(page_size)
+    }
+
+    /// Initiate new baseline snapshot
+    ///
+    /// Baseline snapshot is allowed to change page size, which marks discontinuity in journal history.
+    /// Caller is expected to write whole database into baseline snapshot, since snapshots before
+    /// discontinuity are ignored on export.
+    pub fn new_baseline_snapshot(&mut self, page_size: u32) -> Result<()> {
+        if self.blob_count.is_some() {
+            return Ok(());
+        }
+        self.update_header()?;
+        self.start_snapshot(page_size)
+    }
+
+    /// Write new snapshot header, assumes journal header is up to date
+    ///
+    /// Zero page size is rejected, since it's serialized same as missing page size.
+    fn start_snapshot(&mut self, page_size: u32) -> Result<()> {
+        if page_size == 0 {
+            return Err(Error::InvalidPageSize { page_size });
+        }
         let snapshot_header = SnapshotHeader::new(
             self.header.snapshot_counter,
-            chrono::Utc::now().timestamp_micros(),
+            self.timestamps.next(self.header.last_timestamp),
             Some(page_size),
         );
         self.write_snapshot(&snapshot_header)
@@ -193,10 +768,29 @@ impl<F: Read + Write + Seek> Journal<F> {
             Some(c) => c,
             None => return Err(Error::SnapshotNotStarted),
         };
+        self.check_blob_size(blob.len())?;
         let blob_header = BlobHeader::new(offset, blob_num, blob.len() as u32);
         self.add_blob(&blob_header, blob)
     }
 
+    /// Add new blob with pre-image
+    ///
+    /// Pre-image is an old content of blob range, it's stored only if journal keeps undo log.
+    pub fn new_blob_with_pre_image(
+        &mut self,
+        offset: u64,
+        blob: &[u8],
+        pre_image: &[u8],
+    ) -> Result<()> {
+        let blob_num = match self.blob_count {
+            Some(c) => c,
+            None => return Err(Error::SnapshotNotStarted),
+        };
+        self.check_blob_size(blob.len())?;
+        let blob_header = BlobHeader::new(offset, blob_num, blob.len() as u32);
+        self.write_blob(&blob_header, blob, Some(pre_image))
+    }
+
     /// Add existing snapshot
     ///
     /// Re-syncs journal header
@@ -209,104 +803,1399 @@ impl<F: Read + Write + Seek> Journal<F> {
     ///
     /// This function assumes journal header is up to date
     fn write_snapshot(&mut self, snapshot_header: &SnapshotHeader) -> Result<()> {
-        if snapshot_header.id != self.header.snapshot_counter {
-            return Err(Error::OutOfOrderSnapshot {
-                snapshot_id: snapshot_header.id,
-                journal_snapshot_id: self.header.snapshot_counter,
-            });
+        match snapshot_header.id {
+            id if id > self.header.snapshot_counter => {
+                return Err(Error::SnapshotGap {
+                    have: self.header.snapshot_counter,
+                    got: id,
+                })
+            }
+            id if id < self.header.snapshot_counter => return Err(Error::DuplicateSnapshot { id }),
+            _ => (),
         }
+        let bytes = self.header.snapshot_header_to_bytes(snapshot_header)?;
         self.fd.seek(SeekFrom::Start(self.header.eof))?;
         self.fd.as_writer(self.buffer_sz);
-        self.fd.write_all(&to_bytes(snapshot_header)?)?;
+        self.fd.write_all(&bytes)?;
         self.blob_count = Some(0);
+        self.page_size = snapshot_header.page_size;
+        self.snapshot_timestamp = Some(snapshot_header.timestamp);
         Ok(())
     }
 
     /// Add blob
+    ///
+    /// Journal with undo log requires pre-image, see [`Journal::new_blob_with_pre_image`]
     pub fn add_blob(&mut self, blob_header: &BlobHeader, blob: &[u8]) -> Result<()> {
+        self.write_blob(blob_header, blob, None)
+    }
+
+    fn write_blob(
+        &mut self,
+        blob_header: &BlobHeader,
+        blob: &[u8],
+        pre_image: Option<&[u8]>,
+    ) -> Result<()> {
         if Some(blob_header.blob_num) != self.blob_count {
             return Err(Error::OutOfOrderBlob {
                 blob_num: blob_header.blob_num,
                 blob_count: self.blob_count,
             });
         }
+        blob_header.validate()?;
+        self.check_blob_size(blob_header.blob_size as usize)?;
+        let (blob_header, blob) = self
+            .header
+            .stored_blob(blob_header, blob, self.compression)?;
+        let blob_header = &blob_header;
+        let pre_image = match (self.header.undo_log, pre_image) {
+            (false, _) => None,
+            (true, Some(pre_image)) if pre_image.len() == blob_header.blob_size as usize => {
+                Some(pre_image)
+            }
+            (true, _) => return Err(Error::InvalidPreImage),
+        };
         self.blob_count.as_mut().map(|x| {
             *x += 1;
             *x
         });
-        self.fd.write_all(&to_bytes(blob_header)?)?;
-        self.fd.write_all(blob)?;
+        self.resume_snapshot()?;
+        self.write_blob_padding()?;
+        if blob_header.blob_num == 0 {
+            self.first_blob_position = Some(self.fd.position()?);
+        }
+        self.fd
+            .write_all(&self.header.blob_header_to_bytes(blob_header)?)?;
+        self.fd.write_all(&blob)?;
+        if let Some(pre_image) = pre_image {
+            self.fd.write_all(pre_image)?;
+        }
         Ok(())
     }
 
     /// Commit snapshot
     ///
-    /// * write final empty page to indicate end of snapshot
+    /// * write final empty page to indicate end of snapshot, or mark the only blob of snapshot
+    ///   as last, if journal has implicit terminators
     /// * flush bufwriter (seek() on BufWriter will force flush)
     /// * write new header
-    /// * flush bufwriter
-    /// * switch fd back to raw mode
+    /// * write out bufwriter and switch fd back to raw mode
+    /// * flush fd, if [`FlushPolicy`] says so
     pub fn commit(&mut self) -> Result<()> {
         if !self.snapshot_started() {
             return Ok(());
         }
-        // commit snapshot by writting final empty page
-        self.fd.write_all(&to_bytes(&BlobHeader::last())?)?;
+        self.resume_snapshot()?;
+        let first_blob_position = self.first_blob_position.take();
+        match (
+            self.header.implicit_terminators,
+            self.blob_count,
+            first_blob_position,
+        ) {
+            (true, Some(1), Some(position)) => {
+                let end = self.fd.stream_position()?;
+                self.fd.seek(SeekFrom::Start(position + 8))?;
+                self.fd.write_all(&LAST_BLOB_FLAG.to_be_bytes())?;
+                self.fd.seek(SeekFrom::Start(end))?;
+            }
+            _ => {
+                // commit snapshot by writting final empty page
+                self.write_blob_padding()?;
+                self.fd
+                    .write_all(&self.header.blob_header_to_bytes(&BlobHeader::last())?)?;
+            }
+        }
         self.blob_count = None;
 
         self.header.snapshot_counter += 1;
+        self.header.page_size = self.page_size.take().or(self.header.page_size);
+        self.header.last_timestamp = self
+            .snapshot_timestamp
+            .take()
+            .unwrap_or(self.header.last_timestamp);
         self.header.eof = self.fd.stream_position()?;
 
         Self::write_header(&mut self.fd, &self.header)?;
-        self.fd.flush()?;
-        self.fd.as_raw();
+        self.fd.drain()?;
+        self.unflushed_commits += 1;
+        let flush = match self.flush_policy {
+            FlushPolicy::EveryCommit => true,
+            FlushPolicy::EveryN(n) => self.unflushed_commits >= n,
+            FlushPolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+            FlushPolicy::Manual => false,
+        };
+        if flush {
+            self.flush()?;
+        }
         Ok(())
     }
 
-    /// Get journal header
-    pub fn get_header(&self) -> &Header {
-        &self.header
-    }
-
-    /// Return current snapshot counter
-    pub fn current_snapshot(&self) -> Option<u64> {
-        match self.header.snapshot_counter {
-            0 => None,
-            v => Some(v),
+    /// Write out snapshot in progress, without committing it
+    ///
+    /// Snapshot is provisionally terminated and written through to fd, so it survives crash of
+    /// the process, see [`Journal::recover_synced_snapshot`]. Journal header is left intact,
+    /// snapshot stays invisible to readers until commit. Next blob or commit overwrites
+    /// provisional terminator.
+    pub fn sync_snapshot(&mut self) -> Result<()> {
+        if !self.snapshot_started() {
+            return Ok(());
         }
+        self.resume_snapshot()?;
+        self.write_blob_padding()?;
+        let position = self.fd.position()?;
+        self.fd
+            .write_all(&self.header.blob_header_to_bytes(&BlobHeader::last())?)?;
+        self.fd.drain()?;
+        self.fd.flush_fd()?;
+        self.synced_terminator = Some(position);
+        Ok(())
     }
 
-    /// Update journal header
-    pub fn update_header(&mut self) -> Result<()> {
-        self.fd.as_reader(self.buffer_sz);
-        self.header = Self::read_header(&mut self.fd)?;
+    /// Continue synced snapshot from its provisional terminator
+    fn resume_snapshot(&mut self) -> Result<()> {
+        if let Some(position) = self.synced_terminator.take() {
+            self.fd.seek(SeekFrom::Start(position))?;
+            self.fd.as_writer(self.buffer_sz);
+        }
         Ok(())
     }
 
-    /// Read header from a given fd
+    /// Commit snapshot, synced by [`Journal::sync_snapshot`] but never committed
     ///
-    /// * seek to start of the file
-    /// * read header
-    fn read_header<R: Read + Seek>(fd: &mut R) -> Result<Header> {
-        fd.rewind()?;
-        from_reader(BufReader::new(fd)).map_err(Into::into)
+    /// Snapshot past the end of journal is committed only if it follows last snapshot, all its
+    /// blobs are valid and pass checksum check, and it's terminated. Otherwise it's left as is,
+    /// to be overwritten by next snapshot. Returns true, if snapshot was committed.
+    pub fn recover_synced_snapshot(&mut self) -> Result<bool> {
+        if self.snapshot_started() {
+            return Err(Error::SnapshotInProgress);
+        }
+        self.update_header()?;
+        let scan = self.scan_synced_snapshot();
+        self.implied_terminator = None;
+        let (eof, snapshot_header) = match scan {
+            Ok(Some(found)) => found,
+            Ok(None) | Err(_) => return Ok(false),
+        };
+        self.header.snapshot_counter += 1;
+        self.header.page_size = snapshot_header.page_size;
+        self.header.last_timestamp = snapshot_header.timestamp;
+        self.header.eof = eof;
+        self.fd.drain()?;
+        Self::write_header(&mut self.fd, &self.header)?;
+        self.fd.flush_fd()?;
+        Ok(true)
     }
 
-    /// Write header to a given fd
+    /// Find end and header of terminated snapshot past the end of journal
+    fn scan_synced_snapshot(&mut self) -> Result<Option<(u64, SnapshotHeader)>> {
+        self.fd.seek(SeekFrom::Start(self.header.eof))?;
+        self.prev_timestamp = self.header.base_timestamp();
+        let snapshot_header = self.read_snapshot_header()?;
+        let page_size = match snapshot_header.page_size {
+            Some(page_size) if snapshot_header.id == self.header.snapshot_counter => page_size,
+            _ => return Ok(None),
+        };
+        self.header.check_page_size(page_size)?;
+        let mut stored = vec![];
+        let mut blob_count = 0;
+        loop {
+            let blob_header = self.read_blob_header()?;
+            if blob_header.is_last() {
+                break;
+            }
+            if blob_header.blob_num != blob_count {
+                return Ok(None);
+            }
+            blob_header.validate()?;
+            self.check_blob_size(blob_header.blob_size as usize)?;
+            stored.resize(blob_header.stored_size() as usize, 0);
+            self.fd.read_exact(&mut stored)?;
+            self.header.verify_checksum(&blob_header, &stored)?;
+            if self.header.undo_log {
+                self.fd.seek_relative(blob_header.blob_size as i64)?;
+            }
+            blob_count += 1;
+        }
+        match blob_count {
+            0 => Ok(None),
+            _ => Ok(Some((self.fd.position()?, snapshot_header))),
+        }
+    }
+
+    /// Export database, as of latest snapshot, into given output
     ///
-    /// * seek to start of the file
-    /// * write header
-    fn write_header<W: Write + Seek>(fd: &mut W, header: &Header) -> Result<()> {
-        fd.rewind()?;
-        fd.write_all(&to_bytes(header)?).map_err(Into::into)
+    /// Replay starts from the last page size discontinuity, since snapshot which changed page size
+    /// holds whole database.
+    pub fn export_database<W: Write + Seek>(&mut self, output: &mut W) -> Result<()> {
+        self.export_database_upto(output, u64::MAX)
+    }
+
+    /// Export database, as of snapshot `snapshot_id` inclusive, into given output
+    ///
+    /// Snapshots after `snapshot_id` are not replayed, output file should be empty, since bytes
+    /// written only by later snapshots are left intact.
+    pub fn export_database_as_of<W: Write + Seek>(
+        &mut self,
+        output: &mut W,
+        snapshot_id: u64,
+    ) -> Result<()> {
+        self.update_header()?;
+        if snapshot_id >= self.header.snapshot_counter {
+            return Err(Error::SnapshotNotFound { id: snapshot_id });
+        }
+        self.export_database_upto(output, snapshot_id)
+    }
+
+    fn export_database_upto<W: Write + Seek>(&mut self, output: &mut W, upto: u64) -> Result<()> {
+        let baseline = self.last_baseline_snapshot(upto)?;
+        for data in self.into_iter() {
+            let (snapshot_header, blob_header, blob) = data?;
+            if snapshot_header.id < baseline {
+                continue;
+            }
+            if snapshot_header.id > upto {
+                break;
+            }
+            output.seek(SeekFrom::Start(blob_header.offset))?;
+            output.write_all(&blob)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Export database, as of latest snapshot, as sequence of full pages
+    ///
+    /// Pages are numbered from 1, same as in sqlite. Last page, partially covered by journal,
+    /// is zero-filled. Database is materialized in memory before first page is returned.
+    pub fn export_pages(&mut self, page_size: u32) -> impl Iterator<Item = Result<(u64, Vec<u8>)>> {
+        let database = match page_size {
+            0 => Err(Error::InvalidPageSize { page_size }),
+            _ => {
+                let mut database = std::io::Cursor::new(vec![]);
+                self.export_database(&mut database)
+                    .map(|_| database.into_inner())
+            }
+        };
+        let (database, error) = match database {
+            Ok(database) => (database, None),
+            Err(e) => (vec![], Some(Err(e))),
+        };
+        let page_size = page_size.max(1) as usize;
+        let page_count = database.len().div_ceil(page_size);
+        error.into_iter().chain((0..page_count).map(move |num| {
+            let start = num * page_size;
+            let mut page = database[start..database.len().min(start + page_size)].to_vec();
+            page.resize(page_size, 0);
+            Ok((num as u64 + 1, page))
+        }))
+    }
+
+    /// Read page `page_no`, as of latest snapshot
+    ///
+    /// Pages are numbered from 1, same as in sqlite. Only blobs, overlapping the page, are
+    /// applied, bytes never written are zero-filled. Blobs before last page size discontinuity
+    /// are ignored, same as in [`Journal::export_database`].
+    pub fn read_page(&mut self, page_no: u32, page_size: u32) -> Result<Vec<u8>> {
+        if page_size == 0 {
+            return Err(Error::InvalidPageSize { page_size });
+        }
+        if page_no == 0 {
+            return Err(Error::InvalidPageNumber { page_no });
+        }
+        let baseline = self.last_baseline_snapshot(u64::MAX)?;
+        let start = (page_no as u64 - 1) * page_size as u64;
+        let end = start + page_size as u64;
+        let mut page = vec![0; page_size as usize];
+        for item in self.blobs_in_range(start, end)? {
+            let (snapshot_header, blob_header, blob) = item?;
+            if snapshot_header.id < baseline {
+                continue;
+            }
+            let from = blob_header.offset.max(start);
+            let to = (blob_header.offset + blob.len() as u64).min(end);
+            page[(from - start) as usize..(to - start) as usize].copy_from_slice(
+                &blob[(from - blob_header.offset) as usize..(to - blob_header.offset) as usize],
+            );
+        }
+        Ok(page)
+    }
+
+    /// Compact journal into new journal over `fd`, folding snapshots up to `upto` inclusive into
+    /// single baseline snapshot
+    ///
+    /// * baseline holds whole database as of snapshot `upto`, page by page, its timestamp is
+    ///   picked according to `timestamp` policy
+    /// * snapshots after `upto` are copied with their timestamps, ids are shifted to follow baseline
+    /// * page size and undo log flag are preserved, baseline pre-images are zero-filled
+    pub fn compact<G: Read + Write + Seek>(
+        &mut self,
+        fd: G,
+        upto: u64,
+        timestamp: BaselineTimestamp,
+    ) -> Result<Journal<G>> {
+        self.update_header()?;
+        if upto >= self.header.snapshot_counter {
+            return Err(Error::SnapshotNotFound { id: upto });
+        }
+        self.fold_into(fd, Some(upto), u64::MAX, timestamp)
+    }
+
+    /// Extract snapshots `from..=to` into new standalone journal at `out`
+    ///
+    /// If `from` isn't the first snapshot, database as of snapshot `from - 1` is folded into
+    /// baseline snapshot, same as in [`Journal::compact`], so extracted journal is restorable on
+    /// its own. Ids are shifted to start from 0, snapshot timestamps are preserved.
+    pub fn extract_range<P: AsRef<path::Path>>(
+        &mut self,
+        from: u64,
+        to: u64,
+        out: P,
+    ) -> Result<Journal<fs::File>> {
+        self.update_header()?;
+        if from > to {
+            return Err(Error::InvalidSnapshotRange { from, to });
+        }
+        if to >= self.header.snapshot_counter {
+            return Err(Error::SnapshotNotFound { id: to });
+        }
+        let fd = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .truncate(true)
+            .open(out.as_ref())?;
+        let mut journal = self.fold_into(fd, from.checked_sub(1), to, BaselineTimestamp::Latest)?;
+        journal.path = Some(out.as_ref().into());
+        Ok(journal)
+    }
+
+    /// Copy snapshots up to `copy_upto` inclusive into new journal over `fd`, folding snapshots
+    /// up to `fold_upto` inclusive into baseline snapshot
+    ///
+    /// Assumes journal header is up to date.
+    fn fold_into<G: Read + Write + Seek>(
+        &mut self,
+        fd: G,
+        fold_upto: Option<u64>,
+        copy_upto: u64,
+        timestamp: BaselineTimestamp,
+    ) -> Result<Journal<G>> {
+        let header = Header {
+            undo_log: self.header.undo_log,
+            blob_alignment: self.header.blob_alignment,
+            implicit_terminators: self.header.implicit_terminators,
+            relative_timestamps: self.header.relative_timestamps,
+            ..Header::default()
+        };
+        let mut target = Journal::new(header, fd, None)?;
+        target.set_compression(self.compression);
+        let mut database = std::io::Cursor::new(vec![]);
+        let (mut earliest, mut latest, mut page_size) = (None, 0, None);
+        self.seek_first_snapshot()?;
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            if snapshot_header.id > copy_upto {
+                break;
+            }
+            let folded = Some(snapshot_header.id) <= fold_upto;
+            if folded {
+                // page size change starts database from scratch, same as on export
+                if let (Some(prev), Some(cur)) = (page_size, snapshot_header.page_size) {
+                    if prev != cur {
+                        database = std::io::Cursor::new(vec![]);
+                    }
+                }
+                page_size = snapshot_header.page_size.or(page_size);
+                earliest.get_or_insert(snapshot_header.timestamp);
+                latest = snapshot_header.timestamp;
+            } else {
+                target.add_snapshot(&SnapshotHeader::new(
+                    snapshot_header.id - fold_upto.unwrap_or(0),
+                    snapshot_header.timestamp,
+                    snapshot_header.page_size,
+                ))?;
+            }
+            loop {
+                let blob_header = self.read_blob_header()?;
+                if blob_header.is_last() {
+                    break;
+                }
+                let size = self.header.blob_record_size(&blob_header) as usize;
+                let mut buf = vec![];
+                buf.try_reserve(size)?;
+                buf.resize(size, 0);
+                self.fd.read_exact(buf.as_mut_slice())?;
+                // copied blobs stay compressed
+                let (blob, pre_image) = buf.split_at(blob_header.stored_size() as usize);
+                if folded {
+                    let (_, blob) = blob_header.decompress(blob)?;
+                    database.seek(SeekFrom::Start(blob_header.offset))?;
+                    database.write_all(&blob)?;
+                } else {
+                    target.write_blob(&blob_header, blob, Some(pre_image))?;
+                }
+            }
+            if Some(snapshot_header.id) == fold_upto {
+                let timestamp = match timestamp {
+                    BaselineTimestamp::Earliest => earliest.unwrap_or(latest),
+                    BaselineTimestamp::Latest => latest,
+                    BaselineTimestamp::Explicit(timestamp) => timestamp,
+                };
+                target.add_snapshot(&SnapshotHeader::new(0, timestamp, page_size))?;
+                let database = database.get_ref();
+                let chunk_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE) as usize;
+                for (num, page) in database.chunks(chunk_size).enumerate() {
+                    let offset = (num * chunk_size) as u64;
+                    target.new_blob_with_pre_image(offset, page, &vec![0; page.len()])?;
+                }
+            }
+            target.commit()?;
+        }
+        Ok(target)
+    }
+
+    /// Copy journal into new journal over `fd`, merging consecutive blobs of each snapshot,
+    /// separated by at most `max_gap` bytes, into single blob
+    ///
+    /// * gap between merged blobs is filled with database bytes as of merge point, so replay of
+    ///   merged snapshot gives the same database, gap pre-image is the same bytes
+    /// * only blobs following each other in ascending offset order are merged, merged blob
+    ///   never exceeds max blob size
+    /// * snapshots keep ids, timestamps and page sizes, undo log flag and blob alignment are
+    ///   preserved
+    ///
+    /// Database is materialized in memory while journal is copied.
+    pub fn merge_blobs<G: Read + Write + Seek>(
+        &mut self,
+        fd: G,
+        max_gap: u32,
+    ) -> Result<Journal<G>> {
+        self.update_header()?;
+        let header = Header {
+            undo_log: self.header.undo_log,
+            blob_alignment: self.header.blob_alignment,
+            implicit_terminators: self.header.implicit_terminators,
+            relative_timestamps: self.header.relative_timestamps,
+            ..Header::default()
+        };
+        let mut target = Journal::new(header, fd, None)?;
+        target.set_compression(self.compression);
+        let mut database = std::io::Cursor::new(vec![]);
+        let mut page_size = None;
+        self.seek_first_snapshot()?;
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            // page size change starts database from scratch, same as on export
+            if let (Some(prev), Some(cur)) = (page_size, snapshot_header.page_size) {
+                if prev != cur {
+                    database = std::io::Cursor::new(vec![]);
+                }
+            }
+            page_size = snapshot_header.page_size.or(page_size);
+            target.add_snapshot(&snapshot_header)?;
+            // merged blob as (offset, blob, pre-image)
+            let mut pending: Option<(u64, Vec<u8>, Vec<u8>)> = None;
+            loop {
+                let blob_header = self.read_blob_header()?;
+                if blob_header.is_last() {
+                    break;
+                }
+                let size = self.header.blob_record_size(&blob_header) as usize;
+                let mut buf = vec![];
+                buf.try_reserve(size)?;
+                buf.resize(size, 0);
+                self.fd.read_exact(buf.as_mut_slice())?;
+                let (blob, pre_image) = buf.split_at(blob_header.stored_size() as usize);
+                let (_, blob) = blob_header.decompress(blob)?;
+                let blob = blob.as_ref();
+                let gap = pending.as_ref().and_then(|(offset, merged, _)| {
+                    let end = offset + merged.len() as u64;
+                    let gap = blob_header.offset.checked_sub(end)?;
+                    let merged_size = merged.len() as u64 + gap + blob.len() as u64;
+                    match gap <= max_gap as u64 && merged_size <= MAX_BLOB_SIZE as u64 {
+                        true => Some((end, gap as usize)),
+                        false => None,
+                    }
+                });
+                match (gap, pending.as_mut()) {
+                    (Some((end, gap)), Some((_, merged, merged_pre_image))) => {
+                        // bytes past end of database were never written, i.e. zeroes
+                        let database = database.get_ref();
+                        let start = (end as usize).min(database.len());
+                        let mut gap_bytes =
+                            database[start..(start + gap).min(database.len())].to_vec();
+                        gap_bytes.resize(gap, 0);
+                        merged.extend_from_slice(&gap_bytes);
+                        merged.extend_from_slice(blob);
+                        merged_pre_image.extend_from_slice(&gap_bytes);
+                        merged_pre_image.extend_from_slice(pre_image);
+                    }
+                    _ => {
+                        if let Some((offset, merged, merged_pre_image)) = pending.take() {
+                            target.new_blob_with_pre_image(offset, &merged, &merged_pre_image)?;
+                        }
+                        pending = Some((blob_header.offset, blob.to_vec(), pre_image.to_vec()));
+                    }
+                }
+                database.seek(SeekFrom::Start(blob_header.offset))?;
+                database.write_all(blob)?;
+            }
+            if let Some((offset, merged, merged_pre_image)) = pending.take() {
+                target.new_blob_with_pre_image(offset, &merged, &merged_pre_image)?;
+            }
+            target.commit()?;
+        }
+        Ok(target)
+    }
+
+    /// Find id of last snapshot up to `upto` inclusive, which changed page size
+    ///
+    /// Walks over snapshot headers only, blobs are skipped.
+    fn last_baseline_snapshot(&mut self, upto: u64) -> Result<u64> {
+        self.update_header()?;
+        self.seek_first_snapshot()?;
+        let (mut baseline, mut page_size) = (0, None);
+        for _ in 0..self.header.snapshot_counter.min(upto.saturating_add(1)) {
+            let snapshot_header = self.read_snapshot_header()?;
+            match (page_size, snapshot_header.page_size) {
+                (Some(prev), Some(cur)) if prev != cur => baseline = snapshot_header.id,
+                _ => (),
+            }
+            page_size = snapshot_header.page_size.or(page_size);
+            self.skip_blobs()?;
+        }
+        Ok(baseline)
+    }
+
+    /// Skip blobs of current snapshot, including snapshot terminator
+    ///
+    /// Returns count and total size of skipped blobs
+    fn skip_blobs(&mut self) -> Result<(u32, u64)> {
+        let (mut count, mut bytes) = (0, 0);
+        loop {
+            let blob_header = self.read_blob_header()?;
+            if blob_header.is_last() {
+                return Ok((count, bytes));
+            }
+            count += 1;
+            bytes += blob_header.blob_size as u64;
+            self.fd
+                .seek_relative(self.header.blob_record_size(&blob_header))?;
+        }
+    }
+
+    /// Collect journal metadata
+    ///
+    /// Only headers are read, blobs are skipped.
+    pub fn info(&mut self) -> Result<JournalInfo> {
+        self.update_header()?;
+        self.seek_first_snapshot()?;
+        let mut snapshots = vec![];
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            let (blob_count, bytes) = self.skip_blobs()?;
+            snapshots.push(SnapshotInfo {
+                id: snapshot_header.id,
+                timestamp: snapshot_header.timestamp,
+                page_size: snapshot_header.page_size,
+                blob_count,
+                bytes,
+            });
+        }
+        Ok(JournalInfo {
+            version: self.header.version,
+            magic: self.header.magic,
+            snapshot_count: self.header.snapshot_counter,
+            eof: self.header.eof,
+            snapshots,
+        })
+    }
+
+    /// Count committed snapshots
+    pub fn count_snapshots(&mut self) -> Result<u64> {
+        if self.snapshot_started() {
+            return Err(Error::SnapshotInProgress);
+        }
+        self.update_header()?;
+        Ok(self.header.snapshot_counter)
+    }
+
+    /// Count blobs of committed snapshots
+    ///
+    /// Only headers are read, blobs are skipped.
+    pub fn count_blobs(&mut self) -> Result<u64> {
+        let snapshot_count = self.count_snapshots()?;
+        self.seek_first_snapshot()?;
+        let mut count = 0;
+        for _ in 0..snapshot_count {
+            self.read_snapshot_header()?;
+            count += self.skip_blobs()?.0 as u64;
+        }
+        Ok(count)
+    }
+
+    /// Byte ranges of committed snapshots in journal, as `(id, start offset, length)`
+    ///
+    /// Snapshot spans from its header up to and including its terminator. Only headers are read,
+    /// blobs are skipped.
+    pub fn snapshot_offsets(&mut self) -> Result<Vec<(u64, u64, u64)>> {
+        self.update_header()?;
+        let mut start = self.seek_first_snapshot()?;
+        let mut offsets = Vec::with_capacity(self.header.snapshot_counter as usize);
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            self.skip_blobs()?;
+            let end = self.fd.stream_position()?;
+            offsets.push((snapshot_header.id, start, end - start));
+            start = end;
+        }
+        Ok(offsets)
+    }
+
+    /// Estimate amount of data, replayed by restore starting at snapshot `from_snapshot`
+    /// inclusive, or by restore from scratch
+    ///
+    /// Restore never starts before last page size discontinuity, same as
+    /// [`Journal::export_database`]. Only headers are read, blobs are skipped.
+    pub fn restore_cost(&mut self, from_snapshot: Option<u64>) -> Result<RestoreCost> {
+        let snapshot_count = self.count_snapshots()?;
+        let from = self
+            .last_baseline_snapshot(u64::MAX)?
+            .max(from_snapshot.unwrap_or(0));
+        self.seek_first_snapshot()?;
+        let mut cost = RestoreCost::default();
+        for _ in 0..snapshot_count {
+            let snapshot_header = self.read_snapshot_header()?;
+            let (blobs, bytes) = self.skip_blobs()?;
+            if snapshot_header.id >= from {
+                cost.snapshots += 1;
+                cost.blobs += blobs as u64;
+                cost.bytes += bytes;
+            }
+        }
+        Ok(cost)
+    }
+
+    /// Compare database file against database, materialized from journal
+    ///
+    /// Database is compared page by page, page size is taken from header of database file, or
+    /// from header of materialized database, if file is empty. Pages, present only in one of
+    /// them, are reported as differing too. Volatile database header fields, i.e. change
+    /// counters, aren't compared, since they might not be journaled.
+    ///
+    /// Database isn't materialized as a whole: journal is indexed by blob headers first, then
+    /// each page is assembled from its blobs and compared with page of file.
+    pub fn validate_against_database<P: AsRef<path::Path>>(
+        &mut self,
+        db_path: P,
+    ) -> Result<DriftReport> {
+        let (locations, expected_size) = self.blob_locations()?;
+        self.drift(db_path.as_ref(), locations, expected_size)
+            .map(|(report, _)| report)
+    }
+
+    /// Compare database file against blobs at `locations`, which materialize database of
+    /// `expected_size`, see [`Journal::validate_against_database`]
+    ///
+    /// Returns drift report and blob locations, indexed by pages of report's page size.
+    fn drift(
+        &mut self,
+        db_path: &path::Path,
+        locations: Vec<BlobLocation>,
+        expected_size: u64,
+    ) -> Result<(DriftReport, PageIndex)> {
+        let mut actual = BufReader::new(fs::File::open(db_path)?);
+        let actual_size = actual.get_ref().metadata()?.len();
+        let header_size = page_parser::Header::block_size();
+        let mut header = vec![0; header_size];
+        match (actual_size, expected_size) {
+            // header is left zeroed, so page size is invalid
+            (0, 0) => (),
+            (0, _) => self.read_blobs_into(&mut header, 0, locations.iter())?,
+            _ => actual.read_exact(&mut header)?,
+        };
+        actual.rewind()?;
+
+        let mut report = DriftReport {
+            page_size: 0,
+            expected_size,
+            actual_size,
+            differing_pages: vec![],
+        };
+        if actual_size == 0 && expected_size == 0 {
+            return Ok((report, PageIndex::new(locations, 0)));
+        }
+        let page_size = from_bytes::<page_parser::Header>(&header)?.page_size();
+        if page_size == 0 {
+            return Err(Error::InvalidPageSize { page_size });
+        }
+        report.page_size = page_size;
+        let index = PageIndex::new(locations, page_size as u64);
+        let volatile_ranges = ["change_counter", "version_valid_for"]
+            .map(|name| page_parser::volatile_range(name).unwrap());
+
+        let pages = expected_size.max(actual_size).div_ceil(page_size as u64);
+        let mut page = Vec::with_capacity(page_size as usize);
+        let mut expected = Vec::with_capacity(page_size as usize);
+        for num in 0..pages {
+            let offset = num * page_size as u64;
+            page.clear();
+            (&mut actual)
+                .take(page_size as u64)
+                .read_to_end(&mut page)?;
+            // expected page is cut at the end of materialized database, same as page of file
+            expected.clear();
+            expected.resize(
+                expected_size.saturating_sub(offset).min(page_size as u64) as usize,
+                0,
+            );
+            self.read_blobs_into(&mut expected, offset, index.page(num))?;
+            page_parser::mask_volatile_ranges(&mut page, &expected, &volatile_ranges);
+            if page != expected {
+                report.differing_pages.push(offset);
+            }
+        }
+        Ok((report, index))
+    }
+
+    /// Bring journal up to date with database file, modified behind journal's back
+    ///
+    /// Database file is compared with [`Journal::validate_against_database`], on drift single
+    /// snapshot is appended:
+    /// * pages of file, which differ from journal, are written whole, with pre-images taken from
+    ///   journal
+    /// * if page size of file differs from journal, whole file is written into baseline snapshot
+    ///
+    /// Page by page comparison is skipped, if file has size of materialized database and its
+    /// first page is identical to journal's, change counter included: sqlite bumps change counter
+    /// on every commit in rollback journal mode, so commits of other connections always change
+    /// the first page. Pre-images of differing pages are read ahead of snapshot, so with undo log
+    /// they are kept in memory.
+    ///
+    /// Journal can't express truncation, so pages past the end of shrunk file are left in
+    /// journal, sqlite ignores them by database size in database header.
+    ///
+    /// Returns drift report, if snapshot was appended.
+    pub fn reconcile_with_database<P: AsRef<path::Path>>(
+        &mut self,
+        db_path: P,
+    ) -> Result<Option<DriftReport>> {
+        let db_path = db_path.as_ref();
+        let (locations, expected_size) = self.blob_locations()?;
+        if self.first_page_matches(db_path, &locations, expected_size)? {
+            return Ok(None);
+        }
+        let (report, index) = self.drift(db_path, locations, expected_size)?;
+        let page_size = report.page_size;
+        let offsets: Vec<u64> = match self.header.check_page_size(page_size) {
+            _ if report.is_consistent() => return Ok(None),
+            Ok(_) => report
+                .differing_pages
+                .iter()
+                .copied()
+                .filter(|&offset| offset < report.actual_size)
+                .collect(),
+            Err(_) => (0..report.actual_size.div_ceil(page_size as u64))
+                .map(|num| num * page_size as u64)
+                .collect(),
+        };
+        if offsets.is_empty() {
+            return Ok(None);
+        }
+        // journal can't be read, once snapshot is started
+        let mut pre_images = Vec::with_capacity(offsets.len());
+        if self.header.undo_log {
+            for &offset in offsets.iter() {
+                let mut pre_image = vec![0; page_size as usize];
+                let blobs = index.page(offset / page_size as u64);
+                self.read_blobs_into(&mut pre_image, offset, blobs)?;
+                pre_images.push(pre_image);
+            }
+        }
+
+        match self.header.check_page_size(page_size) {
+            Ok(_) => self.new_snapshot(page_size)?,
+            Err(_) => self.new_baseline_snapshot(page_size)?,
+        };
+        let mut actual = BufReader::new(fs::File::open(db_path)?);
+        let mut page = Vec::with_capacity(page_size as usize);
+        let mut pre_images = pre_images.into_iter();
+        for offset in offsets {
+            page.clear();
+            actual.seek(SeekFrom::Start(offset))?;
+            (&mut actual)
+                .take(page_size as u64)
+                .read_to_end(&mut page)?;
+            // pre-image is ignored without undo log
+            let mut pre_image = pre_images.next().unwrap_or_default();
+            pre_image.resize(page.len(), 0);
+            self.new_blob_with_pre_image(offset, &page, &pre_image)?;
+        }
+        self.commit()?;
+        Ok(Some(report))
+    }
+
+    /// Check if database file has size of database, materialized from blobs at `locations`, and
+    /// the same first page, see [`Journal::reconcile_with_database`]
+    fn first_page_matches(
+        &mut self,
+        db_path: &path::Path,
+        locations: &[BlobLocation],
+        expected_size: u64,
+    ) -> Result<bool> {
+        let mut actual = fs::File::open(db_path)?;
+        let header_size = page_parser::Header::block_size() as u64;
+        if actual.metadata()?.len() != expected_size || expected_size < header_size {
+            return Ok(false);
+        }
+        let mut header = vec![0; header_size as usize];
+        actual.read_exact(&mut header)?;
+        let page_size = match from_bytes::<page_parser::Header>(&header)?.page_size() {
+            0 => return Ok(false),
+            page_size => (page_size as u64).min(expected_size),
+        };
+        let mut page = vec![0; page_size as usize];
+        actual.rewind()?;
+        actual.read_exact(&mut page)?;
+        let mut expected = vec![0; page_size as usize];
+        self.read_blobs_into(&mut expected, 0, locations.iter())?;
+        Ok(page == expected)
+    }
+
+    /// Locate blobs since last page size discontinuity, in journal order
+    ///
+    /// Returns blob locations and size of database, they materialize. Only headers are read,
+    /// blobs are skipped, same as in [`Journal::skip_blobs`].
+    fn blob_locations(&mut self) -> Result<(Vec<BlobLocation>, u64)> {
+        let baseline = self.last_baseline_snapshot(u64::MAX)?;
+        self.seek_first_snapshot()?;
+        let (mut locations, mut size) = (vec![], 0);
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            if snapshot_header.id < baseline {
+                self.skip_blobs()?;
+                continue;
+            }
+            loop {
+                let blob_header = self.read_blob_header()?;
+                if blob_header.is_last() {
+                    break;
+                }
+                blob_header.validate()?;
+                locations.push(BlobLocation {
+                    position: self.fd.position()?,
+                    blob_header,
+                });
+                size = size.max(blob_header.offset + blob_header.blob_size as u64);
+                self.fd
+                    .seek_relative(self.header.blob_record_size(&blob_header))?;
+            }
+        }
+        Ok((locations, size))
+    }
+
+    /// Copy blobs at `locations` into `buf`, which holds database bytes starting at `start`
+    ///
+    /// Only parts of blobs, overlapping `buf`, are copied, later blobs overwrite earlier ones.
+    fn read_blobs_into<'l>(
+        &mut self,
+        buf: &mut [u8],
+        start: u64,
+        locations: impl Iterator<Item = &'l BlobLocation>,
+    ) -> Result<()> {
+        let end = start + buf.len() as u64;
+        let mut stored = vec![];
+        for BlobLocation {
+            position,
+            blob_header,
+        } in locations
+        {
+            let blob_end = blob_header.offset + blob_header.blob_size as u64;
+            if blob_header.offset >= end || blob_end <= start {
+                continue;
+            }
+            stored.resize(blob_header.stored_size() as usize, 0);
+            self.fd.seek(SeekFrom::Start(*position))?;
+            self.fd.read_exact(&mut stored)?;
+            self.header.verify_checksum(blob_header, &stored)?;
+            let (_, blob) = blob_header.decompress(&stored)?;
+            let from = blob_header.offset.max(start);
+            let to = blob_end.min(end);
+            buf[(from - start) as usize..(to - start) as usize].copy_from_slice(
+                &blob[(from - blob_header.offset) as usize..(to - blob_header.offset) as usize],
+            );
+        }
+        Ok(())
+    }
+
+    /// Digest of journal history, for cheap comparison of replicas
+    ///
+    /// SHA-256 over committed snapshots in order: snapshot ids and page sizes, blob headers and
+    /// uncompressed blobs. Snapshot timestamps, pre-images and journal layout, i.e. undo log,
+    /// blob alignment and blob compression, are not hashed, so replicas which applied the same
+    /// snapshots have equal digests. Digest is of history, not of final database: journals with
+    /// the same database but different snapshots, e.g. compacted journal, have different digests.
+    pub fn content_digest(&mut self) -> Result<[u8; 32]> {
+        self.update_header()?;
+        self.seek_first_snapshot()?;
+        let mut hasher = Sha256::new();
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            self.hash_snapshot(&mut hasher, &snapshot_header)?;
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Digest of committed snapshot `id`, for comparison of the same snapshot of replicas
+    ///
+    /// Snapshot is hashed same as in [`Journal::content_digest`], digest equals digest of
+    /// snapshot frames, e.g. of pulled stream, see [`SnapshotDigest`].
+    pub fn snapshot_digest(&mut self, id: u64) -> Result<[u8; 32]> {
+        self.update_header()?;
+        self.snapshot_digest_from(id, &mut DigestCursor::default())
+    }
+
+    /// Digest of committed snapshot `id`, scan starts at `cursor`, unless cursor is past `id`
+    ///
+    /// Cursor is moved past hashed snapshot, so digests of ascending snapshots are computed in
+    /// one pass over journal.
+    fn snapshot_digest_from(&mut self, id: u64, cursor: &mut DigestCursor) -> Result<[u8; 32]> {
+        let mut next_id = match cursor.next {
+            Some((position, next_id)) if next_id <= id => {
+                self.fd.seek(SeekFrom::Start(position))?;
+                self.prev_timestamp = cursor.prev_timestamp;
+                next_id
+            }
+            _ => {
+                self.seek_first_snapshot()?;
+                0
+            }
+        };
+        while next_id < self.header.snapshot_counter {
+            next_id += 1;
+            let snapshot_header = self.read_snapshot_header()?;
+            if snapshot_header.id != id {
+                self.skip_blobs()?;
+                continue;
+            }
+            let mut hasher = Sha256::new();
+            self.hash_snapshot(&mut hasher, &snapshot_header)?;
+            cursor.next = Some((self.fd.position()?, next_id));
+            cursor.prev_timestamp = self.prev_timestamp;
+            return Ok(hasher.finalize().into());
+        }
+        Err(Error::SnapshotNotFound { id })
+    }
+
+    /// Hash snapshot header and blobs of snapshot, which are read up to snapshot terminator
+    fn hash_snapshot(
+        &mut self,
+        hasher: &mut Sha256,
+        snapshot_header: &SnapshotHeader,
+    ) -> Result<()> {
+        hash_snapshot_header(hasher, snapshot_header);
+        let mut blob = vec![];
+        loop {
+            let blob_header = self.read_blob_header()?;
+            if blob_header.is_last() {
+                return hash_blob(hasher, &blob_header, &[]);
+            }
+            blob_header.validate()?;
+            blob.clear();
+            blob.try_reserve(blob_header.stored_size() as usize)?;
+            blob.resize(blob_header.stored_size() as usize, 0);
+            self.fd.read_exact(&mut blob)?;
+            hash_blob(hasher, &blob_header, &blob)?;
+            if self.header.undo_log {
+                self.fd.seek_relative(blob_header.blob_size as i64)?;
+            }
+        }
+    }
+
+    /// Add snapshot of stream, unless journal has it already
+    ///
+    /// Digest of journal snapshot is returned for duplicate snapshot, so blobs of stream
+    /// snapshot can be checked against it instead of being written. Digests of duplicate
+    /// snapshots of one stream are computed from `cursor`, so journal is scanned once.
+    pub(crate) fn add_stream_snapshot(
+        &mut self,
+        snapshot_header: &SnapshotHeader,
+        cursor: &mut DigestCursor,
+    ) -> Result<Option<DuplicateSnapshot>> {
+        match self.add_snapshot(snapshot_header) {
+            Ok(()) => Ok(None),
+            Err(Error::DuplicateSnapshot { id }) => Ok(Some(DuplicateSnapshot {
+                expected: self.snapshot_digest_from(id, cursor)?,
+                digest: SnapshotDigest::new(snapshot_header),
+                id,
+            })),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Serialized committed snapshot, as Protocol frames
+    ///
+    /// Snapshot header is followed by blob headers with blobs, same as in [`crate::Stream`], so
+    /// snapshot can be put into stream between journal version and EndOfStream. Snapshot
+    /// terminator has no Protocol frame, snapshot ends with the next frame. Pre-images are
+    /// not included, compressed blobs are not decompressed.
+    pub fn snapshot_bytes(&mut self, id: u64) -> Result<Vec<u8>> {
+        self.update_header()?;
+        self.seek_first_snapshot()?;
+        for _ in 0..self.header.snapshot_counter {
+            let snapshot_header = self.read_snapshot_header()?;
+            if snapshot_header.id != id {
+                self.skip_blobs()?;
+                continue;
+            }
+            let mut buf = to_bytes(&Protocol::from(snapshot_header))?;
+            loop {
+                let blob_header = self.read_blob_header()?;
+                if blob_header.is_last() {
+                    return Ok(buf);
+                }
+                buf.extend(to_bytes(&Protocol::from(blob_header))?);
+                let blob_start = buf.len();
+                let stored_size = blob_header.stored_size() as usize;
+                buf.try_reserve(stored_size)?;
+                buf.resize(blob_start + stored_size, 0);
+                self.fd.read_exact(&mut buf[blob_start..])?;
+                if self.header.undo_log {
+                    self.fd.seek_relative(blob_header.blob_size as i64)?;
+                }
+            }
+        }
+        Err(Error::SnapshotNotFound { id })
+    }
+
+    /// Undo last snapshot
+    ///
+    /// Pre-images of last snapshot blobs are applied to output in reverse order.
+    /// Journal itself is not modified.
+    pub fn undo_last<W: Write + Seek>(&mut self, mut output: W) -> Result<()> {
+        self.update_header()?;
+        if !self.header.undo_log {
+            return Err(Error::UndoLogDisabled);
+        }
+        let last = match self.header.snapshot_counter {
+            0 => return Ok(()),
+            v => v - 1,
+        };
+        self.seek_first_snapshot()?;
+        for _ in 0..last {
+            self.read_snapshot_header()?;
+            self.skip_blobs()?;
+        }
+        self.read_snapshot_header()?;
+        let mut pre_images = vec![];
+        loop {
+            let blob_header = self.read_blob_header()?;
+            if blob_header.is_last() {
+                break;
+            }
+            self.fd.seek_relative(blob_header.stored_size() as i64)?;
+            let mut pre_image = vec![];
+            pre_image.try_reserve(blob_header.blob_size as usize)?;
+            pre_image.resize(blob_header.blob_size as usize, 0);
+            self.fd.read_exact(pre_image.as_mut_slice())?;
+            pre_images.push((blob_header.offset, pre_image));
+        }
+        for (offset, pre_image) in pre_images.iter().rev() {
+            output.seek(SeekFrom::Start(*offset))?;
+            output.write_all(pre_image)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Apply Protocol stream to journal
+    ///
+    /// Stream has to start with journal version and end with EndOfStream. Streams of older
+    /// journal versions are accepted, e.g. from peers, which weren't upgraded yet.
+    /// Each stream snapshot is committed once next snapshot or end of stream is reached.
+    /// Snapshots already present in journal are skipped, snapshot id gap is an error. Skipped
+    /// snapshot has to match journal snapshot, otherwise histories diverged, e.g. stream comes
+    /// from another writer, and [`Error::SnapshotDivergence`] is returned.
+    pub fn apply_stream<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut reader = BufReader::new(reader);
+        let version = match from_reader::<Protocol, _>(&mut reader)? {
+            Protocol::JournalVersion(v) if (1..=JOURNAL_VERSION).contains(&u32::from(v)) => {
+                u32::from(v)
+            }
+            Protocol::JournalVersion(v) => {
+                return Err(Error::UnexpectedJournalVersion {
+                    expected: JOURNAL_VERSION,
+                    got: v.into(),
+                })
+            }
+            _ => return Err(Error::MissingJournalVersion),
+        };
+        // blob buffer is re-used between blobs
+        let mut blob = vec![];
+        // blobs of duplicate snapshot are hashed instead of written
+        let mut duplicate = None;
+        let mut cursor = DigestCursor::default();
+        loop {
+            match Protocol::read_frame(&mut reader, version)? {
+                Protocol::SnapshotHeader(snapshot_header) => {
+                    duplicate
+                        .take()
+                        .map(DuplicateSnapshot::verify)
+                        .transpose()?;
+                    self.commit()?;
+                    duplicate = self.add_stream_snapshot(&snapshot_header, &mut cursor)?;
+                }
+                Protocol::BlobHeader(blob_header) => {
+                    blob_header.validate()?;
+                    self.check_blob_size(blob_header.blob_size as usize)?;
+                    blob.resize(blob_header.stored_size() as usize, 0);
+                    reader.read_exact(blob.as_mut_slice())?;
+                    match duplicate.as_mut() {
+                        Some(duplicate) => duplicate.digest.update(&blob_header, &blob)?,
+                        None => self.add_blob(&blob_header, blob.as_slice())?,
+                    }
+                }
+                Protocol::EndOfStream(_) => {
+                    duplicate
+                        .take()
+                        .map(DuplicateSnapshot::verify)
+                        .transpose()?;
+                    return self.commit();
+                }
+                Protocol::JournalVersion(_) => {
+                    return Err(Error::UnexpectedFrame {
+                        frame: "JournalVersion".into(),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Iterate over blobs which overlap `[start, end)` range of database
+    ///
+    /// Blobs straddling range boundary are returned whole, so blob headers stay valid and
+    /// filtered blobs can be fed into [`crate::Stream`] to produce a sub-journal.
+    pub fn blobs_in_range(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<impl Iterator<Item = <IntoIter<'_, F> as Iterator>::Item>> {
+        self.update_header()?;
+        Ok(self.into_iter().filter(move |item| match item {
+            Ok((_, blob_header, _)) => {
+                blob_header.offset < end
+                    && blob_header.offset + blob_header.blob_size as u64 > start
+            }
+            Err(_) => true,
+        }))
+    }
+
+    /// Iterate over snapshots, not confirmed by sync backend, i.e. snapshots after
+    /// `confirmed_remote`
+    ///
+    /// `confirmed_remote` is id of last snapshot, sync backend has, `None` if it has none, so
+    /// snapshot 0 is included. Iterator is empty, if backend has all snapshots. Unlike
+    /// [`IntoIter::skip_snapshots`], read errors are not filtered out. Blobs are yielded as
+    /// stored, see [`IntoIter::stored`].
+    pub fn iter_unpushed(
+        &mut self,
+        confirmed_remote: Option<u64>,
+    ) -> impl Iterator<Item = <IntoIter<'_, F> as Iterator>::Item> {
+        let first = confirmed_remote.map_or(0, |id| id.saturating_add(1));
+        self.into_iter().stored().filter(move |item| match item {
+            Ok((snapshot_header, _, _)) => snapshot_header.id >= first,
+            Err(_) => true,
+        })
+    }
+
+    /// Iterate over snapshots with timestamp in `[start_ts, end_ts]`, both bounds inclusive
+    ///
+    /// Snapshot timestamps aren't guaranteed to be monotonic, e.g. wall clock can go back, so
+    /// all snapshots are checked, iteration doesn't stop at the first snapshot past the window.
+    /// Iterator is empty if `start_ts > end_ts`. Same as [`Journal::iter_unpushed`], read errors
+    /// are not filtered out.
+    pub fn iter_between(
+        &mut self,
+        start_ts: i64,
+        end_ts: i64,
+    ) -> impl Iterator<Item = <IntoIter<'_, F> as Iterator>::Item> {
+        self.into_iter().filter(move |item| match item {
+            Ok((snapshot_header, _, _)) => (start_ts..=end_ts).contains(&snapshot_header.timestamp),
+            Err(_) => true,
+        })
+    }
+
+    /// Replace underlying fd, returning previous one
+    ///
+    /// * previous fd is flushed before it's returned
+    /// * header is re-read from new fd, and checked same as by [`Journal::from_fd`]
+    /// * replacement is refused while snapshot is in progress
+    pub fn replace_fd(&mut self, mut new_fd: F) -> Result<F> {
+        if self.snapshot_started() {
+            return Err(Error::SnapshotInProgress);
+        }
+        let header = Self::read_header(&mut new_fd)?;
+        Self::check_header(&header)?;
+        self.fd.drain()?;
+        self.fd.flush_fd()?;
+        self.unflushed_commits = 0;
+        let mut fd = std::mem::replace(&mut self.fd, Fd::Raw(new_fd));
+        self.header = header;
+        // read positions and journal path don't point into new fd
+        self.prev_timestamp = None;
+        self.implied_terminator = None;
+        self.first_blob_position = None;
+        self.path = None;
+        Ok(fd.as_fd())
+    }
+
+    /// Consume journal, returning flushed underlying fd
+    pub fn take_fd(mut self) -> Result<F> {
+        if self.snapshot_started() {
+            return Err(Error::SnapshotInProgress);
+        }
+        self.fd.drain()?;
+        self.fd.flush_fd()?;
+        self.unflushed_commits = 0;
+        Ok(self.fd.as_fd())
+    }
+
+    /// Get journal header
+    pub fn get_header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Return current snapshot counter
+    pub fn current_snapshot(&self) -> Option<u64> {
+        match self.header.snapshot_counter {
+            0 => None,
+            v => Some(v),
+        }
+    }
+
+    /// Update journal header
+    pub fn update_header(&mut self) -> Result<()> {
+        self.fd.as_reader(self.buffer_sz);
+        self.header = Self::read_header(&mut self.fd)?;
+        Ok(())
+    }
+
+    /// Read header from a given fd
+    ///
+    /// * seek to start of the file
+    /// * read header
+    fn read_header<R: Read + Seek>(fd: &mut R) -> Result<Header> {
+        fd.rewind()?;
+        from_reader(BufReader::new(fd)).map_err(Into::into)
+    }
+
+    /// Check header magic, version and blob alignment
+    fn check_header(header: &Header) -> Result<()> {
+        if header.magic != MAGIC {
+            return Err(Error::InvalidMagic {
+                magic: header.magic,
+            });
+        }
+        if !(1..=JOURNAL_VERSION).contains(&header.version) {
+            return Err(Error::UnexpectedJournalVersion {
+                expected: JOURNAL_VERSION,
+                got: header.version,
+            });
+        }
+        header.check_blob_alignment()
+    }
+
+    /// Write header to a given fd
+    ///
+    /// * seek to start of the file
+    /// * write header
+    fn write_header<W: Write + Seek>(fd: &mut W, header: &Header) -> Result<()> {
+        fd.rewind()?;
+        fd.write_all(&to_bytes(header)?).map_err(Into::into)
+    }
+
+    /// Check blob doesn't exceed max blob size of journal
+    fn check_blob_size(&self, blob_size: usize) -> Result<()> {
+        match blob_size > self.max_blob_size as usize {
+            true => Err(Error::BlobTooLarge {
+                blob_size: u32::try_from(blob_size).unwrap_or(u32::MAX),
+                max: self.max_blob_size,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Write zero padding, so blob following blob header is aligned
+    fn write_blob_padding(&mut self) -> Result<()> {
+        if self.header.blob_alignment.is_none() {
+            return Ok(());
+        }
+        let padding = self.header.blob_padding(self.fd.position()?);
+        self.fd.write_all(&vec![0; padding as usize])?;
+        Ok(())
+    }
+
+    /// Seek to header of first snapshot
+    fn seek_first_snapshot(&mut self) -> Result<u64> {
+        self.prev_timestamp = None;
+        Ok(self.fd.seek(SeekFrom::Start(Header::block_size() as u64))?)
+    }
+
+    /// Read snapshot header, relative timestamp is resolved against previous snapshot header
+    fn read_snapshot_header(&mut self) -> Result<SnapshotHeader> {
+        if !self.header.relative_timestamps {
+            return Ok(from_reader::<SnapshotHeader, _>(&mut self.fd)?);
+        }
+        let header_size = self.header.snapshot_header_size();
+        let mut buf = vec![0; header_size];
+        self.fd.read_exact(&mut buf)?;
+        buf.resize(header_size + self.header.absolute_timestamp_size(&buf)?, 0);
+        self.fd.read_exact(&mut buf[header_size..])?;
+        let snapshot_header = self
+            .header
+            .snapshot_header_from_bytes(&buf, self.prev_timestamp)?;
+        self.prev_timestamp = Some(snapshot_header.timestamp);
+        Ok(snapshot_header)
+    }
+
+    /// Read blob header, skipping padding in front of it
+    ///
+    /// Terminator of single blob snapshot, implied by last blob flag, is returned without read.
+    fn read_blob_header(&mut self) -> Result<BlobHeader> {
+        if let Some(position) = self.implied_terminator.take() {
+            if position == self.fd.position()? {
+                return Ok(BlobHeader::last());
+            }
+        }
+        if self.header.blob_alignment.is_some() {
+            let padding = self.header.blob_padding(self.fd.position()?);
+            // reading keeps buffered data, unlike seek
+            std::io::copy(&mut (&mut self.fd).take(padding), &mut std::io::sink())?;
+        }
+        let mut buf = vec![0; self.header.blob_header_size()];
+        self.fd.read_exact(&mut buf)?;
+        let mut blob_header = self.header.blob_header_from_bytes(&buf)?;
+        if self.header.implicit_terminators && blob_header.blob_num & LAST_BLOB_FLAG != 0 {
+            blob_header.blob_num &= !LAST_BLOB_FLAG;
+            let end = self.fd.position()? + self.header.blob_record_size(&blob_header) as u64;
+            self.implied_terminator = Some(end);
+        }
+        Ok(blob_header)
     }
 
     /// Check if snapshot was already started
-    fn snapshot_started(&self) -> bool {
+    pub fn snapshot_started(&self) -> bool {
         self.blob_count.is_some()
     }
 }
 
+impl<F: Read + Write + Seek> Drop for Journal<F> {
+    fn drop(&mut self) {
+        // FIXME: error is swallowed
+        self.flush().ok();
+    }
+}
+
 #[derive(Debug)]
 pub struct IntoIter<'a, F = fs::File>
 where
@@ -316,9 +2205,32 @@ where
     current_snapshot: Option<SnapshotHeader>,
     initialized: bool,
     eoi: bool,
+    /// compressed blobs are yielded as is
+    stored: bool,
 }
 
 impl<'a, F: Write + Read + Seek> IntoIter<'a, F> {
+    /// Yield blobs as stored in journal, i.e. compressed blobs are not decompressed
+    ///
+    /// Stored blobs keep blob headers with compressed size and checksum of compressed blob,
+    /// so they can be streamed to other journals as is.
+    pub fn stored(mut self) -> Self {
+        self.stored = true;
+        self
+    }
+
+    /// Rewind iterator to the first snapshot
+    ///
+    /// Journal header is not re-read, since journal can't change while it's borrowed by iterator.
+    pub fn reset(&mut self) -> Result<()> {
+        if self.initialized {
+            self.journal.seek_first_snapshot()?;
+        }
+        self.current_snapshot = None;
+        self.eoi = self.journal.header.snapshot_counter == 0;
+        Ok(())
+    }
+
     pub fn skip_snapshots(
         self,
         skip: u64,
@@ -341,6 +2253,7 @@ impl<'a, F: Read + Write + Seek> IntoIterator for &'a mut Journal<F> {
             initialized: false,
             current_snapshot: None,
             eoi,
+            stored: false,
         }
     }
 }
@@ -357,59 +2270,59 @@ where
                 self.eoi = true;
                 return Some(Err(e));
             }
-            match self
-                .journal
-                .fd
-                .seek(SeekFrom::Start(Header::block_size() as u64))
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    self.eoi = true;
-                    return Some(Err(e.into()));
-                }
-            };
+            if let Err(e) = self.journal.seek_first_snapshot() {
+                self.eoi = true;
+                return Some(Err(e));
+            }
             self.journal.fd.as_reader(self.journal.buffer_sz);
             self.initialized = true;
         }
-        if self.eoi {
-            return None;
-        }
-        if self.current_snapshot.is_none() {
-            self.current_snapshot = match from_reader::<SnapshotHeader, _>(&mut self.journal.fd) {
-                Ok(s) => Some(s),
+        // snapshots without blobs are skipped in a loop, so stack usage doesn't depend on
+        // number of consecutive empty snapshots
+        let blob_header = loop {
+            if self.eoi {
+                return None;
+            }
+            if self.current_snapshot.is_none() {
+                self.current_snapshot = match self.journal.read_snapshot_header() {
+                    Ok(s) => Some(s),
+                    Err(e) => {
+                        self.eoi = true;
+                        return Some(Err(e));
+                    }
+                };
+            }
+            let blob_header = match self.journal.read_blob_header() {
+                Ok(p) => p,
                 Err(e) => {
                     self.eoi = true;
-                    return Some(Err(e.into()));
+                    return Some(Err(e));
                 }
             };
-        }
-        let blob_header = match from_reader::<BlobHeader, _>(&mut self.journal.fd) {
-            Ok(p) => p,
-            Err(e) => {
-                self.eoi = true;
-                return Some(Err(e.into()));
+            if !blob_header.is_last() {
+                break blob_header;
             }
-        };
-        if blob_header.is_last() {
             if self.current_snapshot.as_ref().unwrap().id + 1
                 == self.journal.header.snapshot_counter
             {
                 self.eoi = true;
-                return None;
             } else {
                 self.current_snapshot = None;
-                return self.next();
             }
+        };
+        if let Err(e) = blob_header.validate() {
+            self.eoi = true;
+            return Some(Err(e));
         }
         let mut buf = vec![];
-        match buf.try_reserve(blob_header.blob_size as usize) {
+        match buf.try_reserve(blob_header.stored_size() as usize) {
             Ok(_) => (),
             Err(e) => {
                 self.eoi = true;
                 return Some(Err(e.into()));
             }
         }
-        buf.resize(blob_header.blob_size as usize, 0);
+        buf.resize(blob_header.stored_size() as usize, 0);
         match self.journal.fd.read_exact(buf.as_mut_slice()) {
             Ok(_) => (),
             Err(e) => {
@@ -417,6 +2330,27 @@ where
                 return Some(Err(e.into()));
             }
         }
+        if let Err(e) = self.journal.header.verify_checksum(&blob_header, &buf) {
+            self.eoi = true;
+            return Some(Err(e));
+        }
+        if self.journal.header.undo_log {
+            let pre_image_size = blob_header.blob_size as i64;
+            if let Err(e) = self.journal.fd.seek_relative(pre_image_size) {
+                self.eoi = true;
+                return Some(Err(e.into()));
+            }
+        }
+        let (blob_header, buf) = match self.stored || blob_header.compressed_size == 0 {
+            true => (blob_header, buf),
+            false => match blob_header.decompress(&buf) {
+                Ok((blob_header, blob)) => (blob_header, blob.into_owned()),
+                Err(e) => {
+                    self.eoi = true;
+                    return Some(Err(e));
+                }
+            },
+        };
         Some(Ok((
             *self.current_snapshot.as_ref().unwrap(),
             blob_header,
@@ -425,6 +2359,174 @@ where
     }
 }
 
+/// Incremental digest of snapshot, fed with snapshot frames, see [`Journal::snapshot_digest`]
+///
+/// Blobs are fed in order, as stored, i.e. compressed blobs are hashed decompressed.
+pub struct SnapshotDigest {
+    hasher: Sha256,
+}
+
+impl SnapshotDigest {
+    pub fn new(snapshot_header: &SnapshotHeader) -> Self {
+        let mut hasher = Sha256::new();
+        hash_snapshot_header(&mut hasher, snapshot_header);
+        Self { hasher }
+    }
+
+    /// Hash blob of snapshot
+    pub fn update(&mut self, blob_header: &BlobHeader, blob: &[u8]) -> Result<()> {
+        hash_blob(&mut self.hasher, blob_header, blob)
+    }
+
+    /// Digest of snapshot, terminated by fed blobs
+    pub fn finalize(mut self) -> [u8; 32] {
+        // terminator can't fail to hash, it has no blob
+        let _ = hash_blob(&mut self.hasher, &BlobHeader::last(), &[]);
+        self.hasher.finalize().into()
+    }
+}
+
+/// Scan position of digests of duplicate snapshots of stream, see [`Journal::apply_stream`]
+#[derive(Debug, Default)]
+pub(crate) struct DigestCursor {
+    /// position and id of snapshot header, following last hashed snapshot
+    next: Option<(u64, u64)>,
+    /// timestamp of last hashed snapshot, base of relative timestamp of next one
+    prev_timestamp: Option<i64>,
+}
+
+/// Stream snapshot, which journal has already, see [`Journal::apply_stream`]
+pub(crate) struct DuplicateSnapshot {
+    id: u64,
+    /// digest of journal snapshot
+    expected: [u8; 32],
+    /// digest of stream snapshot
+    pub(crate) digest: SnapshotDigest,
+}
+
+impl DuplicateSnapshot {
+    /// Check stream snapshot matches journal snapshot
+    pub(crate) fn verify(self) -> Result<()> {
+        match self.digest.finalize() == self.expected {
+            true => Ok(()),
+            false => Err(Error::SnapshotDivergence { id: self.id }),
+        }
+    }
+}
+
+fn hash_snapshot_header(hasher: &mut Sha256, snapshot_header: &SnapshotHeader) {
+    hasher.update(snapshot_header.id.to_be_bytes());
+    hasher.update(snapshot_header.page_size.unwrap_or(0).to_be_bytes());
+}
+
+/// Hash blob header and uncompressed blob
+///
+/// Terminator is hashed too, so snapshot boundaries are part of digest, checksum and compressed
+/// size aren't, so digest doesn't depend on journal version and compression.
+fn hash_blob(hasher: &mut Sha256, blob_header: &BlobHeader, blob: &[u8]) -> Result<()> {
+    hasher.update(&to_bytes(blob_header)?[..BLOB_HEADER_V1_SIZE]);
+    hasher.update(blob_header.decompress(blob)?.1);
+    Ok(())
+}
+
+/// Journal metadata, see [`Journal::info`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct JournalInfo {
+    pub version: u32,
+    pub magic: u32,
+    pub snapshot_count: u64,
+    pub eof: u64,
+    pub snapshots: Vec<SnapshotInfo>,
+}
+
+/// Snapshot metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotInfo {
+    pub id: u64,
+    pub timestamp: i64,
+    pub page_size: Option<u32>,
+    pub blob_count: u32,
+    /// total size of blobs
+    pub bytes: u64,
+}
+
+/// Amount of journal data, replayed by restore, see [`Journal::restore_cost`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreCost {
+    /// snapshot count
+    pub snapshots: u64,
+    /// blob count
+    pub blobs: u64,
+    /// total size of blobs, i.e. bytes written into database
+    pub bytes: u64,
+}
+
+/// Differences between database file and database, materialized from journal, see
+/// [`Journal::validate_against_database`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DriftReport {
+    /// page size, pages are compared with, zero if both databases are empty
+    pub page_size: u32,
+    /// size of database, materialized from journal
+    pub expected_size: u64,
+    /// size of database file
+    pub actual_size: u64,
+    /// offsets of differing pages
+    pub differing_pages: Vec<u64>,
+}
+
+impl DriftReport {
+    /// Database file matches journal
+    pub fn is_consistent(&self) -> bool {
+        self.expected_size == self.actual_size && self.differing_pages.is_empty()
+    }
+}
+
+/// Blob of committed snapshot, located by [`Journal::blob_locations`]
+#[derive(Debug, Clone, Copy)]
+struct BlobLocation {
+    /// position of stored blob in journal
+    position: u64,
+    blob_header: BlobHeader,
+}
+
+/// Blob locations, indexed by database page
+#[derive(Debug)]
+struct PageIndex {
+    locations: Vec<BlobLocation>,
+    /// page number and index of location of each blob, overlapping the page, sorted by page,
+    /// then by journal order
+    pages: Vec<(u64, usize)>,
+}
+
+impl PageIndex {
+    /// Index `locations` by pages of `page_size`, nothing is indexed with zero page size
+    fn new(locations: Vec<BlobLocation>, page_size: u64) -> Self {
+        let mut pages = vec![];
+        for (num, location) in locations.iter().enumerate() {
+            let blob_header = &location.blob_header;
+            let last_byte = blob_header.offset + blob_header.blob_size.max(1) as u64 - 1;
+            if let (Some(first), Some(last)) = (
+                blob_header.offset.checked_div(page_size),
+                last_byte.checked_div(page_size),
+            ) {
+                pages.extend((first..=last).map(|page| (page, num)));
+            }
+        }
+        pages.sort_unstable();
+        Self { locations, pages }
+    }
+
+    /// Locations of blobs, overlapping page `page`, numbered from 0, in journal order
+    fn page(&self, page: u64) -> impl Iterator<Item = &BlobLocation> {
+        let first = self.pages.partition_point(|&(num, _)| num < page);
+        self.pages[first..]
+            .iter()
+            .take_while(move |&&(num, _)| num == page)
+            .map(|&(_, location)| &self.locations[location])
+    }
+}
+
 /// Journal Header
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
 #[block(128)]
@@ -437,19 +2539,258 @@ pub struct Header {
     pub snapshot_counter: u64,
     /// end of last snapshot
     pub eof: u64,
+    /// page size of last snapshot
+    #[serde(
+        serialize_with = "serde_sqlite::se::none_as_zero",
+        deserialize_with = "serde_sqlite::de::zero_as_none"
+    )]
+    pub page_size: Option<u32>,
+    /// journal keeps pre-images of blobs
+    pub undo_log: bool,
+    /// blobs start at offsets, multiple of alignment, blob headers are preceded by padding
+    #[serde(
+        serialize_with = "serde_sqlite::se::none_as_zero",
+        deserialize_with = "serde_sqlite::de::zero_as_none"
+    )]
+    pub blob_alignment: Option<u32>,
+    /// only blob of snapshot is flagged as last instead of being followed by terminator
+    pub implicit_terminators: bool,
+    /// snapshot headers store timestamp as delta from timestamp of previous snapshot
+    pub relative_timestamps: bool,
+    /// timestamp of last snapshot, zero if journal is empty or predates the field
+    pub last_timestamp: i64,
 }
 
 impl Default for Header {
     fn default() -> Self {
         Self {
             magic: MAGIC,
-            version: 1,
+            version: JOURNAL_VERSION,
             snapshot_counter: 0,
             eof: <Self as block::Block>::block_size() as u64,
+            page_size: None,
+            undo_log: false,
+            blob_alignment: None,
+            implicit_terminators: false,
+            relative_timestamps: false,
+            last_timestamp: 0,
+        }
+    }
+}
+
+impl Header {
+    /// Size of blob record on disk, following blob header
+    ///
+    /// Pre-image is never compressed.
+    pub(crate) fn blob_record_size(&self, blob_header: &BlobHeader) -> i64 {
+        match self.undo_log {
+            true => blob_header.stored_size() as i64 + blob_header.blob_size as i64,
+            false => blob_header.stored_size() as i64,
+        }
+    }
+
+    /// Size of blob header on disk, version 1 blob headers have no checksum, version 2 blob
+    /// headers have no compressed size
+    pub(crate) fn blob_header_size(&self) -> usize {
+        match self.version {
+            1 => BLOB_HEADER_V1_SIZE,
+            2 => BLOB_HEADER_V2_SIZE,
+            _ => BlobHeader::block_size(),
+        }
+    }
+
+    /// Blob header with checksum and blob, as they are written into journal of this version
+    ///
+    /// Uncompressed blob is compressed with given compression, compressed blob is kept as is,
+    /// unless journal version predates compression, then blob is decompressed.
+    pub(crate) fn stored_blob<'b>(
+        &self,
+        blob_header: &BlobHeader,
+        blob: &'b [u8],
+        compression: Compression,
+    ) -> Result<(BlobHeader, Cow<'b, [u8]>)> {
+        let blob_header = blob_header.with_checksum(blob)?;
+        match (
+            blob_header.compressed_size,
+            self.version >= COMPRESSION_VERSION,
+        ) {
+            (0, true) => match compression.compress(blob)? {
+                Some(compressed) => Ok((
+                    BlobHeader {
+                        compressed_size: compressed.len() as u32,
+                        crc32: crc32fast::hash(&compressed),
+                        ..blob_header
+                    },
+                    Cow::Owned(compressed),
+                )),
+                None => Ok((blob_header, Cow::Borrowed(blob))),
+            },
+            (0, false) | (_, true) => Ok((blob_header, Cow::Borrowed(blob))),
+            (_, false) => blob_header.decompress(blob),
+        }
+    }
+
+    /// Serialize blob header in on-disk format of journal version
+    pub(crate) fn blob_header_to_bytes(&self, blob_header: &BlobHeader) -> Result<Vec<u8>> {
+        let mut bytes = to_bytes(blob_header)?;
+        bytes.truncate(self.blob_header_size());
+        Ok(bytes)
+    }
+
+    /// Deserialize blob header from on-disk format of journal version
+    ///
+    /// Version 1 blob headers are read with zero checksum, version 1 and 2 blob headers are
+    /// read with zero compressed size.
+    pub(crate) fn blob_header_from_bytes(&self, buf: &[u8]) -> Result<BlobHeader> {
+        let mut bytes = buf.to_vec();
+        bytes.resize(BlobHeader::block_size(), 0);
+        from_bytes::<BlobHeader>(&bytes).map_err(Into::into)
+    }
+
+    /// Check blob matches checksum of blob header
+    ///
+    /// Version 1 journals have no checksums, blobs are not verified.
+    pub(crate) fn verify_checksum(&self, blob_header: &BlobHeader, blob: &[u8]) -> Result<()> {
+        match self.version {
+            1 => Ok(()),
+            _ => blob_header.verify_checksum(blob),
+        }
+    }
+
+    /// Timestamp of last snapshot, relative timestamp of next snapshot is based on, if any
+    pub(crate) fn base_timestamp(&self) -> Option<i64> {
+        (self.snapshot_counter > 0).then_some(self.last_timestamp)
+    }
+
+    /// Size of snapshot header on disk, snapshot header with relative timestamp can be followed
+    /// by absolute timestamp, see [`Header::absolute_timestamp_size`]
+    pub(crate) fn snapshot_header_size(&self) -> usize {
+        match self.relative_timestamps {
+            true => RelativeSnapshotHeader::block_size(),
+            false => SnapshotHeader::block_size(),
+        }
+    }
+
+    /// Serialize snapshot header in on-disk format of journal
+    ///
+    /// Relative timestamp is based on timestamp of last snapshot. Timestamp, which doesn't fit
+    /// delta, e.g. timestamp of first snapshot or timestamp which went back, is flagged and
+    /// appended as is.
+    pub(crate) fn snapshot_header_to_bytes(
+        &self,
+        snapshot_header: &SnapshotHeader,
+    ) -> Result<Vec<u8>> {
+        if !self.relative_timestamps {
+            return Ok(to_bytes(snapshot_header)?);
+        }
+        let page_size = snapshot_header.page_size.unwrap_or(0);
+        if page_size & ABSOLUTE_TIMESTAMP_FLAG != 0 {
+            return Err(Error::InvalidPageSize { page_size });
+        }
+        let delta = self
+            .base_timestamp()
+            .and_then(|base| snapshot_header.timestamp.checked_sub(base))
+            .and_then(|delta| u32::try_from(delta).ok());
+        let relative = RelativeSnapshotHeader {
+            id: snapshot_header.id,
+            timestamp_delta: delta.unwrap_or(0),
+            page_size: match delta {
+                Some(_) => page_size,
+                None => page_size | ABSOLUTE_TIMESTAMP_FLAG,
+            },
+        };
+        let mut bytes = to_bytes(&relative)?;
+        if delta.is_none() {
+            bytes.extend(snapshot_header.timestamp.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// Size of absolute timestamp, following snapshot header with relative timestamp in `buf`
+    pub(crate) fn absolute_timestamp_size(&self, buf: &[u8]) -> Result<usize> {
+        let relative = from_bytes::<RelativeSnapshotHeader>(buf)?;
+        match relative.page_size & ABSOLUTE_TIMESTAMP_FLAG {
+            0 => Ok(0),
+            _ => Ok(ABSOLUTE_TIMESTAMP_SIZE),
+        }
+    }
+
+    /// Deserialize snapshot header from on-disk format of journal
+    ///
+    /// Relative timestamp is added to timestamp `base` of previous snapshot.
+    pub(crate) fn snapshot_header_from_bytes(
+        &self,
+        buf: &[u8],
+        base: Option<i64>,
+    ) -> Result<SnapshotHeader> {
+        if !self.relative_timestamps {
+            return Ok(from_bytes::<SnapshotHeader>(buf)?);
+        }
+        let (header, timestamp) = buf.split_at(RelativeSnapshotHeader::block_size());
+        let relative = from_bytes::<RelativeSnapshotHeader>(header)?;
+        let timestamp = match (relative.page_size & ABSOLUTE_TIMESTAMP_FLAG, base) {
+            (0, Some(base)) => base.saturating_add(relative.timestamp_delta as i64),
+            (0, None) => return Err(Error::MissingBaseTimestamp { id: relative.id }),
+            (_, _) => {
+                let timestamp: [u8; ABSOLUTE_TIMESTAMP_SIZE] = timestamp
+                    .try_into()
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                i64::from_be_bytes(timestamp)
+            }
+        };
+        let page_size = relative.page_size & !ABSOLUTE_TIMESTAMP_FLAG;
+        Ok(SnapshotHeader::new(
+            relative.id,
+            timestamp,
+            Some(page_size).filter(|page_size| *page_size != 0),
+        ))
+    }
+
+    /// Size of zero padding in front of blob header at `position`, so blob is aligned
+    pub(crate) fn blob_padding(&self, position: u64) -> u64 {
+        match self.blob_alignment {
+            Some(alignment) if alignment > 1 => {
+                let alignment = alignment as u64;
+                let blob_start = position + self.blob_header_size() as u64;
+                (alignment - blob_start % alignment) % alignment
+            }
+            _ => 0,
+        }
+    }
+
+    /// Check blob alignment is power of two, up to max sqlite page size
+    pub(crate) fn check_blob_alignment(&self) -> Result<()> {
+        match self.blob_alignment {
+            Some(alignment) if !alignment.is_power_of_two() || alignment > MAX_BLOB_ALIGNMENT => {
+                Err(Error::InvalidBlobAlignment { alignment })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check page size matches page size of previous snapshots
+    pub(crate) fn check_page_size(&self, page_size: u32) -> Result<()> {
+        match self.page_size {
+            Some(expected) if expected != page_size => Err(Error::PageSizeChanged {
+                expected,
+                got: page_size,
+            }),
+            _ => Ok(()),
         }
     }
 }
 
+/// Timestamp of baseline snapshot produced by [`Journal::compact`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaselineTimestamp {
+    /// Timestamp of the first folded snapshot
+    Earliest,
+    /// Timestamp of the last folded snapshot
+    Latest,
+    /// Given timestamp
+    Explicit(i64),
+}
+
 /// Transaction Header
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[block(32)]
@@ -473,13 +2814,31 @@ impl SnapshotHeader {
     }
 }
 
-/// Blob Header
+/// Snapshot header of journal with relative timestamps, see [`Header::relative_timestamps`]
+///
+/// Page size is flagged with [`ABSOLUTE_TIMESTAMP_FLAG`], if header is followed by absolute
+/// timestamp instead of delta.
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[block(16)]
+struct RelativeSnapshotHeader {
+    id: u64,
+    timestamp_delta: u32,
+    page_size: u32,
+}
+
+/// Blob Header
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[block(24)]
 pub struct BlobHeader {
     pub offset: u64,
     pub blob_num: u32,
+    /// Size of uncompressed blob
     pub blob_size: u32,
+    /// CRC32 of blob as stored, i.e. of compressed blob, zero if unknown, i.e. blob header
+    /// isn't written yet or is read from version 1 journal
+    pub crc32: u32,
+    /// Size of zstd compressed blob, zero if blob is not compressed
+    pub compressed_size: u32,
 }
 
 impl BlobHeader {
@@ -488,6 +2847,8 @@ impl BlobHeader {
             offset,
             blob_num,
             blob_size,
+            crc32: 0,
+            compressed_size: 0,
         }
     }
 
@@ -497,11 +2858,105 @@ impl BlobHeader {
             offset: 0,
             blob_num: 0,
             blob_size: 0,
+            crc32: 0,
+            compressed_size: 0,
         }
     }
 
+    /// Blob header with checksum of `blob`
+    ///
+    /// Known checksum is verified first, so blob, corrupted in transit, isn't written.
+    pub fn with_checksum(&self, blob: &[u8]) -> Result<Self> {
+        if self.crc32 != 0 {
+            self.verify_checksum(blob)?;
+        }
+        Ok(Self {
+            crc32: crc32fast::hash(blob),
+            ..*self
+        })
+    }
+
+    /// Check blob matches checksum
+    pub fn verify_checksum(&self, blob: &[u8]) -> Result<()> {
+        match crc32fast::hash(blob) {
+            got if got == self.crc32 => Ok(()),
+            got => Err(Error::ChecksumMismatch {
+                blob_num: self.blob_num,
+                expected: self.crc32,
+                got,
+            }),
+        }
+    }
+
+    /// Size of blob as stored in journal and in protocol stream
+    pub fn stored_size(&self) -> u32 {
+        match self.compressed_size {
+            0 => self.blob_size,
+            compressed_size => compressed_size,
+        }
+    }
+
+    /// Decompress stored blob, uncompressed blob is returned as is
+    ///
+    /// Blob header of decompressed blob has zero compressed size and checksum of decompressed
+    /// blob. Compressed blob can't be decompressed without `compression` feature, see
+    /// [`Error::CompressionUnsupported`].
+    pub fn decompress<'b>(&self, blob: &'b [u8]) -> Result<(Self, Cow<'b, [u8]>)> {
+        if self.compressed_size == 0 {
+            return Ok((*self, Cow::Borrowed(blob)));
+        }
+        self.decompress_zstd(blob)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn decompress_zstd<'b>(&self, _blob: &'b [u8]) -> Result<(Self, Cow<'b, [u8]>)> {
+        Err(Error::CompressionUnsupported)
+    }
+
+    #[cfg(feature = "compression")]
+    fn decompress_zstd<'b>(&self, blob: &'b [u8]) -> Result<(Self, Cow<'b, [u8]>)> {
+        let invalid = || Error::InvalidCompressedBlob {
+            blob_num: self.blob_num,
+        };
+        let blob = zstd::bulk::decompress(blob, self.blob_size as usize).map_err(|_| invalid())?;
+        if blob.len() != self.blob_size as usize {
+            return Err(invalid());
+        }
+        let blob_header = Self {
+            crc32: crc32fast::hash(&blob),
+            compressed_size: 0,
+            ..*self
+        };
+        Ok((blob_header, Cow::Owned(blob)))
+    }
+
     // FIXME: should not be public
     pub fn is_last(&self) -> bool {
         self.offset == 0 && self.blob_num == 0 && self.blob_size == 0
     }
+
+    /// Check blob fits into max possible database size and doesn't exceed max blob size
+    ///
+    /// Blob headers from untrusted input should be validated before blob is allocated.
+    pub fn validate(&self) -> Result<()> {
+        if self.blob_size > MAX_BLOB_SIZE {
+            return Err(Error::BlobTooLarge {
+                blob_size: self.blob_size,
+                max: MAX_BLOB_SIZE,
+            });
+        }
+        // only blobs, which shrink, are stored compressed
+        if self.compressed_size > self.blob_size {
+            return Err(Error::InvalidCompressedBlob {
+                blob_num: self.blob_num,
+            });
+        }
+        match self.offset.checked_add(self.blob_size as u64) {
+            Some(end) if end <= MAX_DATABASE_SIZE => Ok(()),
+            _ => Err(Error::InvalidOffset {
+                offset: self.offset,
+                size: self.blob_size,
+            }),
+        }
+    }
 }
@@ -4,7 +4,13 @@ mod async_bridge;
 mod async_journal;
 
 mod error;
+mod framing;
 mod journal;
+mod locator;
+mod merge;
+#[cfg(unix)]
+mod mmap;
+mod protocol_reader;
 mod stream;
 
 #[cfg(feature = "async_bridge")]
@@ -17,5 +23,15 @@ pub use crate::async_bridge::{
 pub use crate::async_journal::AsyncJournal;
 
 pub use crate::error::Error;
-pub use crate::journal::{BlobHeader, Header, Journal, SnapshotHeader};
-pub use crate::stream::{JournalVersion, Protocol, Stream};
+pub use crate::framing::{LengthDelimitedReader, LengthDelimitedStream};
+pub use crate::journal::{
+    system_clock, BaselineTimestamp, BlobHeader, Clock, Compression, DriftReport, FlushPolicy,
+    Header, Journal, JournalInfo, JournalView, OpenRetry, RestoreCost, SnapshotDigest,
+    SnapshotHeader, SnapshotInfo, JOURNAL_VERSION,
+};
+pub use crate::locator::{JournalLocator, SidecarLocator};
+pub use crate::merge::merge_by_timestamp;
+#[cfg(unix)]
+pub use crate::mmap::MmapCursor;
+pub use crate::protocol_reader::{ProtocolFrame, ProtocolReader};
+pub use crate::stream::{verify_stream, JournalVersion, Protocol, Stream, StreamStats};
@@ -0,0 +1,47 @@
+//! Journal location
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Derives journal path from database path
+pub trait JournalLocator: std::fmt::Debug {
+    fn journal_path(&self, database_path: &Path) -> PathBuf;
+}
+
+/// Journal stored next to database, suffix appended to database file name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SidecarLocator {
+    suffix: Cow<'static, str>,
+}
+
+impl SidecarLocator {
+    /// Default locator, journal for `db` is stored as `db-mycelial`
+    pub const DEFAULT: Self = Self {
+        suffix: Cow::Borrowed("-mycelial"),
+    };
+
+    pub fn new<S: Into<Cow<'static, str>>>(suffix: S) -> Self {
+        Self {
+            suffix: suffix.into(),
+        }
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+}
+
+impl Default for SidecarLocator {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl JournalLocator for SidecarLocator {
+    fn journal_path(&self, database_path: &Path) -> PathBuf {
+        let mut path = OsString::from(database_path);
+        path.push(self.suffix.as_ref());
+        path.into()
+    }
+}
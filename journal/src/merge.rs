@@ -0,0 +1,75 @@
+//! Merge of journals snapshots
+
+use crate::error::Error;
+use crate::journal::{BlobHeader, Journal, SnapshotHeader};
+use std::cmp::Ordering;
+use std::io::{Read, Seek, Write};
+use std::iter::Peekable;
+
+type Item = Result<(SnapshotHeader, BlobHeader, Vec<u8>), Error>;
+
+#[derive(Debug, Clone, Copy)]
+enum Side {
+    A,
+    B,
+}
+
+/// Interleaves snapshots of two journals in timestamp order
+///
+/// Blobs of a snapshot are never split. Snapshots with equal timestamps are ordered by snapshot id,
+/// journal `a` goes first if ids are equal too.
+/// Conflicts are not resolved, merged view is read-only.
+pub fn merge_by_timestamp<'a, F, G>(
+    a: &'a mut Journal<F>,
+    b: &'a mut Journal<G>,
+) -> impl Iterator<Item = Item> + 'a
+where
+    F: Read + Write + Seek,
+    G: Read + Write + Seek,
+{
+    MergeByTimestamp {
+        a: a.into_iter().peekable(),
+        b: b.into_iter().peekable(),
+        current: None,
+    }
+}
+
+#[derive(Debug)]
+struct MergeByTimestamp<A: Iterator<Item = Item>, B: Iterator<Item = Item>> {
+    a: Peekable<A>,
+    b: Peekable<B>,
+    /// side and id of snapshot being yielded
+    current: Option<(Side, u64)>,
+}
+
+impl<A, B> Iterator for MergeByTimestamp<A, B>
+where
+    A: Iterator<Item = Item>,
+    B: Iterator<Item = Item>,
+{
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let side = match (self.a.peek(), self.b.peek()) {
+            (None, None) => return None,
+            (Some(_), None) | (Some(Err(_)), _) => Side::A,
+            (None, Some(_)) | (_, Some(Err(_))) => Side::B,
+            (Some(Ok((a, _, _))), Some(Ok((b, _, _)))) => match self.current {
+                Some((Side::A, id)) if a.id == id => Side::A,
+                Some((Side::B, id)) if b.id == id => Side::B,
+                _ => match (a.timestamp, a.id).cmp(&(b.timestamp, b.id)) {
+                    Ordering::Greater => Side::B,
+                    _ => Side::A,
+                },
+            },
+        };
+        let item = match side {
+            Side::A => self.a.next(),
+            Side::B => self.b.next(),
+        };
+        if let Some(Ok((snapshot_header, _, _))) = item.as_ref() {
+            self.current = Some((side, snapshot_header.id));
+        }
+        item
+    }
+}
@@ -0,0 +1,142 @@
+//! Read-only memory mapped journal file
+
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Read-only cursor over memory mapped file, see [`crate::Journal::open_mmap`]
+///
+/// Reads are copies from mapping, without syscalls, and mapping shares OS page cache with other
+/// readers of the file. Writes fail.
+///
+/// File is expected to only grow, i.e. to be journal, appended by writer: file, which grew since
+/// it was mapped, is remapped on read past end of mapping or on [`MmapCursor::remap`], file
+/// truncated under mapping makes reads of truncated tail fault.
+#[derive(Debug)]
+pub struct MmapCursor {
+    file: fs::File,
+    ptr: *mut libc::c_void,
+    len: usize,
+    pos: u64,
+}
+
+// mapping is read-only and owned by cursor
+unsafe impl Send for MmapCursor {}
+
+impl MmapCursor {
+    /// Open and map file
+    pub fn open<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let file = fs::OpenOptions::new().read(true).open(p)?;
+        let mut cursor = Self {
+            file,
+            ptr: std::ptr::null_mut(),
+            len: 0,
+            pos: 0,
+        };
+        cursor.remap()?;
+        Ok(cursor)
+    }
+
+    /// Map file anew, if its size changed since it was mapped
+    ///
+    /// Position is kept.
+    pub fn remap(&mut self) -> Result<()> {
+        let len = usize::try_from(self.file.metadata()?.len())
+            .map_err(|_| Error::other("file is too large to map"))?;
+        if len == self.len {
+            return Ok(());
+        }
+        self.unmap();
+        // empty file can't be mapped
+        if len == 0 {
+            return Ok(());
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        self.ptr = ptr;
+        self.len = len;
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+
+    fn unmap(&mut self) {
+        if self.len != 0 {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+        self.ptr = std::ptr::null_mut();
+        self.len = 0;
+    }
+}
+
+impl Drop for MmapCursor {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
+
+impl Read for MmapCursor {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // header, updated by writer, can point past end of mapping, i.e. file grew
+        if self.pos >= self.len as u64 {
+            self.remap()?;
+        }
+        let data = self.as_slice();
+        let start = self.pos.min(data.len() as u64) as usize;
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for MmapCursor {
+    fn seek(&mut self, seek: SeekFrom) -> Result<u64> {
+        let pos = match seek {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => (self.len as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Write for MmapCursor {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "memory mapped journal is read-only",
+        ))
+    }
+
+    // nothing is ever written, so there is nothing to flush
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+//! Zero-copy Protocol reader over in-memory stream
+//!
+//! Blob bodies are borrowed from underlying buffer instead of being copied.
+
+use crate::error::Error as JournalError;
+use crate::journal::{BlobHeader, SnapshotHeader};
+use crate::stream::{JournalVersion, Protocol};
+use block::Block;
+use serde_sqlite::from_bytes;
+
+/// Protocol frame, blob header frame carries blob borrowed from stream buffer
+#[derive(Debug, PartialEq)]
+pub enum ProtocolFrame<'a> {
+    JournalVersion(JournalVersion),
+    SnapshotHeader(SnapshotHeader),
+    Blob(BlobHeader, &'a [u8]),
+    EndOfStream,
+}
+
+/// Reader of Protocol frames over contiguous buffer, i.e. whole [`crate::Stream`] in memory
+///
+/// Iteration stops after EndOfStream frame or on first error.
+#[derive(Debug)]
+pub struct ProtocolReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    finished: bool,
+}
+
+impl<'a> ProtocolReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            finished: false,
+        }
+    }
+
+    /// Current position in buffer
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Read next frame
+    ///
+    /// Returns `None` after EndOfStream frame, missing EndOfStream is an error.
+    pub fn next_frame(&mut self) -> Result<Option<ProtocolFrame<'a>>, JournalError> {
+        if self.finished {
+            return Ok(None);
+        }
+        if self.pos == self.buf.len() {
+            return Err(JournalError::MissingEndOfStream);
+        }
+        let protocol = from_bytes::<Protocol>(&self.buf[self.pos..])?;
+        self.pos += protocol.iblock_size();
+        let frame = match protocol {
+            Protocol::JournalVersion(version) => ProtocolFrame::JournalVersion(version),
+            Protocol::SnapshotHeader(snapshot_header) => {
+                ProtocolFrame::SnapshotHeader(snapshot_header)
+            }
+            Protocol::BlobHeader(blob_header) => {
+                let end = self.pos + blob_header.stored_size() as usize;
+                let blob = self
+                    .buf
+                    .get(self.pos..end)
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                self.pos = end;
+                ProtocolFrame::Blob(blob_header, blob)
+            }
+            Protocol::EndOfStream(_) => {
+                self.finished = true;
+                ProtocolFrame::EndOfStream
+            }
+        };
+        Ok(Some(frame))
+    }
+}
+
+impl<'a> Iterator for ProtocolReader<'a> {
+    type Item = Result<ProtocolFrame<'a>, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_frame() {
+            Ok(frame) => frame.map(Ok),
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
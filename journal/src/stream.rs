@@ -1,11 +1,12 @@
 //! Streaming protocol for journal
 
 use crate::error::Error as JournalError;
-use crate::journal::{BlobHeader, IntoIter, Journal, SnapshotHeader};
+use crate::journal::{BlobHeader, Header, IntoIter, Journal, SnapshotHeader, JOURNAL_VERSION};
+use crate::protocol_reader::ProtocolFrame;
 use block::{block, Block};
 use serde::{Deserialize, Serialize};
-use serde_sqlite::to_writer;
-use std::io::{BufRead, Cursor, Read, Seek, Write};
+use serde_sqlite::{from_reader, to_bytes, to_writer};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, Write};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[block(0)]
@@ -69,9 +70,31 @@ impl From<JournalVersion> for Protocol {
 }
 
 impl Protocol {
-    fn end() -> Self {
+    pub(crate) fn end() -> Self {
         Self::EndOfStream(End {})
     }
+
+    /// Read frame of stream of journal `version`
+    ///
+    /// Blob header frames of version 1 and 2 streams are shorter, missing checksum and
+    /// compressed size are read as zero, same as blob headers of journals of these versions.
+    pub(crate) fn read_frame<R: Read>(mut reader: R, version: u32) -> Result<Self, JournalError> {
+        let header = Header {
+            version,
+            ..Header::default()
+        };
+        if header.blob_header_size() == BlobHeader::block_size() {
+            return Ok(from_reader::<Protocol, _>(reader)?);
+        }
+        let mut tag = [0; 4];
+        reader.read_exact(&mut tag)?;
+        if tag[..] != to_bytes(&Protocol::from(BlobHeader::last()))?[..4] {
+            return Ok(from_reader::<Protocol, _>(Cursor::new(tag).chain(reader))?);
+        }
+        let mut buf = vec![0; header.blob_header_size()];
+        reader.read_exact(&mut buf)?;
+        Ok(Self::BlobHeader(header.blob_header_from_bytes(&buf)?))
+    }
 }
 
 #[derive(Debug)]
@@ -83,6 +106,10 @@ pub struct Stream<'a, I: Iterator<Item = <IntoIter<'a> as Iterator>::Item>> {
     buf: Vec<u8>,
     read: usize,
     cur_snapshot_id: Option<u64>,
+    /// blob of last journal item
+    blob: Vec<u8>,
+    /// blob header, which follows already yielded snapshot header
+    pending_blob: Option<BlobHeader>,
     finished: bool,
     _marker: std::marker::PhantomData<&'a ()>,
 }
@@ -90,8 +117,7 @@ pub struct Stream<'a, I: Iterator<Item = <IntoIter<'a> as Iterator>::Item>> {
 // stream, which starts from 'scratch'
 impl<'a, F: Read + Write + Seek> From<&'a mut Journal<F>> for Stream<'a, IntoIter<'a, F>> {
     fn from(journal: &'a mut Journal<F>) -> Self {
-        let version = journal.get_header().version;
-        Stream::new(journal.into_iter(), version)
+        Stream::new(journal.into_iter().stored(), JOURNAL_VERSION)
     }
 }
 
@@ -111,11 +137,46 @@ impl<'a, I: Iterator<Item = <IntoIter<'a> as Iterator>::Item>> Stream<'a, I> {
             buf: Vec::with_capacity(8192),
             read: 0,
             cur_snapshot_id: None,
+            blob: vec![],
+            pending_blob: None,
             finished: false,
             _marker: std::marker::PhantomData,
         }
     }
 
+    /// Yield next Protocol message, blob frames borrow blob from stream
+    ///
+    /// Messages are the same ones, which [`Read`] impl serializes, so transports can frame each
+    /// message on their own. Stream shouldn't be read through both interfaces at once, since
+    /// [`BufRead`] buffer holds message, which was already yielded.
+    pub fn next_frame(&mut self) -> Result<Option<ProtocolFrame<'_>>, JournalError> {
+        // always yield version first
+        if !self.version_written {
+            self.version_written = true;
+            return Ok(Some(ProtocolFrame::JournalVersion(self.version.into())));
+        }
+        if let Some(blob_h) = self.pending_blob.take() {
+            return Ok(Some(ProtocolFrame::Blob(blob_h, self.blob.as_slice())));
+        }
+        match self.iter.next() {
+            Some(Ok((snapshot_h, blob_h, blob))) => {
+                self.blob = blob;
+                if self.cur_snapshot_id != Some(snapshot_h.id) {
+                    self.cur_snapshot_id = Some(snapshot_h.id);
+                    self.pending_blob = Some(blob_h);
+                    return Ok(Some(ProtocolFrame::SnapshotHeader(snapshot_h)));
+                }
+                Ok(Some(ProtocolFrame::Blob(blob_h, self.blob.as_slice())))
+            }
+            Some(Err(e)) => Err(e),
+            None if !self.finished => {
+                self.finished = true;
+                Ok(Some(ProtocolFrame::EndOfStream))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn to_io_error<E: Into<JournalError>>(e: E) -> std::io::Error {
         let e: JournalError = e.into();
         // FIXME: does it make sense to unwrap error?
@@ -147,49 +208,21 @@ impl<'a, I: Iterator<Item = <IntoIter<'a> as Iterator>::Item>> BufRead for Strea
             self.buf.clear();
         }
 
-        // always write version first
-        if !self.version_written {
-            let version: Protocol = JournalVersion::from(self.version).into();
-            self.resize_buf(version.iblock_size());
-            to_writer(self.buf.as_mut_slice(), &version).map_err(Self::to_io_error)?;
-            self.version_written = true;
-            return Ok(self.buf.as_slice());
-        }
-
-        // body write
-        match self.iter.next() {
-            Some(Ok((snapshot_h, page_h, page))) => {
-                let snapshot_id = snapshot_h.id;
-                let snapshot_h: Protocol = snapshot_h.into();
-                let page_h: Protocol = page_h.into();
-
-                // max possible len for given item
-                let total_len = snapshot_h.iblock_size() + page_h.iblock_size() + page.len();
-                self.resize_buf(total_len);
-
-                let mut read_buf = Cursor::new(self.buf.as_mut_slice());
-
-                if self.cur_snapshot_id != Some(snapshot_id) {
-                    to_writer(&mut read_buf, &snapshot_h).map_err(Self::to_io_error)?;
-                    self.cur_snapshot_id = Some(snapshot_id)
-                }
-                to_writer(&mut read_buf, &page_h).map_err(Self::to_io_error)?;
-                read_buf.write_all(page.as_slice())?;
-
-                // real written value with according buffer resize
-                let written = read_buf.position();
-                self.resize_buf(written as usize);
-            }
-            Some(Err(e)) => return Err(Self::to_io_error(e)),
-            None if !self.finished => {
-                self.finished = true;
-
-                let eos = Protocol::end();
-                self.resize_buf(eos.iblock_size());
-                to_writer(self.buf.as_mut_slice(), &eos).map_err(Self::to_io_error)?;
-            }
-            None => (),
+        let (protocol, with_blob): (Protocol, bool) = match self.next_frame() {
+            Ok(Some(ProtocolFrame::JournalVersion(version))) => (version.into(), false),
+            Ok(Some(ProtocolFrame::SnapshotHeader(snapshot_h))) => (snapshot_h.into(), false),
+            Ok(Some(ProtocolFrame::Blob(blob_h, _))) => (blob_h.into(), true),
+            Ok(Some(ProtocolFrame::EndOfStream)) => (Protocol::end(), false),
+            Ok(None) => return Ok(self.buf.as_slice()),
+            Err(e) => return Err(Self::to_io_error(e)),
         };
+        let blob_len = if with_blob { self.blob.len() } else { 0 };
+        let header_len = protocol.iblock_size();
+        self.resize_buf(header_len + blob_len);
+        to_writer(&mut self.buf[..header_len], &protocol).map_err(Self::to_io_error)?;
+        if with_blob {
+            self.buf[header_len..].copy_from_slice(&self.blob);
+        }
         Ok(self.buf.as_slice())
     }
 
@@ -222,3 +255,90 @@ impl<'a, I: Iterator<Item = <IntoIter<'a> as Iterator>::Item>> Read for Stream<'
         Ok(total)
     }
 }
+
+/// Protocol stream stats, collected by [`verify_stream`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+    /// snapshot count
+    pub snapshots: u64,
+    /// blob count
+    pub blobs: u64,
+    /// total size of blobs data
+    pub bytes: u64,
+}
+
+/// Validate Protocol stream without applying it to journal
+///
+/// * stream starts with supported journal version, i.e. current or older one
+/// * snapshot ids are strictly increasing
+/// * blobs belong to snapshot, blob numbers are sequential and blobs fit into database
+/// * stream is terminated by exactly one EndOfStream
+///
+/// Returns first violation found.
+pub fn verify_stream<R: Read>(reader: R) -> Result<StreamStats, JournalError> {
+    let mut reader = BufReader::new(reader);
+    let mut stats = StreamStats::default();
+
+    let version = match from_reader::<Protocol, _>(&mut reader)? {
+        Protocol::JournalVersion(v) if (1..=JOURNAL_VERSION).contains(&u32::from(v)) => {
+            u32::from(v)
+        }
+        Protocol::JournalVersion(v) => {
+            return Err(JournalError::UnexpectedJournalVersion {
+                expected: JOURNAL_VERSION,
+                got: v.into(),
+            })
+        }
+        _ => return Err(JournalError::MissingJournalVersion),
+    };
+
+    let mut snapshot_id: Option<u64> = None;
+    let mut blob_count: Option<u32> = None;
+    loop {
+        if reader.fill_buf()?.is_empty() {
+            return Err(JournalError::MissingEndOfStream);
+        }
+        match Protocol::read_frame(&mut reader, version)? {
+            Protocol::SnapshotHeader(snapshot_header) => {
+                if let Some(id) = snapshot_id {
+                    if snapshot_header.id <= id {
+                        return Err(JournalError::OutOfOrderSnapshot {
+                            snapshot_id: snapshot_header.id,
+                            journal_snapshot_id: id + 1,
+                        });
+                    }
+                }
+                snapshot_id = Some(snapshot_header.id);
+                blob_count = Some(0);
+                stats.snapshots += 1;
+            }
+            Protocol::BlobHeader(blob_header) => {
+                let count = blob_count.ok_or(JournalError::SnapshotNotStarted)?;
+                if blob_header.blob_num != count {
+                    return Err(JournalError::OutOfOrderBlob {
+                        blob_num: blob_header.blob_num,
+                        blob_count,
+                    });
+                }
+                blob_header.validate()?;
+                let size = blob_header.stored_size() as u64;
+                if std::io::copy(&mut (&mut reader).take(size), &mut std::io::sink())? != size {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+                blob_count = Some(count + 1);
+                stats.blobs += 1;
+                stats.bytes += size;
+            }
+            Protocol::EndOfStream(_) => break,
+            Protocol::JournalVersion(_) => {
+                return Err(JournalError::UnexpectedFrame {
+                    frame: "JournalVersion".into(),
+                })
+            }
+        }
+    }
+    if !reader.fill_buf()?.is_empty() {
+        return Err(JournalError::TrailingStreamData);
+    }
+    Ok(stats)
+}
@@ -1,9 +1,17 @@
 use block::Block;
-use journal::{Header, Journal, Protocol, Stream};
+use journal::{
+    merge_by_timestamp, system_clock, verify_stream, BaselineTimestamp, BlobHeader, Compression,
+    Error, FlushPolicy, Header, Journal, JournalLocator, LengthDelimitedReader,
+    LengthDelimitedStream, OpenRetry, Protocol, ProtocolFrame, ProtocolReader, RestoreCost,
+    SidecarLocator, SnapshotDigest, SnapshotHeader, Stream, StreamStats, JOURNAL_VERSION,
+};
+#[cfg(feature = "async_bridge")]
+use journal::{AsyncReadJournalStream, AsyncWriteJournalStream};
 use quickcheck::{quickcheck, Arbitrary, Gen, TestResult};
 use spin_sleep::sleep;
 use std::cell::UnsafeCell;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 #[cfg(feature = "async")]
@@ -20,6 +28,704 @@ fn test_journal_not_exists() {
     assert!(err.journal_not_exists());
 }
 
+#[test]
+fn test_journal_clock_fallback() {
+    fn timestamps(journal: &mut Journal<Cursor<Vec<u8>>>) -> Vec<i64> {
+        journal
+            .into_iter()
+            .map(|item| item.unwrap().0.timestamp)
+            .collect()
+    }
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.set_clock(|| None);
+    for id in 0..3 {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(id * 4096, &[1; 4096]).unwrap();
+        journal.commit().unwrap();
+    }
+    assert_eq!(timestamps(&mut journal), vec![1, 2, 3]);
+
+    // fallback continues from last wall-clock timestamp
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for clock in [system_clock, || None, || None] {
+        journal.set_clock(clock);
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(0, &[1; 4096]).unwrap();
+        journal.commit().unwrap();
+    }
+
+    // fallback continues from last committed timestamp after reopen
+    let mut journal = Journal::from_fd(journal.take_fd().unwrap()).unwrap();
+    journal.set_clock(|| None);
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[1; 4096]).unwrap();
+    journal.commit().unwrap();
+    let timestamps = timestamps(&mut journal);
+    assert!(timestamps[0] > 0);
+    assert_eq!(
+        timestamps[1..],
+        [timestamps[0] + 1, timestamps[0] + 2, timestamps[0] + 3]
+    );
+}
+
+#[test]
+fn test_journal_open_retry() {
+    let journal = tempfile::NamedTempFile::new().unwrap();
+    Journal::create(journal.path()).unwrap();
+    let retry = OpenRetry {
+        attempts: 3,
+        backoff: Duration::from_millis(1),
+    };
+    // fails first `failures` opens with transient error
+    let flaky_open = |failures: u32, calls: &mut u32| {
+        *calls += 1;
+        match *calls <= failures {
+            true => Err(std::io::Error::from(std::io::ErrorKind::Interrupted)),
+            false => std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(journal.path()),
+        }
+    };
+    let mut calls = 0;
+    let fd = retry.run(|| flaky_open(2, &mut calls)).unwrap();
+    assert_eq!(calls, 3);
+    assert!(Journal::from_fd(fd).is_ok());
+
+    let mut calls = 0;
+    let err = retry.run(|| flaky_open(3, &mut calls)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    assert_eq!(calls, 3);
+
+    let mut calls = 0;
+    assert!(OpenRetry::NONE.run(|| flaky_open(1, &mut calls)).is_err());
+    assert_eq!(calls, 1);
+
+    // missing journal isn't retried
+    let mut calls = 0;
+    let err = retry
+        .run(|| {
+            calls += 1;
+            std::fs::File::open(journal.path().with_extension("missing"))
+        })
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(calls, 1);
+    let err = Journal::try_from_with_retry(journal.path().with_extension("missing"), retry);
+    assert!(err.unwrap_err().journal_not_exists());
+}
+
+#[test]
+fn test_error_is_retryable() {
+    use std::io::{Error as IOError, ErrorKind};
+    let io = |kind| Error::IOError(IOError::from(kind));
+    for kind in [
+        ErrorKind::Interrupted,
+        ErrorKind::WouldBlock,
+        ErrorKind::TimedOut,
+        ErrorKind::ConnectionReset,
+        ErrorKind::ConnectionAborted,
+        ErrorKind::ConnectionRefused,
+        ErrorKind::BrokenPipe,
+        ErrorKind::StaleNetworkFileHandle,
+    ] {
+        assert!(io(kind).is_retryable(), "{kind:?}");
+        let e = Error::SerdeSqliteError(serde_sqlite::Error::IoError(IOError::from(kind)));
+        assert!(e.is_retryable(), "{kind:?}");
+    }
+    for kind in [
+        ErrorKind::NotFound,
+        ErrorKind::PermissionDenied,
+        ErrorKind::UnexpectedEof,
+        ErrorKind::InvalidData,
+    ] {
+        assert!(!io(kind).is_retryable(), "{kind:?}");
+    }
+
+    let fatal = [
+        Error::TryReserveError(vec![0_u8].try_reserve(usize::MAX).unwrap_err()),
+        Error::SerdeSqliteError(serde_sqlite::Error::Incomplete),
+        Error::OutOfOrderSnapshot {
+            snapshot_id: 2,
+            journal_snapshot_id: 1,
+        },
+        Error::SnapshotGap { have: 1, got: 2 },
+        Error::DuplicateSnapshot { id: 1 },
+        Error::SnapshotNotStarted,
+        Error::OutOfOrderBlob {
+            blob_num: 2,
+            blob_count: Some(1),
+        },
+        Error::UnexpectedJournalVersion {
+            expected: 1,
+            got: 2,
+        },
+        Error::InvalidOffset { offset: 0, size: 1 },
+        Error::BlobTooLarge {
+            blob_size: 2,
+            max: 1,
+        },
+        Error::PageSizeChanged {
+            expected: 1024,
+            got: 4096,
+        },
+        Error::MissingJournalVersion,
+        Error::MissingEndOfStream,
+        Error::TrailingStreamData,
+        Error::UnexpectedFrame {
+            frame: "Blob".into(),
+        },
+        Error::InvalidPreImage,
+        Error::UndoLogDisabled,
+        Error::SnapshotInProgress,
+        Error::SnapshotNotFound { id: 1 },
+        Error::InvalidSnapshotRange { from: 2, to: 1 },
+        Error::InvalidPageSize { page_size: 0 },
+        Error::InvalidMagic { magic: 0 },
+        Error::InvalidPageNumber { page_no: 0 },
+        Error::InvalidBlobAlignment { alignment: 3 },
+        Error::ChecksumMismatch {
+            blob_num: 0,
+            expected: 1,
+            got: 2,
+        },
+        Error::InvalidCompressedBlob { blob_num: 0 },
+        Error::CompressionUnsupported,
+        Error::SnapshotDivergence { id: 0 },
+    ];
+    for e in fatal {
+        assert!(!e.is_retryable(), "{e:?}");
+    }
+}
+
+/// Fd, which "encrypts" data by xor-ing it with key
+struct XorFd<F> {
+    inner: F,
+    key: u8,
+}
+
+impl<F: Read> Read for XorFd<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        buf[..read].iter_mut().for_each(|b| *b ^= self.key);
+        Ok(read)
+    }
+}
+
+impl<F: Write> Write for XorFd<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let buf = buf.iter().map(|b| b ^ self.key).collect::<Vec<_>>();
+        self.inner.write(&buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<F: Seek> Seek for XorFd<F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[test]
+fn test_journal_from_fd() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("journal");
+    let open = || {
+        let inner = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        XorFd { inner, key: 0x5a }
+    };
+
+    let mut journal = Journal::new(Header::default(), open(), None).unwrap();
+    for i in 0..3 {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(i * 4096, b"plaintext").unwrap();
+        journal.commit().unwrap();
+    }
+    let expected = journal.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+    drop(journal);
+
+    // data at rest is "encrypted"
+    let raw = std::fs::read(&path).unwrap();
+    assert!(!raw.windows(9).any(|w| w == b"plaintext"));
+
+    let mut journal = Journal::from_fd(open()).unwrap();
+    assert_eq!(journal.get_header().snapshot_counter, 3);
+    let blobs = journal.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+    assert_eq!(blobs, expected);
+
+    // header is validated
+    let res = Journal::try_from(&path);
+    assert!(matches!(res, Err(Error::InvalidMagic { .. })), "{res:?}");
+}
+
+#[test]
+fn test_sidecar_locator() {
+    let database_path = Path::new("/tmp/db.sqlite3");
+    assert_eq!(
+        SidecarLocator::default().journal_path(database_path),
+        Path::new("/tmp/db.sqlite3-mycelial")
+    );
+    assert_eq!(
+        SidecarLocator::new(".journal").journal_path(database_path),
+        Path::new("/tmp/db.sqlite3.journal")
+    );
+}
+
+// locator, which places all journals into separate directory
+#[derive(Debug)]
+struct DirLocator(PathBuf);
+
+impl JournalLocator for DirLocator {
+    fn journal_path(&self, database_path: &Path) -> PathBuf {
+        self.0.join(database_path.file_name().unwrap())
+    }
+}
+
+#[test]
+fn test_custom_journal_locator() {
+    let database_dir = tempfile::tempdir().unwrap();
+    let journal_dir = tempfile::tempdir().unwrap();
+    let database_path = database_dir.path().join("db.sqlite3");
+    let locator = DirLocator(journal_dir.path().into());
+
+    let journal_path = locator.journal_path(&database_path);
+    assert_eq!(journal_path, journal_dir.path().join("db.sqlite3"));
+
+    let mut journal = Journal::create(&journal_path).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[1, 2, 3]).unwrap();
+    journal.commit().unwrap();
+    drop(journal);
+
+    let journal = Journal::try_from(locator.journal_path(&database_path)).unwrap();
+    assert_eq!(journal.current_snapshot(), Some(1));
+    assert!(!SidecarLocator::default()
+        .journal_path(&database_path)
+        .exists());
+}
+
+#[test]
+fn test_journal_invalid_offset() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    let res = journal.new_blob(u64::MAX, &[1, 2, 3]);
+    assert!(
+        matches!(
+            res,
+            Err(Error::InvalidOffset {
+                offset: u64::MAX,
+                size: 3
+            })
+        ),
+        "{res:?}"
+    );
+}
+
+// check page size change is rejected for regular snapshot, but accepted for baseline snapshot
+// and exported database is rebuilt from the baseline
+#[test]
+fn test_journal_page_size_change() {
+    // database image with page size encoded at the same place as in sqlite header
+    fn database(page_size: u16, page_count: usize, fill: u8) -> Vec<u8> {
+        let mut db = vec![fill; page_size as usize * page_count];
+        db[16..18].copy_from_slice(&page_size.to_be_bytes());
+        db
+    }
+
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let old_db = database(1024, 8, 1);
+    for (offset, page) in old_db.chunks(1024).enumerate() {
+        journal.new_snapshot(1024).unwrap();
+        journal.new_blob(offset as u64 * 1024, page).unwrap();
+    }
+    journal.commit().unwrap();
+    assert_eq!(journal.get_header().page_size, Some(1024));
+
+    let res = journal.new_snapshot(4096);
+    assert!(
+        matches!(
+            res,
+            Err(Error::PageSizeChanged {
+                expected: 1024,
+                got: 4096
+            })
+        ),
+        "{res:?}"
+    );
+
+    let mut new_db = database(4096, 1, 2);
+    for (offset, page) in new_db.chunks(4096).enumerate() {
+        journal.new_baseline_snapshot(4096).unwrap();
+        journal.new_blob(offset as u64 * 4096, page).unwrap();
+    }
+    journal.commit().unwrap();
+    assert_eq!(journal.get_header().page_size, Some(4096));
+
+    // regular snapshots with new page size are accepted after baseline
+    new_db[4000..4010].copy_from_slice(&[3; 10]);
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(4000, &new_db[4000..4010]).unwrap();
+    journal.commit().unwrap();
+
+    let mut output = Cursor::new(vec![]);
+    journal.export_database(&mut output).unwrap();
+    let output = output.into_inner();
+    assert_eq!(output.len(), 4096);
+    assert_eq!(&output[16..18], &4096_u16.to_be_bytes());
+    assert_eq!(output, new_db);
+}
+
+#[test]
+fn test_journal_zero_page_size() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    assert!(matches!(
+        journal.new_snapshot(0),
+        Err(Error::InvalidPageSize { page_size: 0 })
+    ));
+    assert!(matches!(
+        journal.new_baseline_snapshot(0),
+        Err(Error::InvalidPageSize { page_size: 0 })
+    ));
+    assert!(matches!(
+        journal.new_blob(0, &[1]),
+        Err(Error::SnapshotNotStarted)
+    ));
+    journal.commit().unwrap();
+    assert_eq!(journal.get_header().snapshot_counter, 0);
+}
+
+// journal of empty database records hinted page size, which first snapshot is checked against
+#[test]
+fn test_journal_page_size_hint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("journal");
+    let mut journal = Journal::create(&path).unwrap();
+    assert!(matches!(
+        journal.set_page_size_hint(0),
+        Err(Error::InvalidPageSize { page_size: 0 })
+    ));
+    journal.set_page_size_hint(8192).unwrap();
+    assert_eq!(journal.get_header().page_size, Some(8192));
+
+    // hint is persisted
+    let mut journal = Journal::try_from(&path).unwrap();
+    assert_eq!(journal.get_header().page_size, Some(8192));
+    assert!(matches!(
+        journal.new_snapshot(4096),
+        Err(Error::PageSizeChanged {
+            expected: 8192,
+            got: 4096
+        })
+    ));
+    journal.new_snapshot(8192).unwrap();
+    journal.new_blob(0, &[1; 8192]).unwrap();
+    journal.commit().unwrap();
+    let snapshot_header = journal.into_iter().next().unwrap().unwrap().0;
+    assert_eq!(snapshot_header.page_size, Some(8192));
+
+    // known page size is not overridden
+    journal.set_page_size_hint(1024).unwrap();
+    assert_eq!(journal.get_header().page_size, Some(8192));
+}
+
+// check undo of last snapshot restores database to pre-snapshot state
+#[test]
+fn test_journal_undo_last() {
+    let header = Header {
+        undo_log: true,
+        ..Header::default()
+    };
+    let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+    let mut db = Cursor::new(vec![]);
+
+    // write blob into journal and database
+    fn write<F: Read + Write + Seek>(
+        journal: &mut Journal<F>,
+        db: &mut Cursor<Vec<u8>>,
+        offset: usize,
+        blob: &[u8],
+    ) {
+        let db = db.get_mut();
+        if db.len() < offset + blob.len() {
+            db.resize(offset + blob.len(), 0);
+        }
+        journal.new_snapshot(4096).unwrap();
+        journal
+            .new_blob_with_pre_image(offset as u64, blob, &db[offset..offset + blob.len()])
+            .unwrap();
+        db[offset..offset + blob.len()].copy_from_slice(blob);
+    }
+    write(&mut journal, &mut db, 0, &[1; 64]);
+    write(&mut journal, &mut db, 64, &[2; 64]);
+    journal.commit().unwrap();
+    let expected = db.get_ref().clone();
+
+    write(&mut journal, &mut db, 32, &[3; 64]);
+    write(&mut journal, &mut db, 40, &[4; 8]);
+    write(&mut journal, &mut db, 120, &[5; 16]);
+    journal.commit().unwrap();
+    assert_ne!(db.get_ref(), &expected);
+
+    // pre-images don't leak into iteration
+    let mut exported = Cursor::new(vec![]);
+    journal.export_database(&mut exported).unwrap();
+    assert_eq!(exported.get_ref(), db.get_ref());
+
+    journal.undo_last(&mut db).unwrap();
+    // database grew within undone snapshot, grown part is zeroed
+    assert_eq!(&db.get_ref()[..expected.len()], expected.as_slice());
+    assert!(db.get_ref()[expected.len()..].iter().all(|&b| b == 0));
+
+    // blob without pre-image is rejected
+    journal.new_snapshot(4096).unwrap();
+    assert!(matches!(
+        journal.new_blob(0, &[1]),
+        Err(Error::InvalidPreImage)
+    ));
+
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    assert!(matches!(
+        journal.undo_last(Cursor::new(vec![])),
+        Err(Error::UndoLogDisabled)
+    ));
+}
+
+#[test]
+fn test_journal_info() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let info = journal.info().unwrap();
+    assert_eq!(info.snapshot_count, 0);
+    assert!(info.snapshots.is_empty());
+
+    for blobs in [&[10, 20][..], &[30], &[1, 2, 3]] {
+        for (offset, size) in blobs.iter().enumerate() {
+            journal.new_snapshot(4096).unwrap();
+            journal
+                .new_blob(offset as u64 * 100, &vec![1; *size])
+                .unwrap();
+        }
+        journal.commit().unwrap();
+    }
+
+    let info = journal.info().unwrap();
+    let header = journal.get_header();
+    assert_eq!(info.version, header.version);
+    assert_eq!(info.magic, header.magic);
+    assert_eq!(info.eof, header.eof);
+    assert_eq!(info.snapshot_count, 3);
+    assert_eq!(
+        info.snapshots
+            .iter()
+            .map(|s| (s.id, s.page_size, s.blob_count, s.bytes))
+            .collect::<Vec<_>>(),
+        vec![
+            (0, Some(4096), 2, 30),
+            (1, Some(4096), 1, 30),
+            (2, Some(4096), 3, 6)
+        ]
+    );
+}
+
+#[test]
+fn test_journal_info_skips_blobs_within_buffer() {
+    // fd, which counts seeks
+    struct CountingFd {
+        inner: Cursor<Vec<u8>>,
+        seeks: std::rc::Rc<std::cell::Cell<u64>>,
+    }
+    impl Read for CountingFd {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+    impl Write for CountingFd {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+    impl Seek for CountingFd {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.seeks.set(self.seeks.get() + 1);
+            self.inner.seek(pos)
+        }
+    }
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..100_u64 {
+        journal.new_snapshot(4096).unwrap();
+        for blob_num in 0..10 {
+            journal.new_blob(blob_num * 100, &[i as u8; 100]).unwrap();
+        }
+        journal.commit().unwrap();
+    }
+    let bytes = journal.take_fd().unwrap().into_inner();
+    let seeks = std::rc::Rc::new(std::cell::Cell::new(0));
+    let fd = CountingFd {
+        inner: Cursor::new(bytes),
+        seeks: std::rc::Rc::clone(&seeks),
+    };
+    let mut journal = Journal::from_fd(fd).unwrap();
+
+    // skipped blobs, which are already buffered, don't seek fd
+    seeks.set(0);
+    let info = journal.info().unwrap();
+    assert_eq!(info.snapshot_count, 100);
+    assert!(info.snapshots.iter().all(|s| s.blob_count == 10));
+    assert!(seeks.get() < 100, "{}", seeks.get());
+}
+
+#[test]
+fn test_journal_blobs_in_range() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for blobs in [
+        &[(0, 10), (100, 10)][..],
+        &[(50, 100)],
+        &[(200, 10), (300, 10)],
+    ] {
+        journal.new_snapshot(4096).unwrap();
+        for (offset, size) in blobs {
+            journal.new_blob(*offset, &vec![1; *size]).unwrap();
+        }
+        journal.commit().unwrap();
+    }
+    let mut in_range = |start, end| {
+        journal
+            .blobs_in_range(start, end)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|(snapshot_header, blob_header, blob)| {
+                assert_eq!(blob.len(), blob_header.blob_size as usize);
+                (snapshot_header.id, blob_header.offset)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // straddling blobs are included whole
+    assert_eq!(in_range(105, 200), vec![(0, 100), (1, 50)]);
+    assert_eq!(in_range(0, 50), vec![(0, 0)]);
+    assert_eq!(in_range(150, 200), vec![]);
+    assert_eq!(in_range(0, u64::MAX).len(), 5);
+    assert_eq!(in_range(10, 10), vec![]);
+}
+
+#[test]
+fn test_journal_replace_fd() {
+    // journal with one snapshot per given blob value
+    fn populate(values: &[u8]) -> Journal<Cursor<Vec<u8>>> {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for value in values {
+            journal.new_snapshot(4096).unwrap();
+            journal.new_blob(0, &[*value; 8]).unwrap();
+            journal.commit().unwrap();
+        }
+        journal
+    }
+    fn blobs(journal: &mut Journal<Cursor<Vec<u8>>>) -> Vec<(u64, Vec<u8>)> {
+        journal
+            .into_iter()
+            .map(Result::unwrap)
+            .map(|(snapshot_header, _, blob)| (snapshot_header.id, blob))
+            .collect()
+    }
+
+    // fd of journal, which was read, is replaced and taken
+    let mut journal = populate(&[1]);
+    journal.into_iter().next().unwrap().unwrap();
+    let mut replacement = populate(&[2, 3]);
+    assert_eq!(blobs(&mut replacement).len(), 2);
+    let replacement = replacement.take_fd().unwrap();
+
+    let previous = journal.replace_fd(replacement).unwrap();
+    assert_eq!(journal.current_snapshot(), Some(2));
+    assert_eq!(blobs(&mut journal), vec![(0, vec![2; 8]), (1, vec![3; 8])]);
+
+    // fd without journal header is rejected, journal keeps its fd
+    let res = journal.replace_fd(Cursor::new(vec![0; Header::block_size()]));
+    assert!(
+        matches!(res, Err(Error::InvalidMagic { magic: 0 })),
+        "{res:?}"
+    );
+    assert_eq!(blobs(&mut journal).len(), 2);
+
+    // previous fd is intact and can be reopened
+    let mut previous = Journal::from(Header::default(), previous, None);
+    previous.update_header().unwrap();
+    assert_eq!(blobs(&mut previous), vec![(0, vec![1; 8])]);
+
+    // snapshot in progress can't be moved to another fd
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[4; 8]).unwrap();
+    assert!(matches!(
+        journal.replace_fd(Cursor::new(vec![])),
+        Err(Error::SnapshotInProgress)
+    ));
+    journal.commit().unwrap();
+    assert_eq!(journal.current_snapshot(), Some(3));
+}
+
+// check snapshots of two journals are interleaved by timestamp, without splitting snapshots
+#[test]
+fn test_merge_by_timestamp() {
+    // journal with snapshots at given timestamps, blob data is (tag, snapshot id, blob num)
+    fn journal(tag: u8, timestamps: &[i64]) -> Journal<Cursor<Vec<u8>>> {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for (id, timestamp) in timestamps.iter().enumerate() {
+            let snapshot_header = SnapshotHeader::new(id as u64, *timestamp, None);
+            journal.add_snapshot(&snapshot_header).unwrap();
+            for blob_num in 0..2 {
+                let blob = [tag, id as u8, blob_num as u8];
+                let blob_header = BlobHeader::new(0, blob_num, blob.len() as u32);
+                journal.add_blob(&blob_header, &blob).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        journal
+    }
+    let mut a = journal(b'a', &[10, 30, 30]);
+    let mut b = journal(b'b', &[20, 30, 40]);
+
+    let merged = merge_by_timestamp(&mut a, &mut b)
+        .map(|item| {
+            let (snapshot_header, _, blob) = item.unwrap();
+            (snapshot_header.timestamp, blob)
+        })
+        .collect::<Vec<_>>();
+    let expected = [
+        (10, b'a', 0),
+        (20, b'b', 0),
+        // equal timestamps and ids, journal 'a' goes first
+        (30, b'a', 1),
+        (30, b'b', 1),
+        // equal timestamps, lower id goes first
+        (30, b'a', 2),
+        (40, b'b', 2),
+    ]
+    .into_iter()
+    .flat_map(|(timestamp, tag, id)| (0..2).map(move |num| (timestamp, vec![tag, id, num])))
+    .collect::<Vec<_>>();
+    assert_eq!(merged, expected);
+
+    // merge with empty journal yields same journal
+    let mut empty = journal(b'c', &[]);
+    assert_eq!(
+        merge_by_timestamp(&mut a, &mut empty)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>(),
+        a.into_iter().map(Result::unwrap).collect::<Vec<_>>()
+    );
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct TestBlob {
     offset: u64,
@@ -29,7 +735,8 @@ struct TestBlob {
 impl Arbitrary for TestBlob {
     fn arbitrary(gen: &mut Gen) -> Self {
         Self {
-            offset: u64::arbitrary(gen),
+            // keep blob within max possible sqlite database size
+            offset: u64::arbitrary(gen) >> 17,
             data: Vec::<u8>::arbitrary(gen),
         }
     }
@@ -79,7 +786,7 @@ fn test_journal_snapshotting() {
         let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
         for snapshot in input.iter() {
             for blob in snapshot.blobs.iter() {
-                journal.new_snapshot(0).unwrap();
+                journal.new_snapshot(4096).unwrap();
                 journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
             }
             journal.commit().unwrap();
@@ -108,110 +815,736 @@ fn test_journal_snapshotting() {
     quickcheck(check as fn(Vec<TestSnapshot>));
 }
 
-#[cfg(feature = "async")]
 #[test]
-fn test_async_journal_snapshotting() {
+fn test_journal_count_blobs() {
     fn check(input: Vec<TestSnapshot>) {
-        let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
-
-        // Call the asynchronous function using the `block_on` method
-        let mut result = rt.block_on(async {
-            let mut journal = AsyncJournal::new(Header::default(), Cursor::new(vec![]), None)
-                .await
-                .unwrap();
-            for snapshot in input.iter() {
-                for blob in snapshot.blobs.iter() {
-                    journal.new_snapshot(0).await.unwrap();
-                    journal
-                        .new_blob(blob.offset, blob.data.as_slice())
-                        .await
-                        .unwrap();
-                }
-                journal.commit().await.unwrap();
-            }
-            journal
-        });
-        // iteration over journal always should return same input
-        let restored_input = rt.block_on(async {
-            let mut restored_input: Vec<TestSnapshot> = Vec::new();
-            let stream = result.stream();
-            pin_mut!(stream);
-            let mut last_snapshot_header_id: Option<u64> = None;
-            while let Some(Ok((snapshot_h, blob_h, blob))) = stream.next().await {
-                if last_snapshot_header_id != Some(snapshot_h.id) {
-                    last_snapshot_header_id = Some(snapshot_h.id);
-                    restored_input.push(TestSnapshot { blobs: vec![] });
-                }
-                restored_input.last_mut().unwrap().blobs.push(TestBlob {
-                    offset: blob_h.offset,
-                    data: blob,
-                });
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            journal.new_snapshot(4096).unwrap();
+            for blob in snapshot.blobs.iter() {
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
             }
-            restored_input
-        });
+            journal.commit().unwrap();
+        }
+        let count_blobs = journal.count_blobs().unwrap();
+        assert_eq!(count_blobs, journal.into_iter().count() as u64);
+        assert_eq!(
+            count_blobs,
+            input.iter().map(|s| s.blobs.len() as u64).sum::<u64>()
+        );
+        assert_eq!(journal.count_snapshots().unwrap(), input.len() as u64);
 
-        assert_eq!(restored_input, input);
+        // snapshot in progress isn't counted
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(0, &[1]).unwrap();
+        assert!(matches!(
+            journal.count_blobs(),
+            Err(Error::SnapshotInProgress)
+        ));
     }
     quickcheck(check as fn(Vec<TestSnapshot>));
 }
 
-#[derive(Debug, Clone)]
-struct XorShift {
-    state: u64,
+#[test]
+fn test_journal_snapshot_offsets() {
+    fn check(input: Vec<TestSnapshot>, undo_log: bool) {
+        let header = Header {
+            undo_log,
+            ..Header::default()
+        };
+        let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            journal.new_snapshot(4096).unwrap();
+            for blob in snapshot.blobs.iter() {
+                let pre_image = vec![0; blob.data.len()];
+                journal
+                    .new_blob_with_pre_image(blob.offset, blob.data.as_slice(), &pre_image)
+                    .unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        let bytes = journal.take_fd().unwrap().into_inner();
+        let mut journal = Journal::from_fd(Cursor::new(bytes.clone())).unwrap();
+        let offsets = journal.snapshot_offsets().unwrap();
+        let eof = journal.get_header().eof;
+
+        assert_eq!(offsets.len(), input.len());
+        let mut expected_start = Header::block_size() as u64;
+        for (num, &(id, start, len)) in offsets.iter().enumerate() {
+            assert_eq!(id, num as u64);
+            assert_eq!(start, expected_start);
+            let snapshot_header =
+                serde_sqlite::from_bytes::<SnapshotHeader>(&bytes[start as usize..]).unwrap();
+            assert_eq!(snapshot_header.id, id);
+            // snapshot ends with terminator
+            let terminator = &bytes[(start + len) as usize - BlobHeader::block_size()..];
+            let blob_header = serde_sqlite::from_bytes::<BlobHeader>(terminator).unwrap();
+            assert!(blob_header.is_last());
+            expected_start = start + len;
+        }
+        assert_eq!(expected_start, eof);
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>, bool));
 }
 
-impl XorShift {
-    fn new(seed: u64) -> Self {
-        // seed should never be zero
-        Self { state: seed.max(1) }
+/// Seekable writer, which only counts written bytes
+#[derive(Default)]
+struct WriteCounter {
+    pos: u64,
+    written: u64,
+}
+
+impl Write for WriteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
     }
 
-    fn next(&mut self) -> u64 {
-        let mut s = self.state;
-        s ^= s << 13;
-        s ^= s >> 17;
-        s ^= s << 5;
-        self.state = s;
-        s
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
-impl Arbitrary for XorShift {
-    fn arbitrary(g: &mut Gen) -> Self {
-        Self::new(u64::arbitrary(g))
+impl Seek for WriteCounter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(delta) => self.pos.wrapping_add_signed(delta),
+            SeekFrom::End(_) => unimplemented!(),
+        };
+        Ok(self.pos)
     }
 }
 
-// test journal serialization into Protocol stream (from scratch)
 #[test]
-fn test_journal_stream() {
-    fn check(input: Vec<TestSnapshot>, mut prng: XorShift) -> TestResult {
-        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
-        let mut expected_len = 12; // version + end of stream
-        for snapshot in input.iter() {
-            expected_len += journal::SnapshotHeader::block_size() + 4;
-            for blob in snapshot.blobs.iter() {
-                expected_len += journal::BlobHeader::block_size() + 4 + blob.data.len();
-                journal.new_snapshot(0).unwrap();
-                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
-            }
-            journal.commit().unwrap();
-        }
+fn test_journal_validate_against_database() {
+    let page_size = 4096_u64;
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(page_size as u32).unwrap();
+    let mut first_page = vec![1; page_size as usize];
+    first_page[..16].copy_from_slice(b"SQLite format 3\0");
+    first_page[16..18].copy_from_slice(&(page_size as u16).to_be_bytes());
+    journal.new_blob(0, &first_page).unwrap();
+    for num in 1..3 {
+        journal
+            .new_blob(num * page_size, &[num as u8; 4096])
+            .unwrap();
+    }
+    journal.commit().unwrap();
 
-        let mut stream: Stream<_> = Stream::from(&mut journal);
-        let mut writer = Cursor::new(vec![]);
-        loop {
-            let buf_size = (prng.next() % 100) as usize;
-            // intermidiate buffer of variable size, including 0
-            let mut buf = vec![0; buf_size];
-            let read = stream.read(&mut buf).unwrap();
-            if read == 0 && buf_size != 0 {
-                break;
-            }
-            writer.write_all(&buf[..read]).unwrap();
-        }
-        let buf = writer.into_inner();
-        if expected_len != buf.len() {
+    let db = tempfile::NamedTempFile::new().unwrap();
+    journal.export_database(&mut db.as_file()).unwrap();
+    let report = journal.validate_against_database(db.path()).unwrap();
+    assert!(report.is_consistent(), "{report:?}");
+    assert_eq!(report.page_size, page_size as u32);
+
+    // change counters of database header aren't compared
+    let mut file = db.as_file();
+    for offset in [24, 92] {
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[42; 4]).unwrap();
+    }
+    let report = journal.validate_against_database(db.path()).unwrap();
+    assert!(report.is_consistent(), "{report:?}");
+
+    // out of band edit of the second page
+    file.seek(SeekFrom::Start(page_size + 10)).unwrap();
+    file.write_all(&[42]).unwrap();
+    let report = journal.validate_against_database(db.path()).unwrap();
+    assert!(!report.is_consistent());
+    assert_eq!(report.differing_pages, vec![page_size]);
+
+    // truncated database misses the last page
+    file.set_len(page_size * 2).unwrap();
+    let report = journal.validate_against_database(db.path()).unwrap();
+    assert_eq!(report.expected_size, page_size * 3);
+    assert_eq!(report.actual_size, page_size * 2);
+    assert_eq!(report.differing_pages, vec![page_size, page_size * 2]);
+
+    // empty database file differs in every page
+    file.set_len(0).unwrap();
+    let report = journal.validate_against_database(db.path()).unwrap();
+    assert_eq!(report.page_size, page_size as u32);
+    assert_eq!(report.differing_pages, vec![0, page_size, page_size * 2]);
+}
+
+#[test]
+fn test_journal_reconcile_with_database() {
+    let page_size = 4096_u64;
+    let dir = tempfile::tempdir().unwrap();
+    let journal_path = dir.path().join("journal");
+    let mut journal = Journal::create(&journal_path).unwrap();
+    journal.new_snapshot(page_size as u32).unwrap();
+    let mut first_page = vec![1; page_size as usize];
+    first_page[..16].copy_from_slice(b"SQLite format 3\0");
+    first_page[16..18].copy_from_slice(&(page_size as u16).to_be_bytes());
+    journal.new_blob(0, &first_page).unwrap();
+    journal.new_blob(page_size, &[2; 4096]).unwrap();
+    journal.commit().unwrap();
+
+    let db = tempfile::NamedTempFile::new().unwrap();
+    journal.export_database(&mut db.as_file()).unwrap();
+    assert_eq!(journal.reconcile_with_database(db.path()).unwrap(), None);
+    assert_eq!(journal.current_snapshot(), Some(1));
+
+    // out of band edit of the second page and appended third page
+    let mut file = db.as_file();
+    file.seek(SeekFrom::Start(page_size + 10)).unwrap();
+    file.write_all(&[42]).unwrap();
+    file.seek(SeekFrom::Start(page_size * 2)).unwrap();
+    file.write_all(&[3; 4096]).unwrap();
+    drop(journal);
+    let mut journal = Journal::try_from(&journal_path).unwrap();
+    let report = journal.reconcile_with_database(db.path()).unwrap().unwrap();
+    assert_eq!(report.differing_pages, vec![page_size, page_size * 2]);
+    assert_eq!(journal.current_snapshot(), Some(2));
+    assert!(journal
+        .validate_against_database(db.path())
+        .unwrap()
+        .is_consistent());
+
+    // pages past the end of truncated database are left in journal
+    file.set_len(page_size).unwrap();
+    assert_eq!(journal.reconcile_with_database(db.path()).unwrap(), None);
+    assert_eq!(journal.current_snapshot(), Some(2));
+    assert_eq!(journal.read_page(1, page_size as u32).unwrap(), first_page);
+}
+
+#[test]
+fn test_journal_reconcile_with_database_undo_log() {
+    let page_size = 1024_u64;
+    let header = Header {
+        undo_log: true,
+        ..Header::default()
+    };
+    let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+    #[cfg(feature = "compression")]
+    journal.set_compression(Compression::Zstd { level: 3 });
+    let mut first_page = vec![1; page_size as usize];
+    first_page[..16].copy_from_slice(b"SQLite format 3\0");
+    first_page[16..18].copy_from_slice(&(page_size as u16).to_be_bytes());
+    // diffs of later snapshots overwrite earlier ones, blob straddles second and third pages
+    let snapshots: [&[(u64, &[u8])]; 2] = [
+        &[(0, &first_page), (1024, &[2; 1024]), (2048, &[3; 1024])],
+        &[(1000, &[4; 100]), (2040, &[5; 8])],
+    ];
+    for snapshot in snapshots {
+        journal.new_snapshot(page_size as u32).unwrap();
+        for &(offset, blob) in snapshot {
+            journal
+                .new_blob_with_pre_image(offset, blob, &vec![0; blob.len()])
+                .unwrap();
+        }
+        journal.commit().unwrap();
+    }
+    let db = tempfile::NamedTempFile::new().unwrap();
+    journal.export_database(&mut db.as_file()).unwrap();
+    let exported = std::fs::read(db.path()).unwrap();
+    assert_eq!(journal.reconcile_with_database(db.path()).unwrap(), None);
+
+    // edit, which leaves first page and size intact, isn't compared page by page
+    let mut file = db.as_file();
+    file.seek(SeekFrom::Start(page_size * 2 + 10)).unwrap();
+    file.write_all(&[42]).unwrap();
+    assert_eq!(journal.reconcile_with_database(db.path()).unwrap(), None);
+    let report = journal.validate_against_database(db.path()).unwrap();
+    assert_eq!(report.differing_pages, vec![page_size * 2]);
+
+    // out of band commit bumps change counter of the first page
+    file.seek(SeekFrom::Start(24)).unwrap();
+    file.write_all(&[42; 4]).unwrap();
+    file.seek(SeekFrom::Start(page_size + 500)).unwrap();
+    file.write_all(&[42; 10]).unwrap();
+    let report = journal.reconcile_with_database(db.path()).unwrap().unwrap();
+    assert_eq!(report.differing_pages, vec![page_size, page_size * 2]);
+    assert_eq!(journal.current_snapshot(), Some(3));
+    assert!(journal
+        .validate_against_database(db.path())
+        .unwrap()
+        .is_consistent());
+
+    // pre-images of reconciling snapshot are pages of journal
+    let mut database = Cursor::new(std::fs::read(db.path()).unwrap());
+    journal.undo_last(&mut database).unwrap();
+    assert!(database.get_ref()[page_size as usize..] == exported[page_size as usize..]);
+}
+
+#[test]
+fn test_journal_sync_snapshot() {
+    let headers = [
+        Header::default(),
+        Header {
+            undo_log: true,
+            blob_alignment: Some(512),
+            implicit_terminators: true,
+            ..Header::default()
+        },
+    ];
+    for header in headers {
+        let name = tempfile::NamedTempFile::new().unwrap();
+        let mut journal = Journal::new(header, name.reopen().unwrap(), None).unwrap();
+        journal.new_snapshot(4096).unwrap();
+        journal
+            .new_blob_with_pre_image(0, &[1; 8], &[0; 8])
+            .unwrap();
+        journal.commit().unwrap();
+
+        // synced snapshot is invisible, until it's committed
+        journal.new_snapshot(4096).unwrap();
+        journal
+            .new_blob_with_pre_image(8, &[2; 8], &[0; 8])
+            .unwrap();
+        journal.sync_snapshot().unwrap();
+        let mut reader = Journal::try_from(name.path()).unwrap();
+        assert_eq!(reader.current_snapshot(), Some(1));
+        assert_eq!(reader.into_iter().count(), 1);
+
+        // provisional terminator is overwritten by next blob
+        journal
+            .new_blob_with_pre_image(16, &[3; 8], &[0; 8])
+            .unwrap();
+        journal.sync_snapshot().unwrap();
+        journal.commit().unwrap();
+        let mut reader = Journal::try_from(name.path()).unwrap();
+        let blobs = reader
+            .into_iter()
+            .map(|item| {
+                let (snapshot_header, blob_header, blob) = item.unwrap();
+                (snapshot_header.id, blob_header.offset, blob)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            blobs,
+            vec![(0, 0, vec![1; 8]), (1, 8, vec![2; 8]), (1, 16, vec![3; 8])]
+        );
+        assert!(!reader.recover_synced_snapshot().unwrap());
+    }
+}
+
+#[test]
+fn test_journal_recover_synced_snapshot() {
+    let name = tempfile::NamedTempFile::new().unwrap();
+    let mut journal = Journal::new(Header::default(), name.reopen().unwrap(), None).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[1; 8]).unwrap();
+    journal.commit().unwrap();
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(8, &[2; 8]).unwrap();
+    journal.sync_snapshot().unwrap();
+    // unsynced blob is lost with crashed writer
+    journal.new_blob(16, &[3; 8]).unwrap();
+    std::mem::forget(journal);
+
+    let mut recovered = Journal::try_from(name.path()).unwrap();
+    assert!(recovered.recover_synced_snapshot().unwrap());
+    assert_eq!(recovered.current_snapshot(), Some(2));
+    let mut database = Cursor::new(vec![]);
+    recovered.export_database(&mut database).unwrap();
+    assert_eq!(database.into_inner(), [[1; 8], [2; 8]].concat());
+    assert!(!recovered.recover_synced_snapshot().unwrap());
+
+    // next snapshot follows recovered one
+    recovered.new_snapshot(4096).unwrap();
+    recovered.new_blob(16, &[4; 8]).unwrap();
+    recovered.commit().unwrap();
+    let mut database = Cursor::new(vec![]);
+    recovered.export_database(&mut database).unwrap();
+    assert_eq!(database.into_inner(), [[1; 8], [2; 8], [4; 8]].concat());
+
+    // corrupted synced snapshot is left as is
+    recovered.new_snapshot(4096).unwrap();
+    recovered.new_blob(0, &[5; 8]).unwrap();
+    recovered.sync_snapshot().unwrap();
+    std::mem::forget(recovered);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(name.path())
+        .unwrap();
+    // last byte of blob, followed by terminator
+    file.seek(SeekFrom::End(-25)).unwrap();
+    file.write_all(&[6]).unwrap();
+    let mut journal = Journal::try_from(name.path()).unwrap();
+    assert!(!journal.recover_synced_snapshot().unwrap());
+    assert_eq!(journal.current_snapshot(), Some(3));
+}
+
+#[test]
+fn test_journal_read_page() {
+    let page_size = 1024_u32;
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let mut expected = vec![0; page_size as usize];
+    // scattered diffs of the second page, blob straddling into the third page included
+    let diffs: [&[(u64, &[u8])]; 3] = [
+        &[(1024, &[1; 16]), (1500, &[2; 8])],
+        &[(1030, &[3; 100]), (1000, &[4; 40])],
+        &[(2040, &[5; 20]), (1024 + 512, &[6; 4])],
+    ];
+    for snapshot in diffs.iter() {
+        journal.new_snapshot(page_size).unwrap();
+        for &(offset, blob) in snapshot.iter() {
+            journal.new_blob(offset, blob).unwrap();
+            for (pos, byte) in (offset..).zip(blob.iter()) {
+                if (1024..2048).contains(&pos) {
+                    expected[pos as usize - 1024] = *byte;
+                }
+            }
+        }
+        journal.commit().unwrap();
+    }
+    assert_eq!(journal.read_page(2, page_size).unwrap(), expected);
+
+    // matches page of materialized database
+    let pages = journal
+        .export_pages(page_size)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    for (page_no, page) in pages {
+        assert_eq!(journal.read_page(page_no as u32, page_size).unwrap(), page);
+    }
+
+    // page, partially written by blob of previous page
+    let mut third = vec![0; page_size as usize];
+    third[..12].copy_from_slice(&[5; 12]);
+    assert_eq!(journal.read_page(3, page_size).unwrap(), third);
+    // page never written
+    assert_eq!(journal.read_page(100, page_size).unwrap(), vec![0; 1024]);
+
+    assert!(matches!(
+        journal.read_page(0, page_size),
+        Err(Error::InvalidPageNumber { page_no: 0 })
+    ));
+    assert!(matches!(
+        journal.read_page(1, 0),
+        Err(Error::InvalidPageSize { page_size: 0 })
+    ));
+}
+
+#[test]
+fn test_journal_iter_between() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    // timestamps go back between snapshots 1 and 2
+    let timestamps = [100, 200, 150, 300, 200];
+    for (id, timestamp) in timestamps.into_iter().enumerate() {
+        let id = id as u64;
+        journal
+            .add_snapshot(&SnapshotHeader::new(id, timestamp, Some(4096)))
+            .unwrap();
+        journal.new_blob(id * 4096, &[id as u8; 8]).unwrap();
+        journal.commit().unwrap();
+    }
+    let between = |journal: &mut Journal<_>, start_ts, end_ts| {
+        journal
+            .iter_between(start_ts, end_ts)
+            .map(|item| item.unwrap().0.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        between(&mut journal, i64::MIN, i64::MAX),
+        vec![0, 1, 2, 3, 4]
+    );
+    // both bounds are inclusive
+    assert_eq!(between(&mut journal, 150, 200), vec![1, 2, 4]);
+    assert_eq!(between(&mut journal, 200, 200), vec![1, 4]);
+    assert_eq!(between(&mut journal, 151, 199), vec![]);
+    // snapshots after out of window snapshot are still yielded
+    assert_eq!(between(&mut journal, 100, 150), vec![0, 2]);
+    assert_eq!(between(&mut journal, 300, i64::MAX), vec![3]);
+    assert_eq!(between(&mut journal, 200, 100), vec![]);
+}
+
+#[test]
+fn test_journal_iter_unpushed() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..5_u64 {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(i * 4096, &[i as u8; 8]).unwrap();
+        journal.new_blob(i * 4096 + 100, &[i as u8; 8]).unwrap();
+        journal.commit().unwrap();
+    }
+    let unpushed = |journal: &mut Journal<_>, confirmed_remote| {
+        let mut ids = journal
+            .iter_unpushed(confirmed_remote)
+            .map(|item| item.unwrap().0.id)
+            .collect::<Vec<_>>();
+        ids.dedup();
+        ids
+    };
+    assert_eq!(unpushed(&mut journal, None), vec![0, 1, 2, 3, 4]);
+    assert_eq!(unpushed(&mut journal, Some(0)), vec![1, 2, 3, 4]);
+    assert_eq!(unpushed(&mut journal, Some(2)), vec![3, 4]);
+    assert_eq!(unpushed(&mut journal, Some(3)), vec![4]);
+    assert_eq!(unpushed(&mut journal, Some(4)), vec![]);
+    assert_eq!(unpushed(&mut journal, Some(5)), vec![]);
+    assert_eq!(unpushed(&mut journal, Some(u64::MAX)), vec![]);
+
+    // blobs of unpushed snapshots are yielded whole
+    let blobs = journal.iter_unpushed(Some(3)).count();
+    assert_eq!(blobs, 2);
+
+    // stream of unpushed snapshots applies on top of confirmed ones
+    let mut confirmed = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let mut stream = vec![];
+    Stream::from((JOURNAL_VERSION, journal.iter_unpushed(None).take(4)))
+        .read_to_end(&mut stream)
+        .unwrap();
+    confirmed.apply_stream(stream.as_slice()).unwrap();
+    let mut stream = vec![];
+    Stream::from((JOURNAL_VERSION, journal.iter_unpushed(Some(1))))
+        .read_to_end(&mut stream)
+        .unwrap();
+    confirmed.apply_stream(stream.as_slice()).unwrap();
+    assert_eq!(confirmed.get_header(), journal.get_header());
+}
+
+#[test]
+fn test_journal_blob_alignment() {
+    fn check(input: Vec<TestSnapshot>, large: bool, undo_log: bool) {
+        let alignment = if large { 512 } else { 8 };
+        let header = Header {
+            undo_log,
+            blob_alignment: Some(alignment),
+            ..Header::default()
+        };
+        let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            journal.new_snapshot(4096).unwrap();
+            for blob in snapshot.blobs.iter() {
+                let pre_image = vec![0; blob.data.len()];
+                journal
+                    .new_blob_with_pre_image(blob.offset, blob.data.as_slice(), &pre_image)
+                    .unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        let bytes = journal.take_fd().unwrap().into_inner();
+
+        // every blob starts at aligned offset
+        let mut pos = Header::block_size();
+        for snapshot in input.iter() {
+            pos += SnapshotHeader::block_size();
+            for blob in snapshot.blobs.iter().map(Some).chain([None]) {
+                pos = (pos + BlobHeader::block_size()).next_multiple_of(alignment as usize);
+                let blob_header = serde_sqlite::from_bytes::<BlobHeader>(
+                    &bytes[pos - BlobHeader::block_size()..],
+                )
+                .unwrap();
+                match blob {
+                    Some(blob) => {
+                        assert_eq!(blob_header.offset, blob.offset);
+                        assert_eq!(&bytes[pos..pos + blob.data.len()], blob.data.as_slice());
+                        pos += blob.data.len() * if undo_log { 2 } else { 1 };
+                    }
+                    None => assert!(blob_header.is_last()),
+                }
+            }
+        }
+
+        // iteration skips padding
+        let mut journal = Journal::from_fd(Cursor::new(bytes)).unwrap();
+        assert_eq!(journal.get_header().blob_alignment, Some(alignment));
+        let blobs = journal
+            .into_iter()
+            .map(|item| {
+                let (_, blob_header, blob) = item.unwrap();
+                (blob_header.offset, blob)
+            })
+            .collect::<Vec<_>>();
+        let expected = input
+            .iter()
+            .flat_map(|snapshot| snapshot.blobs.iter())
+            .map(|blob| (blob.offset, blob.data.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(blobs, expected);
+        assert_eq!(journal.count_blobs().unwrap(), expected.len() as u64);
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>, bool, bool));
+
+    for alignment in [3, 0x0002_0000] {
+        let header = Header {
+            blob_alignment: Some(alignment),
+            ..Header::default()
+        };
+        assert!(matches!(
+            Journal::new(header, Cursor::new(vec![]), None),
+            Err(Error::InvalidBlobAlignment { .. })
+        ));
+    }
+}
+
+#[test]
+fn test_journal_restore_cost() {
+    fn check(input: Vec<TestSnapshot>, from: u64) {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            journal.new_snapshot(4096).unwrap();
+            for blob in snapshot.blobs.iter() {
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        let mut output = WriteCounter::default();
+        journal.export_database(&mut output).unwrap();
+        let cost = journal.restore_cost(None).unwrap();
+        assert_eq!(cost.bytes, output.written);
+        assert_eq!(cost.blobs, journal.count_blobs().unwrap());
+        assert_eq!(cost.snapshots, input.len() as u64);
+
+        let from = from % (input.len() as u64 + 1);
+        let expected = input
+            .iter()
+            .skip(from as usize)
+            .flat_map(|snapshot| snapshot.blobs.iter())
+            .map(|blob| blob.data.len() as u64)
+            .sum::<u64>();
+        let cost = journal.restore_cost(Some(from)).unwrap();
+        assert_eq!(cost.bytes, expected);
+        assert_eq!(cost.snapshots, input.len() as u64 - from);
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>, u64));
+}
+
+#[test]
+fn test_journal_restore_cost_after_page_size_change() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(1024).unwrap();
+    journal.new_blob(0, &[1; 2048]).unwrap();
+    journal.commit().unwrap();
+    journal.new_baseline_snapshot(4096).unwrap();
+    journal.new_blob(0, &[2; 4096]).unwrap();
+    journal.commit().unwrap();
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(100, &[3; 10]).unwrap();
+    journal.commit().unwrap();
+
+    // restore starts from baseline, which holds whole database
+    let mut output = WriteCounter::default();
+    journal.export_database(&mut output).unwrap();
+    let cost = journal.restore_cost(None).unwrap();
+    assert_eq!(output.written, 4106);
+    assert_eq!(
+        cost,
+        RestoreCost {
+            snapshots: 2,
+            blobs: 2,
+            bytes: 4106
+        }
+    );
+    assert_eq!(journal.restore_cost(Some(0)).unwrap(), cost);
+    assert_eq!(
+        journal.restore_cost(Some(2)).unwrap(),
+        RestoreCost {
+            snapshots: 1,
+            blobs: 1,
+            bytes: 10
+        }
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_journal_snapshotting() {
+    fn check(input: Vec<TestSnapshot>) {
+        let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+
+        // Call the asynchronous function using the `block_on` method
+        let mut result = rt.block_on(async {
+            let mut journal = AsyncJournal::new(Header::default(), Cursor::new(vec![]), None)
+                .await
+                .unwrap();
+            for snapshot in input.iter() {
+                for blob in snapshot.blobs.iter() {
+                    journal.new_snapshot(4096).await.unwrap();
+                    journal
+                        .new_blob(blob.offset, blob.data.as_slice())
+                        .await
+                        .unwrap();
+                }
+                journal.commit().await.unwrap();
+            }
+            journal
+        });
+        // iteration over journal always should return same input
+        let restored_input = rt.block_on(async {
+            let mut restored_input: Vec<TestSnapshot> = Vec::new();
+            let stream = result.stream();
+            pin_mut!(stream);
+            let mut last_snapshot_header_id: Option<u64> = None;
+            while let Some(Ok((snapshot_h, blob_h, blob))) = stream.next().await {
+                if last_snapshot_header_id != Some(snapshot_h.id) {
+                    last_snapshot_header_id = Some(snapshot_h.id);
+                    restored_input.push(TestSnapshot { blobs: vec![] });
+                }
+                restored_input.last_mut().unwrap().blobs.push(TestBlob {
+                    offset: blob_h.offset,
+                    data: blob,
+                });
+            }
+            restored_input
+        });
+
+        assert_eq!(restored_input, input);
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>));
+}
+
+#[derive(Debug, Clone)]
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // seed should never be zero
+        Self { state: seed.max(1) }
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut s = self.state;
+        s ^= s << 13;
+        s ^= s >> 17;
+        s ^= s << 5;
+        self.state = s;
+        s
+    }
+}
+
+impl Arbitrary for XorShift {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Self::new(u64::arbitrary(g))
+    }
+}
+
+// test journal serialization into Protocol stream (from scratch)
+#[test]
+fn test_journal_stream() {
+    fn check(input: Vec<TestSnapshot>, mut prng: XorShift) -> TestResult {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        let mut expected_len = 12; // version + end of stream
+        for snapshot in input.iter() {
+            expected_len += journal::SnapshotHeader::block_size() + 4;
+            for blob in snapshot.blobs.iter() {
+                expected_len += journal::BlobHeader::block_size() + 4 + blob.data.len();
+                journal.new_snapshot(4096).unwrap();
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+
+        let mut stream: Stream<_> = Stream::from(&mut journal);
+        let mut writer = Cursor::new(vec![]);
+        loop {
+            let buf_size = (prng.next() % 100) as usize;
+            // intermidiate buffer of variable size, including 0
+            let mut buf = vec![0; buf_size];
+            let read = stream.read(&mut buf).unwrap();
+            if read == 0 && buf_size != 0 {
+                break;
+            }
+            writer.write_all(&buf[..read]).unwrap();
+        }
+        let buf = writer.into_inner();
+        if expected_len != buf.len() {
             return TestResult::error(format!(
                 "expected len: {}, got: {}",
                 expected_len,
@@ -223,7 +1556,7 @@ fn test_journal_stream() {
         let mut expected = vec![];
         assert_eq!(
             serde_sqlite::from_reader::<Protocol, _>(&mut reader).unwrap(),
-            Protocol::JournalVersion(1.into())
+            Protocol::JournalVersion(JOURNAL_VERSION.into())
         );
         loop {
             match serde_sqlite::from_reader::<Protocol, _>(&mut reader) {
@@ -254,7 +1587,7 @@ fn test_journal_stream_with_offset() {
         let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
         for snapshot in input.iter() {
             for blob in snapshot.blobs.iter() {
-                journal.new_snapshot(0).unwrap();
+                journal.new_snapshot(4096).unwrap();
                 journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
             }
             journal.commit().unwrap();
@@ -269,7 +1602,8 @@ fn test_journal_stream_with_offset() {
                 expected_len += journal::BlobHeader::block_size() + 4 + blob.data.len();
             }
         }
-        let mut stream: Stream<_> = Stream::from((1, journal.into_iter().skip_snapshots(skip)));
+        let mut stream: Stream<_> =
+            Stream::from((JOURNAL_VERSION, journal.into_iter().skip_snapshots(skip)));
         let mut writer = Cursor::new(vec![]);
         loop {
             let buf_size = (prng.next() % 100) as usize;
@@ -295,7 +1629,7 @@ fn test_journal_stream_with_offset() {
 
         assert_eq!(
             serde_sqlite::from_reader::<Protocol, _>(&mut reader).unwrap(),
-            Protocol::JournalVersion(1.into())
+            Protocol::JournalVersion(JOURNAL_VERSION.into())
         );
         loop {
             match serde_sqlite::from_reader::<Protocol, _>(&mut reader) {
@@ -318,75 +1652,1022 @@ fn test_journal_stream_with_offset() {
     quickcheck(check as fn(Vec<TestSnapshot>, XorShift) -> TestResult);
 }
 
-// check journal rebuild from stream
-// journals should be identical in size and contents
+// valid stream passes verification with stats matching journal contents
+// protocol frame with unknown tag, i.e. produced by newer protocol version
+#[test]
+fn test_protocol_invalid_tag() {
+    let mut frame =
+        serde_sqlite::to_bytes(&Protocol::JournalVersion(JOURNAL_VERSION.into())).unwrap();
+    frame[..4].copy_from_slice(&42_u32.to_be_bytes());
+    assert!(matches!(
+        serde_sqlite::from_bytes::<Protocol>(&frame),
+        Err(serde_sqlite::Error::InvalidEnumTag { tag: 42 })
+    ));
+}
+
+#[test]
+fn test_verify_stream() {
+    fn check(input: Vec<TestSnapshot>) {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        let mut expected = StreamStats::default();
+        for snapshot in input.iter() {
+            expected.snapshots += 1;
+            for blob in snapshot.blobs.iter() {
+                expected.blobs += 1;
+                expected.bytes += blob.data.len() as u64;
+                journal.new_snapshot(4096).unwrap();
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        let stats = verify_stream(Stream::from(&mut journal)).unwrap();
+        assert_eq!(stats, expected);
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>));
+}
+
+#[test]
+fn test_verify_stream_violations() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for offset in [0, 100] {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(offset, &[1, 2, 3]).unwrap();
+        journal.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut journal).read_to_end(&mut stream).unwrap();
+    assert!(verify_stream(stream.as_slice()).is_ok());
+
+    // version header is 4 bytes tag + 4 bytes version, end of stream is 4 bytes tag
+    let (version, rest) = stream.split_at(8);
+    let (body, end) = rest.split_at(rest.len() - 4);
+
+    let res = verify_stream(rest);
+    assert!(matches!(res, Err(Error::MissingJournalVersion)), "{res:?}");
+
+    let res = verify_stream(&stream[..stream.len() - 4]);
+    assert!(matches!(res, Err(Error::MissingEndOfStream)), "{res:?}");
+
+    let res = verify_stream([stream.as_slice(), end].concat().as_slice());
+    assert!(matches!(res, Err(Error::TrailingStreamData)), "{res:?}");
+
+    // replay same body twice, second snapshot 0 follows snapshot 1
+    let res = verify_stream([version, body, body, end].concat().as_slice());
+    assert!(
+        matches!(
+            res,
+            Err(Error::OutOfOrderSnapshot {
+                snapshot_id: 0,
+                journal_snapshot_id: 2
+            })
+        ),
+        "{res:?}"
+    );
+
+    // snapshot ids may have gaps, but must increase
+    let snapshot =
+        serde_sqlite::to_bytes(&Protocol::from(SnapshotHeader::new(5, 0, None))).unwrap();
+    assert!(verify_stream([version, body, &snapshot, end].concat().as_slice()).is_ok());
+}
+
+// check journal rebuild from stream
+// journals should be identical in size and contents
+#[test]
+fn test_journal_rebuild_from_stream() {
+    fn check(input: Vec<TestSnapshot>, mut prng: XorShift) {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            for blob in snapshot.blobs.iter() {
+                journal.new_snapshot(4096).unwrap();
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+
+        let mut stream: Stream<_> = Stream::from(&mut journal);
+        let mut writer = Cursor::new(vec![]);
+        loop {
+            let buf_size = (prng.next() % 100) as usize;
+            // intermidiate buffer of variable size, including 0
+            let mut buf = vec![0; buf_size];
+            let read = stream.read(&mut buf).unwrap();
+            if read == 0 && buf_size != 0 {
+                break;
+            }
+            writer.write_all(&buf[..read]).unwrap();
+        }
+        let buf = writer.into_inner();
+
+        let mut reader = Cursor::new(buf.as_slice());
+        let mut recovered_journal =
+            Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+
+        assert_eq!(
+            serde_sqlite::from_reader::<Protocol, _>(&mut reader).unwrap(),
+            Protocol::JournalVersion(JOURNAL_VERSION.into())
+        );
+        loop {
+            match serde_sqlite::from_reader::<Protocol, _>(&mut reader) {
+                Ok(Protocol::SnapshotHeader(s)) => {
+                    recovered_journal.commit().unwrap();
+                    recovered_journal.add_snapshot(&s).unwrap();
+                }
+                Ok(Protocol::BlobHeader(p)) => {
+                    let mut buf = vec![0; p.blob_size as usize];
+                    reader.read_exact(buf.as_mut_slice()).unwrap();
+                    recovered_journal.add_blob(&p, buf.as_slice()).unwrap();
+                }
+                Ok(Protocol::EndOfStream(_)) => {
+                    recovered_journal.commit().unwrap();
+                    break;
+                }
+                Ok(Protocol::JournalVersion(_)) => {
+                    panic!("version header should not appear in loop")
+                }
+                Err(e) => panic!("unexpected stream error: {e}"),
+            }
+        }
+        assert_eq!(
+            journal.into_iter().count(),
+            recovered_journal.into_iter().count()
+        );
+        assert!(journal
+            .into_iter()
+            .map(Result::unwrap)
+            .zip(recovered_journal.into_iter().map(Result::unwrap))
+            .all(|(left, right)| left.eq(&right)));
+        assert_eq!(journal.get_header(), recovered_journal.get_header());
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>, XorShift));
+}
+
+// journal passed through length-delimited framing
+#[test]
+fn test_journal_length_delimited_stream() {
+    fn check(input: Vec<TestSnapshot>) {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            for blob in snapshot.blobs.iter() {
+                journal.new_snapshot(4096).unwrap();
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        let mut plain = vec![];
+        Stream::from(&mut journal).read_to_end(&mut plain).unwrap();
+
+        // each frame is decodable on its own
+        let mut frames = LengthDelimitedStream::new(Stream::from(&mut journal));
+        let mut frame_count = 0;
+        while let Some(frame) = frames.next_frame().unwrap() {
+            serde_sqlite::from_bytes::<Protocol>(&frame).unwrap();
+            frame_count += 1;
+        }
+        let blob_count = input.iter().map(|s| s.blobs.len()).sum::<usize>();
+        let snapshot_count = input.iter().filter(|s| !s.blobs.is_empty()).count();
+        assert_eq!(frame_count, 2 + snapshot_count + blob_count);
+
+        let mut framed = vec![];
+        LengthDelimitedStream::new(Stream::from(&mut journal))
+            .read_to_end(&mut framed)
+            .unwrap();
+        assert_eq!(framed.len(), plain.len() + 4 * frame_count);
+
+        let mut unframed = vec![];
+        LengthDelimitedReader::new(framed.as_slice())
+            .read_to_end(&mut unframed)
+            .unwrap();
+        assert_eq!(unframed, plain);
+
+        let mut reader = LengthDelimitedReader::new(framed.as_slice());
+        let mut recovered_journal =
+            Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        assert_eq!(
+            serde_sqlite::from_reader::<Protocol, _>(&mut reader).unwrap(),
+            Protocol::JournalVersion(JOURNAL_VERSION.into())
+        );
+        loop {
+            match serde_sqlite::from_reader::<Protocol, _>(&mut reader).unwrap() {
+                Protocol::SnapshotHeader(s) => {
+                    recovered_journal.commit().unwrap();
+                    recovered_journal.add_snapshot(&s).unwrap();
+                }
+                Protocol::BlobHeader(p) => {
+                    let mut buf = vec![0; p.blob_size as usize];
+                    reader.read_exact(buf.as_mut_slice()).unwrap();
+                    recovered_journal.add_blob(&p, buf.as_slice()).unwrap();
+                }
+                Protocol::EndOfStream(_) => {
+                    recovered_journal.commit().unwrap();
+                    break;
+                }
+                Protocol::JournalVersion(_) => {
+                    panic!("version header should not appear in loop")
+                }
+            }
+        }
+        assert!(journal
+            .into_iter()
+            .map(Result::unwrap)
+            .eq(recovered_journal.into_iter().map(Result::unwrap)));
+        assert_eq!(journal.get_header(), recovered_journal.get_header());
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>));
+}
+
+#[test]
+fn test_journal_create_from_stream() {
+    fn blobs(journal: &mut Journal) -> Vec<(SnapshotHeader, BlobHeader, Vec<u8>)> {
+        journal.into_iter().map(Result::unwrap).collect()
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut source = Journal::create(dir.path().join("source")).unwrap();
+    for i in 0..4 {
+        source.new_snapshot(4096).unwrap();
+        source.new_blob(i * 4096, &[i as u8; 100]).unwrap();
+        source.new_blob(i * 4096 + 1000, &[i as u8; 10]).unwrap();
+        source.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut source).read_to_end(&mut stream).unwrap();
+
+    let target_path = dir.path().join("target");
+    let mut target = Journal::create_from_stream(&target_path, stream.as_slice()).unwrap();
+    assert_eq!(target.get_header(), source.get_header());
+    assert_eq!(blobs(&mut target), blobs(&mut source));
+    let mut reopened = Journal::try_from(&target_path).unwrap();
+    assert_eq!(blobs(&mut reopened), blobs(&mut source));
+
+    // existing journal is never overwritten
+    assert!(matches!(
+        Journal::create_from_stream(&target_path, stream.as_slice()),
+        Err(Error::IOError(e)) if e.kind() == std::io::ErrorKind::AlreadyExists
+    ));
+
+    // truncated stream leaves no journal behind
+    let truncated_path = dir.path().join("truncated");
+    assert!(Journal::create_from_stream(&truncated_path, &stream[..stream.len() - 20]).is_err());
+    assert!(!truncated_path.exists());
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn test_journal_snapshot_gap_and_duplicate() {
+    fn populate(count: u8) -> Journal<Cursor<Vec<u8>>> {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for value in 0..count {
+            journal
+                .add_snapshot(&SnapshotHeader::new(value as u64, 0, Some(4096)))
+                .unwrap();
+            journal.new_blob(0, &[value; 8]).unwrap();
+            journal.commit().unwrap();
+        }
+        journal
+    }
+    fn blobs(journal: &mut Journal<Cursor<Vec<u8>>>) -> Vec<(u64, Vec<u8>)> {
+        journal
+            .into_iter()
+            .map(Result::unwrap)
+            .map(|(snapshot_header, _, blob)| (snapshot_header.id, blob))
+            .collect()
+    }
+
+    let mut journal = populate(2);
+    assert!(matches!(
+        journal.add_snapshot(&SnapshotHeader::new(4, 0, None)),
+        Err(Error::SnapshotGap { have: 2, got: 4 })
+    ));
+    assert!(matches!(
+        journal.add_snapshot(&SnapshotHeader::new(1, 0, None)),
+        Err(Error::DuplicateSnapshot { id: 1 })
+    ));
+
+    // already applied snapshots are skipped
+    let mut source = populate(4);
+    let mut stream = vec![];
+    Stream::from(&mut source).read_to_end(&mut stream).unwrap();
+    journal.apply_stream(stream.as_slice()).unwrap();
+    assert_eq!(blobs(&mut journal), blobs(&mut source));
+
+    // stream which starts ahead of journal is rejected
+    let mut journal = populate(1);
+    let mut stream = vec![];
+    Stream::new(source.into_iter().skip_snapshots(2), JOURNAL_VERSION)
+        .read_to_end(&mut stream)
+        .unwrap();
+    assert!(matches!(
+        journal.apply_stream(stream.as_slice()),
+        Err(Error::SnapshotGap { have: 1, got: 2 })
+    ));
+    assert_eq!(blobs(&mut journal), vec![(0, vec![0; 8])]);
+}
+
+// iteration over many consecutive empty snapshots runs in constant stack
+#[test]
+fn test_journal_iter_empty_snapshots() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..50_000_u64 {
+        journal.new_snapshot(4096).unwrap();
+        if i % 10_000 == 9_999 {
+            journal.new_blob(i, &[1; 10]).unwrap();
+        }
+        journal.commit().unwrap();
+    }
+    // trailing empty snapshot
+    journal.new_snapshot(4096).unwrap();
+    journal.commit().unwrap();
+
+    let blobs = std::thread::Builder::new()
+        .stack_size(64 * 1024)
+        .spawn(move || {
+            (&mut journal)
+                .into_iter()
+                .map(|item| {
+                    item.map(|(snapshot_header, blob_header, _)| {
+                        (snapshot_header.id, blob_header.offset)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(
+        blobs,
+        vec![
+            (9_999, 9_999),
+            (19_999, 19_999),
+            (29_999, 29_999),
+            (39_999, 39_999),
+            (49_999, 49_999)
+        ]
+    );
+}
+
+#[test]
+fn test_journal_content_digest() {
+    fn build(header: Header, blobs: &[(u64, Vec<u8>)]) -> Journal<Cursor<Vec<u8>>> {
+        let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+        for (offset, blob) in blobs {
+            journal.new_snapshot(4096).unwrap();
+            journal
+                .new_blob_with_pre_image(*offset, blob, &vec![0; blob.len()])
+                .unwrap();
+            journal.commit().unwrap();
+            // snapshots are timestamped independently
+            sleep(Duration::from_micros(10));
+        }
+        journal
+    }
+    let blobs = vec![
+        (0, vec![1; 4096]),
+        (100, vec![2; 10]),
+        (4096, vec![3; 4096]),
+    ];
+    let digest = build(Header::default(), &blobs).content_digest().unwrap();
+
+    // same history, stored differently
+    let header = Header {
+        undo_log: true,
+        blob_alignment: Some(512),
+        ..Header::default()
+    };
+    assert_eq!(build(header, &blobs).content_digest().unwrap(), digest);
+    assert_eq!(
+        build(Header::default(), &blobs).content_digest().unwrap(),
+        digest
+    );
+
+    // different blob bytes, blob offset, snapshot boundaries, missing snapshot
+    let mut modified = blobs.clone();
+    modified[1].1[9] = 4;
+    assert_ne!(
+        build(Header::default(), &modified)
+            .content_digest()
+            .unwrap(),
+        digest
+    );
+    let mut modified = blobs.clone();
+    modified[1].0 = 101;
+    assert_ne!(
+        build(Header::default(), &modified)
+            .content_digest()
+            .unwrap(),
+        digest
+    );
+    let mut journal = build(Header::default(), &blobs[..1]);
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(100, &[2; 10]).unwrap();
+    journal.new_blob(4096, &[3; 4096]).unwrap();
+    journal.commit().unwrap();
+    assert_ne!(journal.content_digest().unwrap(), digest);
+    assert_ne!(
+        build(Header::default(), &blobs[..2])
+            .content_digest()
+            .unwrap(),
+        digest
+    );
+
+    // empty journals are equal
+    assert_eq!(
+        build(Header::default(), &[]).content_digest().unwrap(),
+        build(header, &[]).content_digest().unwrap()
+    );
+}
+
+#[test]
+fn test_journal_apply_stream_divergence() {
+    fn build(blobs: &[&[u8]]) -> Journal<Cursor<Vec<u8>>> {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for blob in blobs {
+            journal.new_snapshot(4096).unwrap();
+            journal.new_blob(0, blob).unwrap();
+            journal.commit().unwrap();
+        }
+        journal
+    }
+    fn stream(journal: &mut Journal<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut stream = vec![];
+        Stream::from(journal).read_to_end(&mut stream).unwrap();
+        stream
+    }
+    let mut writer = build(&[&[1; 8], &[2; 8], &[3; 8]]);
+    let mut local = build(&[&[1; 8], &[2; 8]]);
+
+    // snapshot digest doesn't depend on timestamp, digest of stream frames is the same
+    for id in 0..2 {
+        assert_eq!(
+            local.snapshot_digest(id).unwrap(),
+            writer.snapshot_digest(id).unwrap()
+        );
+    }
+    assert_ne!(
+        local.snapshot_digest(0).unwrap(),
+        local.snapshot_digest(1).unwrap()
+    );
+    let (snapshot_header, blob_header, blob) = writer.into_iter().nth(2).unwrap().unwrap();
+    let mut digest = SnapshotDigest::new(&snapshot_header);
+    digest.update(&blob_header, &blob).unwrap();
+    assert_eq!(digest.finalize(), writer.snapshot_digest(2).unwrap());
+    assert!(matches!(
+        local.snapshot_digest(2),
+        Err(Error::SnapshotNotFound { id: 2 })
+    ));
+
+    // snapshots of another writer with the same ids are rejected, journal is left intact
+    let digest = local.content_digest().unwrap();
+    for blobs in [
+        &[&[1; 8][..], &[4; 8], &[3; 8]][..],
+        // last snapshot is checked at end of stream
+        &[&[1; 8], &[4; 8]],
+        // snapshot with extra blob
+        &[&[1; 8], &[2; 16]],
+    ] {
+        let mut other = build(blobs);
+        let res = local.apply_stream(stream(&mut other).as_slice());
+        assert!(
+            matches!(res, Err(Error::SnapshotDivergence { id: 1 })),
+            "{res:?}"
+        );
+        assert_eq!(local.content_digest().unwrap(), digest);
+        assert_eq!(local.count_snapshots().unwrap(), 2);
+    }
+
+    // overlapping snapshots of the same history are skipped
+    local.apply_stream(stream(&mut writer).as_slice()).unwrap();
+    assert_eq!(
+        local.content_digest().unwrap(),
+        writer.content_digest().unwrap()
+    );
+}
+
+#[test]
+fn test_journal_apply_stream_duplicates_in_one_pass() {
+    // fd, which counts reads from first snapshot, i.e. journal scans
+    struct CountingFd {
+        inner: Cursor<Vec<u8>>,
+        scans: std::rc::Rc<std::cell::Cell<u64>>,
+    }
+    impl Read for CountingFd {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() == Header::block_size() as u64 {
+                self.scans.set(self.scans.get() + 1);
+            }
+            self.inner.read(buf)
+        }
+    }
+    impl Write for CountingFd {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+    impl Seek for CountingFd {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+    let header = Header {
+        implicit_terminators: true,
+        relative_timestamps: true,
+        ..Header::default()
+    };
+    let mut writer = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+    for i in 0..500_u64 {
+        writer.new_snapshot(4096).unwrap();
+        writer.new_blob(i * 8, &[i as u8; 8]).unwrap();
+        writer.commit().unwrap();
+    }
+    let bytes = writer.take_fd().unwrap().into_inner();
+    let mut writer = Journal::from_fd(Cursor::new(bytes.clone())).unwrap();
+    let scans = std::rc::Rc::new(std::cell::Cell::new(0));
+    let fd = CountingFd {
+        inner: Cursor::new(bytes.clone()),
+        scans: std::rc::Rc::clone(&scans),
+    };
+    let mut local = Journal::from_fd(fd).unwrap();
+    writer.new_snapshot(4096).unwrap();
+    writer.new_blob(0, &[42; 8]).unwrap();
+    writer.commit().unwrap();
+
+    // every duplicate snapshot is hashed, journal is scanned once
+    let mut stream = vec![];
+    Stream::from(&mut writer).read_to_end(&mut stream).unwrap();
+    scans.set(0);
+    local.apply_stream(stream.as_slice()).unwrap();
+    assert_eq!(scans.get(), 1);
+    assert_eq!(
+        local.content_digest().unwrap(),
+        writer.content_digest().unwrap()
+    );
+
+    // divergence of last duplicate is still detected
+    let mut other = Journal::from_fd(Cursor::new(bytes)).unwrap();
+    let (snapshot_header, _, _) = other.into_iter().last().unwrap().unwrap();
+    let mut stream = vec![];
+    Stream::from(&mut other).read_to_end(&mut stream).unwrap();
+    let pos = stream
+        .windows(8)
+        .rposition(|window| window == [snapshot_header.id as u8; 8])
+        .unwrap();
+    stream[pos] ^= 1;
+    let res = local.apply_stream(stream.as_slice());
+    assert!(
+        matches!(res, Err(Error::SnapshotDivergence { id: 499 })),
+        "{res:?}"
+    );
+}
+
+#[test]
+fn test_journal_max_blob_size() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    assert_eq!(journal.max_blob_size(), 0x0100_0000);
+    // hard limit can't be raised
+    journal.set_max_blob_size(u32::MAX);
+    assert_eq!(journal.max_blob_size(), 0x0100_0000);
+
+    journal.set_max_blob_size(16);
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[1; 16]).unwrap();
+    for res in [
+        journal.new_blob(16, &[2; 17]),
+        journal.new_blob_with_pre_image(16, &[2; 17], &[0; 17]),
+        journal.add_blob(&BlobHeader::new(16, 1, 17), &[2; 17]),
+    ] {
+        assert!(
+            matches!(
+                res,
+                Err(Error::BlobTooLarge {
+                    blob_size: 17,
+                    max: 16
+                })
+            ),
+            "{res:?}"
+        );
+    }
+    // rejected blob isn't written, snapshot can go on
+    journal.new_blob(16, &[3; 8]).unwrap();
+    journal.commit().unwrap();
+    let blobs = journal
+        .into_iter()
+        .map(|item| item.unwrap().2)
+        .collect::<Vec<_>>();
+    assert_eq!(blobs, vec![vec![1; 16], vec![3; 8]]);
+
+    // oversized blob of applied stream is rejected before it's allocated
+    let mut source = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    source.new_snapshot(4096).unwrap();
+    source.new_blob(0, &[4; 32]).unwrap();
+    source.commit().unwrap();
+    let mut stream = vec![];
+    Stream::from(&mut source).read_to_end(&mut stream).unwrap();
+    let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    target.set_max_blob_size(16);
+    let res = target.apply_stream(stream.as_slice());
+    assert!(
+        matches!(
+            res,
+            Err(Error::BlobTooLarge {
+                blob_size: 32,
+                max: 16
+            })
+        ),
+        "{res:?}"
+    );
+}
+
+#[test]
+fn test_journal_snapshot_bytes() {
+    let mut source = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..3 {
+        source.new_snapshot(4096).unwrap();
+        source.new_blob(i * 4096, &[i as u8; 100]).unwrap();
+        source.new_blob(i * 4096 + 1000, &[i as u8; 10]).unwrap();
+        source.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut source).read_to_end(&mut stream).unwrap();
+    let version =
+        serde_sqlite::to_bytes(&Protocol::JournalVersion(JOURNAL_VERSION.into())).unwrap();
+    let end_of_stream = &stream[stream.len() - 4..];
+
+    // snapshots are re-sent one by one
+    let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for id in 0..3 {
+        let mut snapshot_stream = version.clone();
+        snapshot_stream.extend(source.snapshot_bytes(id).unwrap());
+        snapshot_stream.extend(end_of_stream);
+        target.apply_stream(snapshot_stream.as_slice()).unwrap();
+        assert_eq!(target.get_header().snapshot_counter, id + 1);
+    }
+    assert_eq!(target.get_header(), source.get_header());
+    let mut target_stream = vec![];
+    Stream::from(&mut target)
+        .read_to_end(&mut target_stream)
+        .unwrap();
+    assert_eq!(target_stream, stream);
+
+    assert!(matches!(
+        source.snapshot_bytes(3),
+        Err(Error::SnapshotNotFound { id: 3 })
+    ));
+}
+
+#[test]
+fn test_journal_export_database_as_of() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(512).unwrap();
+    journal.new_blob(0, &[1; 1024]).unwrap();
+    journal.commit().unwrap();
+    journal.new_snapshot(512).unwrap();
+    journal.new_blob(100, &[2; 10]).unwrap();
+    journal.commit().unwrap();
+    journal.new_snapshot(512).unwrap();
+    journal.new_blob(100, &[3; 10]).unwrap();
+    journal.new_blob(1024, &[4; 512]).unwrap();
+    journal.commit().unwrap();
+
+    let export_as_of = |journal: &mut Journal<_>, snapshot_id| {
+        let mut database = Cursor::new(vec![]);
+        journal
+            .export_database_as_of(&mut database, snapshot_id)
+            .map(|_| database.into_inner())
+    };
+
+    assert_eq!(export_as_of(&mut journal, 0).unwrap(), vec![1; 1024]);
+
+    let mut expected = vec![1; 1024];
+    expected[100..110].copy_from_slice(&[2; 10]);
+    assert_eq!(export_as_of(&mut journal, 1).unwrap(), expected);
+
+    let mut latest = Cursor::new(vec![]);
+    journal.export_database(&mut latest).unwrap();
+    assert_eq!(export_as_of(&mut journal, 2).unwrap(), latest.into_inner());
+
+    assert!(matches!(
+        export_as_of(&mut journal, 3),
+        Err(Error::SnapshotNotFound { id: 3 })
+    ));
+}
+
+#[test]
+fn test_journal_export_pages() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(512).unwrap();
+    journal.new_blob(0, &[1; 1024]).unwrap();
+    journal.commit().unwrap();
+    // sub-page diffs, including one straddling page boundary
+    journal.new_snapshot(512).unwrap();
+    journal.new_blob(100, &[2; 10]).unwrap();
+    journal.new_blob(500, &[3; 20]).unwrap();
+    journal.new_blob(1500, &[4; 30]).unwrap();
+    journal.commit().unwrap();
+
+    let mut database = Cursor::new(vec![]);
+    journal.export_database(&mut database).unwrap();
+    let pages = journal
+        .export_pages(512)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        pages.iter().map(|(num, _)| *num).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    assert!(pages.iter().all(|(_, page)| page.len() == 512));
+    let mut reassembled = pages
+        .into_iter()
+        .flat_map(|(_, page)| page)
+        .collect::<Vec<_>>();
+    // last page is zero-filled
+    assert_eq!(&reassembled[1530..], &[0; 6]);
+    reassembled.truncate(database.get_ref().len());
+    assert_eq!(&reassembled, database.get_ref());
+
+    let res = journal.export_pages(0).collect::<Result<Vec<_>, _>>();
+    assert!(
+        matches!(res, Err(Error::InvalidPageSize { page_size: 0 })),
+        "{res:?}"
+    );
+}
+
+#[test]
+fn test_journal_compact() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for (id, timestamp) in [(0, 100), (1, 200), (2, 300), (3, 400)] {
+        journal
+            .add_snapshot(&SnapshotHeader::new(id, timestamp, Some(512)))
+            .unwrap();
+        journal
+            .add_blob(&BlobHeader::new(0, 0, 512), &[id as u8; 512])
+            .unwrap();
+        journal
+            .add_blob(&BlobHeader::new(id * 512, 1, 10), &[id as u8 + 10; 10])
+            .unwrap();
+        journal.commit().unwrap();
+    }
+    let mut database = Cursor::new(vec![]);
+    journal.export_database(&mut database).unwrap();
+
+    for (policy, expected) in [
+        (BaselineTimestamp::Earliest, 100),
+        (BaselineTimestamp::Latest, 300),
+        (BaselineTimestamp::Explicit(42), 42),
+    ] {
+        let mut compacted = journal.compact(Cursor::new(vec![]), 2, policy).unwrap();
+        assert_eq!(compacted.get_header().snapshot_counter, 2);
+        assert_eq!(compacted.get_header().page_size, Some(512));
+        let info = compacted.info().unwrap();
+        assert_eq!(
+            info.snapshots
+                .iter()
+                .map(|s| (s.id, s.timestamp, s.page_size))
+                .collect::<Vec<_>>(),
+            vec![(0, expected, Some(512)), (1, 400, Some(512))]
+        );
+        // baseline holds whole database as of folded snapshots, page by page
+        assert_eq!(
+            (info.snapshots[0].blob_count, info.snapshots[0].bytes),
+            (3, 1034)
+        );
+
+        let mut compacted_database = Cursor::new(vec![]);
+        compacted.export_database(&mut compacted_database).unwrap();
+        assert_eq!(compacted_database.get_ref(), database.get_ref());
+    }
+
+    let undo_log = Header {
+        undo_log: true,
+        ..Header::default()
+    };
+    let mut journal = Journal::new(undo_log, Cursor::new(vec![]), None).unwrap();
+    for i in 0..3 {
+        journal.new_snapshot(512).unwrap();
+        journal
+            .new_blob_with_pre_image(0, &[i + 1; 512], &[i; 512])
+            .unwrap();
+        journal.commit().unwrap();
+    }
+    let mut compacted = journal
+        .compact(Cursor::new(vec![]), 1, BaselineTimestamp::Latest)
+        .unwrap();
+    assert!(compacted.get_header().undo_log);
+    // pre-image of snapshot after baseline is kept
+    let mut database = Cursor::new(vec![0; 512]);
+    compacted.undo_last(&mut database).unwrap();
+    assert_eq!(database.get_ref(), &vec![2; 512]);
+
+    assert!(matches!(
+        journal.compact(Cursor::new(vec![]), 3, BaselineTimestamp::Latest),
+        Err(Error::SnapshotNotFound { id: 3 })
+    ));
+}
+
+#[test]
+fn test_journal_extract_range() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for id in 0..6_u64 {
+        journal
+            .add_snapshot(&SnapshotHeader::new(id, id as i64 * 100, Some(512)))
+            .unwrap();
+        journal
+            .add_blob(&BlobHeader::new(0, 0, 512), &[id as u8; 512])
+            .unwrap();
+        journal
+            .add_blob(
+                &BlobHeader::new(id * 512 + 100, 1, 10),
+                &[id as u8 + 10; 10],
+            )
+            .unwrap();
+        journal.commit().unwrap();
+    }
+    let dir = tempfile::tempdir().unwrap();
+    for (from, to) in [(0, 2), (2, 4), (3, 3), (1, 5)] {
+        let path = dir.path().join(format!("extract-{from}-{to}"));
+        journal.extract_range(from, to, &path).unwrap();
+
+        // extract is restorable on its own
+        let mut extract = Journal::try_from(&path).unwrap();
+        let mut expected = Cursor::new(vec![]);
+        journal.export_database_as_of(&mut expected, to).unwrap();
+        let mut restored = Cursor::new(vec![]);
+        extract.export_database(&mut restored).unwrap();
+        assert_eq!(restored.get_ref(), expected.get_ref(), "{from}..={to}");
+
+        // ids start from 0, baseline precedes extracted snapshots, unless range starts at 0
+        let info = extract.info().unwrap();
+        let baseline = (from > 0) as u64;
+        assert_eq!(
+            info.snapshots
+                .iter()
+                .map(|s| (s.id, s.timestamp))
+                .collect::<Vec<_>>(),
+            (from.saturating_sub(baseline)..=to)
+                .enumerate()
+                .map(|(id, orig)| (id as u64, orig as i64 * 100))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    let path = dir.path().join("invalid");
+    assert!(matches!(
+        journal.extract_range(3, 2, &path),
+        Err(Error::InvalidSnapshotRange { from: 3, to: 2 })
+    ));
+    assert!(matches!(
+        journal.extract_range(0, 6, &path),
+        Err(Error::SnapshotNotFound { id: 6 })
+    ));
+}
+
+// check concurrent iteration over read-only views of shared journal
+#[test]
+fn test_journal_merge_blobs() {
+    let undo_log = Header {
+        undo_log: true,
+        ..Header::default()
+    };
+    let mut journal = Journal::new(undo_log, Cursor::new(vec![]), None).unwrap();
+    let mut database = vec![1; 512];
+    journal.new_snapshot(512).unwrap();
+    journal
+        .new_blob_with_pre_image(0, &database, &[0; 512])
+        .unwrap();
+    journal.commit().unwrap();
+    journal.new_snapshot(512).unwrap();
+    // near-adjacent runs, far run, overlapping run and run past end of database
+    for (offset, len, byte) in [
+        (10, 4, 2),
+        (16, 4, 3),
+        (20, 2, 4),
+        (100, 4, 5),
+        (102, 4, 6),
+        (508, 4, 7),
+        (514, 4, 8),
+    ] {
+        database.resize(database.len().max(offset + len), 0);
+        let pre_image = database[offset..offset + len].to_vec();
+        database[offset..offset + len].fill(byte);
+        journal
+            .new_blob_with_pre_image(offset as u64, &database[offset..offset + len], &pre_image)
+            .unwrap();
+    }
+    journal.commit().unwrap();
+    let snapshots = journal.info().unwrap().snapshots;
+
+    let mut merged = journal.merge_blobs(Cursor::new(vec![]), 8).unwrap();
+    let info = merged.info().unwrap();
+    assert_eq!(
+        info.snapshots
+            .iter()
+            .map(|s| (s.id, s.timestamp, s.page_size, s.blob_count))
+            .collect::<Vec<_>>(),
+        vec![
+            (0, snapshots[0].timestamp, Some(512), 1),
+            (1, snapshots[1].timestamp, Some(512), 4)
+        ]
+    );
+    let blobs = merged
+        .into_iter()
+        .skip(1)
+        .map(|item| {
+            let (_, blob_header, blob) = item.unwrap();
+            (blob_header.offset, blob.len())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(blobs, vec![(10, 12), (100, 4), (102, 4), (508, 10)]);
+
+    // reconstructed database is unchanged
+    let mut exported = Cursor::new(vec![]);
+    journal.export_database(&mut exported).unwrap();
+    assert_eq!(exported.get_ref(), &database);
+    let mut exported = Cursor::new(vec![]);
+    merged.export_database(&mut exported).unwrap();
+    assert_eq!(exported.get_ref(), &database);
+    assert_eq!(merged.read_page(1, 512).unwrap(), &database[..512]);
+
+    // undo of merged snapshot restores previous snapshot
+    let mut undone = Cursor::new(database.clone());
+    merged.undo_last(&mut undone).unwrap();
+    let mut expected = vec![1; 512];
+    expected.resize(database.len(), 0);
+    assert_eq!(undone.into_inner(), expected);
+
+    // zero gap merges only touching blobs
+    let mut merged = journal.merge_blobs(Cursor::new(vec![]), 0).unwrap();
+    assert_eq!(merged.count_blobs().unwrap(), 7);
+}
+
 #[test]
-fn test_journal_rebuild_from_stream() {
-    fn check(input: Vec<TestSnapshot>, mut prng: XorShift) {
-        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+fn test_journal_view_concurrent_reads() {
+    fn check(input: Vec<TestSnapshot>) {
+        let journal_path = tempfile::NamedTempFile::new().unwrap();
+        let mut journal = Journal::create(journal_path.path()).unwrap();
         for snapshot in input.iter() {
             for blob in snapshot.blobs.iter() {
-                journal.new_snapshot(0).unwrap();
+                journal.new_snapshot(4096).unwrap();
                 journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
             }
             journal.commit().unwrap();
         }
+        let expected = journal.into_iter().map(Result::unwrap).collect::<Vec<_>>();
 
-        let mut stream: Stream<_> = Stream::from(&mut journal);
-        let mut writer = Cursor::new(vec![]);
-        loop {
-            let buf_size = (prng.next() % 100) as usize;
-            // intermidiate buffer of variable size, including 0
-            let mut buf = vec![0; buf_size];
-            let read = stream.read(&mut buf).unwrap();
-            if read == 0 && buf_size != 0 {
-                break;
-            }
-            writer.write_all(&buf[..read]).unwrap();
+        let journal = Arc::new(journal);
+        let handles = (0..4)
+            .map(|_| {
+                let journal = Arc::clone(&journal);
+                std::thread::spawn(move || {
+                    let mut view = journal.view().unwrap();
+                    view.iter().map(Result::unwrap).collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
         }
-        let buf = writer.into_inner();
-
-        let mut reader = Cursor::new(buf.as_slice());
-        let mut recovered_journal =
-            Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>));
+}
 
-        assert_eq!(
-            serde_sqlite::from_reader::<Protocol, _>(&mut reader).unwrap(),
-            Protocol::JournalVersion(1.into())
-        );
-        loop {
-            match serde_sqlite::from_reader::<Protocol, _>(&mut reader) {
-                Ok(Protocol::SnapshotHeader(s)) => {
-                    recovered_journal.commit().unwrap();
-                    recovered_journal.add_snapshot(&s).unwrap();
-                }
-                Ok(Protocol::BlobHeader(p)) => {
-                    let mut buf = vec![0; p.blob_size as usize];
-                    reader.read_exact(buf.as_mut_slice()).unwrap();
-                    recovered_journal.add_blob(&p, buf.as_slice()).unwrap();
-                }
-                Ok(Protocol::EndOfStream(_)) => {
-                    recovered_journal.commit().unwrap();
-                    break;
-                }
-                Ok(Protocol::JournalVersion(_)) => {
-                    panic!("version header should not appear in loop")
-                }
-                Err(e) => panic!("unexpected stream error: {e}"),
-            }
+#[cfg(unix)]
+#[test]
+fn test_journal_open_mmap() {
+    fn restore<F: Read + Write + Seek>(journal: &mut Journal<F>) -> Vec<u8> {
+        let mut database = Cursor::new(vec![]);
+        journal.export_database(&mut database).unwrap();
+        database.into_inner()
+    }
+    fn append(journal: &mut Journal, snapshots: u8) {
+        for id in 0..snapshots {
+            journal.new_snapshot(4096).unwrap();
+            journal.new_blob(id as u64 * 4096, &[id + 1; 4096]).unwrap();
+            journal.new_blob(0, &[id; 100]).unwrap();
+            journal.commit().unwrap();
         }
-        assert_eq!(
-            journal.into_iter().count(),
-            recovered_journal.into_iter().count()
-        );
-        assert!(journal
-            .into_iter()
-            .map(Result::unwrap)
-            .zip(recovered_journal.into_iter().map(Result::unwrap))
-            .all(|(left, right)| left.eq(&right)));
-        assert_eq!(journal.get_header(), recovered_journal.get_header());
     }
-    quickcheck(check as fn(Vec<TestSnapshot>, XorShift));
+    let journal_path = tempfile::NamedTempFile::new().unwrap();
+    let mut journal = Journal::create(journal_path.path()).unwrap();
+    append(&mut journal, 5);
+
+    let mut mmap = Journal::open_mmap(journal_path.path()).unwrap();
+    assert_eq!(mmap.get_header(), journal.get_header());
+    assert_eq!(restore(&mut mmap), restore(&mut journal));
+
+    // snapshots, appended by writer, are visible after reopen
+    let mut journal = Journal::try_from(journal_path.path()).unwrap();
+    append(&mut journal, 3);
+    assert_eq!(mmap.current_snapshot(), Some(5));
+    mmap.reopen().unwrap();
+    assert_eq!(mmap.current_snapshot(), Some(8));
+    assert_eq!(restore(&mut mmap), restore(&mut journal));
+
+    // memory mapped journal is read-only
+    mmap.new_snapshot(4096).unwrap();
+    mmap.new_blob(0, &[0; 4096]).unwrap();
+    assert!(mmap.commit().is_err());
 }
 
 #[derive(Debug)]
@@ -494,7 +2775,7 @@ fn test_journal_concurrent_updates() {
             s.spawn(|| {
                 s1.iter().for_each(|blob| {
                     let guard = lock.lock().unwrap();
-                    journal_1.new_snapshot(0).unwrap();
+                    journal_1.new_snapshot(4096).unwrap();
                     journal_1
                         .new_blob(blob.len() as u64, blob.as_slice())
                         .unwrap();
@@ -506,7 +2787,7 @@ fn test_journal_concurrent_updates() {
             s.spawn(|| {
                 s2.iter().for_each(|blob| {
                     let guard = lock.lock().unwrap();
-                    journal_2.new_snapshot(0).unwrap();
+                    journal_2.new_snapshot(4096).unwrap();
                     journal_2
                         .new_blob(blob.len() as u64, blob.as_slice())
                         .unwrap();
@@ -594,7 +2875,7 @@ fn test_async_journal_and_sync_journal_are_the_same() {
         let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
         for snapshot in input.iter() {
             for blob in snapshot.blobs.iter() {
-                journal.new_snapshot(0).unwrap();
+                journal.new_snapshot(4096).unwrap();
                 journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
             }
             journal.commit().unwrap();
@@ -631,7 +2912,7 @@ fn test_async_journal_and_sync_journal_are_the_same() {
                 .unwrap();
             for snapshot in input.iter() {
                 for blob in snapshot.blobs.iter() {
-                    async_journal.new_snapshot(0).await.unwrap();
+                    async_journal.new_snapshot(4096).await.unwrap();
                     async_journal
                         .new_blob(blob.offset, blob.data.as_slice())
                         .await
@@ -671,3 +2952,1076 @@ fn test_async_journal_and_sync_journal_are_the_same() {
 
     quickcheck(check as fn(Vec<TestSnapshot>));
 }
+
+#[test]
+fn test_journal_blob_checksums() {
+    fn populate(header: Header) -> Vec<u8> {
+        let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+        for id in 0..3 {
+            journal.new_snapshot(4096).unwrap();
+            journal.new_blob(id * 4, &[id as u8 + 1; 4]).unwrap();
+            journal.commit().unwrap();
+        }
+        journal.take_fd().unwrap().into_inner()
+    }
+    fn collect(bytes: Vec<u8>) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let mut journal = Journal::from_fd(Cursor::new(bytes)).unwrap();
+        journal
+            .into_iter()
+            .map(|item| item.map(|(_, blob_header, blob)| (blob_header.offset, blob)))
+            .collect()
+    }
+    let expected = (0..3)
+        .map(|id| (id * 4, vec![id as u8 + 1; 4]))
+        .collect::<Vec<_>>();
+    // position of first byte of last blob, followed by terminator
+    let last_blob = |bytes: &[u8], header_size: usize| bytes.len() - header_size - 4;
+
+    let bytes = populate(Header::default());
+    assert_eq!(collect(bytes.clone()).unwrap(), expected);
+    let mut corrupted = bytes.clone();
+    let pos = last_blob(&corrupted, BlobHeader::block_size());
+    corrupted[pos] ^= 0xFF;
+    let res = collect(corrupted);
+    assert!(
+        matches!(res, Err(Error::ChecksumMismatch { blob_num: 0, .. })),
+        "{res:?}"
+    );
+
+    // blob, corrupted in transit, is not applied
+    let mut source = Journal::from_fd(Cursor::new(bytes.clone())).unwrap();
+    let mut stream = vec![];
+    Stream::from(&mut source).read_to_end(&mut stream).unwrap();
+    // last blob is followed by EndOfStream frame, which is bare 4 byte tag
+    let pos = stream.len() - 4 - 4;
+    stream[pos] ^= 0xFF;
+    let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let res = target.apply_stream(stream.as_slice());
+    assert!(
+        matches!(res, Err(Error::ChecksumMismatch { blob_num: 0, .. })),
+        "{res:?}"
+    );
+
+    // version 1 journal has no checksums, corrupted blob is read as is
+    let v1_bytes = populate(Header {
+        version: 1,
+        ..Header::default()
+    });
+    let blob_records = 3 * (SnapshotHeader::block_size() + 2 * 16 + 4);
+    assert_eq!(v1_bytes.len(), Header::block_size() + blob_records);
+    assert_eq!(collect(v1_bytes.clone()).unwrap(), expected);
+    let mut corrupted = v1_bytes.clone();
+    let pos = last_blob(&corrupted, 16);
+    corrupted[pos] ^= 0xFF;
+    assert_eq!(collect(corrupted).unwrap().last().unwrap().1, [!3, 3, 3, 3]);
+
+    // digest doesn't depend on journal version, streams of both versions are the same
+    let mut v1 = Journal::from_fd(Cursor::new(v1_bytes)).unwrap();
+    let mut current = Journal::from_fd(Cursor::new(bytes)).unwrap();
+    assert_eq!(
+        v1.content_digest().unwrap(),
+        current.content_digest().unwrap()
+    );
+    let mut v1_stream = vec![];
+    Stream::from(&mut v1).read_to_end(&mut v1_stream).unwrap();
+    let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    target.apply_stream(v1_stream.as_slice()).unwrap();
+    assert_eq!(
+        target.content_digest().unwrap(),
+        current.content_digest().unwrap()
+    );
+
+    // unknown version is rejected
+    let mut bytes = populate(Header::default());
+    bytes[4..8].copy_from_slice(&(JOURNAL_VERSION + 1).to_be_bytes());
+    let res = Journal::from_fd(Cursor::new(bytes));
+    assert!(
+        matches!(res, Err(Error::UnexpectedJournalVersion { .. })),
+        "{res:?}"
+    );
+}
+
+/// Re-encode stream as stream of older journal `version`
+///
+/// Blob header frames are truncated to blob header size of that version, i.e. version 1 blob
+/// headers have no checksum, version 1 and 2 blob headers have no compressed size.
+fn stream_of_version(stream: &[u8], version: u32) -> Vec<u8> {
+    let blob_header_size = match version {
+        1 => 16,
+        2 => 20,
+        _ => BlobHeader::block_size(),
+    };
+    let mut bytes = vec![];
+    for frame in ProtocolReader::new(stream) {
+        match frame.unwrap() {
+            ProtocolFrame::JournalVersion(_) => bytes
+                .extend(serde_sqlite::to_bytes(&Protocol::JournalVersion(version.into())).unwrap()),
+            ProtocolFrame::SnapshotHeader(snapshot_header) => {
+                bytes.extend(serde_sqlite::to_bytes(&Protocol::from(snapshot_header)).unwrap())
+            }
+            ProtocolFrame::Blob(blob_header, blob) => {
+                assert_eq!(blob_header.compressed_size, 0);
+                let frame = serde_sqlite::to_bytes(&Protocol::from(blob_header)).unwrap();
+                bytes.extend_from_slice(&frame[..4 + blob_header_size]);
+                bytes.extend_from_slice(blob);
+            }
+            // EndOfStream frame is bare 4 byte tag, same in all versions
+            ProtocolFrame::EndOfStream => bytes.extend_from_slice(&stream[stream.len() - 4..]),
+        }
+    }
+    bytes
+}
+
+#[test]
+fn test_journal_apply_older_stream_versions() {
+    let mut source = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..3_u64 {
+        source.new_snapshot(4096).unwrap();
+        source.new_blob(i * 4096, &[i as u8; 100]).unwrap();
+        source
+            .new_blob(i * 4096 + 100, &[i as u8 + 1; 100])
+            .unwrap();
+        source.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut source).read_to_end(&mut stream).unwrap();
+    let expected = source.content_digest().unwrap();
+
+    for version in [1, 2, JOURNAL_VERSION] {
+        let stream = stream_of_version(&stream, version);
+        assert_eq!(
+            verify_stream(stream.as_slice()).unwrap(),
+            StreamStats {
+                snapshots: 3,
+                blobs: 6,
+                bytes: 600,
+            }
+        );
+
+        // stream is applied to empty journal, and to journal, which has first snapshot already
+        let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        target.apply_stream(stream.as_slice()).unwrap();
+        assert_eq!(target.content_digest().unwrap(), expected, "{version}");
+        let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        target
+            .add_snapshot(&source.into_iter().next().unwrap().unwrap().0)
+            .unwrap();
+        target
+            .add_blob(&BlobHeader::new(0, 0, 100), &[0; 100])
+            .unwrap();
+        target
+            .add_blob(&BlobHeader::new(100, 1, 100), &[1; 100])
+            .unwrap();
+        target.commit().unwrap();
+        target.apply_stream(stream.as_slice()).unwrap();
+        assert_eq!(target.content_digest().unwrap(), expected, "{version}");
+    }
+
+    // unknown version is rejected
+    let stream = stream_of_version(&stream, JOURNAL_VERSION + 1);
+    let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let res = target.apply_stream(stream.as_slice());
+    assert!(
+        matches!(res, Err(Error::UnexpectedJournalVersion { .. })),
+        "{res:?}"
+    );
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_journal_blob_compression() {
+    // pages of repeated bytes shrink, short blob with distinct bytes doesn't
+    let blobs = [
+        (0, vec![1; 4096]),
+        (4096, vec![2; 4096]),
+        (8192, vec![1, 2, 3, 4]),
+        (0, vec![3; 4096]),
+    ];
+    fn populate(header: Header, compression: Compression, blobs: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut journal = Journal::new(header, Cursor::new(vec![]), None).unwrap();
+        journal.set_compression(compression);
+        for (offset, blob) in blobs {
+            journal.new_snapshot(4096).unwrap();
+            journal
+                .new_blob_with_pre_image(*offset, blob, &vec![9; blob.len()])
+                .unwrap();
+            journal.commit().unwrap();
+        }
+        journal.take_fd().unwrap().into_inner()
+    }
+    fn export(journal: &mut Journal<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut database = Cursor::new(vec![]);
+        journal.export_database(&mut database).unwrap();
+        database.into_inner()
+    }
+    fn stream(journal: &mut Journal<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut stream = vec![];
+        Stream::from(journal).read_to_end(&mut stream).unwrap();
+        stream
+    }
+    let header = Header {
+        undo_log: true,
+        ..Header::default()
+    };
+    let zstd = Compression::Zstd { level: 3 };
+    let plain_bytes = populate(header, Compression::None, &blobs);
+    let bytes = populate(header, zstd, &blobs);
+    // pre-images are not compressed
+    assert!(bytes.len() < plain_bytes.len() * 2 / 3);
+    let mut plain = Journal::from_fd(Cursor::new(plain_bytes.clone())).unwrap();
+    let mut journal = Journal::from_fd(Cursor::new(bytes)).unwrap();
+    assert_eq!(journal.compression(), Compression::None);
+
+    // blobs are decompressed on read, digest doesn't depend on compression
+    let read = journal
+        .into_iter()
+        .map(|item| item.map(|(_, blob_header, blob)| (blob_header.offset, blob)))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(read, blobs);
+    assert_eq!(export(&mut journal), export(&mut plain));
+    assert_eq!(
+        journal.content_digest().unwrap(),
+        plain.content_digest().unwrap()
+    );
+    let mut database = Cursor::new(export(&mut journal));
+    journal.undo_last(&mut database).unwrap();
+    assert!(database.get_ref()[..4096] == [9; 4096]);
+
+    // compressed blobs are streamed as is
+    let compressed_stream = stream(&mut journal);
+    assert!(compressed_stream.len() < stream(&mut plain).len() / 4);
+    let stats = verify_stream(compressed_stream.as_slice()).unwrap();
+    assert_eq!(stats.blobs, blobs.len() as u64);
+    let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    target.apply_stream(compressed_stream.as_slice()).unwrap();
+    assert_eq!(
+        target.content_digest().unwrap(),
+        plain.content_digest().unwrap()
+    );
+    assert_eq!(stream(&mut target), compressed_stream);
+
+    // version 2 journal can't store compressed blobs, compression is ignored and compressed
+    // blobs from stream are decompressed
+    let v2_header = Header {
+        version: 2,
+        ..header
+    };
+    assert_eq!(
+        populate(v2_header, zstd, &blobs).len(),
+        populate(v2_header, Compression::None, &blobs).len()
+    );
+    let v2_header = Header {
+        version: 2,
+        ..Header::default()
+    };
+    let mut v2 = Journal::new(v2_header, Cursor::new(vec![]), None).unwrap();
+    v2.apply_stream(compressed_stream.as_slice()).unwrap();
+    assert!(v2
+        .into_iter()
+        .stored()
+        .all(|item| item.unwrap().1.compressed_size == 0));
+    assert_eq!(export(&mut v2), export(&mut plain));
+    assert_eq!(
+        v2.content_digest().unwrap(),
+        plain.content_digest().unwrap()
+    );
+
+    // compressed blob, which doesn't decompress, is rejected on read
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    let blob_header = BlobHeader {
+        compressed_size: 4,
+        ..BlobHeader::new(0, 0, 8)
+    };
+    journal.add_blob(&blob_header, &[1, 2, 3, 4]).unwrap();
+    journal.commit().unwrap();
+    let res = journal.into_iter().collect::<Result<Vec<_>, _>>();
+    assert!(
+        matches!(res, Err(Error::InvalidCompressedBlob { blob_num: 0 })),
+        "{res:?}"
+    );
+}
+
+#[cfg(not(feature = "compression"))]
+#[test]
+fn test_journal_compression_unsupported() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.set_compression(Compression::Zstd { level: 3 });
+    journal.new_snapshot(4096).unwrap();
+    let res = journal.new_blob(0, &[1; 4096]);
+    assert!(matches!(res, Err(Error::CompressionUnsupported)), "{res:?}");
+
+    // compressed blob is stored and read as is, but isn't decompressed
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    let blob_header = BlobHeader {
+        compressed_size: 4,
+        ..BlobHeader::new(0, 0, 8)
+    };
+    journal.add_blob(&blob_header, &[1, 2, 3, 4]).unwrap();
+    journal.commit().unwrap();
+    let stored = journal
+        .into_iter()
+        .stored()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(stored[0].2, [1, 2, 3, 4]);
+    let res = journal.into_iter().collect::<Result<Vec<_>, _>>();
+    assert!(matches!(res, Err(Error::CompressionUnsupported)), "{res:?}");
+}
+
+#[test]
+fn test_journal_compressed_stream_pass_through() {
+    // compressed blob, which doesn't decompress, fails any decompression on the way
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    let blob_header = BlobHeader {
+        compressed_size: 4,
+        ..BlobHeader::new(0, 0, 8)
+    };
+    journal.add_blob(&blob_header, &[1, 2, 3, 4]).unwrap();
+    journal.commit().unwrap();
+    let stored = |journal: &mut Journal<Cursor<Vec<u8>>>| {
+        journal
+            .into_iter()
+            .stored()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    let expected = stored(&mut journal);
+
+    // stream of whole journal and stream of unpushed snapshots, as sent by replicator
+    let mut streams = vec![vec![], vec![]];
+    Stream::from(&mut journal)
+        .read_to_end(&mut streams[0])
+        .unwrap();
+    Stream::from((JOURNAL_VERSION, journal.iter_unpushed(None)))
+        .read_to_end(&mut streams[1])
+        .unwrap();
+    for stream in streams {
+        // receiver, which compresses its own blobs, stores compressed blob as is
+        let mut target = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        target.set_compression(Compression::Zstd { level: 3 });
+        target.apply_stream(stream.as_slice()).unwrap();
+        assert_eq!(stored(&mut target), expected);
+        let res = target.into_iter().collect::<Result<Vec<_>, _>>();
+        assert!(
+            matches!(
+                res,
+                Err(Error::InvalidCompressedBlob { blob_num: 0 } | Error::CompressionUnsupported)
+            ),
+            "{res:?}"
+        );
+    }
+}
+
+#[test]
+fn test_journal_implicit_terminators() {
+    // single blob snapshots, interleaved with empty and multi blob ones
+    let snapshots = (0..100_u64)
+        .map(|i| match i % 10 {
+            3 => vec![],
+            7 => vec![(i * 10, vec![i as u8; 3]), (i * 10 + 5, vec![1; 2])],
+            _ => vec![(i * 10, vec![i as u8; i as usize % 5 + 1])],
+        })
+        .collect::<Vec<_>>();
+    let write = |journal: &mut Journal| {
+        for blobs in snapshots.iter() {
+            journal.new_snapshot(4096).unwrap();
+            for (offset, blob) in blobs.iter() {
+                journal.new_blob(*offset, blob).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let (plain_path, implicit_path) = (dir.path().join("plain"), dir.path().join("implicit"));
+    write(&mut Journal::create(&plain_path).unwrap());
+    write(&mut Journal::create_with_implicit_terminators(&implicit_path).unwrap());
+
+    let single_blob = snapshots.iter().filter(|blobs| blobs.len() == 1).count() as u64;
+    let size = |path: &Path| std::fs::metadata(path).unwrap().len();
+    assert_eq!(
+        size(&plain_path) - size(&implicit_path),
+        single_blob * BlobHeader::block_size() as u64
+    );
+
+    let mut plain = Journal::try_from(&plain_path).unwrap();
+    let mut implicit = Journal::try_from(&implicit_path).unwrap();
+    assert!(implicit.get_header().implicit_terminators);
+    // timestamps differ, snapshots are compared by id
+    let collect = |journal: &mut Journal| {
+        journal
+            .into_iter()
+            .map(|item| item.map(|(snapshot, blob_header, blob)| (snapshot.id, blob_header, blob)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    assert_eq!(collect(&mut plain), collect(&mut implicit));
+    assert_eq!(
+        plain.content_digest().unwrap(),
+        implicit.content_digest().unwrap()
+    );
+
+    // snapshot, appended to reopened journal, has implied terminator too
+    let mut implicit = Journal::try_from(&implicit_path).unwrap();
+    implicit.new_snapshot(4096).unwrap();
+    implicit.new_blob(0, &[42; 4]).unwrap();
+    implicit.commit().unwrap();
+    let items = collect(&mut implicit);
+    let blob_header = BlobHeader::new(0, 0, 4).with_checksum(&[42; 4]).unwrap();
+    assert_eq!(items.last().unwrap().1, blob_header);
+    assert_eq!(items.len(), collect(&mut plain).len() + 1);
+}
+
+// aligned journal written by async journal is read by sync journal and vice versa
+#[cfg(feature = "async")]
+#[test]
+fn test_async_journal_blob_alignment() {
+    let header = Header {
+        blob_alignment: Some(64),
+        ..Header::default()
+    };
+    let blobs = [(0, vec![1; 10]), (4096, vec![2; 100]), (10, vec![3; 1])];
+    let name = tempfile::NamedTempFile::new().unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let fd = tokio::fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(name.path())
+            .await
+            .unwrap();
+        let mut journal = AsyncJournal::new(header, fd, None).await.unwrap();
+        for (offset, blob) in blobs.iter() {
+            journal.new_snapshot(4096).await.unwrap();
+            journal.new_blob(*offset, blob).await.unwrap();
+            journal.new_blob(*offset + 8192, blob).await.unwrap();
+            journal.commit().await.unwrap();
+        }
+    });
+    let expected = blobs
+        .iter()
+        .flat_map(|(offset, blob)| [(*offset, blob.clone()), (*offset + 8192, blob.clone())])
+        .collect::<Vec<_>>();
+
+    let mut journal = Journal::try_from(name.path()).unwrap();
+    let restored = journal
+        .into_iter()
+        .map(|item| {
+            let (_, blob_header, blob) = item.unwrap();
+            (blob_header.offset, blob)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(restored, expected);
+
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[4; 3]).unwrap();
+    journal.commit().unwrap();
+    let restored = rt.block_on(async {
+        let mut journal = AsyncJournal::try_from(name.path()).await.unwrap();
+        let stream = journal.stream();
+        pin_mut!(stream);
+        let mut restored = vec![];
+        while let Some(item) = stream.next().await {
+            let (_, blob_header, blob) = item.unwrap();
+            restored.push((blob_header.offset, blob));
+        }
+        restored
+    });
+    assert_eq!(restored.len(), expected.len() + 1);
+    assert_eq!(restored[..expected.len()], expected);
+    assert_eq!(restored.last(), Some(&(0, vec![4; 3])));
+}
+
+#[test]
+fn test_journal_relative_timestamps() {
+    // rapid snapshots, interleaved with timestamps which don't fit delta: gaps, past ones
+    let mut timestamp = system_clock().unwrap();
+    let timestamps = (0..1000)
+        .map(|i| {
+            timestamp += match i {
+                0 => 0,
+                100 => -1_000_000,
+                200 => u32::MAX as i64 + 1,
+                300 => u32::MAX as i64,
+                _ => 3,
+            };
+            timestamp
+        })
+        .collect::<Vec<_>>();
+    // first snapshot, past one and one after gap
+    let absolute = 3;
+    let write = |journal: &mut Journal| {
+        for (id, timestamp) in timestamps.iter().enumerate() {
+            let snapshot_header = SnapshotHeader::new(id as u64, *timestamp, Some(4096));
+            journal.add_snapshot(&snapshot_header).unwrap();
+            journal.new_blob(id as u64 * 10, &[id as u8; 4]).unwrap();
+            journal.commit().unwrap();
+        }
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let (plain_path, relative_path) = (dir.path().join("plain"), dir.path().join("relative"));
+    write(&mut Journal::create(&plain_path).unwrap());
+    write(&mut Journal::create_with_relative_timestamps(&relative_path).unwrap());
+
+    let size = |path: &Path| std::fs::metadata(path).unwrap().len();
+    assert_eq!(
+        size(&plain_path) - size(&relative_path),
+        timestamps.len() as u64 * 16 - absolute * 8
+    );
+
+    let mut plain = Journal::try_from(&plain_path).unwrap();
+    let mut relative = Journal::try_from(&relative_path).unwrap();
+    assert!(relative.get_header().relative_timestamps);
+    let collect =
+        |journal: &mut Journal| journal.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    let items = collect(&mut relative);
+    assert_eq!(items, collect(&mut plain));
+    let restored = items
+        .iter()
+        .map(|(snapshot_header, _, _)| snapshot_header.timestamp)
+        .collect::<Vec<_>>();
+    assert_eq!(restored, timestamps);
+    let info = |journal: &mut Journal| {
+        let info = journal.info().unwrap();
+        info.snapshots
+            .iter()
+            .map(|snapshot| snapshot.timestamp)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(info(&mut relative), timestamps);
+    assert_eq!(
+        plain.content_digest().unwrap(),
+        relative.content_digest().unwrap()
+    );
+
+    // snapshot, appended to reopened journal, is relative to last snapshot
+    let mut relative = Journal::try_from(&relative_path).unwrap();
+    assert_eq!(relative.get_header().last_timestamp, timestamps[999]);
+    let before = size(&relative_path);
+    let snapshot_header = SnapshotHeader::new(1000, timestamps[999] + 1, Some(4096));
+    relative.add_snapshot(&snapshot_header).unwrap();
+    relative.new_blob(0, &[42; 4]).unwrap();
+    relative.sync_snapshot().unwrap();
+    drop(relative);
+    let mut relative = Journal::try_from(&relative_path).unwrap();
+    assert!(relative.recover_synced_snapshot().unwrap());
+    assert_eq!(relative.get_header().last_timestamp, timestamps[999] + 1);
+    let snapshot_header = collect(&mut relative).last().unwrap().0;
+    assert_eq!(snapshot_header.timestamp, timestamps[999] + 1);
+    assert_eq!(
+        size(&relative_path) - before,
+        (16 + 2 * BlobHeader::block_size() + 4) as u64
+    );
+
+    // compacted journal keeps relative timestamps
+    let name = tempfile::NamedTempFile::new().unwrap();
+    let mut compacted = relative
+        .compact(name.reopen().unwrap(), 500, BaselineTimestamp::Latest)
+        .unwrap();
+    assert!(compacted.get_header().relative_timestamps);
+    assert_eq!(info(&mut compacted)[1..], info(&mut relative)[501..]);
+}
+
+// single blob snapshots without terminators written by async journal are read by sync journal
+// and vice versa
+#[cfg(feature = "async")]
+#[test]
+fn test_async_journal_implicit_terminators() {
+    let header = Header {
+        implicit_terminators: true,
+        ..Header::default()
+    };
+    let name = tempfile::NamedTempFile::new().unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let fd = tokio::fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(name.path())
+            .await
+            .unwrap();
+        let mut journal = AsyncJournal::new(header, fd, None).await.unwrap();
+        for i in 0..3 {
+            journal.new_snapshot(4096).await.unwrap();
+            for num in 0..i {
+                journal.new_blob(num * 10, &[i as u8; 4]).await.unwrap();
+            }
+            journal.commit().await.unwrap();
+        }
+    });
+    let expected = vec![(0, vec![1; 4]), (0, vec![2; 4]), (10, vec![2; 4])];
+
+    let mut journal = Journal::try_from(name.path()).unwrap();
+    let restored = journal
+        .into_iter()
+        .map(|item| {
+            let (_, blob_header, blob) = item.unwrap();
+            (blob_header.offset, blob)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(restored, expected);
+
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(20, &[3; 1]).unwrap();
+    journal.commit().unwrap();
+    let restored = rt.block_on(async {
+        let mut journal = AsyncJournal::try_from(name.path()).await.unwrap();
+        let stream = journal.stream();
+        pin_mut!(stream);
+        let mut restored = vec![];
+        while let Some(item) = stream.next().await {
+            let (_, blob_header, blob) = item.unwrap();
+            restored.push((blob_header.offset, blob_header.blob_num, blob));
+        }
+        restored
+    });
+    let mut expected = expected
+        .into_iter()
+        .zip([0, 0, 1])
+        .map(|((offset, blob), blob_num)| (offset, blob_num, blob))
+        .collect::<Vec<_>>();
+    expected.push((20, 0, vec![3; 1]));
+    assert_eq!(restored, expected);
+}
+
+// relative timestamps written by async journal are read by sync journal and vice versa
+#[cfg(feature = "async")]
+#[test]
+fn test_async_journal_relative_timestamps() {
+    let header = Header {
+        relative_timestamps: true,
+        ..Header::default()
+    };
+    let name = tempfile::NamedTempFile::new().unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let timestamps = [1_000_000, 1_000_005, 999_000, 1_000_010];
+    rt.block_on(async {
+        let fd = tokio::fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(name.path())
+            .await
+            .unwrap();
+        let mut journal = AsyncJournal::new(header, fd, None).await.unwrap();
+        for (id, timestamp) in timestamps[..3].iter().enumerate() {
+            let snapshot_header = SnapshotHeader::new(id as u64, *timestamp, Some(4096));
+            journal.add_snapshot(&snapshot_header).await.unwrap();
+            journal.new_blob(0, &[id as u8; 4]).await.unwrap();
+            journal.commit().await.unwrap();
+        }
+        journal.flush().await.unwrap();
+    });
+
+    let mut journal = Journal::try_from(name.path()).unwrap();
+    let snapshot_header = SnapshotHeader::new(3, timestamps[3], Some(4096));
+    journal.add_snapshot(&snapshot_header).unwrap();
+    journal.new_blob(0, &[3; 4]).unwrap();
+    journal.commit().unwrap();
+    let restored = journal
+        .into_iter()
+        .map(|item| item.unwrap().0.timestamp)
+        .collect::<Vec<_>>();
+    assert_eq!(restored, timestamps);
+
+    let restored = rt.block_on(async {
+        let mut journal = AsyncJournal::try_from(name.path()).await.unwrap();
+        let stream = journal.stream();
+        pin_mut!(stream);
+        let mut restored = vec![];
+        while let Some(item) = stream.next().await {
+            restored.push(item.unwrap().0.timestamp);
+        }
+        restored
+    });
+    assert_eq!(restored, timestamps);
+}
+
+// async journal protocol stream is byte for byte the same as sync one
+#[cfg(feature = "async")]
+#[test]
+fn test_async_journal_protocol_stream() {
+    fn check(input: Vec<TestSnapshot>, from_snapshot: u64) {
+        let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+        for snapshot in input.iter() {
+            journal.new_snapshot(4096).unwrap();
+            for blob in snapshot.blobs.iter() {
+                journal.new_blob(blob.offset, blob.data.as_slice()).unwrap();
+            }
+            journal.commit().unwrap();
+        }
+        let header = *journal.get_header();
+        let bytes = journal.take_fd().unwrap().into_inner();
+        let mut journal = Journal::from_fd(Cursor::new(bytes.clone())).unwrap();
+        let from_snapshot = from_snapshot % (header.snapshot_counter + 2);
+        let mut expected = vec![];
+        Stream::from((
+            JOURNAL_VERSION,
+            journal.into_iter().skip_snapshots(from_snapshot),
+        ))
+        .read_to_end(&mut expected)
+        .unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let streamed = rt.block_on(async {
+            let mut journal = AsyncJournal::from(header, Cursor::new(bytes), None);
+            let mut streamed = vec![];
+            journal
+                .write_protocol_stream(&mut streamed, from_snapshot)
+                .await
+                .unwrap();
+            streamed
+        });
+        assert_eq!(streamed, expected);
+    }
+    quickcheck(check as fn(Vec<TestSnapshot>, u64));
+}
+
+// async journal stream decompresses blobs, protocol stream keeps them compressed
+#[cfg(all(feature = "async", feature = "compression"))]
+#[test]
+fn test_async_journal_compressed_stream() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.set_compression(Compression::Zstd { level: 3 });
+    for i in 0..3 {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(i * 4096, &[i as u8; 4096]).unwrap();
+        journal.commit().unwrap();
+    }
+    let header = *journal.get_header();
+    let bytes = journal.take_fd().unwrap().into_inner();
+    let mut journal = Journal::from_fd(Cursor::new(bytes.clone())).unwrap();
+    let decompressed = journal.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    let stored = journal
+        .into_iter()
+        .stored()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert!(stored.iter().all(
+        |(_, blob_header, blob)| blob_header.compressed_size as usize == blob.len()
+            && blob.len() < 4096
+    ));
+    let mut expected = vec![];
+    Stream::from(&mut journal)
+        .read_to_end(&mut expected)
+        .unwrap();
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let mut journal = AsyncJournal::from(header, Cursor::new(bytes), None);
+        let streamed = journal
+            .stream()
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(streamed, decompressed);
+        assert!(streamed
+            .iter()
+            .all(|(_, blob_header, blob)| blob_header.compressed_size == 0 && blob.len() == 4096));
+        let streamed = journal
+            .stored_stream()
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(streamed, stored);
+        let mut streamed = vec![];
+        journal
+            .write_protocol_stream(&mut streamed, 0)
+            .await
+            .unwrap();
+        assert_eq!(streamed, expected);
+    });
+}
+
+// journal passed through async bridge with read ahead, worker stays ahead of handle by
+// channel depth
+#[cfg(feature = "async_bridge")]
+#[test]
+fn test_async_bridge_channel_depth() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn blobs(journal: &mut Journal) -> Vec<(u64, Vec<u8>)> {
+        journal
+            .into_iter()
+            .map(Result::unwrap)
+            .map(|(_, blob_header, blob)| (blob_header.offset, blob))
+            .collect()
+    }
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let source_path = dir.path().join("source");
+    let mut source = Journal::create(&source_path).unwrap();
+    for i in 0..8 {
+        source.new_snapshot(4096).unwrap();
+        source
+            .new_blob(i as u64 * 0x0002_0000, &vec![i; 0x0002_0000])
+            .unwrap();
+        source.commit().unwrap();
+    }
+    let mut expected = vec![];
+    Read::read_to_end(&mut Stream::from(&mut source), &mut expected).unwrap();
+
+    for depth in [1, 4] {
+        let mut reader = rt.block_on(async {
+            AsyncReadJournalStream::new(&source_path, 0).spawn_with_depth(depth)
+        });
+        // channel is filled without reads, one more buffer is blocked in send
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while reader.buffered() < depth + 1 && std::time::Instant::now() < deadline {
+            sleep(Duration::from_millis(1));
+        }
+        sleep(Duration::from_millis(10));
+        assert_eq!(reader.buffered(), depth + 1);
+
+        let target_path = dir.path().join(format!("target-{depth}"));
+        let streamed = rt.block_on(async {
+            let mut streamed = vec![];
+            reader.read_to_end(&mut streamed).await.unwrap();
+            reader.join().await.unwrap().unwrap();
+
+            let mut writer = AsyncWriteJournalStream::new(&target_path).spawn_with_depth(depth);
+            writer.write_all(&streamed).await.unwrap();
+            writer.shutdown().await.unwrap();
+            writer.join().await.unwrap().unwrap();
+            streamed
+        });
+        assert_eq!(streamed, expected);
+        let mut target = Journal::try_from(&target_path).unwrap();
+        assert_eq!(blobs(&mut target), blobs(&mut source));
+    }
+}
+
+// writer shut down before EndOfStream, incomplete snapshot is not committed
+#[cfg(feature = "async_bridge")]
+#[test]
+fn test_async_bridge_shutdown_mid_frame() {
+    use tokio::io::AsyncWriteExt;
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut source = Journal::create(dir.path().join("source")).unwrap();
+    for i in 0..3 {
+        source.new_snapshot(4096).unwrap();
+        source.new_blob(i * 4096, &[i as u8; 1000]).unwrap();
+        source.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Read::read_to_end(&mut Stream::from(&mut source), &mut stream).unwrap();
+    let committed = source
+        .into_iter()
+        .map(Result::unwrap)
+        .filter(|(snapshot_header, _, _)| snapshot_header.id < 2)
+        .collect::<Vec<_>>();
+
+    // cut in the middle of last blob, and right before EndOfStream
+    let end_of_stream_len = 4;
+    for cut in [
+        stream.len() - end_of_stream_len - 500,
+        stream.len() - end_of_stream_len,
+    ] {
+        let target_path = dir.path().join(format!("target-{cut}"));
+        let result = rt.block_on(async {
+            let mut writer = AsyncWriteJournalStream::new(&target_path).spawn();
+            writer.write_all(&stream[..cut]).await.unwrap();
+            writer.shutdown().await.unwrap();
+            writer.join().await.unwrap()
+        });
+        assert!(
+            matches!(result, Err(Error::MissingEndOfStream)),
+            "{result:?}"
+        );
+
+        let mut target = Journal::try_from(&target_path).unwrap();
+        assert_eq!(target.current_snapshot(), Some(2));
+        let blobs = target.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(blobs, committed);
+    }
+}
+
+// stream of version 1 peer is written into current version journal
+#[cfg(feature = "async_bridge")]
+#[test]
+fn test_async_bridge_older_stream_version() {
+    use tokio::io::AsyncWriteExt;
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut source = Journal::create(dir.path().join("source")).unwrap();
+    for i in 0..3 {
+        source.new_snapshot(4096).unwrap();
+        source.new_blob(i * 4096, &[i as u8; 1000]).unwrap();
+        source.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Read::read_to_end(&mut Stream::from(&mut source), &mut stream).unwrap();
+    let stream = stream_of_version(&stream, 1);
+
+    let target_path = dir.path().join("target");
+    rt.block_on(async {
+        let mut writer = AsyncWriteJournalStream::new(&target_path).spawn();
+        writer.write_all(&stream).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.join().await.unwrap().unwrap();
+    });
+    let mut target = Journal::try_from(&target_path).unwrap();
+    assert_eq!(target.get_header().version, JOURNAL_VERSION);
+    assert_eq!(
+        target.content_digest().unwrap(),
+        source.content_digest().unwrap()
+    );
+}
+
+// stream of another writer, which diverges from journal, is rejected as is
+#[cfg(feature = "async_bridge")]
+#[test]
+fn test_async_bridge_divergence() {
+    use tokio::io::AsyncWriteExt;
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let target_path = dir.path().join("target");
+    let mut target = Journal::create(&target_path).unwrap();
+    let mut other = Journal::create(dir.path().join("other")).unwrap();
+    for (journal, blob) in [(&mut target, [1; 8]), (&mut other, [2; 8])] {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(0, &blob).unwrap();
+        journal.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Read::read_to_end(&mut Stream::from(&mut other), &mut stream).unwrap();
+    let result = rt.block_on(async {
+        let mut writer = AsyncWriteJournalStream::new(&target_path).spawn();
+        writer.write_all(&stream).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.join().await.unwrap()
+    });
+    assert!(
+        matches!(result, Err(Error::SnapshotDivergence { id: 0 })),
+        "{result:?}"
+    );
+    assert_eq!(target.count_snapshots().unwrap(), 1);
+}
+
+#[test]
+fn test_journal_flush_policy() {
+    // cursor which counts flushes
+    struct FlushCounter {
+        cursor: Cursor<Vec<u8>>,
+        flushes: Arc<Mutex<usize>>,
+    }
+
+    impl Read for FlushCounter {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.cursor.read(buf)
+        }
+    }
+
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.cursor.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            *self.flushes.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    impl Seek for FlushCounter {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    let flushes = Arc::new(Mutex::new(0));
+    let fd = FlushCounter {
+        cursor: Cursor::new(vec![]),
+        flushes: Arc::clone(&flushes),
+    };
+    let mut journal = Journal::new(Header::default(), fd, None).unwrap();
+    journal.set_flush_policy(FlushPolicy::EveryN(10));
+    let commit = |journal: &mut Journal<FlushCounter>, value: u8| {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(0, &[value; 8]).unwrap();
+        journal.commit().unwrap();
+    };
+
+    for value in 0..25 {
+        commit(&mut journal, value);
+        let expected = (value as usize + 1) / 10;
+        assert_eq!(*flushes.lock().unwrap(), expected, "commit {value}");
+    }
+    journal.flush().unwrap();
+    assert_eq!(*flushes.lock().unwrap(), 3);
+    // nothing to flush
+    journal.flush().unwrap();
+    assert_eq!(*flushes.lock().unwrap(), 3);
+
+    let blobs = journal
+        .into_iter()
+        .map(Result::unwrap)
+        .map(|(snapshot_header, _, blob)| (snapshot_header.id, blob))
+        .collect::<Vec<_>>();
+    let expected = (0..25)
+        .map(|value| (value as u64, vec![value; 8]))
+        .collect::<Vec<_>>();
+    assert_eq!(blobs, expected);
+
+    // pending commits are flushed on drop
+    commit(&mut journal, 25);
+    assert_eq!(*flushes.lock().unwrap(), 3);
+    drop(journal);
+    assert_eq!(*flushes.lock().unwrap(), 4);
+}
+
+#[test]
+fn test_journal_iter_reset() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for value in 0..5 {
+        journal.new_snapshot(4096).unwrap();
+        for offset in 0..3 {
+            journal.new_blob(offset * 4096, &[value; 16]).unwrap();
+        }
+        journal.commit().unwrap();
+    }
+
+    let mut iter = journal.into_iter();
+    let first = iter.by_ref().map(Result::unwrap).collect::<Vec<_>>();
+    assert_eq!(first.len(), 15);
+    assert!(iter.next().is_none());
+
+    iter.reset().unwrap();
+    let second = iter.by_ref().map(Result::unwrap).collect::<Vec<_>>();
+    assert_eq!(first, second);
+
+    // reset in the middle of iteration
+    iter.reset().unwrap();
+    iter.by_ref().take(4).for_each(drop);
+    iter.reset().unwrap();
+    assert_eq!(iter.map(Result::unwrap).collect::<Vec<_>>(), first);
+}
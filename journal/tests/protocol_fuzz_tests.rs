@@ -0,0 +1,97 @@
+//! Protocol decoding of arbitrary input returns errors, but never panics or aborts
+
+use journal::{
+    verify_stream, Error, Header, Journal, LengthDelimitedReader, LengthDelimitedStream, Protocol,
+    ProtocolReader, Stream,
+};
+use quickcheck::{Gen, QuickCheck};
+use serde_sqlite::from_bytes;
+use std::io::{Cursor, Read};
+
+/// Run every decoder over input
+fn decode(input: &[u8]) {
+    let _ = from_bytes::<Protocol>(input);
+    let _ = verify_stream(input);
+    ProtocolReader::new(input).for_each(drop);
+    let _ = LengthDelimitedStream::new(input).read_to_end(&mut vec![]);
+    let _ = LengthDelimitedReader::new(input).read_to_end(&mut vec![]);
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let _ = journal.apply_stream(input);
+}
+
+/// Valid stream of small journal
+fn valid_stream() -> Vec<u8> {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..3 {
+        journal.new_snapshot(4096).unwrap();
+        journal.new_blob(i * 4096, &[i as u8; 32]).unwrap();
+        journal.new_blob(i * 4096 + 100, &[i as u8; 8]).unwrap();
+        journal.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut journal).read_to_end(&mut stream).unwrap();
+    stream
+}
+
+fn quickcheck() -> QuickCheck {
+    QuickCheck::new().tests(2000).gen(Gen::new(256))
+}
+
+#[test]
+fn test_decode_arbitrary_bytes() {
+    fn check(input: Vec<u8>) {
+        decode(&input);
+    }
+    quickcheck().quickcheck(check as fn(Vec<u8>));
+}
+
+#[test]
+fn test_decode_mutated_stream() {
+    // each mutation overwrites byte at (position % stream length)
+    fn check(mutations: Vec<(usize, u8)>, truncate: usize) {
+        let mut stream = valid_stream();
+        for (pos, byte) in mutations {
+            let len = stream.len();
+            stream[pos % len] = byte;
+        }
+        stream.truncate(stream.len() - truncate % stream.len());
+        decode(&stream);
+    }
+    quickcheck().quickcheck(check as fn(Vec<(usize, u8)>, usize));
+}
+
+#[test]
+fn test_decode_oversized_blob() {
+    let mut stream = valid_stream();
+    // blob size of the first blob: version frame (4 + 4), snapshot header frame (4 + 32),
+    // blob header tag (4), offset (8) and blob num (4)
+    let pos = 8 + 36 + 4 + 8 + 4;
+    assert_eq!(&stream[pos..pos + 4], &32_u32.to_be_bytes());
+    stream[pos..pos + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    decode(&stream);
+
+    // blob is rejected before it's allocated
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let res = journal.apply_stream(stream.as_slice());
+    assert!(
+        matches!(
+            res,
+            Err(Error::BlobTooLarge {
+                blob_size: u32::MAX,
+                max: 0x0100_0000,
+            })
+        ),
+        "{res:?}"
+    );
+    let res = verify_stream(stream.as_slice());
+    assert!(
+        matches!(
+            res,
+            Err(Error::BlobTooLarge {
+                blob_size: u32::MAX,
+                max: 0x0100_0000,
+            })
+        ),
+        "{res:?}"
+    );
+}
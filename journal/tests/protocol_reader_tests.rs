@@ -0,0 +1,128 @@
+use journal::{Error, Header, Journal, ProtocolFrame, ProtocolReader, Stream};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{Cursor, Read};
+
+// allocations are counted per thread, so concurrently running tests don't interfere
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+#[test]
+fn test_protocol_reader() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    let mut expected = vec![];
+    for i in 0..5 {
+        journal.new_snapshot(4096).unwrap();
+        for j in 0..4 {
+            let blob = vec![(i * 4 + j) as u8; 100 + j * 10];
+            journal.new_blob(j as u64 * 4096, &blob).unwrap();
+            expected.push(blob);
+        }
+        journal.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut journal).read_to_end(&mut stream).unwrap();
+
+    let mut frames = Vec::with_capacity(64);
+    let before = allocations();
+    frames.extend(ProtocolReader::new(stream.as_slice()).map(Result::unwrap));
+    assert_eq!(
+        allocations(),
+        before,
+        "frames are parsed without allocation"
+    );
+
+    let blobs = frames
+        .iter()
+        .filter_map(|frame| match frame {
+            ProtocolFrame::Blob(_, blob) => Some(*blob),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(blobs, expected);
+    // blobs point into stream buffer
+    let range = stream.as_ptr_range();
+    assert!(blobs.iter().all(|blob| range.contains(&blob.as_ptr())));
+
+    let snapshot_ids = frames
+        .iter()
+        .filter_map(|frame| match frame {
+            ProtocolFrame::SnapshotHeader(snapshot_header) => Some(snapshot_header.id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(snapshot_ids, vec![0, 1, 2, 3, 4]);
+    assert!(matches!(
+        frames.first(),
+        Some(ProtocolFrame::JournalVersion(_))
+    ));
+    assert_eq!(frames.last(), Some(&ProtocolFrame::EndOfStream));
+}
+
+#[test]
+fn test_protocol_reader_truncated_stream() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    journal.new_snapshot(4096).unwrap();
+    journal.new_blob(0, &[1; 100]).unwrap();
+    journal.commit().unwrap();
+    let mut stream = vec![];
+    Stream::from(&mut journal).read_to_end(&mut stream).unwrap();
+
+    // cut inside of the blob
+    let res = ProtocolReader::new(&stream[..stream.len() - 10]).collect::<Result<Vec<_>, _>>();
+    assert!(matches!(res, Err(Error::IOError(_))), "{res:?}");
+
+    // cut before EndOfStream
+    let res = ProtocolReader::new(&stream[..stream.len() - 4]).collect::<Result<Vec<_>, _>>();
+    assert!(matches!(res, Err(Error::MissingEndOfStream)), "{res:?}");
+}
+
+#[test]
+fn test_stream_next_frame() {
+    let mut journal = Journal::new(Header::default(), Cursor::new(vec![]), None).unwrap();
+    for i in 0..3 {
+        journal.new_snapshot(4096).unwrap();
+        for j in 0..3 {
+            journal
+                .new_blob(j as u64 * 4096, &vec![(i * 3 + j) as u8; 50 + j * 10])
+                .unwrap();
+        }
+        journal.commit().unwrap();
+    }
+    let mut stream = vec![];
+    Stream::from(&mut journal).read_to_end(&mut stream).unwrap();
+    let expected = ProtocolReader::new(stream.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    let mut stream = Stream::from(&mut journal);
+    let mut count = 0;
+    while let Some(frame) = stream.next_frame().unwrap() {
+        assert_eq!(frame, expected[count]);
+        count += 1;
+    }
+    assert_eq!(count, expected.len());
+    // stream stays exhausted
+    assert!(stream.next_frame().unwrap().is_none());
+}
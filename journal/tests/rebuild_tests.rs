@@ -0,0 +1,86 @@
+use journal::{Journal, Stream};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fs;
+
+// allocated bytes are tracked per thread, so concurrently running tests don't interfere
+struct PeakAlloc;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // thread locals can be already destroyed on thread exit
+        let _ = ALLOCATED.try_with(|allocated| {
+            allocated.set(allocated.get() + layout.size());
+            PEAK.with(|peak| peak.set(peak.get().max(allocated.get())));
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = ALLOCATED
+            .try_with(|allocated| allocated.set(allocated.get().saturating_sub(layout.size())));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAlloc = PeakAlloc;
+
+/// Reset peak to currently allocated amount, returning it
+fn reset_peak() -> usize {
+    let allocated = ALLOCATED.with(|allocated| allocated.get());
+    PEAK.with(|peak| peak.set(allocated));
+    allocated
+}
+
+fn peak() -> usize {
+    PEAK.with(|peak| peak.get())
+}
+
+#[test]
+fn test_journal_rebuild_from() {
+    let dir = tempfile::tempdir().unwrap();
+    let source_path = dir.path().join("source");
+    let mut source = Journal::create(&source_path).unwrap();
+    for i in 0..1000_u64 {
+        source.new_snapshot(4096).unwrap();
+        for j in 0..4 {
+            source.new_blob(j * 4096, &[(i + j) as u8; 4096]).unwrap();
+        }
+        source.commit().unwrap();
+    }
+    let stream_path = dir.path().join("stream");
+    let mut stream_file = fs::File::create(&stream_path).unwrap();
+    std::io::copy(&mut Stream::from(&mut source), &mut stream_file).unwrap();
+    drop(stream_file);
+    let stream_size = fs::metadata(&stream_path).unwrap().len();
+    assert!(stream_size > 16_000_000);
+
+    // rebuild replaces existing journal
+    let target_path = dir.path().join("target");
+    fs::write(&target_path, b"garbage").unwrap();
+
+    let before = reset_peak();
+    let stream = fs::File::open(&stream_path).unwrap();
+    let mut target = Journal::rebuild_from(stream, &target_path).unwrap();
+    let used = peak() - before;
+    assert!(used < 1 << 20, "peak memory usage: {used} bytes");
+
+    assert_eq!(target.get_header(), source.get_header());
+    drop(target);
+    assert_eq!(
+        fs::read(&target_path).unwrap(),
+        fs::read(&source_path).unwrap()
+    );
+
+    // failed rebuild keeps existing journal intact
+    let truncated = &fs::read(&stream_path).unwrap()[..1000];
+    assert!(Journal::rebuild_from(truncated, &target_path).is_err());
+    target = Journal::try_from(&target_path).unwrap();
+    assert_eq!(target.get_header(), source.get_header());
+}
@@ -6,39 +6,40 @@ pub struct SQLiteAllocator {
     pub free: unsafe extern "C" fn(*mut c_void),
 }
 
-const PTR_ISIZE: isize = core::mem::size_of::<usize>() as isize;
 const PTR_USIZE: usize = core::mem::size_of::<usize>();
+/// Block header holds free function of allocator, which allocated block, and block address
+const HEADER_SIZE: usize = 2 * PTR_USIZE;
 
 unsafe impl GlobalAlloc for SQLiteAllocator {
-    // v--------------------------|
-    // ------------------------------------------------------------
-    // |     padding     |       ptr        |  aligned mem block  |
-    // ------------------------------------------------------------
+    // v--------------------------------------|
+    // ------------------------------------------------------------------
+    // |     padding     |    free    |   ptr    |  aligned mem block  |
+    // ------------------------------------------------------------------
+    //
+    // Block is freed with free function of allocator, which allocated it, so blocks, allocated
+    // before allocator functions were replaced, e.g. by `init!`, are freed correctly.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let align = layout.align();
-        let header_size = match align {
-            align if align <= PTR_USIZE => PTR_USIZE,
-            align => align,
-        };
-        let size = header_size + layout.size();
+        let align = layout.align().max(PTR_USIZE);
+        let size = HEADER_SIZE + align - 1 + layout.size();
         let block = (self.malloc)(size as u64) as *mut u8;
         if block.is_null() {
             return block;
         }
-        let padding = match (block as usize) % layout.align() {
-            0 => header_size,
-            v => align - v,
-        } as isize;
-        *(block.offset(padding - PTR_ISIZE) as *mut usize) = block as usize;
-        block.offset(padding)
+        let aligned = (block as usize + HEADER_SIZE + align - 1) & !(align - 1);
+        let offset = aligned - block as usize;
+        let ptr = block.add(offset);
+        *(ptr.sub(PTR_USIZE) as *mut usize) = block as usize;
+        *(ptr.sub(HEADER_SIZE) as *mut usize) = self.free as usize;
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        // address of tag
-        let addr = (ptr as usize - PTR_USIZE) as *mut usize;
         // address of original block
-        let block = (*addr) as *mut c_void;
-        (self.free)(block)
+        let block = *(ptr.sub(PTR_USIZE) as *mut usize) as *mut c_void;
+        // free function of allocator, which allocated block
+        let free: unsafe extern "C" fn(*mut c_void) =
+            core::mem::transmute(*(ptr.sub(HEADER_SIZE) as *mut usize));
+        free(block)
     }
 }
 
@@ -49,13 +50,22 @@ macro_rules! setup {
         static mut SQLITE3_API: *mut libsqlite_sys::ffi::sqlite3_api_routines =
             core::ptr::null_mut();
 
-        // stub
-        unsafe extern "C" fn _libsqlite3_stub_malloc(_: u64) -> *mut core::ffi::c_void {
-            panic!("libsqlite3 not initialized");
+        // system allocator serves allocations until sqlite is initialized, e.g. in executable
+        // which links crate directly, while sqlite loads its own copy as extension.
+        // Blocks, allocated before init, are still freed by system allocator after init.
+        extern "C" {
+            #[link_name = "malloc"]
+            fn _libsqlite3_system_malloc(size: usize) -> *mut core::ffi::c_void;
+            #[link_name = "free"]
+            fn _libsqlite3_system_free(ptr: *mut core::ffi::c_void);
         }
 
-        unsafe extern "C" fn _libsqlite_stub_free(_: *mut core::ffi::c_void) {
-            panic!("libsqlite3 not initialized");
+        unsafe extern "C" fn _libsqlite3_stub_malloc(size: u64) -> *mut core::ffi::c_void {
+            _libsqlite3_system_malloc(size as usize)
+        }
+
+        unsafe extern "C" fn _libsqlite_stub_free(ptr: *mut core::ffi::c_void) {
+            _libsqlite3_system_free(ptr)
         }
 
         #[global_allocator]
@@ -8,6 +8,8 @@ pub enum SqliteValue<'a> {
     Double(f64),
     Blob(&'a [u8]),
     Text(&'a str),
+    /// Text value, which is not valid UTF-8
+    InvalidText(&'a [u8]),
     Null,
 }
 
@@ -15,6 +17,30 @@ impl<'a> SqliteValue<'a> {
     pub fn is_null(&self) -> bool {
         matches!(self, Self::Null)
     }
+
+    /// Text value, `None` for any other type, including text which is not valid UTF-8
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Self::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Integer value, `None` for any other type
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::I64(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Blob value, `None` for any other type
+    pub fn as_blob(&self) -> Option<&'a [u8]> {
+        match self {
+            Self::Blob(blob) => Some(blob),
+            _ => None,
+        }
+    }
 }
 
 /// Iterator over *mut *mut ffi::sqlite3_value
@@ -22,6 +48,7 @@ impl<'a> SqliteValue<'a> {
 pub struct SqliteValueIter<'a> {
     iter: iter::PtrIter<'a, *mut ffi::sqlite3_value>,
     api: *mut ffi::sqlite3_api_routines,
+    unchecked_utf8: bool,
 }
 
 impl<'a> SqliteValueIter<'a> {
@@ -33,8 +60,26 @@ impl<'a> SqliteValueIter<'a> {
         Self {
             iter: iter::PtrIter::new(argc, value),
             api,
+            unchecked_utf8: false,
         }
     }
+
+    /// Skip UTF-8 validation of text values
+    ///
+    /// # Safety
+    /// Caller must guarantee all text values are valid UTF-8, i.e. database uses UTF-8 encoding
+    /// and values are not malformed.
+    pub unsafe fn with_unchecked_utf8(mut self) -> Self {
+        self.unchecked_utf8 = true;
+        self
+    }
+
+    /// Next value as text, `None` if values are exhausted or next value is not text
+    ///
+    /// Value is consumed in either case.
+    pub fn try_next_str(&mut self) -> Option<&'a str> {
+        self.next().and_then(|value| value.as_str())
+    }
 }
 
 impl<'a> Iterator for SqliteValueIter<'a> {
@@ -52,9 +97,14 @@ impl<'a> Iterator for SqliteValueIter<'a> {
                         (*self.api).value_text.unwrap()(value),
                         (*self.api).value_bytes.unwrap()(value) as usize,
                     );
-                    SqliteValue::Text(core::str::from_utf8_unchecked(core::slice::from_raw_parts(
-                        text, len,
-                    )))
+                    let text = core::slice::from_raw_parts(text, len);
+                    match self.unchecked_utf8 {
+                        true => SqliteValue::Text(core::str::from_utf8_unchecked(text)),
+                        false => match core::str::from_utf8(text) {
+                            Ok(text) => SqliteValue::Text(text),
+                            Err(_) => SqliteValue::InvalidText(text),
+                        },
+                    }
                 }
                 ffi::SQLITE_INTEGER => SqliteValue::I64((*self.api).value_int64.unwrap()(value)),
                 ffi::SQLITE_FLOAT => SqliteValue::Double((*self.api).value_double.unwrap()(value)),
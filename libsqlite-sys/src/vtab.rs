@@ -1,7 +1,7 @@
 //! Various helpers around sqlite VTables
 use crate::ffi;
 use crate::sqlite_value::{SqliteValue, SqliteValueIter};
-use core::ffi::c_int;
+use core::ffi::{c_char, c_int};
 
 #[derive(Debug)]
 pub enum UpdateType<'a> {
@@ -68,3 +68,40 @@ impl<'a>
         }
     }
 }
+
+/// Set error message, which sqlite reports along with error code returned from vtab method
+///
+/// # Safety
+/// `api` and `vtab` should be valid pointers.
+pub unsafe fn set_vtab_error(
+    api: *mut ffi::sqlite3_api_routines,
+    vtab: *mut ffi::sqlite3_vtab,
+    msg: &str,
+) {
+    set_error_message(api, &mut (*vtab).zErrMsg, msg)
+}
+
+/// Store error message into `dst`, such as `zErrMsg` of vtab or `pzErr` of `xConnect`
+///
+/// Message is copied into memory allocated with `sqlite3_malloc64`, since sqlite releases it with
+/// `sqlite3_free`. Previous message, if any, is freed. Message is truncated at first nul byte.
+///
+/// # Safety
+/// `api` and `dst` should be valid pointers, `*dst` should be null or allocated by sqlite.
+pub unsafe fn set_error_message(
+    api: *mut ffi::sqlite3_api_routines,
+    dst: *mut *mut c_char,
+    msg: &str,
+) {
+    let msg = msg.as_bytes();
+    let len = msg.iter().position(|&b| b == 0).unwrap_or(msg.len());
+    if !(*dst).is_null() {
+        (*api).free.unwrap()((*dst).cast());
+    }
+    let buf = (*api).malloc64.unwrap()(len as u64 + 1).cast::<u8>();
+    if !buf.is_null() {
+        core::ptr::copy_nonoverlapping(msg.as_ptr(), buf, len);
+        *buf.add(len) = 0;
+    }
+    *dst = buf.cast();
+}
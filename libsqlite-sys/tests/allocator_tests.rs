@@ -1,5 +1,7 @@
+use core::alloc::GlobalAlloc;
 use quickcheck::{Arbitrary, Gen, TestResult};
 use std::alloc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[global_allocator]
 static mut SQLITE3_ALLOCATOR: libsqlite_sys::alloc::SQLiteAllocator =
@@ -34,6 +36,7 @@ const PTR_SIZE: isize = std::mem::size_of::<usize>() as isize;
 // 1. allocated addresses should be aligned
 // 2. allocated block should contain requested amount of bytes
 // 3. tag should be written properly in initial allocated block
+// 4. free function of allocator should be written in front of tag
 fn test_allocator() {
     fn check(allocs: Vec<TestAlloc>) -> TestResult {
         let mut allocated = allocs
@@ -58,12 +61,13 @@ fn test_allocator() {
                     let addr = ((result as usize) - PTR_SIZE as usize) as *mut usize;
                     *addr
                 };
-                // +----------------------------------------
-                // | real_addr | padding | header | result |
-                // +----------------------------------------
-                //      ^___________________|
+                // +-----------------------------------------------
+                // | real_addr | padding | free | header | result |
+                // +-----------------------------------------------
+                //      ^__________________________|
                 //  real_addr - pointer to block provided by 'malloc'
                 //  padding   - calculated offset for block alignment
+                //  free      - 'free' of allocator, which allocated block
                 //  header    - contains value of real_addr, to call 'free' properly
                 //  result    - some value calcuated on top of real_add + layout
 
@@ -71,9 +75,17 @@ fn test_allocator() {
                 // to store header, which contains real address of the allocated block
                 assert!(real_block_addr < result as usize);
 
-                // SQLiteAllocator asks for block of size + layout in order to properly align final block
-                // resulting address should always between (real_address, real_address + layout]
-                assert!(real_block_addr + t.layout.max(PTR_SIZE as usize) >= result as usize);
+                // SQLiteAllocator asks for block of size + free + header + layout in order to
+                // properly align final block, resulting address should always be between
+                // (real_address, real_address + free + header + layout)
+                let header_size = 2 * PTR_SIZE as usize;
+                assert!(
+                    real_block_addr + header_size + t.layout.max(PTR_SIZE as usize)
+                        > result as usize
+                );
+
+                let free: usize = unsafe { *((result as usize - header_size) as *mut usize) };
+                assert_eq!(free, libc::free as unsafe extern "C" fn(_) as usize);
 
                 // smoke test? cast allocated block to slice of bytes, zero all stuff
                 let slice: &mut [u8] = unsafe { std::slice::from_raw_parts_mut(result, t.size) };
@@ -92,3 +104,37 @@ fn test_allocator() {
     }
     quickcheck::quickcheck(check as fn(Vec<TestAlloc>) -> TestResult);
 }
+
+static SYSTEM_FREES: AtomicUsize = AtomicUsize::new(0);
+static SQLITE_FREES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "C" fn _test_system_free(ptr: *mut core::ffi::c_void) {
+    SYSTEM_FREES.fetch_add(1, Ordering::SeqCst);
+    libc::free(ptr)
+}
+
+unsafe extern "C" fn _test_sqlite_free(ptr: *mut core::ffi::c_void) {
+    SQLITE_FREES.fetch_add(1, Ordering::SeqCst);
+    libc::free(ptr)
+}
+
+#[test]
+// blocks, allocated before allocator functions are replaced, as by `init!`, are freed by
+// allocator, which allocated them
+fn test_allocator_frees_with_allocating_allocator() {
+    let mut allocator = libsqlite_sys::alloc::SQLiteAllocator {
+        malloc: _test_malloc64_wrap,
+        free: _test_system_free,
+    };
+    let layout = alloc::Layout::from_size_align(100, 16).unwrap();
+    let before = unsafe { allocator.alloc(layout) };
+    allocator.free = _test_sqlite_free;
+    let after = unsafe { allocator.alloc(layout) };
+
+    unsafe { allocator.dealloc(before, layout) };
+    assert_eq!(SYSTEM_FREES.load(Ordering::SeqCst), 1);
+    assert_eq!(SQLITE_FREES.load(Ordering::SeqCst), 0);
+    unsafe { allocator.dealloc(after, layout) };
+    assert_eq!(SYSTEM_FREES.load(Ordering::SeqCst), 1);
+    assert_eq!(SQLITE_FREES.load(Ordering::SeqCst), 1);
+}
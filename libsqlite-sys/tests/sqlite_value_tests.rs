@@ -0,0 +1,93 @@
+use core::ffi::{c_int, c_uchar};
+use libsqlite_sys::ffi;
+use libsqlite_sys::sqlite_value::{SqliteValue, SqliteValueIter};
+
+// test values are passed to api routines in place of sqlite3_value pointers
+struct TestValue(&'static [u8]);
+
+unsafe extern "C" fn value_type(_value: *mut ffi::sqlite3_value) -> c_int {
+    ffi::SQLITE_TEXT
+}
+
+unsafe extern "C" fn value_text(value: *mut ffi::sqlite3_value) -> *const c_uchar {
+    let value = &*value.cast::<TestValue>();
+    value.0.as_ptr()
+}
+
+unsafe extern "C" fn value_bytes(value: *mut ffi::sqlite3_value) -> c_int {
+    let value = &*value.cast::<TestValue>();
+    value.0.len() as c_int
+}
+
+fn api() -> ffi::sqlite3_api_routines {
+    let mut api: ffi::sqlite3_api_routines = unsafe { std::mem::zeroed() };
+    api.value_type = Some(value_type);
+    api.value_text = Some(value_text);
+    api.value_bytes = Some(value_bytes);
+    api
+}
+
+#[test]
+fn test_invalid_utf8_text() {
+    let mut api = api();
+    let mut values = [
+        TestValue(b"key"),
+        TestValue(b"\xff\xfe\x00v"),
+        TestValue(b""),
+    ];
+    let mut ptrs = values
+        .iter_mut()
+        .map(|value| (value as *mut TestValue).cast::<ffi::sqlite3_value>())
+        .collect::<Vec<_>>();
+
+    let iter = SqliteValueIter::new(ptrs.len() as c_int, ptrs.as_mut_ptr(), &mut api);
+    assert_eq!(
+        iter.collect::<Vec<_>>(),
+        vec![
+            SqliteValue::Text("key"),
+            SqliteValue::InvalidText(b"\xff\xfe\x00v"),
+            SqliteValue::Text(""),
+        ]
+    );
+}
+
+#[test]
+fn test_value_accessors() {
+    let values = [
+        SqliteValue::I64(42),
+        SqliteValue::Double(0.5),
+        SqliteValue::Blob(b"blob"),
+        SqliteValue::Text("text"),
+        SqliteValue::InvalidText(b"\xff"),
+        SqliteValue::Null,
+    ];
+    assert_eq!(
+        values.iter().map(SqliteValue::as_str).collect::<Vec<_>>(),
+        vec![None, None, None, Some("text"), None, None]
+    );
+    assert_eq!(
+        values.iter().map(SqliteValue::as_i64).collect::<Vec<_>>(),
+        vec![Some(42), None, None, None, None, None]
+    );
+    assert_eq!(
+        values.iter().map(SqliteValue::as_blob).collect::<Vec<_>>(),
+        vec![None, None, Some(&b"blob"[..]), None, None, None]
+    );
+}
+
+#[test]
+fn test_try_next_str() {
+    let mut api = api();
+    let mut values = [TestValue(b"key"), TestValue(b"\xff"), TestValue(b"value")];
+    let mut ptrs = values
+        .iter_mut()
+        .map(|value| (value as *mut TestValue).cast::<ffi::sqlite3_value>())
+        .collect::<Vec<_>>();
+
+    let mut iter = SqliteValueIter::new(ptrs.len() as c_int, ptrs.as_mut_ptr(), &mut api);
+    assert_eq!(iter.try_next_str(), Some("key"));
+    // invalid text is consumed too
+    assert_eq!(iter.try_next_str(), None);
+    assert_eq!(iter.try_next_str(), Some("value"));
+    assert_eq!(iter.try_next_str(), None);
+}
@@ -0,0 +1,53 @@
+use core::ffi::{c_void, CStr};
+use libsqlite_sys::ffi;
+use libsqlite_sys::vtab::{set_error_message, set_vtab_error};
+
+unsafe extern "C" fn malloc64(size: u64) -> *mut c_void {
+    libc::malloc(size as libc::size_t)
+}
+
+unsafe extern "C" fn free(ptr: *mut c_void) {
+    libc::free(ptr)
+}
+
+fn api() -> ffi::sqlite3_api_routines {
+    let mut api: ffi::sqlite3_api_routines = unsafe { std::mem::zeroed() };
+    api.malloc64 = Some(malloc64);
+    api.free = Some(free);
+    api
+}
+
+#[test]
+fn test_set_vtab_error() {
+    let mut api = api();
+    let mut vtab: ffi::sqlite3_vtab = unsafe { std::mem::zeroed() };
+    unsafe {
+        set_vtab_error(&mut api, &mut vtab, "invalid key: \"foo\"");
+        assert_eq!(
+            CStr::from_ptr(vtab.zErrMsg).to_str().unwrap(),
+            "invalid key: \"foo\""
+        );
+
+        // previous message is replaced, message is truncated at nul byte
+        set_vtab_error(&mut api, &mut vtab, "invalid value\0garbage");
+        assert_eq!(
+            CStr::from_ptr(vtab.zErrMsg).to_str().unwrap(),
+            "invalid value"
+        );
+        free(vtab.zErrMsg.cast());
+    }
+}
+
+#[test]
+fn test_set_error_message() {
+    let mut api = api();
+    let mut err: *mut core::ffi::c_char = std::ptr::null_mut();
+    unsafe {
+        set_error_message(&mut api, &mut err, "failed to declare table");
+        assert_eq!(
+            CStr::from_ptr(err).to_str().unwrap(),
+            "failed to declare table"
+        );
+        free(err.cast());
+    }
+}
@@ -1,14 +1,40 @@
 //! mycelite configuration
 use crate::{deallocate, SQLITE3_API};
-use libsqlite_sys::{c_str, ffi, sqlite_value::SqliteValue, vtab::UpdateType};
-use once_cell::sync::Lazy;
-use std::collections::BTreeMap;
+use journal::OpenRetry;
+use libsqlite_sys::vtab::{set_error_message, set_vtab_error, UpdateType};
+use libsqlite_sys::{c_str, ffi, sqlite_value::SqliteValue};
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{c_char, c_int, c_void, CStr, CString};
 use std::mem;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use utils::{GapDiffer, LruRegistry, PageDiffer};
 
-static CONFIG_REGISTRY: Lazy<Mutex<BTreeMap<String, Arc<Mutex<Config>>>>> =
-    Lazy::new(|| Mutex::new(BTreeMap::new()));
+/// Default share of changed page bytes, above which whole page is journaled instead of diff
+pub(crate) const DEFAULT_FULL_PAGE_THRESHOLD_PCT: u8 = 60;
+
+/// Default page size of journal, recorded for empty databases
+pub(crate) const DEFAULT_PAGE_SIZE: u32 = 4096;
+
+/// Default time to wait for replicator thread on database close, before detaching it
+pub(crate) const DEFAULT_REPLICATOR_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of replicator status polls, while writer waits for sync backend to catch up
+#[allow(dead_code)]
+pub(crate) const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time writer waits for sync backend to catch up, before sync fails with `SQLITE_BUSY`
+pub(crate) const BACKPRESSURE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default endpoint of sync backend
+const DEFAULT_ENDPOINT: &str = "https://us-east-1.mycelial.com";
+
+/// Max number of configs kept in registry for databases which are not open anymore
+const CONFIG_REGISTRY_CAPACITY: usize = 16;
+
+static CONFIG_REGISTRY: Mutex<LruRegistry<Mutex<Config>>> =
+    Mutex::new(LruRegistry::new(CONFIG_REGISTRY_CAPACITY));
 
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct ConfigRegistry {}
@@ -18,17 +44,6 @@ impl ConfigRegistry {
         Self {}
     }
 
-    pub fn register_config(self, database_path: &str) {
-        let mut map = CONFIG_REGISTRY.lock().unwrap();
-        if map.get(database_path).is_some() {
-            return;
-        }
-        let mut config = Config::new(database_path);
-        // FIXME: error is swallowed
-        config.read().ok();
-        map.insert(database_path.into(), Arc::new(Mutex::new(config)));
-    }
-
     #[allow(dead_code)]
     pub fn unregister_config(self, database_path: &str) {
         CONFIG_REGISTRY
@@ -37,12 +52,37 @@ impl ConfigRegistry {
             .unwrap();
     }
 
+    /// Get config for database, config is read from disk on first access
+    ///
+    /// Config stays in registry while it's referenced, afterwards it can be evicted.
     pub fn get(self, database_path: &str) -> Arc<Mutex<Config>> {
-        self.register_config(database_path);
-        CONFIG_REGISTRY
-            .lock()
-            .map(|map| Arc::clone(map.get(database_path).unwrap()))
-            .unwrap()
+        let mut map = CONFIG_REGISTRY.lock().unwrap();
+        map.get_or_insert_with(database_path, || {
+            let mut config = Config::new(database_path);
+            // FIXME: error is swallowed
+            config.read().ok();
+            // FIXME: error is swallowed
+            config.ensure_persisted().ok();
+            Mutex::new(config)
+        })
+    }
+}
+
+/// Provenance of config value
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ConfigSource {
+    /// value is filled in by mycelite
+    Default,
+    /// value is set by user
+    User,
+}
+
+impl ConfigSource {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConfigSource::Default => "default",
+            ConfigSource::User => "user",
+        }
     }
 }
 
@@ -50,36 +90,195 @@ impl ConfigRegistry {
 pub(crate) struct Config {
     path: String,
     state: BTreeMap<String, String>,
+    /// keys, explicitly set by user
+    user_keys: BTreeSet<String>,
 }
 
 impl Config {
     pub fn new<P: Into<String>>(database_path: P) -> Self {
-        let path = {
-            let mut path: String = database_path.into();
-            path.push_str("-mycelite-config");
-            path
+        // in-memory/temporary databases have empty path, config is not persisted for them
+        let path = match database_path.into() {
+            path if path.is_empty() => path,
+            mut path => {
+                path.push_str("-mycelite-config");
+                path
+            }
         };
         let mut s = Self {
             path,
             state: BTreeMap::new(),
+            user_keys: BTreeSet::new(),
         };
-        s.insert("endpoint", "https://us-east-1.mycelial.com");
+        s.state.insert("endpoint".into(), DEFAULT_ENDPOINT.into());
         s
     }
 
+    /// Whether value of key is set by user or filled in by default
+    pub fn source(&self, key: &str) -> ConfigSource {
+        match self.user_keys.contains(key) {
+            true => ConfigSource::User,
+            false => ConfigSource::Default,
+        }
+    }
+
     pub fn get(&self, key: &str) -> Option<&str> {
         self.state.get(key).map(|s| s.as_str())
     }
 
-    fn insert(&mut self, key: &str, value: &str) {
-        if Self::allowed_keys().contains(&key) {
-            self.state.insert(key.to_string(), value.to_string());
+    fn insert(&mut self, key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !Self::allowed_keys().contains(&key) {
+            return Ok(());
+        }
+        match key {
+            "user_agent" if !is_header_value(value) => {
+                return Err(format!("invalid user agent: {value:?}").into())
+            }
+            "headers" => {
+                parse_headers(value)?;
+            }
+            "default_page_size" if !is_page_size(value) => {
+                return Err(format!("invalid default page size: {value:?}").into())
+            }
+            "diff_algorithm" if utils::page_differ(value).is_none() => {
+                return Err(format!("invalid diff algorithm: {value:?}").into())
+            }
+            "journal_open_attempts" => match value.parse::<u32>() {
+                Ok(attempts) if attempts > 0 => (),
+                _ => return Err(format!("invalid journal open attempts: {value:?}").into()),
+            },
+            "full_page_threshold_pct" => match value.parse::<u8>() {
+                Ok(pct) if pct <= 100 => (),
+                _ => return Err(format!("invalid full page threshold: {value:?}").into()),
+            },
+            "replication_enabled" if value.parse::<bool>().is_err() => {
+                return Err(format!("invalid replication enabled flag: {value:?}").into())
+            }
+            "max_unconfirmed_snapshots" if value.parse::<u64>().is_err() => {
+                return Err(format!("invalid max unconfirmed snapshots: {value:?}").into())
+            }
+            "restore_as_of_snapshot" if value.parse::<u64>().is_err() => {
+                return Err(format!("invalid snapshot id: {value:?}").into())
+            }
+            "snapshot_debounce_ms" if value.parse::<u64>().is_err() => {
+                return Err(format!("invalid snapshot debounce: {value:?}").into())
+            }
+            "replicator_join_timeout_ms" if value.parse::<u64>().is_err() => {
+                return Err(format!("invalid replicator join timeout: {value:?}").into())
+            }
+            "volatile_ranges" if parse_volatile_ranges(value).is_none() => {
+                return Err(format!("invalid volatile ranges: {value:?}").into())
+            }
+            _ => (),
+        }
+        self.state.insert(key.to_string(), value.to_string());
+        self.user_keys.insert(key.to_string());
+        Ok(())
+    }
+
+    /// Share of changed page bytes, above which whole page is journaled instead of diff
+    pub fn full_page_threshold_pct(&self) -> u8 {
+        self.get("full_page_threshold_pct")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_FULL_PAGE_THRESHOLD_PCT)
+    }
+
+    /// Page size of journal, which is created for empty database
+    pub fn default_page_size(&self) -> u32 {
+        self.get("default_page_size")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+    }
+
+    /// Retry policy of journal opens, transient filesystem errors are retried up to
+    /// `journal_open_attempts` attempts in total
+    pub fn journal_open_retry(&self) -> OpenRetry {
+        let default = OpenRetry::default();
+        OpenRetry {
+            attempts: self
+                .get("journal_open_attempts")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default.attempts),
+            ..default
         }
     }
 
+    /// Page diff algorithm: `gap` (default) or `page`
+    pub fn page_differ(&self) -> Box<dyn PageDiffer + Send + Sync> {
+        self.get("diff_algorithm")
+            .and_then(utils::page_differ)
+            .unwrap_or_else(|| Box::new(GapDiffer))
+    }
+
+    /// Replication is paused if set to `false`, replicator stays alive and resumes once re-enabled
+    pub fn replication_enabled(&self) -> bool {
+        self.get("replication_enabled")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(true)
+    }
+
+    /// Time to wait for replicator thread on database close, before detaching it
+    #[allow(dead_code)]
+    pub fn replicator_join_timeout(&self) -> Duration {
+        self.get("replicator_join_timeout_ms")
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_REPLICATOR_JOIN_TIMEOUT)
+    }
+
+    /// Max count of local snapshots, sync backend can lag behind, before writer is throttled
+    ///
+    /// Writer blocks on sync until backend catches up. Backpressure is disabled, if not set or
+    /// zero.
+    pub fn max_unconfirmed_snapshots(&self) -> Option<u64> {
+        self.get("max_unconfirmed_snapshots")
+            .and_then(|value| value.parse().ok())
+            .filter(|max| *max > 0)
+    }
+
+    /// Window, within which syncs are coalesced into single snapshot
+    ///
+    /// Every sync writes its changes into journal file, but snapshot is committed by first sync
+    /// after window elapses, or on close, so debounce delays only snapshot visibility: changes
+    /// aren't replicated meanwhile. Snapshot of crashed process is committed by next writer,
+    /// which opens database. Disabled by default.
+    pub fn snapshot_debounce(&self) -> Duration {
+        self.get("snapshot_debounce_ms")
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// Byte ranges of database header, excluded from journaled diffs, e.g.
+    /// `change_counter,version_valid_for`
+    ///
+    /// Only volatile header fields, which carry no database content, can be listed, restore
+    /// recomputes them from journal. Nothing is excluded by default.
+    pub fn volatile_ranges(&self) -> Vec<std::ops::Range<usize>> {
+        self.get("volatile_ranges")
+            .and_then(parse_volatile_ranges)
+            .unwrap_or_default()
+    }
+
+    /// Snapshot id, as of which reader restores database, instead of latest snapshot
+    ///
+    /// Only read-only replicas honor it, since writes on top of past snapshot would diverge
+    /// from journal history.
+    pub fn restore_as_of_snapshot(&self) -> Option<u64> {
+        self.get("restore_as_of_snapshot")
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Directory of temporary files, into which reader restores database before it's copied over
+    ///
+    /// Defaults to database directory.
+    pub fn restore_tmp_dir(&self) -> Option<PathBuf> {
+        self.get("restore_tmp_dir").map(PathBuf::from)
+    }
+
     fn delete(&mut self, pos: usize) {
         if let Some(key) = Self::allowed_keys().get(pos) {
             self.state.remove(*key);
+            self.user_keys.remove(*key);
         };
     }
 
@@ -91,6 +290,16 @@ impl Config {
         Ok(())
     }
 
+    /// Write current state, if config file doesn't exist yet
+    ///
+    /// Fresh config holds only defaults, which are otherwise persisted only on first vtab write.
+    pub fn ensure_persisted(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.path.is_empty() || std::path::Path::new(self.path.as_str()).exists() {
+            return Ok(());
+        }
+        self.write()
+    }
+
     fn read(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let value = match std::fs::read_to_string(self.path.as_str()) {
             Ok(value) => value,
@@ -99,37 +308,171 @@ impl Config {
         };
         let map = toml::from_str::<BTreeMap<String, String>>(&value)?;
         map.into_iter().for_each(|(key, value)| {
+            // defaults are persisted along with user values, persisted default value is still default
+            if !is_default(&key, &value) {
+                self.user_keys.insert(key.clone());
+            }
             self.state.insert(key, value);
         });
         Ok(())
     }
 
     fn allowed_keys() -> &'static [&'static str] {
-        &["client_id", "domain", "endpoint", "secret"]
+        &[
+            "client_id",
+            "default_page_size",
+            "diff_algorithm",
+            "domain",
+            "endpoint",
+            "full_page_threshold_pct",
+            "headers",
+            "journal_open_attempts",
+            "max_unconfirmed_snapshots",
+            "replication_enabled",
+            "replicator_join_timeout_ms",
+            "restore_as_of_snapshot",
+            "restore_tmp_dir",
+            "secret",
+            "snapshot_debounce_ms",
+            "user_agent",
+            "volatile_ranges",
+        ]
     }
 
-    fn rows(&self) -> impl Iterator<Item = (i64, &str, &str)> {
+    fn rows(&self) -> impl Iterator<Item = (i64, &str, &str, ConfigSource)> {
         self.state.iter().map(|(k, v)| {
             (
                 Self::allowed_keys().iter().position(|r| r == k).unwrap() as i64,
                 k.as_str(),
                 v.as_str(),
+                self.source(k),
             )
         })
     }
 }
 
+/// Parse extra request headers, configured as `name1=value1;name2=value2`
+pub(crate) fn parse_headers(value: &str) -> Result<Vec<(&str, &str)>, Box<dyn std::error::Error>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected 'name=value', got: {pair:?}"))?;
+            let (name, value) = (name.trim(), value.trim());
+            if !is_header_name(name) {
+                return Err(format!("invalid header name: {name:?}").into());
+            }
+            if !is_header_value(value) {
+                return Err(format!("invalid value for header {name:?}: {value:?}").into());
+            }
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Header name should be a non-empty token (RFC 7230, section 3.2.6)
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Parse comma separated names of volatile database header fields, see
+/// [`page_parser::volatile_range`]
+fn parse_volatile_ranges(value: &str) -> Option<Vec<std::ops::Range<usize>>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(page_parser::volatile_range)
+        .collect()
+}
+
+/// Value matches the one filled in by [`Config::new`]
+fn is_default(key: &str, value: &str) -> bool {
+    key == "endpoint" && value == DEFAULT_ENDPOINT
+}
+
+/// Sqlite page size is a power of two between 512 and 65536
+fn is_page_size(value: &str) -> bool {
+    match value.parse::<u32>() {
+        Ok(size) => size.is_power_of_two() && (512..=65536).contains(&size),
+        Err(_) => false,
+    }
+}
+
+/// Header value should consist of visible ascii characters, spaces or tabs
+fn is_header_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b == b' ' || b == b'\t' || b.is_ascii_graphic())
+}
+
+/// Error of config vtab operation
+#[derive(Debug)]
+enum ConfigError {
+    /// Update is neither insert/update of text key and value, nor delete by row id
+    Malformed(&'static str),
+    /// Config value failed validation
+    Invalid(Box<dyn std::error::Error>),
+    /// Config file write failed
+    Write {
+        path: String,
+        error: Box<dyn std::error::Error>,
+    },
+}
+
+impl ConfigError {
+    /// Sqlite result code of error
+    fn code(&self) -> c_int {
+        match self {
+            Self::Malformed(_) => ffi::SQLITE_MISUSE,
+            Self::Invalid(_) => ffi::SQLITE_CONSTRAINT,
+            Self::Write { .. } => ffi::SQLITE_IOERR,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Malformed(msg) => write!(f, "{msg}"),
+            Self::Invalid(error) => write!(f, "{error}"),
+            Self::Write { path, error } => write!(f, "failed to write config {path:?}: {error}"),
+        }
+    }
+}
+
+/// Result code of vtab operation, error message is set on vtab
+unsafe fn vtab_result(p_vtab: *mut ffi::sqlite3_vtab, result: Result<(), ConfigError>) -> c_int {
+    match result {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(e) => {
+            set_vtab_error(SQLITE3_API, p_vtab, &e.to_string());
+            e.code()
+        }
+    }
+}
+
 #[repr(C)]
 struct VTab {
     vtab: ffi::sqlite3_vtab,
     database_path: String,
+    // keeps config in registry between update and sync
+    config: Arc<Mutex<Config>>,
 }
 
 impl VTab {
     unsafe fn new(database_path: String) -> Self {
+        let config = ConfigRegistry::new().get(database_path.as_str());
         Self {
             vtab: mem::zeroed(),
             database_path,
+            config,
         }
     }
 
@@ -150,7 +493,7 @@ impl VTab {
 struct VTabCursor {
     cur: ffi::sqlite3_vtab_cursor,
     offset: usize,
-    rows: Vec<(i64, String, String)>,
+    rows: Vec<(i64, String, String, ConfigSource)>,
 }
 
 impl VTabCursor {
@@ -160,7 +503,7 @@ impl VTabCursor {
             .lock()
             .unwrap()
             .rows()
-            .map(|(rowid, k, v)| (rowid, k.to_owned(), v.to_owned()))
+            .map(|(rowid, k, v, source)| (rowid, k.to_owned(), v.to_owned(), source))
             .collect();
         Self {
             cur: mem::zeroed(),
@@ -188,13 +531,14 @@ unsafe extern "C" fn x_connect(
     _argc: c_int,
     _argv: *const *const c_char,
     pp_vtab: *mut *mut ffi::sqlite3_vtab,
-    _err: *mut *mut c_char,
+    err: *mut *mut c_char,
 ) -> c_int {
     let rc = (*SQLITE3_API).declare_vtab.unwrap()(
         db,
-        c_str!("CREATE TABLE mycelite_config(key text, value text)"),
+        c_str!("CREATE TABLE mycelite_config(key text, value text, source text hidden)"),
     );
     if rc != ffi::SQLITE_OK {
+        set_error_message(SQLITE3_API, err, "failed to declare mycelite_config table");
         return rc;
     };
     let database_path = CStr::from_ptr((*SQLITE3_API).db_filename.unwrap()(db, c_str!("main")))
@@ -261,6 +605,7 @@ unsafe extern "C" fn x_column(
     let value = match n {
         0 => row.1.clone(),
         1 => row.2.clone(),
+        2 => row.3.as_str().to_owned(),
         _ => return ffi::SQLITE_ERROR,
     };
     let len = value.len();
@@ -284,37 +629,31 @@ unsafe extern "C" fn x_rowid(
 }
 
 unsafe extern "C" fn x_update(
-    vtab: *mut ffi::sqlite3_vtab,
+    p_vtab: *mut ffi::sqlite3_vtab,
     argc: c_int,
     value: *mut *mut ffi::sqlite3_value,
     _p_rowid: *mut ffi::sqlite3_int64,
 ) -> c_int {
-    let vtab = VTab::as_mut(vtab);
-    let config = ConfigRegistry::new().get(vtab.database_path.as_str());
-    let mut config = config.lock().unwrap();
-    match UpdateType::from((argc, value, SQLITE3_API)) {
+    let vtab = VTab::as_mut(p_vtab);
+    let mut config = vtab.config.lock().unwrap();
+    let result = match UpdateType::from((argc, value, SQLITE3_API)) {
         UpdateType::Delete {
             row_id: SqliteValue::I64(row_id),
-        } => config.delete(row_id as usize),
-        UpdateType::Update { mut columns, .. } => match (columns.next(), columns.next()) {
-            (Some(SqliteValue::Text(key)), Some(SqliteValue::Text(value))) => {
-                config.insert(key, value)
-            }
-            _ => {
-                return ffi::SQLITE_MISUSE;
-            }
-        },
-        UpdateType::Insert { mut columns, .. } => match (columns.next(), columns.next()) {
-            (Some(SqliteValue::Text(key)), Some(SqliteValue::Text(value))) => {
-                config.insert(key, value)
-            }
-            _ => {
-                return ffi::SQLITE_MISUSE;
+        } => {
+            config.delete(row_id as usize);
+            Ok(())
+        }
+        UpdateType::Update { mut columns, .. } | UpdateType::Insert { mut columns, .. } => {
+            match (columns.try_next_str(), columns.try_next_str()) {
+                (Some(key), Some(value)) => config.insert(key, value).map_err(ConfigError::Invalid),
+                _ => Err(ConfigError::Malformed(
+                    "config key and value should be text",
+                )),
             }
-        },
-        _ => return ffi::SQLITE_MISUSE,
-    }
-    ffi::SQLITE_OK
+        }
+        _ => Err(ConfigError::Malformed("unsupported config update")),
+    };
+    vtab_result(p_vtab, result)
 }
 
 unsafe extern "C" fn x_begin(_p_vtab: *mut ffi::sqlite3_vtab) -> c_int {
@@ -323,12 +662,12 @@ unsafe extern "C" fn x_begin(_p_vtab: *mut ffi::sqlite3_vtab) -> c_int {
 
 unsafe extern "C" fn x_sync(p_vtab: *mut ffi::sqlite3_vtab) -> c_int {
     let vtab = VTab::as_mut(p_vtab);
-    let config = ConfigRegistry::new().get(vtab.database_path.as_str());
-    let mut config = config.lock().unwrap();
-    if config.write().is_err() {
-        return ffi::SQLITE_ERROR;
-    };
-    ffi::SQLITE_OK
+    let mut config = vtab.config.lock().unwrap();
+    let result = config.write().map_err(|error| ConfigError::Write {
+        path: config.path.clone(),
+        error,
+    });
+    vtab_result(p_vtab, result)
 }
 
 unsafe extern "C" fn x_commit(_p_vtab: *mut ffi::sqlite3_vtab) -> c_int {
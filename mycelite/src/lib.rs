@@ -1,11 +1,15 @@
 #![allow(clippy::missing_safety_doc)]
 
 mod config;
+mod logger;
 mod replicator;
+#[cfg(feature = "test-support")]
+pub mod test_support;
 mod vfs;
 use libsqlite_sys::ffi;
 use once_cell::sync::OnceCell;
-use std::ffi::{c_char, c_int};
+use std::ffi::{c_char, c_int, CStr};
+pub use vfs::{MYCELITE_FCNTL_IS_JOURNALED, MYCELITE_FCNTL_UNCONFIRMED_SNAPSHOTS};
 
 struct DefaultVfs(*mut ffi::sqlite3_vfs);
 
@@ -21,6 +25,25 @@ static DEFAULT_VFS: OnceCell<DefaultVfs> = OnceCell::new();
 
 libsqlite_sys::setup!();
 
+/// Register VFS as default one, unless VFS with same name is already registered
+///
+/// Extension can be loaded several times, or through several init functions, VFS is registered
+/// on first load only.
+unsafe fn register_vfs(vfs: *mut ffi::sqlite3_vfs) {
+    let registered = (*SQLITE3_API).vfs_find.unwrap()((*vfs).zName);
+    if !registered.is_null() {
+        let name = CStr::from_ptr((*vfs).zName).to_string_lossy();
+        // repeated load of extension is expected, VFS of other extension under same name isn't
+        if registered != vfs {
+            log::warn!("other VFS is already registered as {name:?}, skipping registration");
+        } else {
+            log::debug!("VFS {name:?} is already registered, skipping registration");
+        }
+        return;
+    }
+    (*SQLITE3_API).vfs_register.unwrap()(vfs, 1);
+}
+
 #[no_mangle]
 pub unsafe fn sqlite3_mycelite_init(
     db: *mut ffi::sqlite3,
@@ -39,11 +62,12 @@ pub unsafe fn mycelite_reader(
     api: *mut ffi::sqlite3_api_routines,
 ) -> c_int {
     libsqlite_sys::init!(api);
+    logger::init();
     let default_vfs = (*SQLITE3_API).vfs_find.unwrap()(std::ptr::null_mut());
     DEFAULT_VFS.set(DefaultVfs(default_vfs)).ok();
 
     vfs::MclVFSReader.init(DEFAULT_VFS.get_unchecked().0);
-    (*SQLITE3_API).vfs_register.unwrap()(vfs::MclVFSReader.as_base(), 1);
+    register_vfs(vfs::MclVFSReader.as_base());
     ffi::SQLITE_OK_LOAD_PERMANENTLY
 }
 
@@ -54,11 +78,12 @@ pub unsafe fn mycelite_writer(
     api: *mut ffi::sqlite3_api_routines,
 ) -> c_int {
     libsqlite_sys::init!(api);
+    logger::init();
     let default_vfs = (*SQLITE3_API).vfs_find.unwrap()(std::ptr::null_mut());
     DEFAULT_VFS.set(DefaultVfs(default_vfs)).ok();
 
     vfs::MclVFSWriter.init(DEFAULT_VFS.get_unchecked().0);
-    (*SQLITE3_API).vfs_register.unwrap()(vfs::MclVFSWriter.as_base(), 1);
+    register_vfs(vfs::MclVFSWriter.as_base());
     ffi::SQLITE_OK_LOAD_PERMANENTLY
 }
 
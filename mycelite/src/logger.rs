@@ -0,0 +1,38 @@
+//! Stderr logger of extension
+//!
+//! Extension, loaded into sqlite, links its own copy of `log`, host process can't install logger
+//! for it. Logger is installed on extension load, unless other logger is already set.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Env var with max level of logged records, e.g. `info`, `off` disables logging
+const LOG_LEVEL_ENV: &str = "MYCELITE_LOG";
+
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("mycelite: {}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install stderr logger, level is read from [`LOG_LEVEL_ENV`], warnings are logged by default
+pub fn init() {
+    let level = std::env::var(LOG_LEVEL_ENV)
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Warn);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
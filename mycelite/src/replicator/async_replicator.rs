@@ -0,0 +1,418 @@
+//! Async replicator prototype
+//!
+//! Same behaviour as http replicator, but driven by tokio runtime on dedicated thread.
+//!
+//! ** For demo use only! **
+
+use super::{
+    basic_auth_header, Message, ReplicatorState, IDEMPOTENCY_KEY_HEADER, RESTORE_LOCK_TIMEOUT,
+};
+use crate::config::{parse_headers, BACKPRESSURE_POLL_INTERVAL};
+use futures::{pin_mut, StreamExt};
+use journal::{AsyncJournal, AsyncReadJournalStream, AsyncWriteJournalStream};
+use reqwest::{Body, Client, Method, RequestBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, TryLockError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter, SeekFrom};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use utils::{Backoff, LockError, ReplicatorStatus, StagedFile};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type Replicator = AsyncReplicator;
+
+pub type ReplicatorHandle = AsyncReplicatorHandle;
+
+pub struct AsyncReplicator {
+    journal_path: PathBuf,
+    state: ReplicatorState,
+    client: Client,
+}
+
+impl AsyncReplicator {
+    /// Create replicator over journal at `journal_path`
+    ///
+    /// Reader, opened before first sync, creates empty journal, which is populated by pulls.
+    pub fn new<P: AsRef<Path>>(
+        journal_path: P,
+        database_path: String,
+        read_only: bool,
+        lock: Arc<RwLock<()>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        match journal::Journal::try_from(journal_path.as_ref()) {
+            Ok(_) => (),
+            Err(e) if e.journal_not_exists() && read_only => {
+                journal::Journal::create(journal_path.as_ref())?;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            journal_path: journal_path.as_ref().into(),
+            state: ReplicatorState::new(database_path, read_only, lock),
+            client: Client::new(),
+        })
+    }
+
+    /// Spawn replicator loop on a dedicated thread with single threaded tokio runtime
+    pub fn spawn(mut self) -> AsyncReplicatorHandle {
+        let (tx, mut rx) = unbounded_channel();
+        let status = Arc::clone(&self.state.status);
+        let join_timeout = self.state.replicator_join_timeout();
+        let local_h = Some(std::thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    // replicator never runs, error is reported through status
+                    let error = format!("failed to build replicator runtime: {e}");
+                    log::error!("{error}");
+                    self.state.status.set_last_error(Some(error));
+                    return;
+                }
+            };
+            runtime.block_on(self.enter_loop(&mut rx))
+        }));
+        AsyncReplicatorHandle::new(tx, local_h, status, join_timeout)
+    }
+
+    /// local loop
+    ///
+    /// listens for notifications pulls/pushes snapshots, restores underlying database to latest
+    /// snapshot
+    async fn enter_loop(&mut self, rx: &mut UnboundedReceiver<Message>) {
+        loop {
+            match (self.state.replication_enabled(), self.state.read_only) {
+                // replication is paused, backlog is pushed from backend snapshot id on resume
+                (false, _) => (),
+                (true, true) => {
+                    let pulled = self.maybe_pull_snapshots().await;
+                    let result = match self.state.pulled(pulled) {
+                        Ok(true) => {
+                            let restored = self.restore_latest_snapshot().await;
+                            self.state.restored(restored)
+                        }
+                        Ok(false) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    self.state
+                        .status
+                        .set_last_error(result.err().map(|e| e.to_string()));
+                }
+                (true, false) => {
+                    let result = self.maybe_push_snapshots().await;
+                    let unconfirmed = self.unconfirmed_snapshots();
+                    self.state.pushed(result, unconfirmed);
+                }
+            }
+            match tokio::time::timeout(POLL_INTERVAL, rx.recv()).await {
+                Err(_elapsed) => (),
+                Ok(None) => return,
+                Ok(Some(Message::Quit)) => return,
+                Ok(Some(Message::NewLocalSnapshot)) => (),
+            };
+        }
+    }
+
+    /// Return last local snapshot id
+    async fn current_snapshot(&self) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let journal = AsyncJournal::try_from(self.journal_path.as_path()).await?;
+        Ok(journal.current_snapshot().await)
+    }
+
+    /// Push local snapshots, if any
+    async fn maybe_push_snapshots(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let local_snapshot_id = match self.current_snapshot().await? {
+            None => return Ok(()),
+            Some(v) => v,
+        };
+        let url = match self.state.get_url()? {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+        // snapshot push always requires authorization (for now)
+        let client_id = self.state.get_key("client_id");
+        let secret = self.state.get_key("secret");
+        if client_id.is_none() || secret.is_none() {
+            return Ok(());
+        };
+        let remote_snapshot_id = match self
+            .get_backend_current_snapshot(&url, client_id.as_deref(), secret.as_deref())
+            .await
+        {
+            Ok(Some(v)) if v >= local_snapshot_id => {
+                self.state.status.set_ids(Some(local_snapshot_id), Some(v));
+                return Ok(());
+            }
+            Ok(Some(v)) => v,
+            Ok(None) => 0,
+            Err(_) => return Err("error".into()),
+        };
+        self.state
+            .status
+            .set_ids(Some(local_snapshot_id), Some(remote_snapshot_id));
+
+        let mut reader =
+            AsyncReadJournalStream::new(self.journal_path.as_path(), remote_snapshot_id).spawn();
+        let body = async_stream::stream! {
+            loop {
+                let mut buf = vec![0; 0x0001_0000];
+                match reader.read(buf.as_mut_slice()).await {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        buf.truncate(read);
+                        yield Ok(buf);
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                }
+            }
+        };
+
+        self.request(Method::POST, &url, client_id.as_deref(), secret.as_deref())?
+            .header(
+                IDEMPOTENCY_KEY_HEADER,
+                self.state
+                    .idempotency_key(remote_snapshot_id, local_snapshot_id),
+            )
+            .body(Body::wrap_stream(body))
+            .send()
+            .await?
+            .error_for_status()?;
+        self.state
+            .status
+            .set_ids(Some(local_snapshot_id), Some(local_snapshot_id));
+        Ok(())
+    }
+
+    /// Pulls remote snapshots, if any
+    async fn maybe_pull_snapshots(
+        &mut self,
+    ) -> Result<(Option<u64>, Option<u64>), Box<dyn std::error::Error>> {
+        let local_snapshot_id = self.current_snapshot().await?;
+        let url = match self.state.get_url()? {
+            Some(url) => url,
+            None => return Ok((local_snapshot_id, local_snapshot_id)),
+        };
+
+        let client_id = self.state.get_key("client_id");
+        let secret = self.state.get_key("secret");
+
+        let remote_snapshot_id = self
+            .get_backend_current_snapshot(&url, client_id.as_deref(), secret.as_deref())
+            .await?;
+        self.state
+            .status
+            .set_ids(local_snapshot_id, remote_snapshot_id);
+        match remote_snapshot_id {
+            Some(v) if local_snapshot_id < Some(v) => (),
+            v => return Ok((local_snapshot_id, v)),
+        };
+
+        let res = self
+            .request(Method::GET, &url, client_id.as_deref(), secret.as_deref())?
+            .query(&[("snapshot-id", super::pull_from(local_snapshot_id))])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        // stream is validated and applied to the journal by the journal bridge, last local
+        // snapshot is pulled again, so divergence of backend history is detected
+        let mut writer = AsyncWriteJournalStream::new(self.journal_path.as_path()).spawn();
+        let body = res.bytes_stream();
+        pin_mut!(body);
+        while let Some(chunk) = body.next().await {
+            writer.write_all(&chunk?).await?;
+        }
+        writer.shutdown().await?;
+        writer.join().await?.map_err(super::pull_error)?;
+
+        let current_snapshot_id = self.current_snapshot().await?;
+        self.state
+            .status
+            .set_ids(current_snapshot_id, remote_snapshot_id);
+        Ok((local_snapshot_id, current_snapshot_id))
+    }
+
+    // FIXME: snapshot is recovered from scratch each time
+    // database lock is held only while staged database is copied over, copy is blocking
+    // FIXME: page size discontinuities are not handled, see Journal::export_database
+    // database is restored as of `restore_as_of_snapshot` instead of latest snapshot, if it's set
+    async fn restore_latest_snapshot(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let as_of = self.state.restore_as_of_snapshot();
+        let mut journal = AsyncJournal::try_from(self.journal_path.as_path()).await?;
+        // journal can lack snapshot until it's pulled
+        if let Some(snapshot_id) = as_of {
+            if journal.current_snapshot().await <= Some(snapshot_id) {
+                return Err(journal::Error::SnapshotNotFound { id: snapshot_id }.into());
+            }
+        }
+        // database is exported into staging file first, so failed export leaves database intact
+        let staged = StagedFile::new(
+            &self.state.database_path,
+            self.state.restore_tmp_dir().as_deref(),
+        );
+        let mut output =
+            BufWriter::with_capacity(0x0010_0000, tokio::fs::File::from_std(staged.create()?));
+        // stream borrows journal, it's dropped before journal is queried again
+        {
+            let stream = journal.stream();
+            pin_mut!(stream);
+            while let Some(data) = stream.next().await {
+                let (snapshot_header, blob_header, blob) = data?;
+                if as_of.is_some_and(|snapshot_id| snapshot_header.id > snapshot_id) {
+                    break;
+                }
+                output.seek(SeekFrom::Start(blob_header.offset)).await?;
+                output.write_all(&blob).await?;
+            }
+        }
+        output.flush().await?;
+        // volatile header fields might not be journaled
+        let counter = match as_of {
+            Some(snapshot_id) => Some(snapshot_id + 1),
+            None => journal.current_snapshot().await,
+        };
+        let mut output = output.into_inner().into_std().await;
+        page_parser::write_change_counter(&mut output, counter.unwrap_or(0) as u32)?;
+        output.sync_all()?;
+        drop(output);
+        // database can be concurrently written by sqlite, restore is retried on next iteration
+        let mut backoff = Backoff::new(RESTORE_LOCK_TIMEOUT);
+        let lock = loop {
+            match self.state.lock.try_write() {
+                Ok(lock) => break lock,
+                Err(TryLockError::Poisoned(_)) => return Err(LockError::Poisoned.into()),
+                Err(TryLockError::WouldBlock) => match backoff.next() {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => return Err(LockError::Timeout.into()),
+                },
+            }
+        };
+        staged.commit()?;
+        drop(lock);
+        self.state.restored_as_of = as_of;
+        Ok(())
+    }
+
+    /// Count and total size of local snapshots, which sync backend hasn't confirmed yet
+    pub fn unconfirmed_snapshots(&mut self) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        // only headers are read, blocking read is short
+        let info = journal::Journal::try_from(self.journal_path.as_path())?.info()?;
+        Ok(self.state.unconfirmed_snapshots(&info))
+    }
+
+    /// Fetch last snapshot id seen by sync backend
+    async fn get_backend_current_snapshot(
+        &self,
+        url: &str,
+        client_id: Option<&str>,
+        secret: Option<&str>,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+        let res = self
+            .request(Method::HEAD, url, client_id, secret)?
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        match res.headers().get("x-snapshot-id") {
+            Some(value) if value.to_str()?.is_empty() => Ok(None),
+            Some(value) => Ok(Some(value.to_str()?.parse()?)),
+            None => Err("backend didn't return x-snapshot-id".into()),
+        }
+    }
+
+    /// Build request with configured user agent, extra headers and authorization
+    fn request(
+        &self,
+        method: Method,
+        url: &str,
+        client_id: Option<&str>,
+        secret: Option<&str>,
+    ) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+        let mut req = self
+            .client
+            .request(method, url)
+            .header("User-Agent", self.state.user_agent());
+        if let Some(headers) = self.state.get_key("headers") {
+            for (name, value) in parse_headers(&headers)? {
+                req = req.header(name, value);
+            }
+        }
+        if let Some(b) = basic_auth_header(client_id, secret) {
+            req = req.header("Authorization", b)
+        }
+        Ok(req)
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncReplicatorHandle {
+    tx: UnboundedSender<Message>,
+    handle: Option<JoinHandle<()>>,
+    status: Arc<ReplicatorStatus>,
+    join_timeout: Duration,
+}
+
+impl Drop for AsyncReplicatorHandle {
+    fn drop(&mut self) {
+        self.tx.send(Message::Quit).ok();
+        if let Some(handle) = self.handle.take() {
+            // replicator can be stuck in network call, database close shouldn't hang on it
+            if utils::join_with_timeout(handle, self.join_timeout).is_err() {
+                log::warn!(
+                    "replicator thread didn't quit within {:?}, detaching",
+                    self.join_timeout
+                );
+            }
+        }
+    }
+}
+
+impl AsyncReplicatorHandle {
+    fn new(
+        tx: UnboundedSender<Message>,
+        handle: Option<JoinHandle<()>>,
+        status: Arc<ReplicatorStatus>,
+        join_timeout: Duration,
+    ) -> Self {
+        Self {
+            tx,
+            handle,
+            status,
+            join_timeout,
+        }
+    }
+
+    pub fn new_snapshot(&mut self) {
+        self.tx.send(Message::NewLocalSnapshot).ok();
+    }
+
+    /// Replicator status, updated by replicator thread
+    pub fn status(&self) -> Arc<ReplicatorStatus> {
+        Arc::clone(&self.status)
+    }
+
+    /// Wait for sync backend to lag less than `max` snapshots behind `snapshot_counter` of local
+    /// journal
+    ///
+    /// Replicator is woken up to push backlog right away. Returns `false` on timeout.
+    pub fn wait_for_backend(&mut self, snapshot_counter: u64, max: u64, timeout: Duration) -> bool {
+        let poll = BACKPRESSURE_POLL_INTERVAL;
+        if self
+            .status
+            .wait_remote_within(snapshot_counter, max, poll, Duration::ZERO)
+        {
+            return true;
+        }
+        self.new_snapshot();
+        self.status
+            .wait_remote_within(snapshot_counter, max, poll, timeout)
+    }
+}
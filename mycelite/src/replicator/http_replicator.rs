@@ -2,52 +2,50 @@
 //!
 //! ** For demo use only! **
 
-use crate::config::{Config, ConfigRegistry};
-use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
-use journal::{Journal, Protocol, Stream};
-use serde_sqlite::de;
-use std::io::{Seek, SeekFrom, Write};
+use super::{
+    basic_auth_header, Message, ReplicatorState, IDEMPOTENCY_KEY_HEADER, RESTORE_LOCK_TIMEOUT,
+};
+use crate::config::{parse_headers, BACKPRESSURE_POLL_INTERVAL};
+use journal::{Journal, Stream, JOURNAL_VERSION};
 use std::path::Path;
 use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 use std::thread::JoinHandle;
-
-enum Message {
-    /// New snapshot added locally
-    NewLocalSnapshot,
-    /// Notification from ReplicatorHandle about closed DB File
-    Quit,
-}
+use std::time::Duration;
+use utils::{ReplicatorStatus, StagedFile};
 
 pub struct Replicator {
-    database_path: String,
     journal: Journal,
-    read_only: bool,
-    lock: Arc<Mutex<()>>,
-    config: Arc<Mutex<Config>>,
+    state: ReplicatorState,
 }
 
 impl Replicator {
+    /// Create replicator over journal at `journal_path`
+    ///
+    /// Reader, opened before first sync, creates empty journal, which is populated by pulls.
     pub fn new<P: AsRef<Path>>(
         journal_path: P,
         database_path: String,
         read_only: bool,
-        lock: Arc<Mutex<()>>,
-    ) -> Self {
-        let config = ConfigRegistry::new().get(database_path.as_str());
-        Self {
-            journal: Journal::try_from(journal_path).unwrap(),
-            database_path,
-            read_only,
-            lock,
-            config,
-        }
+        lock: Arc<RwLock<()>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let journal = match Journal::try_from(journal_path.as_ref()) {
+            Ok(journal) => journal,
+            Err(e) if e.journal_not_exists() && read_only => Journal::create(journal_path)?,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            journal,
+            state: ReplicatorState::new(database_path, read_only, lock),
+        })
     }
 
     pub fn spawn(mut self) -> ReplicatorHandle {
         let (tx, mut rx) = channel();
+        let status = Arc::clone(&self.state.status);
+        let join_timeout = self.state.replicator_join_timeout();
         let local_h = Some(std::thread::spawn(move || self.enter_loop(&mut rx)));
-        ReplicatorHandle::new(tx, local_h)
+        ReplicatorHandle::new(tx, local_h, status, join_timeout)
     }
 
     /// local loop
@@ -56,18 +54,27 @@ impl Replicator {
     /// snapshot
     fn enter_loop(&mut self, rx: &mut Receiver<Message>) {
         loop {
-            match self.read_only {
-                true => {
-                    match self.maybe_pull_snapshots() {
-                        Ok((last, new)) if last < new => {
-                            self.restore_latest_snapshot().ok();
+            match (self.state.replication_enabled(), self.state.read_only) {
+                // replication is paused, backlog is pushed from backend snapshot id on resume
+                (false, _) => (),
+                (true, true) => {
+                    let pulled = self.maybe_pull_snapshots();
+                    let result = match self.state.pulled(pulled) {
+                        Ok(true) => {
+                            let restored = self.restore_latest_snapshot();
+                            self.state.restored(restored)
                         }
-                        Ok(_) => (),
-                        Err(_e) => (),
+                        Ok(false) => Ok(()),
+                        Err(e) => Err(e),
                     };
+                    self.state
+                        .status
+                        .set_last_error(result.err().map(|e| e.to_string()));
                 }
-                false => {
-                    self.maybe_push_snapshots().ok();
+                (true, false) => {
+                    let result = self.maybe_push_snapshots();
+                    let unconfirmed = self.unconfirmed_snapshots();
+                    self.state.pushed(result, unconfirmed);
                 }
             }
             match rx.recv_timeout(std::time::Duration::from_secs(5)) {
@@ -87,13 +94,13 @@ impl Replicator {
             None => return Ok(()),
             Some(v) => v,
         };
-        let url = match self.get_url() {
+        let url = match self.state.get_url()? {
             Some(url) => url,
             None => return Ok(()),
         };
         // snapshot push always requires authorization (for now)
-        let client_id = self.get_key("client_id");
-        let secret = self.get_key("secret");
+        let client_id = self.state.get_key("client_id");
+        let secret = self.state.get_key("secret");
         if client_id.is_none() || secret.is_none() {
             return Ok(());
         };
@@ -103,26 +110,38 @@ impl Replicator {
             secret.as_deref(),
         ) {
             Ok(Some(v)) if v >= local_snapshot_id => {
+                self.state.status.set_ids(Some(local_snapshot_id), Some(v));
                 return Ok(());
             }
             Ok(Some(v)) => v,
             Ok(None) => 0,
             Err(_) => return Err("error".into()),
         };
+        self.state
+            .status
+            .set_ids(Some(local_snapshot_id), Some(remote_snapshot_id));
 
-        let mut req = ureq::post(&url);
-        if let Some(b) = self.get_basic_auth_header(client_id.as_deref(), secret.as_deref()) {
-            req = req.set("Authorization", &b)
-        }
+        let req = self
+            .request("POST", &url, client_id.as_deref(), secret.as_deref())?
+            .set(
+                IDEMPOTENCY_KEY_HEADER,
+                &self
+                    .state
+                    .idempotency_key(remote_snapshot_id, local_snapshot_id),
+            );
 
-        let version = self.journal.get_header().version;
+        // remote id is snapshot counter of backend, i.e. id of first snapshot backend lacks
+        let confirmed_remote = remote_snapshot_id.checked_sub(1);
         let stream = Stream::from((
-            version,
-            self.journal.into_iter().skip_snapshots(remote_snapshot_id),
+            JOURNAL_VERSION,
+            self.journal.iter_unpushed(confirmed_remote),
         ));
 
         // FIXME: status code are not checked
         req.send(stream)?;
+        self.state
+            .status
+            .set_ids(Some(local_snapshot_id), Some(local_snapshot_id));
         Ok(())
     }
 
@@ -131,77 +150,79 @@ impl Replicator {
         &mut self,
     ) -> Result<(Option<u64>, Option<u64>), Box<dyn std::error::Error>> {
         let local_snapshot_id = self.journal.current_snapshot();
-        let url = match self.get_url() {
+        let url = match self.state.get_url()? {
             Some(url) => url,
             None => return Ok((local_snapshot_id, local_snapshot_id)),
         };
 
-        let client_id = self.get_key("client_id");
-        let secret = self.get_key("secret");
+        let client_id = self.state.get_key("client_id");
+        let secret = self.state.get_key("secret");
 
-        match self.get_backend_current_snapshot(&url, client_id.as_deref(), secret.as_deref())? {
+        let remote_snapshot_id =
+            self.get_backend_current_snapshot(&url, client_id.as_deref(), secret.as_deref())?;
+        self.state
+            .status
+            .set_ids(local_snapshot_id, remote_snapshot_id);
+        match remote_snapshot_id {
             Some(v) if local_snapshot_id < Some(v) => (),
             v => return Ok((local_snapshot_id, v)),
         };
 
-        let mut req =
-            ureq::get(&url).query("snapshot-id", &local_snapshot_id.unwrap_or(0).to_string());
-
-        if let Some(b) = self.get_basic_auth_header(client_id.as_deref(), secret.as_deref()) {
-            req = req.set("Authorization", &b)
-        }
-        let res = req.call()?;
+        let res = self
+            .request("GET", &url, client_id.as_deref(), secret.as_deref())?
+            .query(
+                "snapshot-id",
+                &super::pull_from(local_snapshot_id).to_string(),
+            )
+            .call()?;
 
-        let mut reader = res.into_reader();
-
-        match de::from_reader::<Protocol, _>(&mut reader)? {
-            Protocol::JournalVersion(v) if v == 1_u32.into() => (),
-            Protocol::JournalVersion(v) => {
-                return Err(format!("unexpected journal version: {v:?}").into())
-            }
-            _ => return Err("expected version header".into()),
-        };
-        loop {
-            match de::from_reader::<Protocol, _>(&mut reader)? {
-                Protocol::SnapshotHeader(snapshot_header) => {
-                    self.journal.commit()?;
-                    self.journal.add_snapshot(&snapshot_header)?
-                }
-                Protocol::BlobHeader(blob_header) => {
-                    let mut blob = vec![0; blob_header.blob_size as usize];
-                    reader.read_exact(blob.as_mut_slice())?;
-                    self.journal.add_blob(&blob_header, blob.as_slice())?;
-                }
-                Protocol::EndOfStream(_) => {
-                    self.journal.commit()?;
-                    break;
-                }
-                Protocol::JournalVersion(_) => return Err("version header was not expected".into()),
-            }
-        }
+        // last local snapshot is pulled again, so divergence of backend history is detected
+        self.journal
+            .apply_stream(res.into_reader())
+            .map_err(super::pull_error)?;
+        self.state
+            .status
+            .set_ids(self.journal.current_snapshot(), remote_snapshot_id);
         Ok((local_snapshot_id, self.journal.current_snapshot()))
     }
 
-    // FIXME: move to journal API
     // FIXME: snapshot is recovered from scratch each time
+    // database is restored as of `restore_as_of_snapshot` instead of latest snapshot, if it's set
     fn restore_latest_snapshot(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let lock = self.lock.lock().map_err(|_e| "failed to lock")?;
-        let mut output = std::io::BufWriter::with_capacity(
-            0x0010_0000,
-            std::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&self.database_path)?,
+        let as_of = self.state.restore_as_of_snapshot();
+        // database is exported into staging file first, so failed export leaves database intact
+        let staged = StagedFile::new(
+            &self.state.database_path,
+            self.state.restore_tmp_dir().as_deref(),
         );
-        for data in self.journal.into_iter() {
-            let (_snapshot_header, page_header, page) = data?;
-            output.seek(SeekFrom::Start(page_header.offset))?;
-            output.write_all(&page)?;
+        let mut output = std::io::BufWriter::with_capacity(0x0010_0000, staged.create()?);
+        match as_of {
+            Some(snapshot_id) => self
+                .journal
+                .export_database_as_of(&mut output, snapshot_id)?,
+            None => self.journal.export_database(&mut output)?,
         }
+        // volatile header fields might not be journaled
+        let counter = as_of
+            .map(|snapshot_id| snapshot_id + 1)
+            .or(self.journal.current_snapshot())
+            .unwrap_or(0);
+        page_parser::write_change_counter(&mut output, counter as u32)?;
+        output.into_inner()?.sync_all()?;
+        // database can be concurrently written by sqlite, restore is retried on next iteration
+        let lock = utils::write_with_backoff(&self.state.lock, RESTORE_LOCK_TIMEOUT)?;
+        staged.commit()?;
         drop(lock);
+        self.state.restored_as_of = as_of;
         Ok(())
     }
 
+    /// Count and total size of local snapshots, which sync backend hasn't confirmed yet
+    pub fn unconfirmed_snapshots(&mut self) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        let info = self.journal.info()?;
+        Ok(self.state.unconfirmed_snapshots(&info))
+    }
+
     /// Fetch last snapshot id seen by sync backend
     fn get_backend_current_snapshot(
         &self,
@@ -209,12 +230,10 @@ impl Replicator {
         client_id: Option<&str>,
         secret: Option<&str>,
     ) -> Result<Option<u64>, Box<dyn std::error::Error>> {
-        let mut req = ureq::head(url).timeout(std::time::Duration::from_secs(5));
-
-        if let Some(b) = self.get_basic_auth_header(client_id, secret) {
-            req = req.set("Authorization", &b)
-        }
-        let res = req.call()?;
+        let res = self
+            .request("HEAD", url, client_id, secret)?
+            .timeout(std::time::Duration::from_secs(5))
+            .call()?;
 
         match res.header("x-snapshot-id") {
             Some(value) if value.is_empty() => Ok(None),
@@ -223,30 +242,24 @@ impl Replicator {
         }
     }
 
-    fn get_key(&self, key: &str) -> Option<String> {
-        self.config.lock().unwrap().get(key).map(|s| s.to_owned())
-    }
-
-    fn get_url(&self) -> Option<String> {
-        if let (Some(endpoint), Some(domain)) = (self.get_key("endpoint"), self.get_key("domain")) {
-            return Some(format!("{endpoint}/domain/{domain}"));
-        }
-        None
-    }
-
-    fn get_basic_auth_header(
+    /// Build request with configured user agent, extra headers and authorization
+    fn request(
         &self,
+        method: &str,
+        url: &str,
         client_id: Option<&str>,
         secret: Option<&str>,
-    ) -> Option<String> {
-        if let (Some(client_id), Some(secret)) = (client_id, secret) {
-            return Some(format!(
-                "Basic {}",
-                BASE64.encode(format!("{client_id}:{secret}"))
-            ));
-        } else {
-            None
+    ) -> Result<ureq::Request, Box<dyn std::error::Error>> {
+        let mut req = ureq::request(method, url).set("User-Agent", &self.state.user_agent());
+        if let Some(headers) = self.state.get_key("headers") {
+            for (name, value) in parse_headers(&headers)? {
+                req = req.set(name, value);
+            }
+        }
+        if let Some(b) = basic_auth_header(client_id, secret) {
+            req = req.set("Authorization", &b)
         }
+        Ok(req)
     }
 }
 
@@ -254,21 +267,63 @@ impl Replicator {
 pub struct ReplicatorHandle {
     tx: Sender<Message>,
     handle: Option<JoinHandle<()>>,
+    status: Arc<ReplicatorStatus>,
+    join_timeout: std::time::Duration,
 }
 
 impl Drop for ReplicatorHandle {
     fn drop(&mut self) {
         self.tx.send(Message::Quit).ok();
-        self.handle.take().map(|h| h.join());
+        if let Some(handle) = self.handle.take() {
+            // replicator can be stuck in network call, database close shouldn't hang on it
+            if utils::join_with_timeout(handle, self.join_timeout).is_err() {
+                log::warn!(
+                    "replicator thread didn't quit within {:?}, detaching",
+                    self.join_timeout
+                );
+            }
+        }
     }
 }
 
 impl ReplicatorHandle {
-    fn new(tx: Sender<Message>, handle: Option<JoinHandle<()>>) -> Self {
-        Self { tx, handle }
+    fn new(
+        tx: Sender<Message>,
+        handle: Option<JoinHandle<()>>,
+        status: Arc<ReplicatorStatus>,
+        join_timeout: std::time::Duration,
+    ) -> Self {
+        Self {
+            tx,
+            handle,
+            status,
+            join_timeout,
+        }
     }
 
     pub fn new_snapshot(&mut self) {
         self.tx.send(Message::NewLocalSnapshot).ok();
     }
+
+    /// Replicator status, updated by replicator thread
+    pub fn status(&self) -> Arc<ReplicatorStatus> {
+        Arc::clone(&self.status)
+    }
+
+    /// Wait for sync backend to lag less than `max` snapshots behind `snapshot_counter` of local
+    /// journal
+    ///
+    /// Replicator is woken up to push backlog right away. Returns `false` on timeout.
+    pub fn wait_for_backend(&mut self, snapshot_counter: u64, max: u64, timeout: Duration) -> bool {
+        let poll = BACKPRESSURE_POLL_INTERVAL;
+        if self
+            .status
+            .wait_remote_within(snapshot_counter, max, poll, Duration::ZERO)
+        {
+            return true;
+        }
+        self.new_snapshot();
+        self.status
+            .wait_remote_within(snapshot_counter, max, poll, timeout)
+    }
 }
@@ -1,5 +1,250 @@
-#[cfg_attr(not(feature = "replicator"), path = "noop_replicator.rs")]
-#[cfg_attr(feature = "replicator", path = "http_replicator.rs")]
+#[cfg_attr(
+    not(any(feature = "replicator", feature = "async_replicator")),
+    path = "noop_replicator.rs"
+)]
+#[cfg_attr(
+    all(feature = "replicator", not(feature = "async_replicator")),
+    path = "http_replicator.rs"
+)]
+#[cfg_attr(feature = "async_replicator", path = "async_replicator.rs")]
 mod replicator_impl;
 
 pub use replicator_impl::*;
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+use crate::config::{Config, ConfigRegistry};
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+use std::path::PathBuf;
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+use std::sync::{Arc, Mutex, RwLock};
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+use std::time::Duration;
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+use utils::ReplicatorStatus;
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+const DEFAULT_USER_AGENT: &str = concat!("mycelite/", env!("CARGO_PKG_VERSION"));
+
+/// Header, which identifies pushed snapshot range, so retried push can be deduplicated by backend
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+const IDEMPOTENCY_KEY_HEADER: &str = "X-Idempotency-Key";
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+const RESTORE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+enum Message {
+    /// New snapshot added locally
+    NewLocalSnapshot,
+    /// Notification from ReplicatorHandle about closed DB File
+    Quit,
+}
+
+/// Replication errors, which are neither journal nor transport errors
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+#[derive(Debug)]
+pub enum ReplicatorError {
+    /// Sync backend has snapshot `id`, which differs from local snapshot `id`, i.e. backend got
+    /// history of another writer, pulled snapshots are not applied
+    Divergence { id: u64 },
+}
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+impl std::fmt::Display for ReplicatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Divergence { id } => write!(
+                f,
+                "history of sync backend diverged from local journal at snapshot {id}"
+            ),
+        }
+    }
+}
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+impl std::error::Error for ReplicatorError {}
+
+/// Convert error of applying pulled stream, journal divergence is reported as
+/// [`ReplicatorError::Divergence`]
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+fn pull_error(e: journal::Error) -> Box<dyn std::error::Error> {
+    match e {
+        journal::Error::SnapshotDivergence { id } => ReplicatorError::Divergence { id }.into(),
+        e => e.into(),
+    }
+}
+
+/// Snapshot id, pull starts from, so last local snapshot is pulled again and checked against
+/// backend, see [`ReplicatorError::Divergence`]
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+fn pull_from(local_snapshot_id: Option<u64>) -> u64 {
+    local_snapshot_id.map_or(0, |id| id - 1)
+}
+
+/// check if failed replication step is worth retrying
+///
+/// journal errors are classified by [`journal::Error::is_retryable`], divergence never resolves
+/// itself, other errors, i.e. network or lock timeouts, are assumed transient.
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+fn is_retryable(e: &(dyn std::error::Error + 'static)) -> bool {
+    if e.is::<ReplicatorError>() {
+        return false;
+    }
+    match e.downcast_ref::<journal::Error>() {
+        Some(e) => e.is_retryable(),
+        None => true,
+    }
+}
+
+/// Transport independent state of replicator: config, status and progress of restore
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+struct ReplicatorState {
+    database_path: String,
+    read_only: bool,
+    lock: Arc<RwLock<()>>,
+    config: Arc<Mutex<Config>>,
+    /// pulled snapshots are not yet restored into database
+    restore_pending: bool,
+    status: Arc<ReplicatorStatus>,
+    /// snapshot id, as of which database was last restored, if not latest
+    restored_as_of: Option<u64>,
+    /// partial endpoint config was already reported
+    missing_domain_warned: bool,
+}
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+impl ReplicatorState {
+    fn new(database_path: String, read_only: bool, lock: Arc<RwLock<()>>) -> Self {
+        let config = ConfigRegistry::new().get(database_path.as_str());
+        Self {
+            database_path,
+            read_only,
+            lock,
+            config,
+            restore_pending: false,
+            status: Arc::new(ReplicatorStatus::new()),
+            restored_as_of: None,
+            missing_domain_warned: false,
+        }
+    }
+
+    /// Handle result of pull, returns whether database needs to be restored
+    ///
+    /// database is restored anew, once new snapshots are pulled or point in time is changed
+    fn pulled(
+        &mut self,
+        result: Result<(Option<u64>, Option<u64>), Box<dyn std::error::Error>>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.restored_as_of != self.restore_as_of_snapshot() {
+            self.restore_pending = true;
+        }
+        let (last, new) = result?;
+        if last < new {
+            self.restore_pending = true;
+        }
+        Ok(self.restore_pending)
+    }
+
+    /// Handle result of restore
+    fn restored(
+        &mut self,
+        result: Result<(), Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // fatal error, e.g. corrupted journal, fails again, restore is retried
+        // once new snapshots are pulled
+        self.restore_pending = match result.as_ref() {
+            Err(e) => is_retryable(e.as_ref()),
+            Ok(_) => false,
+        };
+        result
+    }
+
+    /// Report result of push and local snapshots, which are not yet confirmed by backend
+    fn pushed(
+        &self,
+        result: Result<(), Box<dyn std::error::Error>>,
+        unconfirmed: Result<(u64, u64), Box<dyn std::error::Error>>,
+    ) {
+        self.status.set_last_push_ok(result.is_ok());
+        if let Ok((snapshots, bytes)) = unconfirmed {
+            self.status.set_unconfirmed(snapshots, bytes);
+        }
+        self.status
+            .set_last_error(result.err().map(|e| e.to_string()));
+    }
+
+    /// Count and total size of snapshots of `info`, which sync backend hasn't confirmed yet
+    fn unconfirmed_snapshots(&self, info: &journal::JournalInfo) -> (u64, u64) {
+        // remote id is snapshot counter of backend, i.e. id of first snapshot backend lacks
+        let remote_id = self.status.get().remote_id.unwrap_or(0);
+        info.snapshots
+            .iter()
+            .filter(|snapshot| snapshot.id >= remote_id)
+            .fold((0, 0), |(count, bytes), snapshot| {
+                (count + 1, bytes + snapshot.bytes)
+            })
+    }
+
+    /// Idempotency key of push of snapshots from `from` up to `to`, exclusive
+    fn idempotency_key(&self, from: u64, to: u64) -> String {
+        let domain = self.get_key("domain").unwrap_or_default();
+        format!("{domain}:{from}:{to}")
+    }
+
+    fn user_agent(&self) -> String {
+        self.get_key("user_agent")
+            .unwrap_or_else(|| DEFAULT_USER_AGENT.into())
+    }
+
+    fn restore_tmp_dir(&self) -> Option<PathBuf> {
+        self.config.lock().unwrap().restore_tmp_dir()
+    }
+
+    fn restore_as_of_snapshot(&self) -> Option<u64> {
+        self.config.lock().unwrap().restore_as_of_snapshot()
+    }
+
+    fn replication_enabled(&self) -> bool {
+        self.config.lock().unwrap().replication_enabled()
+    }
+
+    fn replicator_join_timeout(&self) -> Duration {
+        self.config.lock().unwrap().replicator_join_timeout()
+    }
+
+    fn get_key(&self, key: &str) -> Option<String> {
+        self.config.lock().unwrap().get(key).map(|s| s.to_owned())
+    }
+
+    /// Backend url, replication is disabled with an error if endpoint is set without domain
+    fn get_url(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        match (self.get_key("endpoint"), self.get_key("domain")) {
+            (Some(endpoint), Some(domain)) => {
+                self.missing_domain_warned = false;
+                Ok(Some(format!("{endpoint}/domain/{domain}")))
+            }
+            (Some(_), None) => {
+                let error = "replication disabled: missing config key 'domain'";
+                if !self.missing_domain_warned {
+                    self.missing_domain_warned = true;
+                    log::warn!("{error}");
+                }
+                Err(error.into())
+            }
+            (None, _) => Ok(None),
+        }
+    }
+}
+
+#[cfg(any(feature = "replicator", feature = "async_replicator"))]
+fn basic_auth_header(client_id: Option<&str>, secret: Option<&str>) -> Option<String> {
+    match (client_id, secret) {
+        (Some(client_id), Some(secret)) => Some(format!(
+            "Basic {}",
+            BASE64.encode(format!("{client_id}:{secret}"))
+        )),
+        _ => None,
+    }
+}
@@ -1,5 +1,7 @@
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use utils::ReplicatorStatus;
 
 pub struct Replicator {}
 
@@ -8,9 +10,9 @@ impl Replicator {
         _journal_path: P,
         _database_path: String,
         _read_only: bool,
-        _lock: Arc<Mutex<()>>,
-    ) -> Self {
-        Self {}
+        _lock: Arc<RwLock<()>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {})
     }
 
     pub fn spawn(self) -> ReplicatorHandle {
@@ -22,4 +24,13 @@ pub struct ReplicatorHandle {}
 
 impl ReplicatorHandle {
     pub fn new_snapshot(&self) {}
+
+    /// There's no sync backend, writer never waits
+    pub fn wait_for_backend(&self, _snapshot_counter: u64, _max: u64, _timeout: Duration) -> bool {
+        true
+    }
+
+    pub fn status(&self) -> Arc<ReplicatorStatus> {
+        Arc::new(ReplicatorStatus::new())
+    }
 }
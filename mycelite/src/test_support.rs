@@ -0,0 +1,142 @@
+//! Test support: real sqlite connections with compiled mycelite extension loaded
+//!
+//! Extension is loaded from path in `MYCELITE_EXTENSION` env var, or from cargo target dir of
+//! test executable. VFS registration is process wide, extension is loaded once.
+
+use journal::{Journal, JournalLocator, SidecarLocator};
+use once_cell::sync::OnceCell;
+use rusqlite::{params, Connection, LoadExtensionGuard, OpenFlags};
+use std::path::{Path, PathBuf};
+
+/// Env var with path of compiled extension
+pub const EXTENSION_ENV: &str = "MYCELITE_EXTENSION";
+
+pub const WRITER_VFS: &str = "mycelite_writer";
+pub const READER_VFS: &str = "mycelite_reader";
+
+/// Path of compiled extension
+pub fn extension_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(EXTENSION_ENV) {
+        return path.into();
+    }
+    // test executable lives in target/<profile>/deps, extension in target/<profile>, or in deps
+    // dir, if mycelite is built as dependency of other crate
+    let exe = std::env::current_exe().expect("test executable path");
+    let deps = exe.parent().expect("cargo target dir");
+    let name = format!(
+        "{}mycelite{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    );
+    let path = deps.parent().expect("cargo target dir").join(&name);
+    match path.exists() {
+        true => path,
+        false => deps.join(name),
+    }
+}
+
+/// Load extension into process, registering mycelite VFSes
+pub fn load_extension() -> rusqlite::Result<()> {
+    static LOADED: OnceCell<()> = OnceCell::new();
+    LOADED
+        .get_or_try_init(|| {
+            // extension stays loaded after connection is closed
+            let conn = Connection::open_in_memory()?;
+            unsafe {
+                let _guard = LoadExtensionGuard::new(&conn)?;
+                conn.load_extension(extension_path(), None)
+            }
+        })
+        .map(|_| ())
+}
+
+/// Database in temporary directory, opened through mycelite VFSes
+#[derive(Debug)]
+pub struct TestDatabase {
+    // directory is removed on drop
+    dir: tempfile::TempDir,
+    path: PathBuf,
+}
+
+impl TestDatabase {
+    pub fn new() -> Self {
+        load_extension().expect("failed to load mycelite extension");
+        let dir = tempfile::tempdir().expect("failed to create temporary dir");
+        let path = dir.path().join("db.sqlite");
+        Self { dir, path }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn journal_path(&self) -> PathBuf {
+        SidecarLocator::DEFAULT.journal_path(self.path())
+    }
+
+    /// Temporary dir, database and its journal are in
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Open connection through writer VFS
+    pub fn writer(&self) -> rusqlite::Result<Connection> {
+        self.open(WRITER_VFS)
+    }
+
+    /// Open connection through reader VFS
+    pub fn reader(&self) -> rusqlite::Result<Connection> {
+        self.open(READER_VFS)
+    }
+
+    /// Open connection to database at `path` through writer VFS, e.g. in child process
+    pub fn writer_at(path: &Path) -> rusqlite::Result<Connection> {
+        load_extension()?;
+        Self::open_at(path, WRITER_VFS)
+    }
+
+    fn open(&self, vfs: &str) -> rusqlite::Result<Connection> {
+        Self::open_at(self.path(), vfs)
+    }
+
+    /// Open connection to database at `path` through VFS named `vfs`
+    pub fn open_at(path: &Path, vfs: &str) -> rusqlite::Result<Connection> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE;
+        let conn = Connection::open_with_flags_and_vfs(path, flags, vfs)?;
+        // config vtab is registered per connection
+        unsafe {
+            let _guard = LoadExtensionGuard::new(&conn)?;
+            conn.load_extension(extension_path(), Some("mycelite_config"))?;
+        }
+        Ok(conn)
+    }
+
+    /// Set config key through config vtab of connection
+    pub fn configure(conn: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO mycelite_config(key, value) VALUES (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Open journal of database
+    pub fn journal(&self) -> Result<Journal, journal::Error> {
+        Journal::try_from(self.journal_path())
+    }
+
+    /// Count of snapshots in journal, zero if there's no journal yet
+    pub fn snapshot_count(&self) -> u64 {
+        match self.journal() {
+            Ok(journal) => journal.current_snapshot().unwrap_or(0),
+            Err(e) if e.journal_not_exists() => 0,
+            Err(e) => panic!("failed to open journal: {e}"),
+        }
+    }
+}
+
+impl Default for TestDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
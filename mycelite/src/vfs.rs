@@ -1,11 +1,155 @@
+use crate::config::{
+    Config, ConfigRegistry, BACKPRESSURE_TIMEOUT, DEFAULT_FULL_PAGE_THRESHOLD_PCT,
+};
 use crate::replicator;
-use journal::Journal;
+use journal::{Journal, JournalLocator, SidecarLocator};
 use libsqlite_sys::c_str;
 use libsqlite_sys::ffi;
-use std::ffi::{c_char, c_int, c_void, CStr};
+use once_cell::sync::OnceCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
 use std::mem;
+use std::ops::Deref;
+use std::path::Path;
 use std::ptr;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, TryLockError};
+use std::time::{Duration, Instant};
+use utils::{Debounce, GapDiffer, LruRegistry, PendingPage, PendingPages};
+
+/// Env var with suffix of registered VFS names, e.g. `mycelite_writer<suffix>`
+///
+/// Allows several copies of extension to be loaded into one process under distinct names.
+const VFS_NAME_SUFFIX_ENV: &str = "MYCELITE_VFS_NAME_SUFFIX";
+
+/// Pages read at once on journal bootstrap
+const BOOTSTRAP_READAHEAD_PAGES: usize = 64;
+
+/// Magic of sqlite rollback journal header
+const ROLLBACK_JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// Custom `xFileControl` opcode, stores 1 into `int` argument if writes to file are journaled, 0
+/// otherwise
+///
+/// Value is outside of range of sqlite's own `SQLITE_FCNTL_*` opcodes.
+pub const MYCELITE_FCNTL_IS_JOURNALED: c_int = 0x4d43_4c01;
+
+/// Custom `xFileControl` opcode, stores count and total size of local snapshots, which sync
+/// backend hasn't confirmed yet, into `[u64; 2]` argument
+///
+/// Counts are updated by writer's replicator after each push attempt, `SQLITE_NOTFOUND` is
+/// returned for file without journal.
+pub const MYCELITE_FCNTL_UNCONFIRMED_SNAPSHOTS: c_int = 0x4d43_4c02;
+
+/// Databases open in process, keyed by VFS mode and database path
+///
+/// Entries are removed once last connection to database is closed, so unused entries aren't kept.
+static DATABASE_REGISTRY: Mutex<LruRegistry<DatabaseSlot>> = Mutex::new(LruRegistry::new(0));
+
+/// Registry entry of database, database is opened once per entry, outside of registry lock
+type DatabaseSlot = OnceCell<SharedDatabase>;
+
+/// Journal, lock and replicator of database, shared by all connections to database file
+///
+/// Every connection opens its own `MclVFSFile`, per-file journal wouldn't serialize writes of
+/// different connections to the same journal. Writes of connections are serialized by sqlite's
+/// own file locks, `lock` only keeps replicator from restoring database under connections: each
+/// connection holds it for reading, while sqlite holds any lock on database file.
+///
+/// Pending pages are shared too: with debounced syncs they outlive transaction, so snapshot
+/// always holds net change of all connections.
+struct SharedDatabase {
+    key: String,
+    journal: Mutex<Journal>,
+    lock: Arc<RwLock<()>>,
+    replicator: Mutex<replicator::ReplicatorHandle>,
+    /// pages written since last snapshot, journaled on sync
+    pending: Mutex<PendingPages>,
+    debounce: Mutex<Debounce>,
+    /// database page size, zero if not known yet
+    page_size: AtomicUsize,
+}
+
+impl SharedDatabase {
+    /// Get database from registry, opening it on first access
+    ///
+    /// Registry is locked only to look up database entry, so open of one database doesn't hold
+    /// up opens of the others. Concurrent opens of the same database wait for the first one,
+    /// failed open is retried by next connection.
+    fn get(
+        key: &str,
+        open: impl FnOnce() -> Result<Self, Box<dyn std::error::Error>>,
+    ) -> Result<DatabaseRef, Box<dyn std::error::Error>> {
+        let slot = DATABASE_REGISTRY
+            .lock()
+            .unwrap()
+            .get_or_insert_with(key, OnceCell::new);
+        slot.get_or_try_init(open)?;
+        Ok(DatabaseRef(slot))
+    }
+
+    /// Database page size, as of write of `buf` at `offset`, `None` if database has no header yet
+    ///
+    /// Page size is cached, cache is refreshed by database header writes, since page size is
+    /// changed only by rewrite of the first page, e.g. by vacuum.
+    unsafe fn page_size(
+        &self,
+        pfile: *mut ffi::sqlite3_file,
+        buf: &[u8],
+        offset: ffi::sqlite_int64,
+    ) -> Option<usize> {
+        // database header size
+        let mut header = [0_u8; 100];
+        let header = match (offset, self.page_size.load(Ordering::Relaxed)) {
+            (0, _) if buf.len() >= header.len() => &buf[..header.len()],
+            (_, 0) => {
+                let len = header.len() as c_int;
+                match MclVFSIO.xRead.unwrap()(pfile, header.as_mut_ptr().cast(), len, 0) {
+                    ffi::SQLITE_OK => &header[..],
+                    _ => return None,
+                }
+            }
+            (_, page_size) => return Some(page_size),
+        };
+        let page_size = serde_sqlite::from_bytes::<page_parser::Header>(header)
+            .ok()?
+            .page_size() as usize;
+        match page_size.is_power_of_two() && page_size >= 512 {
+            true => {
+                self.page_size.store(page_size, Ordering::Relaxed);
+                Some(page_size)
+            }
+            false => None,
+        }
+    }
+}
+
+/// Connection's reference to opened database in registry
+struct DatabaseRef(Arc<DatabaseSlot>);
+
+impl DatabaseRef {
+    /// Release connection's reference, database is removed from registry by last connection
+    fn release(self) {
+        let mut registry = DATABASE_REGISTRY.lock().unwrap();
+        // the other reference is held by registry, references are only taken under its lock
+        let removed = match Arc::strong_count(&self.0) {
+            2 => registry.remove(&self.key),
+            _ => None,
+        };
+        // replicator is joined on drop, registry shouldn't be locked meanwhile
+        drop(registry);
+        drop(self);
+        drop(removed);
+    }
+}
+
+impl Deref for DatabaseRef {
+    type Target = SharedDatabase;
+
+    fn deref(&self) -> &SharedDatabase {
+        // reference is handed out only once database is opened
+        self.0.get().expect("database is opened")
+    }
+}
 
 macro_rules! vfs_vtable {
     ($name:expr) => {
@@ -43,6 +187,8 @@ macro_rules! vfs_vtable {
 pub struct MclVFS {
     base: ffi::sqlite3_vfs,
     read_only: bool,
+    // derives journal path from database path
+    locator: &'static (dyn JournalLocator + Sync),
     // initialized on extention load
     real: *mut ffi::sqlite3_vfs,
 }
@@ -52,6 +198,7 @@ pub struct MclVFS {
 pub static mut MclVFSReader: MclVFS = MclVFS {
     base: vfs_vtable!("mycelite_reader"),
     read_only: true,
+    locator: &SidecarLocator::DEFAULT,
     // initialized on extention load
     real: ptr::null_mut(),
 };
@@ -61,6 +208,7 @@ pub static mut MclVFSReader: MclVFS = MclVFS {
 pub static mut MclVFSWriter: MclVFS = MclVFS {
     base: vfs_vtable!("mycelite_writer"),
     read_only: false,
+    locator: &SidecarLocator::DEFAULT,
     // initialized on extention load
     real: ptr::null_mut(),
 };
@@ -73,6 +221,7 @@ impl MclVFS {
             self.real = real;
             self.base.szOsFile = mem::size_of::<MclVFSFile>() as c_int + (*real).szOsFile;
             self.base.mxPathname = (*real).mxPathname;
+            self.base.zName = vfs_name(self.base.zName);
         }
     }
 
@@ -98,14 +247,31 @@ impl MclVFS {
     }
 }
 
+/// VFS name with suffix from [`VFS_NAME_SUFFIX_ENV`] appended, if one is set
+///
+/// Custom name is leaked, since sqlite holds on to VFS name until process exit.
+unsafe fn vfs_name(name: *const c_char) -> *const c_char {
+    let suffix = match std::env::var(VFS_NAME_SUFFIX_ENV) {
+        Ok(suffix) if !suffix.is_empty() => suffix,
+        _ => return name,
+    };
+    let mut custom = CStr::from_ptr(name).to_bytes().to_vec();
+    custom.extend_from_slice(suffix.as_bytes());
+    match CString::new(custom) {
+        Ok(custom) => custom.into_raw(),
+        Err(_) => name,
+    }
+}
+
 #[repr(C)]
 struct MclVFSFile {
     base: ffi::sqlite3_file,
-    journal: Option<mem::ManuallyDrop<Journal>>,
+    shared: Option<mem::ManuallyDrop<DatabaseRef>>,
     read_only: bool,
-    replicator: Option<mem::ManuallyDrop<replicator::ReplicatorHandle>>,
-    mutex: Option<mem::ManuallyDrop<Arc<Mutex<()>>>>,
-    mutex_guard: Option<mem::ManuallyDrop<MutexGuard<'static, ()>>>,
+    config: Option<mem::ManuallyDrop<Arc<Mutex<Config>>>>,
+    /// lock of shared database, see [`SharedDatabase`]
+    db_lock: Option<mem::ManuallyDrop<Arc<RwLock<()>>>>,
+    db_lock_guard: Option<mem::ManuallyDrop<RwLockReadGuard<'static, ()>>>,
     vfs: *mut ffi::sqlite3_vfs,
     real: ffi::sqlite3_file,
 }
@@ -115,8 +281,14 @@ impl MclVFSFile {
     unsafe fn init(&mut self, vfs: *mut ffi::sqlite3_vfs) {
         self.vfs = vfs;
         self.read_only = MclVFS::from_raw_ptr(vfs).read_only;
-        self.mutex = Some(mem::ManuallyDrop::new(Arc::new(Mutex::new(()))));
-        self.mutex_guard = None
+        self.config = None;
+        self.db_lock = None;
+        self.db_lock_guard = None
+    }
+
+    /// Writes are captured into journal: main database, opened with writer VFS
+    fn is_journaling(&self) -> bool {
+        self.shared.is_some() && !self.read_only
     }
 
     /// downcast pfile ptr to MclVFSFile struct ptr
@@ -133,7 +305,7 @@ impl MclVFSFile {
         database_path: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let db = page_parser::Database::new(database_path);
-        let iter = match db.into_raw_page_iter() {
+        let iter = match db.into_raw_page_iter_with_readahead(BOOTSTRAP_READAHEAD_PAGES) {
             Ok(iter) => iter,
             Err(e) => {
                 if let Some(err) = e.downcast_ref::<std::io::Error>() {
@@ -157,63 +329,314 @@ impl MclVFSFile {
         Ok(journal.commit()?)
     }
 
+    /// restore database from journal
+    ///
+    /// happens if journal exists, but database file is missing or empty, i.e. database was
+    /// removed or moved without its journal.
+    fn restore_database(
+        &self,
+        journal: &mut Journal,
+        database_path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let database_size = match std::fs::metadata(database_path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if database_size != 0 || journal.current_snapshot().is_none() {
+            return Ok(());
+        }
+        let mut output = std::io::BufWriter::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(database_path)?,
+        );
+        journal.export_database(&mut output)?;
+        // volatile header fields might not be journaled
+        let counter = journal.current_snapshot().unwrap_or(0) as u32;
+        page_parser::write_change_counter(&mut output, counter)?;
+        output.into_inner()?.sync_all()?;
+        Ok(())
+    }
+
+    /// append reconciling snapshot, if journal is behind database
+    ///
+    /// happens if database was modified without mycelite VFS, e.g. by plain sqlite connection,
+    /// while journal was left behind. readers never touch journal, their journal is replicated.
+    /// returns true, if snapshot was appended.
+    fn reconcile_journal(
+        &self,
+        journal: &mut Journal,
+        database_path: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.read_only {
+            return Ok(false);
+        }
+        match std::fs::metadata(database_path) {
+            Ok(metadata) if metadata.len() != 0 => (),
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        // database holds uncommitted pages of crashed transaction, until sqlite plays back its
+        // hot rollback journal
+        if hot_journal_exists(database_path)? {
+            return Ok(false);
+        }
+        match journal.reconcile_with_database(database_path)? {
+            Some(report) => {
+                log::warn!(
+                    "journal was behind database, reconciled {} differing pages",
+                    report.differing_pages.len()
+                );
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
     fn setup_journal(
         &mut self,
         flags: c_int,
         zname: *const c_char,
     ) -> Result<(), Box<dyn std::error::Error>> {
         if flags & ffi::SQLITE_OPEN_MAIN_DB == 0 {
-            self.journal = None;
-            self.replicator = None;
+            self.shared = None;
             return Ok(());
         }
 
         let database_path = unsafe { CStr::from_ptr(zname) }.to_str()?.to_owned();
-        let journal_path = {
-            let mut s = database_path.clone();
-            s.push_str("-mycelial");
-            s
+        let journal_path = unsafe { MclVFS::from_raw_ptr(self.vfs) }
+            .locator
+            .journal_path(Path::new(&database_path));
+        let config = ConfigRegistry::new().get(database_path.as_str());
+        let mode = match self.read_only {
+            true => "reader",
+            false => "writer",
         };
-        let (journal, bootstrapped) = match Journal::try_from(&journal_path) {
-            Ok(j) => (j, false),
+        let key = format!("{mode}:{database_path}");
+        let shared = SharedDatabase::get(&key, || {
+            self.open_shared(key.clone(), &journal_path, database_path, &config)
+        })?;
+        // connections to database share one lock
+        self.db_lock = Some(mem::ManuallyDrop::new(Arc::clone(&shared.lock)));
+        self.shared = Some(mem::ManuallyDrop::new(shared));
+        self.config = Some(mem::ManuallyDrop::new(config));
+        Ok(())
+    }
+
+    /// open journal and spawn replicator, done by first connection to database
+    fn open_shared(
+        &self,
+        key: String,
+        journal_path: &Path,
+        database_path: String,
+        config: &Mutex<Config>,
+    ) -> Result<SharedDatabase, Box<dyn std::error::Error>> {
+        let retry = config.lock().unwrap().journal_open_retry();
+        let (journal, appended) = match Journal::try_from_with_retry(journal_path, retry) {
+            Ok(mut j) => {
+                // snapshot, synced within debounce window, outlives crashed writer
+                let recovered = !self.read_only && j.recover_synced_snapshot()?;
+                self.restore_database(&mut j, &database_path)?;
+                let reconciled = self.reconcile_journal(&mut j, &database_path)?;
+                (j, recovered || reconciled)
+            }
             Err(e) if e.journal_not_exists() => {
-                let mut journal = Journal::create(&journal_path)?;
+                let mut journal = Journal::create_with_retry(journal_path, retry)?;
                 self.bootstrap_journal(&mut journal, &database_path)?;
+                // no-op, unless database was empty
+                let page_size = config.lock().unwrap().default_page_size();
+                journal.set_page_size_hint(page_size)?;
                 (journal, true)
             }
             Err(e) => return Err(e.into()),
         };
-        self.journal = Some(mem::ManuallyDrop::new(journal));
 
-        let lock = Arc::clone(self.mutex.as_ref().unwrap());
-        self.replicator = Some(mem::ManuallyDrop::new(
-            replicator::Replicator::new(&journal_path, database_path, self.read_only, lock).spawn(),
-        ));
+        let lock = Arc::new(RwLock::new(()));
+        let mut replicator = replicator::Replicator::new(
+            journal_path,
+            database_path,
+            self.read_only,
+            Arc::clone(&lock),
+        )?
+        .spawn();
+        if appended {
+            replicator.new_snapshot();
+        }
+        Ok(SharedDatabase {
+            key,
+            journal: Mutex::new(journal),
+            lock,
+            replicator: Mutex::new(replicator),
+            pending: Mutex::new(PendingPages::new()),
+            debounce: Mutex::new(Debounce::new(Duration::ZERO)),
+            page_size: AtomicUsize::new(0),
+        })
+    }
 
-        if bootstrapped {
-            if let Some(r) = self.replicator.as_mut() {
-                r.new_snapshot();
+    /// journal net change of pages written since last snapshot
+    ///
+    /// writes are deferred until sync, so pages of transaction which sqlite spilled into database
+    /// file and then rolled back from its rollback journal never reach the journal.
+    ///
+    /// syncs within debounce window write their pages into the same snapshot, which is synced to
+    /// journal file but committed only once window passes, unless `force` is set.
+    fn journal_pending_pages(&mut self, force: bool) -> Result<(), journal::Error> {
+        let shared = match self.shared.as_ref() {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let (threshold, differ, window, volatile_ranges) = match self.config.as_ref() {
+            Some(config) => {
+                let config = config.lock().unwrap();
+                (
+                    config.full_page_threshold_pct(),
+                    config.page_differ(),
+                    config.snapshot_debounce(),
+                    config.volatile_ranges(),
+                )
+            }
+            None => (
+                DEFAULT_FULL_PAGE_THRESHOLD_PCT,
+                Box::new(GapDiffer) as _,
+                Duration::ZERO,
+                vec![],
+            ),
+        };
+        let mut pending = shared.pending.lock().unwrap();
+        let changed = pending.drain_changed().collect::<Vec<_>>();
+        let mut journal = shared.journal.lock().unwrap();
+        // sync of transaction without changes still bumps change counter of database header,
+        // such snapshot would be noise in journal history
+        let noise = changed.iter().all(|changed| {
+            changed.offset == 0
+                && page_parser::is_change_counter_update(&changed.pre_image, &changed.page)
+        });
+        if !noise {
+            for PendingPage {
+                offset,
+                pre_image,
+                mut page,
+            } in changed
+            {
+                if offset == 0 {
+                    page_parser::mask_volatile_ranges(&mut page, &pre_image, &volatile_ranges);
+                }
+                journal.new_snapshot(page.len() as u32)?;
+                for (diff_offset, diff) in
+                    utils::diff_or_page(differ.as_ref(), &page, &pre_image, threshold)
+                {
+                    let pre_image = &pre_image[diff_offset..diff_offset + diff.len()];
+                    journal.new_blob_with_pre_image(
+                        offset + diff_offset as u64,
+                        diff,
+                        pre_image,
+                    )?;
+                }
             }
         }
-        Ok(())
+        if !journal.snapshot_started() {
+            return Ok(());
+        }
+        let mut debounce = shared.debounce.lock().unwrap();
+        debounce.set_window(window);
+        match force || debounce.ready(Instant::now()) {
+            true => journal.commit(),
+            false => journal.sync_snapshot(),
+        }
+    }
+
+    /// wait for sync backend to catch up, if it lags too many snapshots behind journal
+    ///
+    /// returns false, if backend didn't catch up in time.
+    fn wait_for_backend(&self) -> bool {
+        let (shared, config) = match (self.shared.as_ref(), self.config.as_ref()) {
+            (Some(shared), Some(config)) if !self.read_only => (shared, config),
+            _ => return true,
+        };
+        let max = {
+            let config = config.lock().unwrap();
+            match config.max_unconfirmed_snapshots() {
+                // paused replicator doesn't push, writer would wait for nothing
+                Some(max) if config.replication_enabled() => max,
+                _ => return true,
+            }
+        };
+        let snapshot_counter = shared.journal.lock().unwrap().get_header().snapshot_counter;
+        shared.replicator.lock().unwrap().wait_for_backend(
+            snapshot_counter,
+            max,
+            BACKPRESSURE_TIMEOUT,
+        )
     }
 
-    fn lock(&'static mut self) {
-        if self.mutex_guard.is_some() {
+    /// journal changes, deferred by debounce, on close
+    ///
+    /// skipped while other connection holds database lock, its sync journals them instead.
+    fn journal_deferred_pages(&mut self) {
+        let lock = match self.db_lock.as_ref() {
+            Some(lock) if self.is_journaling() => Arc::clone(lock),
+            _ => return,
+        };
+        let _guard = match lock.try_write() {
+            Ok(guard) => guard,
+            Err(_) => return,
+        };
+        if let Err(e) = self.journal_pending_pages(true) {
+            log::error!("failed to journal deferred changes on close: {e}");
             return;
+        }
+        if let Some(shared) = self.shared.as_ref() {
+            shared.replicator.lock().unwrap().new_snapshot();
+        }
+    }
+
+    /// take shared database lock, it's held until sqlite releases its last lock on file
+    ///
+    /// lock is never waited for: returns false, while replicator restores database, so sqlite
+    /// reports busy lock and retries it according to its busy handler.
+    fn lock(&'static mut self) -> bool {
+        if self.db_lock_guard.is_some() {
+            return true;
         };
-        if let Some(mutex) = self.mutex.as_ref() {
-            self.mutex_guard = Some(mem::ManuallyDrop::new(mutex.lock().unwrap()))
+        let guard = match self.db_lock.as_ref().map(|lock| lock.try_read()) {
+            Some(Ok(guard)) => guard,
+            // lock guards no data, there's nothing to recover after panicked restore
+            Some(Err(TryLockError::Poisoned(e))) => e.into_inner(),
+            Some(Err(TryLockError::WouldBlock)) => return false,
+            None => return true,
         };
+        self.db_lock_guard = Some(mem::ManuallyDrop::new(guard));
+        true
     }
 
     fn unlock(&mut self) {
-        if self.mutex_guard.is_some() {
-            self.mutex_guard.take().map(mem::ManuallyDrop::into_inner);
+        if self.db_lock_guard.is_some() {
+            self.db_lock_guard.take().map(mem::ManuallyDrop::into_inner);
         }
     }
 }
 
+/// check if rollback journal of database starts with journal header, i.e. it's hot
+///
+/// journal of committed transaction is deleted, truncated or zeroed, depending on journal mode.
+fn hot_journal_exists(database_path: &str) -> std::io::Result<bool> {
+    let mut magic = [0; 8];
+    let mut file = match std::fs::File::open(format!("{database_path}-journal")) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    match std::io::Read::read_exact(&mut file, &mut magic) {
+        Ok(()) => Ok(magic == ROLLBACK_JOURNAL_MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 // VFS methods
 
 unsafe extern "C" fn mvfs_open(
@@ -351,9 +774,12 @@ static MclVFSIO: ffi::sqlite3_io_methods = ffi::sqlite3_io_methods {
 unsafe extern "C" fn mvfs_io_close(pfile: *mut ffi::sqlite3_file) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
     file.unlock();
-    file.mutex.take().map(mem::ManuallyDrop::into_inner);
-    file.journal.take().map(mem::ManuallyDrop::into_inner);
-    file.replicator.take().map(mem::ManuallyDrop::into_inner);
+    file.journal_deferred_pages();
+    file.db_lock.take().map(mem::ManuallyDrop::into_inner);
+    if let Some(shared) = file.shared.take() {
+        mem::ManuallyDrop::into_inner(shared).release();
+    }
+    file.config.take().map(mem::ManuallyDrop::into_inner);
     (*file.real.pMethods).xClose.unwrap()(&mut file.real)
 }
 
@@ -374,34 +800,50 @@ unsafe extern "C" fn mvfs_io_write(
     offset: ffi::sqlite_int64,
 ) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
-    if file.read_only && file.journal.is_some() {
-        // FIXME: this is a hack for reader-only and virtual table
-        if offset == 0 {
-            return ffi::SQLITE_OK;
-        } else {
-            return ffi::SQLITE_READONLY;
-        }
+    if file.read_only && file.shared.is_some() {
+        let buf = std::slice::from_raw_parts(buf.cast::<u8>(), amt as usize);
+        return match is_noop_write(pfile, buf, offset) {
+            true => ffi::SQLITE_OK,
+            false => ffi::SQLITE_READONLY,
+        };
     }
-    let result = match file.journal.as_mut() {
-        Some(journal) => {
+    let shared = file.shared.as_ref();
+    let mut pending = shared.map(|shared| shared.pending.lock().unwrap());
+    let result = match (shared, pending.as_mut()) {
+        // zero-length write changes nothing, it's never journaled
+        (_, Some(_)) if amt == 0 => Ok(()),
+        (Some(shared), Some(pending)) => {
             let new_page = std::slice::from_raw_parts(buf.cast::<u8>(), amt as usize);
-            let mut old_page = vec![0_u8; amt as usize];
-            let mut iter =
+            // write is split into pages of database, so writes of part of a page, e.g. of
+            // 100-byte database header, and writes spanning several pages are journaled by page
+            if let Some(page_size) = shared.page_size(pfile, new_page, offset) {
+                let result = pending.write_partial(page_size, offset as u64, new_page, |offset| {
+                    let mut old_page = vec![0_u8; page_size];
+                    let ptr = old_page.as_mut_ptr().cast();
+                    match MclVFSIO.xRead.unwrap()(pfile, ptr, page_size as c_int, offset as _) {
+                        ffi::SQLITE_OK | ffi::SQLITE_IOERR_SHORT_READ => Ok(old_page),
+                        other => Err(other),
+                    }
+                });
+                if let Err(_e) = result {
+                    return ffi::SQLITE_ERROR;
+                }
+                return (*file.real.pMethods).xWrite.unwrap()(&mut file.real, buf, amt, offset);
+            }
+            // database has no header yet, write is journaled as is
+            // pre-image is read only on first write of the page since last sync
+            pending.write(offset as u64, new_page, || {
+                let mut old_page = vec![0_u8; amt as usize];
                 match MclVFSIO.xRead.unwrap()(pfile, old_page.as_mut_ptr().cast(), amt, offset) {
                     // existing page
-                    ffi::SQLITE_OK => utils::get_diff(new_page, &old_page),
-                    // new page
-                    ffi::SQLITE_IOERR_SHORT_READ => utils::get_diff(new_page, &[]),
-                    _other => return ffi::SQLITE_ERROR,
-                };
-            iter.try_for_each(|(diff_offset, diff)| {
-                let diff_offset = diff_offset as i64 + offset;
-                journal
-                    .new_snapshot(amt as u32)
-                    .and_then(|_| journal.new_blob(diff_offset as u64, diff))
+                    ffi::SQLITE_OK => Ok(old_page),
+                    // new page, short read zero-fills old page, so pre-image is zeroed
+                    ffi::SQLITE_IOERR_SHORT_READ => Ok(old_page),
+                    other => Err(other),
+                }
             })
         }
-        None => Ok(()),
+        _ => Ok(()),
     };
     if let Err(_e) = result {
         return ffi::SQLITE_ERROR;
@@ -409,22 +851,48 @@ unsafe extern "C" fn mvfs_io_write(
     (*file.real.pMethods).xWrite.unwrap()(&mut file.real, buf, amt, offset)
 }
 
+/// Check if write to reader's database file leaves database as it is
+///
+/// Reader never changes database, but change counter bump (e.g. of transaction, which only
+/// updated config vtab) and rollback playback of refused transaction are let through as no-ops,
+/// so refused write leaves no hot journal behind and connection stays readable.
+unsafe fn is_noop_write(
+    pfile: *mut ffi::sqlite3_file,
+    buf: &[u8],
+    offset: ffi::sqlite_int64,
+) -> bool {
+    let mut current = vec![0_u8; buf.len()];
+    let ptr = current.as_mut_ptr().cast();
+    match MclVFSIO.xRead.unwrap()(pfile, ptr, buf.len() as c_int, offset) {
+        ffi::SQLITE_OK => (),
+        _ => return false,
+    }
+    current == buf || (offset == 0 && page_parser::is_change_counter_update(&current, buf))
+}
+
 unsafe extern "C" fn mvfs_io_truncate(
     pfile: *mut ffi::sqlite3_file,
     size: ffi::sqlite3_int64,
 ) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
+    // pages past new end of database, e.g. appended by rolled back transaction, are gone
+    if let Some(shared) = file.shared.as_ref() {
+        shared.pending.lock().unwrap().truncate(size.max(0) as u64);
+    }
     (*file.real.pMethods).xTruncate.unwrap()(&mut file.real, size)
 }
 
 unsafe extern "C" fn mvfs_io_sync(pfile: *mut ffi::sqlite3_file, flags: c_int) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
-    match file.journal.as_mut().map(|journal| journal.commit()) {
-        None | Some(Ok(_)) => (),
-        Some(Err(_e)) => return ffi::SQLITE_ERROR,
+    // backpressure: journal doesn't grow, while sync backend falls behind
+    if !file.wait_for_backend() {
+        return ffi::SQLITE_BUSY;
+    }
+    if let Err(_e) = file.journal_pending_pages(false) {
+        return ffi::SQLITE_ERROR;
     };
-    if let Some(replicator) = file.replicator.as_mut() {
-        replicator.new_snapshot();
+    if let Some(shared) = file.shared.as_ref() {
+        shared.replicator.lock().unwrap().new_snapshot();
     }
     (*file.real.pMethods).xSync.unwrap()(&mut file.real, flags)
 }
@@ -434,6 +902,18 @@ unsafe extern "C" fn mvfs_io_file_size(
     psize: *mut ffi::sqlite3_int64,
 ) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
+    // replica database is rewritten by replicator restore under database lock.
+    // if sqlite doesn't hold the lock yet - wait for restore to finish, so size is consistent.
+    // writer is not affected: uncommitted snapshot only contains changes made by sqlite itself.
+    let _guard = match file.db_lock.as_ref() {
+        Some(lock) if file.read_only && file.shared.is_some() && file.db_lock_guard.is_none() => {
+            match lock.read() {
+                Ok(guard) => Some(guard),
+                Err(_e) => return ffi::SQLITE_IOERR_FSTAT,
+            }
+        }
+        _ => None,
+    };
     (*file.real.pMethods).xFileSize.unwrap()(&mut file.real, psize)
 }
 
@@ -441,15 +921,16 @@ unsafe extern "C" fn mvfs_io_lock(pfile: *mut ffi::sqlite3_file, elock: c_int) -
     let file = MclVFSFile::from_ptr(pfile);
     let real = (&mut file.real) as *mut ffi::sqlite3_file;
     // lock only main database file
-    if file.journal.is_some() {
-        file.lock();
+    if file.shared.is_some() && !file.lock() {
+        return ffi::SQLITE_BUSY;
     }
     (*(*real).pMethods).xLock.unwrap()(real, elock)
 }
 
 unsafe extern "C" fn mvfs_io_unlock(pfile: *mut ffi::sqlite3_file, elock: c_int) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
-    if file.journal.is_some() {
+    // downgrade to shared lock keeps database locked
+    if file.shared.is_some() && elock == ffi::SQLITE_LOCK_NONE {
         file.unlock()
     }
     (*file.real.pMethods).xUnlock.unwrap()(&mut file.real, elock)
@@ -469,6 +950,18 @@ unsafe extern "C" fn mvfs_io_file_control(
     p_arg: *mut c_void,
 ) -> c_int {
     let file = MclVFSFile::from_ptr(pfile);
+    if op == MYCELITE_FCNTL_IS_JOURNALED {
+        *p_arg.cast::<c_int>() = file.is_journaling() as c_int;
+        return ffi::SQLITE_OK;
+    }
+    if op == MYCELITE_FCNTL_UNCONFIRMED_SNAPSHOTS {
+        let status = match file.shared.as_ref() {
+            Some(shared) => shared.replicator.lock().unwrap().status().get(),
+            None => return ffi::SQLITE_NOTFOUND,
+        };
+        *p_arg.cast::<[u64; 2]>() = [status.unconfirmed_snapshots, status.unconfirmed_bytes];
+        return ffi::SQLITE_OK;
+    }
     (*file.real.pMethods).xFileControl.unwrap()(&mut file.real, op, p_arg)
 }
 
@@ -18,6 +18,25 @@ impl Database {
 
     /// Initialize iterator over raw sqlite pages
     pub fn into_raw_page_iter(&self) -> Result<RawPageIter, Box<dyn std::error::Error>> {
+        self.raw_page_iter(None)
+    }
+
+    /// Initialize iterator over raw sqlite pages, which reads `pages` pages at once
+    ///
+    /// Reader buffer is sized to hold `pages` pages, so large database is read in fewer
+    /// syscalls. Pages are yielded from buffer one by one, same as with
+    /// [`Database::into_raw_page_iter`].
+    pub fn into_raw_page_iter_with_readahead(
+        &self,
+        pages: usize,
+    ) -> Result<RawPageIter, Box<dyn std::error::Error>> {
+        self.raw_page_iter(Some(pages))
+    }
+
+    fn raw_page_iter(
+        &self,
+        readahead: Option<usize>,
+    ) -> Result<RawPageIter, Box<dyn std::error::Error>> {
         let mut fd = std::fs::OpenOptions::new()
             .read(true)
             .open(self.path.as_path())?;
@@ -33,8 +52,13 @@ impl Database {
             }
         };
         fd.rewind()?;
+        let fd = match readahead {
+            Some(pages) => BufReader::with_capacity(pages.max(1) * page_size.max(1) as usize, fd),
+            None => BufReader::new(fd),
+        };
         Ok(RawPageIter {
-            fd: BufReader::new(fd),
+            fd,
+            offset: 0,
             page_size,
             pages_left,
         })
@@ -45,6 +69,8 @@ impl Database {
 pub struct RawPageIter {
     // for now only file iter, but in-memory option also can be supported
     fd: BufReader<std::fs::File>,
+    // tracked, since stream position of buffered reader costs a syscall
+    offset: u64,
     page_size: u64,
     pages_left: u64,
 }
@@ -57,10 +83,8 @@ impl Iterator for RawPageIter {
             return None;
         };
         self.pages_left -= 1;
-        let offset = match self.fd.stream_position() {
-            Err(e) => return Some(Err(e)),
-            Ok(offset) => offset,
-        };
+        let offset = self.offset;
+        self.offset += self.page_size;
         let mut page = vec![0; self.page_size as usize];
         match self.fd.read_exact(page.as_mut_slice()) {
             Ok(_) => Some(Ok((offset, RawPage::new(page)))),
@@ -4,4 +4,7 @@ pub(crate) mod page;
 
 pub use database::Database;
 pub use header::Header;
-pub use page::RawPage;
+pub use page::{
+    is_change_counter_update, mask_volatile_ranges, volatile_range, write_change_counter, PageType,
+    RawPage,
+};
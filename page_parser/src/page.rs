@@ -1,4 +1,102 @@
 //! Sqlite Page
+use std::io::{Seek, SeekFrom, Write};
+
+/// Sqlite header magic, present at the start of the first page
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Size of database header, which precedes b-tree page header on the first page
+const DATABASE_HEADER_SIZE: usize = 100;
+
+/// Database header fields, which sqlite bumps on every write transaction: file change counter
+/// and version-valid-for number
+const CHANGE_COUNTER_RANGES: [std::ops::Range<usize>; 2] = [24..28, 92..96];
+
+/// Byte range of volatile database header field by name: `change_counter` or
+/// `version_valid_for`
+///
+/// Volatile fields carry no database content, they're bumped on every write transaction.
+pub fn volatile_range(name: &str) -> Option<std::ops::Range<usize>> {
+    match name {
+        "change_counter" => Some(CHANGE_COUNTER_RANGES[0].clone()),
+        "version_valid_for" => Some(CHANGE_COUNTER_RANGES[1].clone()),
+        _ => None,
+    }
+}
+
+/// Copy volatile `ranges` of first page pre-image into page, so page diff doesn't include them
+///
+/// Pages, which aren't first pages of database, are left intact.
+pub fn mask_volatile_ranges(page: &mut [u8], pre_image: &[u8], ranges: &[std::ops::Range<usize>]) {
+    if !page.starts_with(MAGIC) || !pre_image.starts_with(MAGIC) {
+        return;
+    }
+    for range in ranges {
+        if range.end <= page.len().min(pre_image.len()) {
+            page[range.clone()].copy_from_slice(&pre_image[range.clone()]);
+        }
+    }
+}
+
+/// Set file change counter and version-valid-for number of database header to `counter`
+///
+/// Restored database gets counters, derived from journal instead of volatile header fields, which
+/// might not be journaled. Equal counters keep in-header database size valid. Database, shorter
+/// than database header, is left intact.
+pub fn write_change_counter<W: Write + Seek>(
+    database: &mut W,
+    counter: u32,
+) -> std::io::Result<()> {
+    if database.seek(SeekFrom::End(0))? < DATABASE_HEADER_SIZE as u64 {
+        return Ok(());
+    }
+    for range in CHANGE_COUNTER_RANGES.iter() {
+        database.seek(SeekFrom::Start(range.start as u64))?;
+        database.write_all(&counter.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// Check if first page differs from its pre-image only by change counters of database header
+///
+/// Such write carries no database changes, e.g. transaction which didn't modify any rows.
+pub fn is_change_counter_update(pre_image: &[u8], page: &[u8]) -> bool {
+    page.starts_with(MAGIC)
+        && pre_image.starts_with(MAGIC)
+        && page.len() == pre_image.len()
+        && page
+            .iter()
+            .zip(pre_image)
+            .enumerate()
+            .all(|(i, (new, old))| {
+                new == old || CHANGE_COUNTER_RANGES.iter().any(|r| r.contains(&i))
+            })
+}
+
+/// [B-tree page type]<https://www.sqlite.org/fileformat.html#b_tree_pages>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageType {
+    IndexInterior,
+    TableInterior,
+    IndexLeaf,
+    TableLeaf,
+    /// Not a b-tree page: overflow, freelist, pointer map or lock-byte page.
+    ///
+    /// Such pages have no type byte, telling them apart requires following freelist and
+    /// overflow chains.
+    Other(u8),
+}
+
+impl From<u8> for PageType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x02 => Self::IndexInterior,
+            0x05 => Self::TableInterior,
+            0x0a => Self::IndexLeaf,
+            0x0d => Self::TableLeaf,
+            v => Self::Other(v),
+        }
+    }
+}
 
 /// Sqlite Raw Page
 ///
@@ -14,4 +112,35 @@ impl RawPage {
     pub fn as_slice(&self) -> &[u8] {
         self.0.as_slice()
     }
+
+    /// Check if page is the first page of database, which starts with database header
+    pub fn is_first_page(&self) -> bool {
+        self.0.starts_with(MAGIC)
+    }
+
+    /// Page type, from b-tree page header
+    pub fn page_type(&self) -> PageType {
+        self.0
+            .get(self.btree_header_offset())
+            .copied()
+            .unwrap_or(0)
+            .into()
+    }
+
+    /// Number of cells on b-tree page, meaningless for other page types
+    pub fn cell_count(&self) -> u16 {
+        let offset = self.btree_header_offset() + 3;
+        match self.0.get(offset..offset + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            None => 0,
+        }
+    }
+
+    /// b-tree page header follows database header on the first page
+    fn btree_header_offset(&self) -> usize {
+        match self.is_first_page() {
+            true => DATABASE_HEADER_SIZE,
+            false => 0,
+        }
+    }
 }
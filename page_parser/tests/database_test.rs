@@ -0,0 +1,77 @@
+//! validate raw page iterator with read-ahead against default one
+
+use page_parser::Database;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+
+fn collect(
+    iter: impl Iterator<Item = std::io::Result<(u64, page_parser::RawPage)>>,
+) -> Vec<(u64, Vec<u8>)> {
+    iter.map(|page| {
+        let (offset, page) = page.unwrap();
+        (offset, page.as_slice().to_vec())
+    })
+    .collect()
+}
+
+#[test]
+fn test_raw_page_iter_with_readahead() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/test.db");
+    let database = Database::new(path);
+    let expected = collect(database.into_raw_page_iter().unwrap());
+    assert_eq!(expected.len(), 14);
+    // zero read-ahead is the same as single page one, 5 doesn't divide page count
+    for pages in [0, 1, 5, 14, 100] {
+        let pages = collect(database.into_raw_page_iter_with_readahead(pages).unwrap());
+        assert_eq!(pages, expected);
+    }
+}
+
+/// Database file of `pages` pages of `page_size`, removed on drop
+struct TempDatabase(PathBuf);
+
+impl TempDatabase {
+    fn new(name: &str, page_size: u16, pages: usize) -> Self {
+        let path = std::env::temp_dir().join(format!("{name}-{}.db", std::process::id()));
+        let mut fd = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        let mut page = vec![0; page_size as usize];
+        page[..16].copy_from_slice(b"SQLite format 3\0");
+        page[16..18].copy_from_slice(&page_size.to_be_bytes());
+        fd.write_all(&page).unwrap();
+        for num in 1..pages {
+            page.fill(num as u8);
+            fd.write_all(&page).unwrap();
+        }
+        fd.flush().unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+// benchmark, run with `cargo test -p page_parser --release -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_raw_page_iter_readahead() {
+    // 1GiB database
+    let db = TempDatabase::new("bench_readahead", 4096, 256 * 1024);
+    let database = Database::new(&db.0);
+    let bench = |name: &str, readahead: Option<usize>| {
+        let start = Instant::now();
+        let iter = match readahead {
+            Some(pages) => database.into_raw_page_iter_with_readahead(pages),
+            None => database.into_raw_page_iter(),
+        };
+        let count = iter.unwrap().filter(|page| page.is_ok()).count();
+        println!("{name}: {count} pages in {:?}", start.elapsed());
+    };
+    bench("default", None);
+    for pages in [1, 16, 256] {
+        bench(&format!("readahead {pages}"), Some(pages));
+    }
+}
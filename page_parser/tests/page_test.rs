@@ -0,0 +1,128 @@
+//! validate page type and cell count against known database
+//!
+//! fixture is created with sqlite3 (page size 512):
+//! ```sql
+//! CREATE TABLE a(x integer primary key, y text);
+//! CREATE INDEX a_y ON a(y);
+//! CREATE TABLE b(z);
+//! -- 100 rows of (i, 'value-i')
+//! INSERT INTO a ...;
+//! INSERT INTO b VALUES (randomblob(2000));
+//! ```
+
+use page_parser::{
+    is_change_counter_update, mask_volatile_ranges, volatile_range, write_change_counter, Database,
+    PageType, RawPage,
+};
+use std::io::Cursor;
+
+fn pages() -> Vec<RawPage> {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/test.db");
+    Database::new(path)
+        .into_raw_page_iter()
+        .unwrap()
+        .map(|page| page.unwrap().1)
+        .collect()
+}
+
+#[test]
+fn test_first_page() {
+    let pages = pages();
+    let first = &pages[0];
+    assert!(first.is_first_page());
+    // sqlite_schema: table a, index a_y, table b
+    assert_eq!(first.page_type(), PageType::TableLeaf);
+    assert_eq!(first.cell_count(), 3);
+}
+
+#[test]
+fn test_page_types() {
+    let pages = pages();
+    assert_eq!(pages.len(), 14);
+    assert!(pages[1..].iter().all(|page| !page.is_first_page()));
+
+    // root page of table a
+    assert_eq!(pages[1].page_type(), PageType::TableInterior);
+    assert_eq!(pages[1].cell_count(), 2);
+    // root page of index a_y
+    assert_eq!(pages[2].page_type(), PageType::IndexInterior);
+    assert_eq!(pages[2].cell_count(), 2);
+    // root page of table b, single row with payload spilled to overflow pages
+    assert_eq!(pages[3].page_type(), PageType::TableLeaf);
+    assert_eq!(pages[3].cell_count(), 1);
+    assert_eq!(pages[4].page_type(), PageType::IndexLeaf);
+    assert!(matches!(pages[13].page_type(), PageType::Other(_)));
+}
+
+#[test]
+fn test_change_counter_update() {
+    let pages = pages();
+    let pre_image = pages[0].as_slice();
+    let mut page = pre_image.to_vec();
+    assert!(is_change_counter_update(pre_image, &page));
+
+    // file change counter and version-valid-for number
+    page[27] += 1;
+    page[95] += 1;
+    assert!(is_change_counter_update(pre_image, &page));
+
+    // database size change is a real change
+    page[31] += 1;
+    assert!(!is_change_counter_update(pre_image, &page));
+
+    // only first page has change counters
+    let pre_image = pages[1].as_slice();
+    let mut page = pre_image.to_vec();
+    page[27] += 1;
+    assert!(!is_change_counter_update(pre_image, &page));
+}
+
+#[test]
+fn test_mask_volatile_ranges() {
+    let pages = pages();
+    let pre_image = pages[0].as_slice();
+    let ranges = ["change_counter", "version_valid_for"]
+        .map(|name| volatile_range(name).unwrap())
+        .to_vec();
+    assert_eq!(ranges, vec![24..28, 92..96]);
+    assert_eq!(volatile_range("page_size"), None);
+
+    let mut page = pre_image.to_vec();
+    page[27] += 1;
+    page[95] += 1;
+    page[31] += 1;
+    mask_volatile_ranges(&mut page, pre_image, &ranges);
+    // only database size change is left
+    assert_eq!(page[27], pre_image[27]);
+    assert_eq!(page[95], pre_image[95]);
+    assert_ne!(page[31], pre_image[31]);
+
+    // only change counter is masked
+    page[27] += 1;
+    page[95] += 1;
+    mask_volatile_ranges(&mut page, pre_image, &ranges[..1]);
+    assert_eq!(page[27], pre_image[27]);
+    assert_ne!(page[95], pre_image[95]);
+
+    // pages other than first page are left intact
+    let pre_image = pages[1].as_slice();
+    let mut page = pre_image.to_vec();
+    page[27] += 1;
+    mask_volatile_ranges(&mut page, pre_image, &ranges);
+    assert_ne!(page[27], pre_image[27]);
+}
+
+#[test]
+fn test_write_change_counter() {
+    let mut database = Cursor::new(pages()[0].as_slice().to_vec());
+    write_change_counter(&mut database, 0x0102_0304).unwrap();
+    let page = database.into_inner();
+    assert_eq!(page[24..28], [1, 2, 3, 4]);
+    assert_eq!(page[92..96], [1, 2, 3, 4]);
+    assert!(is_change_counter_update(pages()[0].as_slice(), &page));
+
+    // database without header is left intact
+    let mut database = Cursor::new(vec![]);
+    write_change_counter(&mut database, 1).unwrap();
+    assert!(database.into_inner().is_empty());
+}
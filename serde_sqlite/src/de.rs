@@ -286,10 +286,11 @@ impl<'a, 'de, R: Read> de::EnumAccess<'de> for EnumAccess<'a, R> {
     {
         let mut buf = [0_u8; 4];
         self.de.reader.read_exact(&mut buf)?;
-        let tag = u32::from_be_bytes(buf) as u64;
-        let de = IntoDeserializer::<Error>::into_deserializer(tag);
-        let tag = V::deserialize(de)?;
-        Ok((tag, VariantAccess { de: self.de }))
+        let tag = u32::from_be_bytes(buf);
+        let de = IntoDeserializer::<Error>::into_deserializer(tag as u64);
+        // variant identifier can only fail on tag without corresponding variant
+        let variant = V::deserialize(de).map_err(|_| Error::InvalidEnumTag { tag })?;
+        Ok((variant, VariantAccess { de: self.de }))
     }
 
     fn variant_seed<V>(self, _seed: V) -> Result<(V::Value, Self::Variant), Error>
@@ -387,9 +388,12 @@ impl<R: std::io::Read> CountingReader<R> {
         if left == 0 {
             return Ok(());
         }
-        let mut buf = vec![0; left];
-        self.read_exact(buf.as_mut_slice())?;
-        Ok(())
+        // padding is skipped without heap allocated scratch buffer
+        let left = left as u64;
+        match std::io::copy(&mut self.take(left), &mut std::io::sink())? {
+            read if read == left => Ok(()),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
+        }
     }
 }
 
@@ -428,6 +432,11 @@ where
 {
     let mut cbr = CountingReader::new(reader);
     let res = T::deserialize(&mut SqliteDe::from_reader(&mut cbr))?;
-    cbr.discard_padding(res.iblock_size() - cbr.read)?;
+    let expected = res.iblock_size();
+    let left = expected.checked_sub(cbr.read).ok_or(Error::BlockOverread {
+        read: cbr.read,
+        expected,
+    })?;
+    cbr.discard_padding(left)?;
     Ok(res)
 }
\ No newline at end of file
@@ -10,6 +10,8 @@ pub enum Error {
     Incomplete,
     Unexpected,
     Unsupported(&'static str),
+    InvalidEnumTag { tag: u32 },
+    BlockOverread { read: usize, expected: usize },
     OutOfMemory(std::collections::TryReserveError),
 }
 
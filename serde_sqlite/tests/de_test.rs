@@ -214,3 +214,31 @@ fn test_deserialization_newtype_enum() {
     let b = res.unwrap();
     assert_eq!(B::A(A::S(S{})), b);
 }
+
+#[test]
+fn test_deserialization_invalid_enum_tag() {
+    let mut block = vec![0; 8];
+    block[..4].copy_from_slice(&2_u32.to_be_bytes());
+    assert!(matches!(
+        from_bytes::<A>(&block),
+        Err(Error::InvalidEnumTag { tag: 2 })
+    ));
+}
+
+// declared block size is smaller than deserialized fields
+#[derive(Debug, Deserialize)]
+#[block(4)]
+struct Overread {
+    _value: u64,
+}
+
+#[test]
+fn test_deserialization_block_overread() {
+    assert!(matches!(
+        from_bytes::<Overread>(&[0; 8]),
+        Err(Error::BlockOverread {
+            read: 8,
+            expected: 4
+        })
+    ));
+}
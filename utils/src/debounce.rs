@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+/// Lets action through at most once per window
+///
+/// Calls within window since last let through action are refused, caller is expected to retry
+/// later. Zero window lets every call through.
+#[derive(Debug, Clone, Copy)]
+pub struct Debounce {
+    window: Duration,
+    last: Option<Instant>,
+}
+
+impl Debounce {
+    pub const fn new(window: Duration) -> Self {
+        Self { window, last: None }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Check if window passed since last action at `now`, recording action if so
+    pub fn ready(&mut self, now: Instant) -> bool {
+        match self.last {
+            Some(last) if now.saturating_duration_since(last) < self.window => false,
+            _ => {
+                self.last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce_coalesces_rapid_calls() {
+        let start = Instant::now();
+        let mut debounce = Debounce::new(Duration::from_millis(100));
+        // call every 10ms for one second
+        let ready = (0..100)
+            .filter(|i| debounce.ready(start + Duration::from_millis(i * 10)))
+            .count();
+        assert_eq!(ready, 10);
+        assert!(debounce.ready(start + Duration::from_millis(1100)));
+    }
+
+    #[test]
+    fn test_debounce_zero_window() {
+        let now = Instant::now();
+        let mut debounce = Debounce::new(Duration::ZERO);
+        assert!((0..10).all(|_| debounce.ready(now)));
+
+        debounce.set_window(Duration::from_secs(1));
+        assert!(!debounce.ready(now));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::get_diff;
+use std::iter;
+
+/// Page diff algorithm
+///
+/// Diff is a sequence of `(offset, bytes)` pairs, which turns old page into new one once applied.
+pub trait PageDiffer {
+    fn diff<'a>(
+        &self,
+        new_page: &'a [u8],
+        old_page: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (usize, &'a [u8])> + 'a>;
+}
+
+/// Changed byte runs, runs separated by small gaps are merged, see [`get_diff`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GapDiffer;
+
+impl PageDiffer for GapDiffer {
+    fn diff<'a>(
+        &self,
+        new_page: &'a [u8],
+        old_page: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (usize, &'a [u8])> + 'a> {
+        Box::new(get_diff(new_page, old_page))
+    }
+}
+
+/// Whole page, if page changed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WholePageDiffer;
+
+impl PageDiffer for WholePageDiffer {
+    fn diff<'a>(
+        &self,
+        new_page: &'a [u8],
+        old_page: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (usize, &'a [u8])> + 'a> {
+        match get_diff(new_page, old_page).next() {
+            Some(_) => Box::new(iter::once((0, new_page))),
+            None => Box::new(iter::empty()),
+        }
+    }
+}
+
+/// Lookup page differ by name: `gap` or `page`
+pub fn page_differ(name: &str) -> Option<Box<dyn PageDiffer + Send + Sync>> {
+    match name {
+        "gap" => Some(Box::new(GapDiffer)),
+        "page" => Some(Box::new(WholePageDiffer)),
+        _ => None,
+    }
+}
+
+/// Diff new page against old one with given differ, whole page is returned if diff covers more
+/// than `full_page_threshold_pct` percent of the page
+pub fn diff_or_page<'a>(
+    differ: &dyn PageDiffer,
+    new_page: &'a [u8],
+    old_page: &'a [u8],
+    full_page_threshold_pct: u8,
+) -> Vec<(usize, &'a [u8])> {
+    let diff = differ.diff(new_page, old_page).collect::<Vec<_>>();
+    let diff_size = diff.iter().map(|(_, blob)| blob.len()).sum::<usize>();
+    match diff_size * 100 > new_page.len() * full_page_threshold_pct as usize {
+        true => vec![(0, new_page)],
+        false => diff,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{quickcheck, TestResult};
+
+    fn apply(old_page: &[u8], diff: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut page = old_page.to_vec();
+        crate::apply_diff(&mut page, diff.iter().copied());
+        page
+    }
+
+    #[test]
+    fn test_page_differ_lookup() {
+        assert!(page_differ("gap").is_some());
+        assert!(page_differ("page").is_some());
+        assert!(page_differ("rolling-hash").is_none());
+    }
+
+    #[test]
+    fn test_whole_page_differ() {
+        let old_page = [0; 100];
+        let mut new_page = [0; 100];
+        new_page[10] = 1;
+        let diff = WholePageDiffer
+            .diff(&new_page, &old_page)
+            .collect::<Vec<_>>();
+        assert_eq!(diff, vec![(0, &new_page[..])]);
+        assert_eq!(WholePageDiffer.diff(&old_page, &old_page).count(), 0);
+        // threshold doesn't affect whole page diff
+        assert_eq!(
+            diff_or_page(&WholePageDiffer, &new_page, &old_page, 100),
+            vec![(0, &new_page[..])]
+        );
+    }
+
+    quickcheck! {
+        // both differs restore new page from old one
+        fn prop_differs_restore_page(new: Vec<u8>, old: Vec<u8>, threshold: u8) -> TestResult {
+            if new.len() != old.len() {
+                return TestResult::discard();
+            }
+            let threshold = threshold % 101;
+            for name in ["gap", "page"] {
+                let differ = page_differ(name).unwrap();
+                let diff = diff_or_page(differ.as_ref(), &new, &old, threshold);
+                if apply(&old, &diff) != new {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use crate::Backoff;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Join thread, waiting at most `timeout` for it to finish
+///
+/// Thread is polled with exponential backoff. If thread doesn't finish in time, its handle is
+/// given back, so caller can either keep waiting or detach thread by dropping the handle.
+pub fn join_with_timeout<T>(
+    handle: JoinHandle<T>,
+    timeout: Duration,
+) -> Result<thread::Result<T>, JoinHandle<T>> {
+    let mut backoff = Backoff::new(timeout);
+    loop {
+        if handle.is_finished() {
+            return Ok(handle.join());
+        }
+        match backoff.next() {
+            Some(delay) => thread::sleep(delay),
+            None => return Err(handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Instant;
+
+    #[test]
+    fn test_join_with_timeout() {
+        let handle = thread::spawn(|| 42);
+        assert_eq!(
+            join_with_timeout(handle, Duration::from_secs(5))
+                .map(Result::unwrap)
+                .ok(),
+            Some(42)
+        );
+    }
+
+    #[test]
+    fn test_join_with_timeout_slow_thread() {
+        let (tx, rx) = channel::<()>();
+        // thread is stuck until channel is closed
+        let handle = thread::spawn(move || rx.recv().is_err());
+        let start = Instant::now();
+        let handle = join_with_timeout(handle, Duration::from_millis(50)).unwrap_err();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+
+        drop(tx);
+        assert!(handle.join().unwrap());
+    }
+}
@@ -1,3 +1,20 @@
+mod debounce;
+mod differ;
+mod join;
+mod lock;
+mod pending;
+mod registry;
+mod staged;
+mod status;
+
+pub use debounce::Debounce;
+pub use differ::{diff_or_page, page_differ, GapDiffer, PageDiffer, WholePageDiffer};
+pub use join::join_with_timeout;
+pub use lock::{lock_with_backoff, write_with_backoff, Backoff, LockError};
+pub use pending::{PendingPage, PendingPages};
+pub use registry::LruRegistry;
+pub use staged::StagedFile;
+pub use status::{ReplicatorStatus, ReplicatorStatusSnapshot};
 use std::iter;
 
 const GAP: usize = 16;
@@ -21,6 +38,32 @@ pub fn get_diff<'a>(
     .map(|(start, end)| (start, &new_page[start..=end]))
 }
 
+/// Apply diff, produced by [`get_diff`], to `base`
+///
+/// Each run is written at its offset, `base` grows to fit runs past its end, gap between end of
+/// `base` and run is zero-filled. Diff doesn't carry length of new page: `base` isn't truncated
+/// and trailing zeroes of new page past end of old one are not part of diff, so they are not
+/// appended.
+pub fn apply_diff<'a>(base: &mut Vec<u8>, diff: impl Iterator<Item = (usize, &'a [u8])>) {
+    for (offset, bytes) in diff {
+        let end = offset + bytes.len();
+        if base.len() < end {
+            base.resize(end, 0);
+        }
+        base[offset..end].copy_from_slice(bytes);
+    }
+}
+
+/// Diff new page against old one, whole page is returned if diff covers more than
+/// `full_page_threshold_pct` percent of the page
+pub fn get_diff_or_page<'a>(
+    new_page: &'a [u8],
+    old_page: &'a [u8],
+    full_page_threshold_pct: u8,
+) -> Vec<(usize, &'a [u8])> {
+    diff_or_page(&GapDiffer, new_page, old_page, full_page_threshold_pct)
+}
+
 pub struct Diff<I> {
     iter: I,
     gap: usize,
@@ -163,31 +206,78 @@ mod tests {
         assert_eq!(results.collect::<Vec<(usize, &[u8])>>(), expected);
     }
 
+    #[test]
+    fn test_apply_diff_past_end_of_base() {
+        let mut base = vec![1, 2, 3];
+        apply_diff(&mut base, [(2, &[30, 40][..]), (6, &[7][..])].into_iter());
+        assert_eq!(base, vec![1, 2, 30, 40, 0, 0, 7]);
+
+        // base is never truncated
+        let old = vec![1; 8];
+        let mut base = old.clone();
+        apply_diff(&mut base, get_diff(&[1, 2], &old));
+        assert_eq!(base, vec![1, 2, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_get_diff_or_page_mostly_changed_page() {
+        let old_page: &[u8] = &[0; 100];
+        let mut new_page = [1; 100];
+        new_page[..30].copy_from_slice(&[0; 30]);
+
+        let results = get_diff_or_page(&new_page, old_page, 60);
+        let expected: Vec<(usize, &[u8])> = vec![(0, &new_page)];
+        assert_eq!(results, expected);
+
+        // below threshold diff is kept
+        let results = get_diff_or_page(&new_page, old_page, 80);
+        let expected: Vec<(usize, &[u8])> = vec![(30, &new_page[30..])];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_get_diff_or_page_slightly_changed_page() {
+        let old_page: &[u8] = &[0; 100];
+        let mut new_page = [0; 100];
+        new_page[10] = 1;
+        new_page[90] = 1;
+
+        let results = get_diff_or_page(&new_page, old_page, 60);
+        let expected: Vec<(usize, &[u8])> = vec![(10, &[1]), (90, &[1])];
+        assert_eq!(results, expected);
+
+        // unchanged page produces no blobs even with zero threshold
+        assert!(get_diff_or_page(old_page, old_page, 0).is_empty());
+    }
+
     quickcheck! {
         fn prop_get_diff_when_pages_exist(new: Vec<u8>, old: Vec<u8>) -> TestResult {
             if new.len() != old.len() {
                 return TestResult::discard();
             }
-            let diff = get_diff(&new, &old);
             let mut brand_new = old.clone();
-
-            for (offset, bytes) in diff {
-                for (i, val) in bytes.iter().enumerate() {
-                    brand_new[offset + i] = *val;
-                }
-            }
+            apply_diff(&mut brand_new, get_diff(&new, &old));
             TestResult::from_bool(new == brand_new)
         }
 
         fn prop_get_diff_when_old_page_not_exists(new: Vec<u8>) -> TestResult {
             let old: Vec<u8> = vec![];
-            let diff = get_diff(&new, &old);
             let mut brand_new = vec![0; new.len()];
-            for (offset, bytes) in diff {
-                for (i, val) in bytes.iter().enumerate() {
-                    brand_new[offset + i] = *val;
-                }
+            apply_diff(&mut brand_new, get_diff(&new, &old));
+            TestResult::from_bool(new == brand_new)
+        }
+
+        // base grows to fit new page, up to trailing zeroes, which are not in diff
+        fn prop_apply_diff_when_old_page_is_shorter(new: Vec<u8>, old: Vec<u8>) -> TestResult {
+            if old.len() > new.len() {
+                return TestResult::discard();
+            }
+            let mut brand_new = old.clone();
+            apply_diff(&mut brand_new, get_diff(&new, &old));
+            if brand_new.len() > new.len() || new[brand_new.len()..].iter().any(|b| *b != 0) {
+                return TestResult::failed();
             }
+            brand_new.resize(new.len(), 0);
             TestResult::from_bool(new == brand_new)
         }
     }
@@ -0,0 +1,173 @@
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockWriteGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+const INITIAL_DELAY: Duration = Duration::from_millis(1);
+const MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// Exponential backoff delays, bounded by total timeout
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+    deadline: Instant,
+}
+
+impl Backoff {
+    pub fn new(timeout: Duration) -> Self {
+        Self::with_delays(INITIAL_DELAY, MAX_DELAY, timeout)
+    }
+
+    pub fn with_delays(initial_delay: Duration, max_delay: Duration, timeout: Duration) -> Self {
+        Self {
+            delay: initial_delay,
+            max_delay,
+            deadline: Instant::now() + timeout,
+        }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let left = self.deadline.checked_duration_since(Instant::now())?;
+        if left.is_zero() {
+            return None;
+        }
+        let delay = self.delay.min(left);
+        self.delay = (self.delay * 2).min(self.max_delay);
+        Some(delay)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockError {
+    /// Lock was poisoned by panicked holder
+    Poisoned,
+    /// Lock wasn't released within timeout
+    Timeout,
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for LockError {}
+
+/// Try to lock mutex, retrying with exponential backoff until timeout
+pub fn lock_with_backoff<T>(
+    mutex: &Mutex<T>,
+    timeout: Duration,
+) -> Result<MutexGuard<'_, T>, LockError> {
+    let mut backoff = Backoff::new(timeout);
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(_)) => return Err(LockError::Poisoned),
+            Err(TryLockError::WouldBlock) => match backoff.next() {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(LockError::Timeout),
+            },
+        }
+    }
+}
+
+/// Try to lock `RwLock` for writing, retrying with exponential backoff until timeout
+///
+/// Readers are never blocked by waiting writer: lock is only held once all readers are gone.
+pub fn write_with_backoff<T>(
+    lock: &RwLock<T>,
+    timeout: Duration,
+) -> Result<RwLockWriteGuard<'_, T>, LockError> {
+    let mut backoff = Backoff::new(timeout);
+    loop {
+        match lock.try_write() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(_)) => return Err(LockError::Poisoned),
+            Err(TryLockError::WouldBlock) => match backoff.next() {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(LockError::Timeout),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let delays = Backoff::with_delays(
+            Duration::from_millis(1),
+            Duration::from_millis(4),
+            Duration::from_millis(50),
+        )
+        .take(5)
+        .collect::<Vec<_>>();
+        assert_eq!(delays[..3], [1, 2, 4].map(Duration::from_millis));
+        assert!(delays.iter().all(|d| *d <= Duration::from_millis(4)));
+        assert_eq!(Backoff::new(Duration::ZERO).next(), None);
+    }
+
+    #[test]
+    fn test_lock_with_backoff_times_out() {
+        let mutex = Mutex::new(());
+        let _guard = mutex.lock().unwrap();
+        let start = Instant::now();
+        let res = lock_with_backoff(&mutex, Duration::from_millis(50));
+        assert_eq!(res.err(), Some(LockError::Timeout));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_lock_with_backoff_retries() {
+        let mutex = Arc::new(Mutex::new(()));
+        let guard = mutex.lock().unwrap();
+        let handle = {
+            let mutex = Arc::clone(&mutex);
+            std::thread::spawn(move || lock_with_backoff(&mutex, Duration::from_secs(5)).is_ok())
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_write_with_backoff_waits_for_readers() {
+        let lock = Arc::new(RwLock::new(()));
+        let first = lock.read().unwrap();
+        // readers share lock while writer keeps retrying
+        let second = lock.try_read().unwrap();
+        let res = write_with_backoff(&lock, Duration::from_millis(20));
+        assert_eq!(res.err(), Some(LockError::Timeout));
+        let handle = {
+            let lock = Arc::clone(&lock);
+            std::thread::spawn(move || write_with_backoff(&lock, Duration::from_secs(5)).is_ok())
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(lock.try_read().is_ok());
+        drop(first);
+        drop(second);
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_lock_with_backoff_poisoned() {
+        let mutex = Arc::new(Mutex::new(()));
+        {
+            let mutex = Arc::clone(&mutex);
+            std::thread::spawn(move || {
+                let _guard = mutex.lock().unwrap();
+                panic!("poison");
+            })
+            .join()
+            .ok();
+        }
+        let res = lock_with_backoff(&mutex, Duration::from_millis(10));
+        assert_eq!(res.err(), Some(LockError::Poisoned));
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+
+/// Pages written to database since last sync
+///
+/// Keeps pre-image from the first write and content of the last write of each page, so only
+/// net change of a transaction gets journaled. Pages restored to original content, e.g. by
+/// sqlite rolling back transaction which already spilled into database file, produce no diff.
+#[derive(Debug, Default)]
+pub struct PendingPages {
+    pages: BTreeMap<u64, (Vec<u8>, Vec<u8>)>,
+}
+
+/// Net change of a single page
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingPage {
+    pub offset: u64,
+    pub pre_image: Vec<u8>,
+    pub page: Vec<u8>,
+}
+
+impl PendingPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record page write
+    ///
+    /// `pre_image` is called only on first write to given offset.
+    /// Pre-image is zero-filled (or cut) to page size, same as pre-image of a new page.
+    /// Zero-length writes change nothing and aren't recorded.
+    pub fn write<E, F>(&mut self, offset: u64, page: &[u8], pre_image: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Result<Vec<u8>, E>,
+    {
+        if page.is_empty() {
+            return Ok(());
+        }
+        match self.pages.get_mut(&offset) {
+            Some((pre_image, current)) => {
+                pre_image.resize(page.len(), 0);
+                current.clear();
+                current.extend_from_slice(page);
+            }
+            None => {
+                let mut pre_image = pre_image()?;
+                pre_image.resize(page.len(), 0);
+                self.pages.insert(offset, (pre_image, page.to_vec()));
+            }
+        };
+        Ok(())
+    }
+
+    /// Record write of part of a page, e.g. sqlite updating only 100-byte database header
+    ///
+    /// Write is merged into pending page(s) of `page_size`, so it's journaled with the rest of
+    /// page changes and correct page size.
+    /// `pre_image` is called with page offset, only on first write to given page.
+    pub fn write_partial<E, F>(
+        &mut self,
+        page_size: usize,
+        offset: u64,
+        data: &[u8],
+        mut pre_image: F,
+    ) -> Result<(), E>
+    where
+        F: FnMut(u64) -> Result<Vec<u8>, E>,
+    {
+        let mut offset = offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let page_offset = offset - offset % page_size as u64;
+            let start = (offset - page_offset) as usize;
+            let len = data.len().min(page_size - start);
+            let (_, current) = match self.pages.get_mut(&page_offset) {
+                Some(entry) => entry,
+                None => {
+                    let mut pre_image = pre_image(page_offset)?;
+                    pre_image.resize(page_size, 0);
+                    let current = pre_image.clone();
+                    self.pages
+                        .entry(page_offset)
+                        .or_insert((pre_image, current))
+                }
+            };
+            current.resize(page_size, 0);
+            current[start..start + len].copy_from_slice(&data[..len]);
+            offset += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// Forget pages at or past `size`, e.g. written by transaction which sqlite rolled back by
+    /// truncating database to its original size
+    pub fn truncate(&mut self, size: u64) {
+        self.pages.split_off(&size);
+    }
+
+    /// Number of written pages
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Drain pages which content differs from pre-image, ordered by offset
+    pub fn drain_changed(&mut self) -> impl Iterator<Item = PendingPage> {
+        std::mem::take(&mut self.pages)
+            .into_iter()
+            .filter(|(_, (pre_image, page))| pre_image != page)
+            .map(|(offset, (pre_image, page))| PendingPage {
+                offset,
+                pre_image,
+                page,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_io() -> Result<Vec<u8>, ()> {
+        panic!("pre-image is read only once")
+    }
+
+    #[test]
+    fn test_pending_pages_net_change() {
+        let mut pending = PendingPages::new();
+        pending
+            .write(0, &[1; 4], || Ok::<_, ()>(vec![0; 4]))
+            .unwrap();
+        pending.write(0, &[2; 4], no_io).unwrap();
+        // new page, short read
+        pending.write(8, &[3; 4], || Ok::<_, ()>(vec![])).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            pending.drain_changed().collect::<Vec<_>>(),
+            vec![
+                PendingPage {
+                    offset: 0,
+                    pre_image: vec![0; 4],
+                    page: vec![2; 4]
+                },
+                PendingPage {
+                    offset: 8,
+                    pre_image: vec![0; 4],
+                    page: vec![3; 4]
+                },
+            ]
+        );
+        assert!(pending.is_empty());
+    }
+
+    // transaction spilled into database file and rolled back by sqlite
+    #[test]
+    fn test_pending_pages_rolled_back_transaction() {
+        let mut pending = PendingPages::new();
+        // cache spill
+        pending
+            .write(0, &[9; 4], || Ok::<_, ()>(vec![1; 4]))
+            .unwrap();
+        pending
+            .write(4, &[9; 4], || Ok::<_, ()>(vec![2; 4]))
+            .unwrap();
+        // rollback journal playback
+        pending.write(0, &[1; 4], no_io).unwrap();
+        pending.write(4, &[2; 4], no_io).unwrap();
+        assert_eq!(pending.drain_changed().count(), 0);
+    }
+
+    // pages appended by spilled transaction are cut by rollback
+    #[test]
+    fn test_pending_pages_truncate() {
+        let mut pending = PendingPages::new();
+        pending
+            .write(0, &[9; 4], || Ok::<_, ()>(vec![1; 4]))
+            .unwrap();
+        pending.write(4, &[9; 4], || Ok::<_, ()>(vec![])).unwrap();
+        pending.write(8, &[9; 4], || Ok::<_, ()>(vec![])).unwrap();
+        pending.write(0, &[1; 4], no_io).unwrap();
+        pending.truncate(4);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.drain_changed().count(), 0);
+    }
+
+    // sqlite updates database header separately from the rest of the first page
+    #[test]
+    fn test_pending_pages_header_write() {
+        let mut first_page = vec![1; 512];
+        let mut pending = PendingPages::new();
+        pending
+            .write_partial(512, 0, &[2; 100], |offset| {
+                assert_eq!(offset, 0);
+                Ok::<_, ()>(first_page.clone())
+            })
+            .unwrap();
+        // page body write keeps pre-image read on header write
+        let mut page = vec![2; 100];
+        page.resize(512, 3);
+        pending.write(0, &page, no_io).unwrap();
+        // header write after page body is merged
+        pending
+            .write_partial(512, 0, &[4; 100], |_| no_io())
+            .unwrap();
+        page[..100].copy_from_slice(&[4; 100]);
+        assert_eq!(
+            pending.drain_changed().collect::<Vec<_>>(),
+            vec![PendingPage {
+                offset: 0,
+                pre_image: first_page.clone(),
+                page,
+            }]
+        );
+
+        // write spanning page boundary
+        first_page.resize(1024, 1);
+        pending
+            .write_partial(512, 500, &[5; 24], |offset| {
+                Ok::<_, ()>(first_page[offset as usize..offset as usize + 512].to_vec())
+            })
+            .unwrap();
+        let pages = pending.drain_changed().collect::<Vec<_>>();
+        assert_eq!(
+            pages
+                .iter()
+                .map(|p| (p.offset, p.page.len()))
+                .collect::<Vec<_>>(),
+            vec![(0, 512), (512, 512)]
+        );
+        assert_eq!(&pages[0].page[500..], &[5; 12]);
+        assert_eq!(&pages[1].page[..12], &[5; 12]);
+        assert_eq!(&pages[1].page[12..], &[1; 500]);
+    }
+
+    // page-aligned write of two pages, e.g. 1024-byte write into database of 512-byte pages
+    #[test]
+    fn test_pending_pages_multi_page_write() {
+        let database = (0..2048).map(|i| (i / 512) as u8).collect::<Vec<_>>();
+        let pre_image =
+            |offset: u64| Ok::<_, ()>(database[offset as usize..offset as usize + 512].to_vec());
+        let mut pending = PendingPages::new();
+        // page written before multi-page write keeps its pre-image
+        pending.write(512, &[7; 512], || pre_image(512)).unwrap();
+        let mut data = vec![8; 1024];
+        data[1000..].fill(9);
+        pending.write_partial(512, 512, &data, pre_image).unwrap();
+        assert_eq!(
+            pending.drain_changed().collect::<Vec<_>>(),
+            vec![
+                PendingPage {
+                    offset: 512,
+                    pre_image: vec![1; 512],
+                    page: data[..512].to_vec(),
+                },
+                PendingPage {
+                    offset: 1024,
+                    pre_image: vec![2; 512],
+                    page: data[512..].to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_pages_zero_length_write() {
+        let mut pending = PendingPages::new();
+        pending.write(0, &[], no_io).unwrap();
+        pending.write_partial(512, 0, &[], |_| no_io()).unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_pending_pages_pre_image_error() {
+        let mut pending = PendingPages::new();
+        assert_eq!(pending.write(0, &[1; 4], || Err("io")), Err("io"));
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Keyed registry of shared values with bounded number of unused entries
+///
+/// Entry is in use while anyone besides registry holds its `Arc`, such entries are never evicted.
+/// Once number of unused entries grows over `capacity`, least recently used ones are dropped.
+#[derive(Debug)]
+pub struct LruRegistry<V> {
+    capacity: usize,
+    tick: u64,
+    entries: BTreeMap<String, (u64, Arc<V>)>,
+}
+
+impl<V> LruRegistry<V> {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Get value by key, inserting value produced by `f` if key is missing
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: &str, f: F) -> Arc<V> {
+        match self.try_get_or_insert_with(key, || Ok::<_, std::convert::Infallible>(f())) {
+            Ok(value) => value,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Get value by key, inserting value produced by `f` if key is missing
+    ///
+    /// Error of `f` is returned as is, registry is left unchanged.
+    pub fn try_get_or_insert_with<F, E>(&mut self, key: &str, f: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Result<V, E>,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        let value = match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.0 = tick;
+                Arc::clone(&entry.1)
+            }
+            None => {
+                let value = Arc::new(f()?);
+                self.entries.insert(key.into(), (tick, Arc::clone(&value)));
+                value
+            }
+        };
+        self.evict();
+        Ok(value)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Arc<V>> {
+        self.entries.remove(key).map(|(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict(&mut self) {
+        let mut unused = self
+            .entries
+            .iter()
+            .filter(|(_, (_, value))| Arc::strong_count(value) == 1)
+            .map(|(key, (tick, _))| (*tick, key.clone()))
+            .collect::<Vec<_>>();
+        if unused.len() <= self.capacity {
+            return;
+        }
+        unused.sort_unstable();
+        let excess = unused.len() - self.capacity;
+        for (_, key) in unused.into_iter().take(excess) {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_is_bounded() {
+        let mut registry = LruRegistry::new(4);
+        for i in 0..1000 {
+            let key = format!("db-{i}");
+            let value = registry.get_or_insert_with(&key, || i);
+            assert_eq!(*value, i);
+            drop(value);
+        }
+        assert!(registry.len() <= 5, "{}", registry.len());
+    }
+
+    #[test]
+    fn test_registry_keeps_entries_in_use() {
+        let mut registry = LruRegistry::new(0);
+        let held = (0..10)
+            .map(|i| registry.get_or_insert_with(&format!("db-{i}"), || i))
+            .collect::<Vec<_>>();
+        for i in 10..100 {
+            registry.get_or_insert_with(&format!("db-{i}"), || i);
+        }
+        assert!((0..10).all(|i| registry.contains_key(&format!("db-{i}"))));
+        // same value is handed out while entry is in use
+        assert!(Arc::ptr_eq(
+            &held[0],
+            &registry.get_or_insert_with("db-0", || 42)
+        ));
+
+        drop(held);
+        registry.get_or_insert_with("db-100", || 100);
+        assert!(registry.len() <= 1, "{}", registry.len());
+    }
+
+    #[test]
+    fn test_registry_evicts_least_recently_used() {
+        let mut registry = LruRegistry::new(1);
+        registry.get_or_insert_with("a", || 1);
+        registry.get_or_insert_with("b", || 2);
+        // touch "a", so "b" becomes least recently used
+        registry.get_or_insert_with("a", || 0);
+        registry.get_or_insert_with("c", || 3);
+        assert!(registry.contains_key("a"));
+        assert!(!registry.contains_key("b"));
+        assert!(registry.contains_key("c"));
+        assert_eq!(*registry.get_or_insert_with("a", || 0), 1);
+    }
+
+    #[test]
+    fn test_registry_insert_error() {
+        let mut registry = LruRegistry::new(1);
+        let res = registry.try_get_or_insert_with("a", || Err("failed"));
+        assert_eq!(res, Err("failed"));
+        assert!(!registry.contains_key("a"));
+        let value = registry.try_get_or_insert_with("a", || Ok::<_, &str>(1));
+        assert_eq!(value.map(|value| *value), Ok(1));
+        // existing value is returned, without calling `f`
+        let value = registry.try_get_or_insert_with("a", || Err("unused"));
+        assert_eq!(value.map(|value| *value), Ok(1));
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// File contents, staged in temporary file before target file is updated
+///
+/// Temporary file is named `<target file name>.tmp` and lives in `tmp_dir`, or next to target.
+/// Interrupted staging leaves target intact, temporary file is removed on drop.
+///
+/// Target is updated in place instead of being replaced by rename, since file can be open,
+/// and open handles would keep pointing to replaced file.
+#[derive(Debug)]
+pub struct StagedFile {
+    target: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl StagedFile {
+    pub fn new<P: AsRef<Path>>(target: P, tmp_dir: Option<&Path>) -> Self {
+        let target = target.as_ref().to_path_buf();
+        let mut file_name = target.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".tmp");
+        let tmp_path = match tmp_dir {
+            Some(tmp_dir) => tmp_dir.join(file_name),
+            None => target.with_file_name(file_name),
+        };
+        Self { target, tmp_path }
+    }
+
+    /// Path of temporary file
+    pub fn tmp_path(&self) -> &Path {
+        self.tmp_path.as_path()
+    }
+
+    /// Create empty temporary file
+    pub fn create(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.tmp_path)
+    }
+
+    /// Copy staged contents over target, target is truncated to staged length
+    pub fn commit(self) -> io::Result<()> {
+        let mut staged = File::open(&self.tmp_path)?;
+        let len = staged.metadata()?.len();
+        let mut target = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.target)?;
+        io::copy(&mut staged, &mut target)?;
+        target.set_len(len)?;
+        target.sync_all()
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.tmp_path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("staged-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_staged_file_commit() {
+        let dir = test_dir("commit");
+        let target = dir.join("db");
+        fs::write(&target, [1; 100]).unwrap();
+
+        let staged = StagedFile::new(&target, None);
+        assert_eq!(staged.tmp_path(), dir.join("db.tmp"));
+        staged.create().unwrap().write_all(&[2; 10]).unwrap();
+        staged.commit().unwrap();
+        // target is truncated to staged length
+        assert_eq!(fs::read(&target).unwrap(), vec![2; 10]);
+        assert!(!dir.join("db.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_staged_file_interrupted() {
+        let dir = test_dir("interrupted");
+        let tmp_dir = dir.join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let target = dir.join("db");
+        fs::write(&target, [1; 100]).unwrap();
+
+        let staged = StagedFile::new(&target, Some(&tmp_dir));
+        assert_eq!(staged.tmp_path(), tmp_dir.join("db.tmp"));
+        staged.create().unwrap().write_all(&[2; 10]).unwrap();
+        drop(staged);
+        assert_eq!(fs::read(&target).unwrap(), vec![1; 100]);
+        assert!(!tmp_dir.join("db.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
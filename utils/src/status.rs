@@ -0,0 +1,261 @@
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Encoding of absent snapshot id
+const NONE: u64 = u64::MAX;
+
+/// Replicator status, updated by replicator thread and read concurrently by anyone
+///
+/// Counters are updated lock-free, consistent view over them is provided by
+/// [`ReplicatorStatus::get`] through sequence lock. Only `last_error` is guarded by mutex.
+///
+/// Status assumes single writer (replicator thread), concurrent updates are not synchronized.
+#[derive(Debug)]
+pub struct ReplicatorStatus {
+    /// sequence counter, odd while update is in progress
+    seq: AtomicU64,
+    local_id: AtomicU64,
+    remote_id: AtomicU64,
+    last_push_ok: AtomicBool,
+    unconfirmed_snapshots: AtomicU64,
+    unconfirmed_bytes: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+/// Consistent view over replicator status counters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicatorStatusSnapshot {
+    /// last local snapshot id
+    pub local_id: Option<u64>,
+    /// last snapshot id seen by sync backend
+    pub remote_id: Option<u64>,
+    /// result of last push attempt
+    pub last_push_ok: bool,
+    /// count of local snapshots, not confirmed by sync backend yet
+    pub unconfirmed_snapshots: u64,
+    /// total size of blobs of unconfirmed snapshots
+    pub unconfirmed_bytes: u64,
+}
+
+impl Default for ReplicatorStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplicatorStatus {
+    pub fn new() -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            local_id: AtomicU64::new(NONE),
+            remote_id: AtomicU64::new(NONE),
+            last_push_ok: AtomicBool::new(false),
+            unconfirmed_snapshots: AtomicU64::new(0),
+            unconfirmed_bytes: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    /// Read consistent view over status counters
+    pub fn get(&self) -> ReplicatorStatusSnapshot {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let snapshot = ReplicatorStatusSnapshot {
+                local_id: decode(self.local_id.load(Ordering::Relaxed)),
+                remote_id: decode(self.remote_id.load(Ordering::Relaxed)),
+                last_push_ok: self.last_push_ok.load(Ordering::Relaxed),
+                unconfirmed_snapshots: self.unconfirmed_snapshots.load(Ordering::Relaxed),
+                unconfirmed_bytes: self.unconfirmed_bytes.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return snapshot;
+            }
+        }
+    }
+
+    /// Update local and remote snapshot ids
+    pub fn set_ids(&self, local_id: Option<u64>, remote_id: Option<u64>) {
+        self.update(|| {
+            self.local_id.store(encode(local_id), Ordering::Relaxed);
+            self.remote_id.store(encode(remote_id), Ordering::Relaxed);
+        })
+    }
+
+    /// Update result of last push
+    pub fn set_last_push_ok(&self, ok: bool) {
+        self.update(|| self.last_push_ok.store(ok, Ordering::Relaxed))
+    }
+
+    /// Update count and size of snapshots, not confirmed by sync backend yet
+    pub fn set_unconfirmed(&self, snapshots: u64, bytes: u64) {
+        self.update(|| {
+            self.unconfirmed_snapshots
+                .store(snapshots, Ordering::Relaxed);
+            self.unconfirmed_bytes.store(bytes, Ordering::Relaxed);
+        })
+    }
+
+    /// Wait until sync backend lags less than `max` snapshots behind `snapshot_counter` of local
+    /// journal, polling status every `poll`
+    ///
+    /// Backend lag is derived from remote id, which is updated on every push, rather than from
+    /// unconfirmed count, which is only recomputed after push. Returns `false`, if backend still
+    /// lags `max` or more snapshots behind after `timeout`.
+    pub fn wait_remote_within(
+        &self,
+        snapshot_counter: u64,
+        max: u64,
+        poll: Duration,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remote_id = self.get().remote_id.unwrap_or(0);
+            if snapshot_counter.saturating_sub(remote_id) < max {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            std::thread::sleep(poll.min(deadline - now));
+        }
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    pub fn set_last_error(&self, error: Option<String>) {
+        *self.last_error.lock().unwrap() = error;
+    }
+
+    fn update<F: FnOnce()>(&self, f: F) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        f();
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+fn encode(id: Option<u64>) -> u64 {
+    id.unwrap_or(NONE)
+}
+
+fn decode(id: u64) -> Option<u64> {
+    match id {
+        NONE => None,
+        id => Some(id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_status_defaults() {
+        let status = ReplicatorStatus::new();
+        assert_eq!(status.get(), ReplicatorStatusSnapshot::default());
+        assert_eq!(status.last_error(), None);
+
+        status.set_ids(Some(2), None);
+        status.set_last_push_ok(true);
+        status.set_unconfirmed(2, 100);
+        status.set_last_error(Some("error".into()));
+        assert_eq!(
+            status.get(),
+            ReplicatorStatusSnapshot {
+                local_id: Some(2),
+                remote_id: None,
+                last_push_ok: true,
+                unconfirmed_snapshots: 2,
+                unconfirmed_bytes: 100,
+            }
+        );
+        assert_eq!(status.last_error().as_deref(), Some("error"));
+    }
+
+    // slow backend confirms one snapshot per tick, writer waits for it once limit is reached
+    #[test]
+    fn test_status_wait_remote_within() {
+        let status = Arc::new(ReplicatorStatus::new());
+        let produced = Arc::new(AtomicU64::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let backend = {
+            let (status, produced, done) = (
+                Arc::clone(&status),
+                Arc::clone(&produced),
+                Arc::clone(&done),
+            );
+            std::thread::spawn(move || {
+                let (mut confirmed, mut max_unconfirmed) = (0, 0);
+                while !done.load(Ordering::Relaxed) {
+                    let local = produced.load(Ordering::Relaxed);
+                    max_unconfirmed = max_unconfirmed.max(local - confirmed);
+                    confirmed += (local > confirmed) as u64;
+                    status.set_ids(Some(local), Some(confirmed));
+                    std::thread::sleep(Duration::from_millis(2));
+                }
+                max_unconfirmed
+            })
+        };
+
+        let (max, poll, timeout) = (4, Duration::from_millis(1), Duration::from_secs(10));
+        let start = Instant::now();
+        for _ in 0..50 {
+            let snapshot_counter = produced.load(Ordering::Relaxed);
+            assert!(status.wait_remote_within(snapshot_counter, max, poll, timeout));
+            produced.fetch_add(1, Ordering::Relaxed);
+        }
+        let elapsed = start.elapsed();
+        done.store(true, Ordering::Relaxed);
+        let max_unconfirmed = backend.join().unwrap();
+        // writer is throttled to backend pace, instead of running ahead of it
+        assert!(
+            elapsed >= Duration::from_millis(2 * (50 - max)),
+            "{elapsed:?}"
+        );
+        assert!(max_unconfirmed <= max, "{max_unconfirmed}");
+
+        // backend, which doesn't catch up, times out
+        let timeout = Duration::from_millis(10);
+        assert!(!status.wait_remote_within(100, max, poll, timeout));
+    }
+
+    // writer keeps local and remote ids equal, reader should never observe them diverged
+    #[test]
+    fn test_status_no_torn_reads() {
+        let status = Arc::new(ReplicatorStatus::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (status, done) = (Arc::clone(&status), Arc::clone(&done));
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                loop {
+                    let snapshot = status.get();
+                    assert_eq!(snapshot.local_id, snapshot.remote_id);
+                    reads += 1;
+                    if done.load(Ordering::Relaxed) {
+                        return reads;
+                    }
+                }
+            })
+        };
+        for id in 0..200_000 {
+            status.set_ids(Some(id), Some(id));
+        }
+        done.store(true, Ordering::Relaxed);
+        assert!(reader.join().unwrap() > 0);
+        assert_eq!(status.get().local_id, Some(199_999));
+    }
+}